@@ -22,4 +22,28 @@ fn main() {
     ], &[
         "opentelemetry-proto"
     ]).unwrap();
+    if std::env::var_os("CARGO_FEATURE_PROMETHEUS").is_some() {
+        tonic_build::configure()
+            .build_client(false)
+            .build_server(false)
+            .compile(
+                &["prometheus-proto/prompb/remote.proto"],
+                &["prometheus-proto"],
+            )
+            .unwrap();
+    }
+    if std::env::var_os("CARGO_FEATURE_ZIPKIN").is_some() {
+        tonic_build::configure()
+            .build_client(false)
+            .build_server(false)
+            .compile(&["zipkin-proto/zipkin.proto"], &["zipkin-proto"])
+            .unwrap();
+    }
+    if std::env::var_os("CARGO_FEATURE_JAEGER").is_some() {
+        tonic_build::configure()
+            .build_client(true)
+            .build_server(true)
+            .compile(&["jaeger-proto/collector.proto"], &["jaeger-proto"])
+            .unwrap();
+    }
 }
@@ -27,12 +27,22 @@ pub type OpenTelemetryReceiver = Receiver<OpenTelemetryEvents>;
 /// Creates a logs service with the specified asynchronous sender channel
 pub struct LogsServiceForwarder {
     channel: OpenTelemetrySender,
+    processors: crate::logs::LogsProcessorChain,
 }
 
 impl LogsServiceForwarder {
     /// Creates a logs service forwarding agent
     pub fn with_sender(channel: OpenTelemetrySender) -> Self {
-        LogsServiceForwarder { channel }
+        LogsServiceForwarder {
+            channel,
+            processors: crate::logs::LogsProcessorChain::new(),
+        }
+    }
+
+    /// Runs the processor chain on every request before it is forwarded
+    pub fn with_processors(mut self, processors: crate::logs::LogsProcessorChain) -> Self {
+        self.processors = processors;
+        self
     }
 }
 
@@ -42,12 +52,21 @@ impl crate::logs::LogsService for LogsServiceForwarder {
         &self,
         request: tonic::Request<logs_base::ExportLogsServiceRequest>,
     ) -> Result<tonic::Response<logs_base::ExportLogsServiceResponse>, tonic::Status> {
-        match self.channel.send(OpenTelemetryEvents::from(request)).await {
+        let remote = request.remote_addr();
+        let mut request = request.into_inner();
+        let disposition = self.processors.process(&mut request).await?;
+        if request.resource_logs.is_empty() {
+            return Ok(tonic::Response::new(logs_base::ExportLogsServiceResponse {
+                partial_success: Some(disposition.into()),
+            }));
+        }
+        match self
+            .channel
+            .send(OpenTelemetryEvents::Logs(request, remote))
+            .await
+        {
             Ok(_) => Ok(tonic::Response::new(logs_base::ExportLogsServiceResponse {
-                partial_success: Some(logs_base::ExportLogsPartialSuccess {
-                    rejected_log_records: 0,
-                    error_message: "Ok".to_string(),
-                }),
+                partial_success: Some(disposition.into()),
             })),
             Err(e) => Err(tonic::Status::internal(format!(
                 "Logs gRPC forwarder channel sender failed to dispatch {}",
@@ -60,12 +79,22 @@ impl crate::logs::LogsService for LogsServiceForwarder {
 /// Creates a metrics service with the specified asynchronous sender channel
 pub struct MetricsServiceForwarder {
     channel: OpenTelemetrySender,
+    processors: crate::metrics::MetricsProcessorChain,
 }
 
 impl MetricsServiceForwarder {
     /// Creates a metrics service forwarding agent
     pub fn with_sender(channel: OpenTelemetrySender) -> Self {
-        MetricsServiceForwarder { channel }
+        MetricsServiceForwarder {
+            channel,
+            processors: crate::metrics::MetricsProcessorChain::new(),
+        }
+    }
+
+    /// Runs the processor chain on every request before it is forwarded
+    pub fn with_processors(mut self, processors: crate::metrics::MetricsProcessorChain) -> Self {
+        self.processors = processors;
+        self
     }
 }
 
@@ -75,13 +104,24 @@ impl crate::metrics::MetricsService for MetricsServiceForwarder {
         &self,
         request: tonic::Request<metrics_base::ExportMetricsServiceRequest>,
     ) -> Result<tonic::Response<metrics_base::ExportMetricsServiceResponse>, tonic::Status> {
-        match self.channel.send(OpenTelemetryEvents::from(request)).await {
+        let remote = request.remote_addr();
+        let mut request = request.into_inner();
+        let disposition = self.processors.process(&mut request).await?;
+        if request.resource_metrics.is_empty() {
+            return Ok(tonic::Response::new(
+                metrics_base::ExportMetricsServiceResponse {
+                    partial_success: Some(disposition.into()),
+                },
+            ));
+        }
+        match self
+            .channel
+            .send(OpenTelemetryEvents::Metrics(request, remote))
+            .await
+        {
             Ok(_) => Ok(tonic::Response::new(
                 metrics_base::ExportMetricsServiceResponse {
-                    partial_success: Some(metrics_base::ExportMetricsPartialSuccess {
-                        rejected_data_points: 0,
-                        error_message: "snot".to_string(),
-                    }),
+                    partial_success: Some(disposition.into()),
                 },
             )),
             Err(e) => Err(tonic::Status::internal(format!(
@@ -95,12 +135,22 @@ impl crate::metrics::MetricsService for MetricsServiceForwarder {
 /// Creates a trace service with the specified asynchronous sender channel
 pub struct TraceServiceForwarder {
     channel: OpenTelemetrySender,
+    processors: crate::trace::TraceProcessorChain,
 }
 
 impl TraceServiceForwarder {
     /// Creates a trace service forwarding agent
     pub fn with_sender(channel: OpenTelemetrySender) -> Self {
-        TraceServiceForwarder { channel }
+        TraceServiceForwarder {
+            channel,
+            processors: crate::trace::TraceProcessorChain::new(),
+        }
+    }
+
+    /// Runs the processor chain on every request before it is forwarded
+    pub fn with_processors(mut self, processors: crate::trace::TraceProcessorChain) -> Self {
+        self.processors = processors;
+        self
     }
 }
 
@@ -110,13 +160,24 @@ impl trace_base::trace_service_server::TraceService for TraceServiceForwarder {
         &self,
         request: tonic::Request<trace_base::ExportTraceServiceRequest>,
     ) -> Result<tonic::Response<trace_base::ExportTraceServiceResponse>, tonic::Status> {
-        match self.channel.send(OpenTelemetryEvents::from(request)).await {
+        let remote = request.remote_addr();
+        let mut request = request.into_inner();
+        let disposition = self.processors.process(&mut request).await?;
+        if request.resource_spans.is_empty() {
+            return Ok(tonic::Response::new(
+                trace_base::ExportTraceServiceResponse {
+                    partial_success: Some(disposition.into()),
+                },
+            ));
+        }
+        match self
+            .channel
+            .send(OpenTelemetryEvents::Trace(request, remote))
+            .await
+        {
             Ok(_) => Ok(tonic::Response::new(
                 trace_base::ExportTraceServiceResponse {
-                    partial_success: Some(trace_base::ExportTracePartialSuccess {
-                        rejected_spans: 0,
-                        error_message: "snot".to_string(),
-                    }),
+                    partial_success: Some(disposition.into()),
                 },
             )),
             Err(e) => Err(tonic::Status::internal(format!(
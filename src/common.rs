@@ -12,20 +12,300 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-/// This modules defines the OpenTelemetry gRPC service definitions introduced
-/// in v0.19 which make Otel gRPC responses fallible and transporting error context
-/// back to the requestor. In prior versions of Otel, the gRPC responses contained
-/// no error context and were always successful.
-///
-/// The change from infallible to fallible is a major breaking change forcing implementors
-/// to course correct their handlers to return errors. This module standardises this handling
-/// and provides a simple way to handle the error context in a uniform way as far as use in
-/// tremor is concerned.
-///
+//! Facilities shared across the crate: the [`FallibleOtelResponse`] wrapper for
+//! the fallible OpenTelemetry gRPC responses introduced in v0.19, splitting of
+//! requests by message size, timestamp parsing, attribute helpers and, in
+//! [`receiver`], what the protocol receivers have in common.
+//!
+//! Prior to v0.19 the gRPC responses contained no error context and were always
+//! successful. The change from infallible to fallible is a major breaking change
+//! forcing implementors to course correct their handlers to return errors; the
+//! wrapper standardises this handling so that the error context is dealt with
+//! in a uniform way as far as use in tremor is concerned.
+
 use crate::opentelemetry::proto::collector::{
     logs::v1::ExportLogsServiceResponse, metrics::v1::ExportMetricsServiceResponse,
     trace::v1::ExportTraceServiceResponse,
 };
+use crate::opentelemetry::proto::common::v1::{
+    any_value, AnyValue, InstrumentationScope, KeyValue,
+};
+use crate::opentelemetry::proto::resource::v1::Resource;
+use prost::Message;
+
+/// What the protocol receivers have in common
+#[cfg(any(
+    feature = "statsd",
+    feature = "line-protocol",
+    feature = "syslog",
+    feature = "filelog",
+    feature = "jaeger",
+    feature = "zipkin-receiver"
+))]
+pub mod receiver;
+
+/// Resource presented by the iterators when an envelope has no resource set
+pub(crate) static EMPTY_RESOURCE: Resource = Resource {
+    attributes: Vec::new(),
+    dropped_attributes_count: 0,
+};
+
+/// Instrumentation scope presented by the iterators when an envelope has no scope set
+pub(crate) static EMPTY_SCOPE: InstrumentationScope = InstrumentationScope {
+    name: String::new(),
+    version: String::new(),
+    attributes: Vec::new(),
+    dropped_attributes_count: 0,
+};
+
+/// Renders bytes such as trace and span ids as lowercase hex
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+pub(crate) const SECONDS_PER_DAY: i64 = 86_400;
+
+/// The number of days from the epoch to a date of the proleptic Gregorian
+/// calendar
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The number of days in a month of the proleptic Gregorian calendar
+pub(crate) fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parses a fixed number of digits
+fn digits(s: &str, count: usize) -> Option<(i64, &str)> {
+    let number = s.get(..count)?;
+    if !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((number.parse().ok()?, s.get(count..)?))
+}
+
+/// Parses a time of day, `HH:MM:SS`, into seconds and the rest
+pub(crate) fn time_of_day(s: &str) -> Option<(i64, &str)> {
+    let (hours, s) = digits(s, 2)?;
+    let (minutes, s) = digits(s.strip_prefix(':')?, 2)?;
+    let (seconds, s) = digits(s.strip_prefix(':')?, 2)?;
+    (hours < 24 && minutes < 60 && seconds < 61)
+        .then_some((hours * 3600 + minutes * 60 + seconds, s))
+}
+
+/// Nanoseconds since the epoch of a point in time, `None` before the epoch
+pub(crate) fn to_nanos(seconds: i64, nanos: i64) -> Option<u64> {
+    u64::try_from(seconds.checked_mul(NANOS_PER_SECOND)?.checked_add(nanos)?).ok()
+}
+
+/// Parses an RFC 3339 timestamp, such as `2003-10-11T22:14:15.003Z`, into
+/// nanoseconds since the epoch. `None` if it is malformed or before the epoch.
+pub fn parse_rfc3339(s: &str) -> Option<u64> {
+    let (year, s) = digits(s, 4)?;
+    let (month, s) = digits(s.strip_prefix('-')?, 2)?;
+    let (day, s) = digits(s.strip_prefix('-')?, 2)?;
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+    let (time, s) = time_of_day(s.strip_prefix(['T', 't'])?)?;
+    let (nanos, s) = match s.strip_prefix('.') {
+        Some(fraction) => {
+            let length = fraction.bytes().take_while(u8::is_ascii_digit).count();
+            if length == 0 {
+                return None;
+            }
+            let (fraction, rest) = fraction.split_at(length);
+            // only nanosecond precision is kept
+            let padded = format!("{:0<9}", fraction.get(..9).unwrap_or(fraction));
+            (padded.parse::<i64>().ok()?, rest)
+        }
+        None => (0, s),
+    };
+    let offset = match s {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match s.get(..1)? {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let (hours, rest) = digits(s.get(1..)?, 2)?;
+            let (minutes, rest) = digits(rest.strip_prefix(':')?, 2)?;
+            if !rest.is_empty() {
+                return None;
+            }
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+    let seconds = days_from_civil(year, month, day) * SECONDS_PER_DAY + time - offset;
+    to_nanos(seconds, nanos)
+}
+
+/// Appends a string to `out` as a JSON string literal
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Appends an attribute value to `out` as JSON
+fn push_json(out: &mut String, value: Option<&any_value::Value>) {
+    match value {
+        Some(any_value::Value::StringValue(s)) => push_json_string(out, s),
+        Some(any_value::Value::BytesValue(b)) => push_json_string(out, &hex(b)),
+        Some(any_value::Value::ArrayValue(a)) => {
+            out.push('[');
+            for (i, v) in a.values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                push_json(out, v.value.as_ref());
+            }
+            out.push(']');
+        }
+        Some(any_value::Value::KvlistValue(kvs)) => {
+            out.push('{');
+            for (i, kv) in kvs.values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                push_json_string(out, &kv.key);
+                out.push(':');
+                push_json(out, kv.value.as_ref().and_then(|v| v.value.as_ref()));
+            }
+            out.push('}');
+        }
+        Some(any_value::Value::DoubleValue(d)) if d.is_finite() => out.push_str(&d.to_string()),
+        Some(any_value::Value::DoubleValue(d)) => push_json_string(out, &d.to_string()),
+        Some(any_value::Value::IntValue(i)) => out.push_str(&i.to_string()),
+        Some(any_value::Value::BoolValue(b)) => out.push_str(&b.to_string()),
+        None => out.push_str("null"),
+    }
+}
+
+/// Renders an attribute value as a string for formats without typed values.
+/// Strings are rendered as they are, bytes as hex and arrays and maps as JSON.
+pub fn any_value_to_string(value: &AnyValue) -> String {
+    match &value.value {
+        Some(any_value::Value::StringValue(s)) => s.clone(),
+        Some(any_value::Value::BytesValue(b)) => hex(b),
+        Some(any_value::Value::DoubleValue(d)) => d.to_string(),
+        Some(any_value::Value::IntValue(i)) => i.to_string(),
+        Some(any_value::Value::BoolValue(b)) => b.to_string(),
+        other => {
+            let mut out = String::new();
+            push_json(&mut out, other.as_ref());
+            out
+        }
+    }
+}
+
+/// Finds the first element of `items` that matches the predicate, appending the
+/// element produced by `make` when none does
+pub(crate) fn find_or_push<T, P, M>(items: &mut Vec<T>, is_match: P, make: M) -> &mut T
+where
+    P: Fn(&T) -> bool,
+    M: FnOnce() -> T,
+{
+    let idx = match items.iter().position(is_match) {
+        Some(idx) => idx,
+        None => {
+            items.push(make());
+            items.len() - 1
+        }
+    };
+    // ALLOW: idx is either a found position or the index of the element just pushed
+    &mut items[idx]
+}
+
+/// Checks if two attribute lists carry the same attributes in any order,
+/// repeated attributes have to be repeated as often in both
+fn same_attributes(a: &[KeyValue], b: &[KeyValue]) -> bool {
+    // attribute values have no order, their encodings do
+    let sorted = |attributes: &[KeyValue]| {
+        let mut encoded: Vec<Vec<u8>> = attributes.iter().map(Message::encode_to_vec).collect();
+        encoded.sort_unstable();
+        encoded
+    };
+    a.len() == b.len() && sorted(a) == sorted(b)
+}
+
+/// Checks if two envelopes carry the same resource, regardless of the order
+/// of its attributes
+pub(crate) fn same_resource(a: Option<&Resource>, b: Option<&Resource>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            a.dropped_attributes_count == b.dropped_attributes_count
+                && same_attributes(&a.attributes, &b.attributes)
+        }
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+/// Checks if two envelopes carry the same instrumentation scope, regardless of
+/// the order of its attributes
+pub(crate) fn same_scope(
+    a: Option<&InstrumentationScope>,
+    b: Option<&InstrumentationScope>,
+) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            a.name == b.name
+                && a.version == b.version
+                && a.dropped_attributes_count == b.dropped_attributes_count
+                && same_attributes(&a.attributes, &b.attributes)
+        }
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+/// Nanoseconds since the epoch, times before it are taken to be the epoch
+pub(crate) fn nanos(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_nanos()).unwrap_or(u64::MAX))
+}
+
+/// A string value, such as a log record body
+pub(crate) fn any_string(value: &str) -> AnyValue {
+    AnyValue {
+        value: Some(any_value::Value::StringValue(value.to_string())),
+    }
+}
+
+/// An attribute of any value
+pub(crate) fn key_value(key: &str, value: AnyValue) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(value),
+    }
+}
+
+/// A string attribute
+pub(crate) fn string_value(key: &str, value: &str) -> KeyValue {
+    key_value(key, any_string(value))
+}
 
 /// Prior to v0.19, responses were infallible. Since v0.19, they propagate error context.
 /// This struct is a convenience wrapper to make handling the error context easier to
@@ -62,6 +342,178 @@ impl FallibleOtelResponse {
     pub fn is_ok(&self) -> bool {
         self.rejected_logs == 0 && self.rejected_metrics == 0 && self.rejected_spans == 0
     }
+
+    /// Folds the response of another request into this one, summing rejected counts
+    /// and joining distinct non-empty error messages. Once a response rejected
+    /// anything, the messages of responses that rejected nothing are skipped.
+    pub fn merge(&mut self, other: FallibleOtelResponse) {
+        match (self.is_ok(), other.is_ok()) {
+            (true, false) => self.error_message.clear(),
+            (false, true) => return,
+            _ => (),
+        }
+        self.rejected_logs += other.rejected_logs;
+        self.rejected_metrics += other.rejected_metrics;
+        self.rejected_spans += other.rejected_spans;
+        if !other.error_message.is_empty()
+            && !self
+                .error_message
+                .split("; ")
+                .any(|m| m == other.error_message)
+        {
+            if !self.error_message.is_empty() {
+                self.error_message.push_str("; ");
+            }
+            self.error_message.push_str(&other.error_message);
+        }
+    }
+}
+
+/// Combines the responses for the chunks of a split request into a single response
+impl FromIterator<FallibleOtelResponse> for FallibleOtelResponse {
+    fn from_iter<T: IntoIterator<Item = FallibleOtelResponse>>(iter: T) -> Self {
+        iter.into_iter()
+            .fold(Self::new(0, 0, 0, String::new()), |mut acc, r| {
+                acc.merge(r);
+                acc
+            })
+    }
+}
+
+/// The default maximum message size accepted by tonic and most OpenTelemetry backends
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Limits applied when splitting an export request into several smaller ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SplitLimits {
+    /// Upper bound for the encoded size of each chunk in bytes
+    pub max_bytes: Option<usize>,
+    /// Upper bound for the number of items ( log records, data points or spans ) in each chunk
+    pub max_items: Option<usize>,
+}
+
+impl SplitLimits {
+    /// Creates split limits bounding the encoded size of each chunk
+    pub fn with_max_bytes(max_bytes: usize) -> Self {
+        Self {
+            max_bytes: Some(max_bytes),
+            max_items: None,
+        }
+    }
+
+    /// Creates split limits bounding the number of items of each chunk
+    pub fn with_max_items(max_items: usize) -> Self {
+        Self {
+            max_bytes: None,
+            max_items: Some(max_items),
+        }
+    }
+
+    /// Checks if a chunk of the given size and item count would exceed the limits
+    pub fn exceeded_by(&self, bytes: usize, items: usize) -> bool {
+        self.max_bytes.is_some_and(|max| bytes > max)
+            || self.max_items.is_some_and(|max| items > max)
+    }
+}
+
+/// Upper bound of the encoded length of a length delimited field holding an
+/// envelope that is still to be filled, as its length prefix can grow up to
+/// the maximum varint length
+fn envelope_len(len: usize) -> usize {
+    1 + 10 + len
+}
+
+/// Encoded length of a length delimited field holding a message of `len` bytes
+pub(crate) fn field_len(len: usize) -> usize {
+    1 + prost::length_delimiter_len(len) + len
+}
+
+/// Closes the scope envelope that is currently filled into its resource envelope
+fn close_scope<R, S>(
+    resource: &mut Option<R>,
+    scope: &mut Option<S>,
+    scopes_of: fn(&mut R) -> &mut Vec<S>,
+) {
+    if let (Some(r), Some(s)) = (resource.as_mut(), scope.take()) {
+        scopes_of(r).push(s);
+    }
+}
+
+/// Splits resource envelopes holding scope envelopes holding items into chunks that
+/// honour the split limits. Envelopes are duplicated into every chunk that carries
+/// some of their items, envelopes without items are dropped.
+///
+/// `split_item` breaks up an item that on its own exceeds the limits it is given,
+/// which are the chunk limits less the overhead of its envelopes. Items that
+/// can't be broken up further end up in a chunk on their own.
+pub(crate) fn split_envelopes<R, S, I>(
+    resources: Vec<R>,
+    limits: &SplitLimits,
+    scopes_of: fn(&mut R) -> &mut Vec<S>,
+    items_of: fn(&mut S) -> &mut Vec<I>,
+    weight_of: fn(&I) -> usize,
+    split_item: impl Fn(I, &SplitLimits) -> Vec<I>,
+) -> Vec<Vec<R>>
+where
+    R: Message + Clone,
+    S: Message + Clone,
+    I: Message,
+{
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut bytes = 0;
+    let mut count = 0;
+    for mut resource in resources {
+        let scopes = std::mem::take(scopes_of(&mut resource));
+        let resource_cost = envelope_len(resource.encoded_len());
+        let mut open_resource: Option<R> = None;
+        for mut scope in scopes {
+            let items = std::mem::take(items_of(&mut scope));
+            let scope_cost = envelope_len(scope.encoded_len());
+            let item_limits = SplitLimits {
+                max_bytes: limits
+                    .max_bytes
+                    .map(|max| max.saturating_sub(resource_cost + scope_cost)),
+                max_items: limits.max_items,
+            };
+            let mut open_scope: Option<S> = None;
+            for item in items.into_iter().flat_map(|i| split_item(i, &item_limits)) {
+                let cost = field_len(item.encoded_len());
+                let weight = weight_of(&item);
+                let mut extra = cost;
+                if open_scope.is_none() {
+                    extra += scope_cost;
+                }
+                if open_resource.is_none() {
+                    extra += resource_cost;
+                }
+                if count > 0 && limits.exceeded_by(bytes + extra, count + weight) {
+                    close_scope(&mut open_resource, &mut open_scope, scopes_of);
+                    chunk.extend(open_resource.take());
+                    chunks.push(std::mem::take(&mut chunk));
+                    bytes = 0;
+                    count = 0;
+                }
+                if open_resource.is_none() {
+                    bytes += resource_cost;
+                    open_resource = Some(resource.clone());
+                }
+                let s = open_scope.get_or_insert_with(|| {
+                    bytes += scope_cost;
+                    scope.clone()
+                });
+                items_of(s).push(item);
+                bytes += cost;
+                count += weight;
+            }
+            close_scope(&mut open_resource, &mut open_scope, scopes_of);
+        }
+        chunk.extend(open_resource);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
 }
 
 impl From<ExportLogsServiceResponse> for FallibleOtelResponse {
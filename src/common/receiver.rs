@@ -0,0 +1,48 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// The largest datagram accepted by the UDP receivers
+pub const MAX_DATAGRAM: usize = 65_535;
+
+/// An error receiving telemetry and delivering it through a service
+#[derive(Debug)]
+pub enum ReceiverError {
+    /// Reading the socket or files, or writing a checkpoint, failed
+    Io(std::io::Error),
+    /// The received telemetry could not be delivered
+    Deliver(Box<tonic::Status>),
+}
+
+impl std::fmt::Display for ReceiverError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReceiverError::Io(e) => write!(f, "failed to receive: {e}"),
+            ReceiverError::Deliver(status) => write!(f, "failed to deliver: {status}"),
+        }
+    }
+}
+
+impl std::error::Error for ReceiverError {}
+
+impl From<std::io::Error> for ReceiverError {
+    fn from(e: std::io::Error) -> Self {
+        ReceiverError::Io(e)
+    }
+}
+
+impl From<tonic::Status> for ReceiverError {
+    fn from(status: tonic::Status) -> Self {
+        ReceiverError::Deliver(Box::new(status))
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connectors consume the telemetry of one signal and produce another.
+//!
+//! They plug into a trace processor chain as processors that leave requests as
+//! they are, aggregate what they observe, and are flushed on an interval into
+//! requests for the metrics channels.
+
+use crate::common::any_value_to_string;
+use crate::opentelemetry::proto::metrics::v1::HistogramDataPoint;
+use crate::opentelemetry::proto::resource::v1::Resource;
+use crate::opentelemetry::proto::trace::v1::Span;
+
+mod servicegraph;
+mod spanmetrics;
+
+pub use servicegraph::{
+    ServiceGraph, DEFAULT_LATENCY_BOUNDS, DEFAULT_MAX_PENDING, DEFAULT_PEER_ATTRIBUTES,
+    DEFAULT_WAIT, REQUEST_CLIENT_METRIC, REQUEST_FAILED_METRIC, REQUEST_SERVER_METRIC,
+    REQUEST_TOTAL_METRIC,
+};
+pub use spanmetrics::{
+    DurationHistogram, SpanMetrics, CALLS_METRIC, DEFAULT_DURATION_BOUNDS, DURATION_METRIC,
+    ERRORS_METRIC,
+};
+
+/// The service name of spans whose resource has none
+const UNKNOWN_SERVICE: &str = "unknown_service";
+
+fn service_name(resource: &Resource) -> String {
+    resource
+        .attributes
+        .iter()
+        .find(|kv| kv.key == "service.name")
+        .and_then(|kv| kv.value.as_ref())
+        .map_or_else(|| UNKNOWN_SERVICE.to_string(), any_value_to_string)
+}
+
+fn duration_nanos(span: &Span) -> f64 {
+    let nanos = span
+        .end_time_unix_nano
+        .saturating_sub(span.start_time_unix_nano);
+    // durations beyond 2^53 nanoseconds lose precision
+    #[allow(clippy::cast_precision_loss)]
+    let nanos = nanos as f64;
+    nanos
+}
+
+fn duration_seconds(span: &Span) -> f64 {
+    duration_nanos(span) / 1e9
+}
+
+/// Divided from nanoseconds directly, as scaling seconds up would move
+/// durations on a bucket bound across it
+fn duration_millis(span: &Span) -> f64 {
+    duration_nanos(span) / 1e6
+}
+
+/// Records a value in the first bucket whose upper bound it does not exceed
+fn record_explicit(point: &mut HistogramDataPoint, value: f64) {
+    let bucket = point
+        .explicit_bounds
+        .iter()
+        .position(|bound| value <= *bound)
+        .unwrap_or(point.explicit_bounds.len());
+    if let Some(count) = point.bucket_counts.get_mut(bucket) {
+        *count += 1;
+    }
+    point.count += 1;
+    point.sum = Some(point.sum.unwrap_or_default() + value);
+    point.min = Some(point.min.map_or(value, |min| min.min(value)));
+    point.max = Some(point.max.map_or(value, |max| max.max(value)));
+}
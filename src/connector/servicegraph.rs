@@ -0,0 +1,596 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{duration_seconds, record_explicit, service_name};
+use crate::common::{any_value_to_string, nanos, string_value};
+use crate::metrics::MetricsService;
+use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest;
+use crate::opentelemetry::proto::common::v1::InstrumentationScope;
+use crate::opentelemetry::proto::metrics::v1::{
+    metric::Data, number_data_point, AggregationTemporality, Histogram, HistogramDataPoint, Metric,
+    NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum,
+};
+use crate::opentelemetry::proto::trace::v1::{span::SpanKind, status::StatusCode, Span};
+use crate::processor::{Disposition, Processor};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+/// The name of the metric counting requests between services
+pub const REQUEST_TOTAL_METRIC: &str = "traces_service_graph_request_total";
+/// The name of the metric counting failed requests between services
+pub const REQUEST_FAILED_METRIC: &str = "traces_service_graph_request_failed_total";
+/// The name of the metric of request latencies seen by the server
+pub const REQUEST_SERVER_METRIC: &str = "traces_service_graph_request_server_seconds";
+/// The name of the metric of request latencies seen by the client
+pub const REQUEST_CLIENT_METRIC: &str = "traces_service_graph_request_client_seconds";
+
+/// The bucket bounds of latency histograms unless configured otherwise, in
+/// seconds
+pub const DEFAULT_LATENCY_BOUNDS: [f64; 16] = [
+    0.002, 0.004, 0.006, 0.008, 0.01, 0.05, 0.1, 0.2, 0.4, 0.8, 1.0, 1.4, 2.0, 5.0, 10.0, 15.0,
+];
+
+/// The span attributes naming the peer of a client span, in order of
+/// preference, when the server side is not instrumented
+pub const DEFAULT_PEER_ATTRIBUTES: [&str; 4] =
+    ["peer.service", "db.name", "server.address", "net.peer.name"];
+
+/// How long a half of an edge waits for the other unless configured otherwise
+pub const DEFAULT_WAIT: Duration = Duration::from_secs(2);
+
+/// The number of unmatched halves kept unless configured otherwise
+pub const DEFAULT_MAX_PENDING: usize = 10_000;
+
+/// The client of edges into root server spans when virtual nodes are enabled
+const USER_NODE: &str = "user";
+
+/// The connection type of edges between messaging producers and consumers
+const MESSAGING_SYSTEM: &str = "messaging_system";
+
+/// The connection type of edges into nodes derived from peer attributes
+const VIRTUAL_NODE: &str = "virtual_node";
+
+/// One side of a request between two services
+#[derive(Debug, Clone)]
+struct Half {
+    service: String,
+    seconds: f64,
+    failed: bool,
+    messaging: bool,
+    peer: Option<String>,
+}
+
+/// The halves of a request seen so far, keyed by the trace id and the span id
+/// of the client span
+#[derive(Debug, Default)]
+struct Pending {
+    client: Option<Half>,
+    server: Option<Half>,
+    expires_unix_nano: u64,
+}
+
+/// The client, server and connection type of an edge
+type EdgeKey = (String, String, Option<&'static str>);
+
+/// The aggregates of the requests along an edge
+#[derive(Debug, Clone)]
+struct Edge {
+    start_time_unix_nano: u64,
+    total: u64,
+    failed: u64,
+    client: HistogramDataPoint,
+    server: HistogramDataPoint,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    pending: HashMap<(Vec<u8>, Vec<u8>), Pending>,
+    edges: BTreeMap<EdgeKey, Edge>,
+    expired: u64,
+    dropped: u64,
+}
+
+/// Derives a service dependency graph from spans.
+///
+/// Client and producer spans are paired with the server and consumer spans
+/// that are their children, which may arrive in different requests and from
+/// different services. Every pair is an edge from the client to the server
+/// service counting requests and failed requests, and recording the latency
+/// seen by either side.
+///
+/// Halves that find no match within the wait period expire. With virtual
+/// nodes enabled, expired client halves carrying a peer attribute become edges
+/// to a node named by that attribute, and root server spans become edges from
+/// a `user` node.
+pub struct ServiceGraph {
+    bounds: Vec<f64>,
+    wait: Duration,
+    max_pending: usize,
+    virtual_nodes: bool,
+    peer_attributes: Vec<String>,
+    temporality: AggregationTemporality,
+    state: Mutex<State>,
+}
+
+impl Default for ServiceGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServiceGraph {
+    /// Creates a connector producing cumulative metrics without virtual nodes
+    pub fn new() -> Self {
+        ServiceGraph {
+            bounds: DEFAULT_LATENCY_BOUNDS.to_vec(),
+            wait: DEFAULT_WAIT,
+            max_pending: DEFAULT_MAX_PENDING,
+            virtual_nodes: false,
+            peer_attributes: DEFAULT_PEER_ATTRIBUTES
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            temporality: AggregationTemporality::Cumulative,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Sets the bucket bounds of the latency histograms in seconds
+    pub fn with_bounds(mut self, bounds: Vec<f64>) -> Self {
+        self.bounds = bounds;
+        self
+    }
+
+    /// Sets how long a half of an edge waits for the other
+    pub fn with_wait(mut self, wait: Duration) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    /// Limits the number of unmatched halves, further halves are dropped
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// Sets whether edges into virtual nodes are derived from unmatched client
+    /// halves and root server spans
+    pub fn with_virtual_nodes(mut self, virtual_nodes: bool) -> Self {
+        self.virtual_nodes = virtual_nodes;
+        self
+    }
+
+    /// Sets the span attributes naming virtual nodes, in order of preference
+    pub fn with_peer_attributes(mut self, attributes: &[&str]) -> Self {
+        self.peer_attributes = attributes.iter().map(ToString::to_string).collect();
+        self
+    }
+
+    /// Sets whether the metrics are cumulative or reset on every flush
+    pub fn with_temporality(mut self, temporality: AggregationTemporality) -> Self {
+        self.temporality = temporality;
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// The number of halves waiting for their match
+    pub fn pending(&self) -> usize {
+        self.state().pending.len()
+    }
+
+    /// The number of halves that expired without a match
+    pub fn expired_halves(&self) -> u64 {
+        self.state().expired
+    }
+
+    /// The number of halves dropped because too many were pending
+    pub fn dropped_halves(&self) -> u64 {
+        self.state().dropped
+    }
+
+    fn half(&self, service: String, span: &Span) -> Half {
+        Half {
+            service,
+            seconds: duration_seconds(span),
+            failed: span.status.as_ref().map(|status| status.code()) == Some(StatusCode::Error),
+            messaging: matches!(span.kind(), SpanKind::Producer | SpanKind::Consumer),
+            peer: self.peer_attributes.iter().find_map(|name| {
+                span.attributes
+                    .iter()
+                    .find(|kv| kv.key == *name)
+                    .and_then(|kv| kv.value.as_ref())
+                    .map(any_value_to_string)
+            }),
+        }
+    }
+
+    fn record(&self, state: &mut State, client: Option<&Half>, server: Option<&Half>, now: u64) {
+        let key = match (client, server) {
+            (Some(client), Some(server)) => (
+                client.service.clone(),
+                server.service.clone(),
+                (client.messaging || server.messaging).then_some(MESSAGING_SYSTEM),
+            ),
+            (Some(client), None) => match &client.peer {
+                Some(peer) => (client.service.clone(), peer.clone(), Some(VIRTUAL_NODE)),
+                None => return,
+            },
+            (None, Some(server)) => (
+                USER_NODE.to_string(),
+                server.service.clone(),
+                Some(VIRTUAL_NODE),
+            ),
+            (None, None) => return,
+        };
+        let histogram = || HistogramDataPoint {
+            bucket_counts: vec![0; self.bounds.len() + 1],
+            explicit_bounds: self.bounds.clone(),
+            ..HistogramDataPoint::default()
+        };
+        let edge = state.edges.entry(key).or_insert_with(|| Edge {
+            start_time_unix_nano: now,
+            total: 0,
+            failed: 0,
+            client: histogram(),
+            server: histogram(),
+        });
+        edge.total += 1;
+        if client.is_some_and(|half| half.failed) || server.is_some_and(|half| half.failed) {
+            edge.failed += 1;
+        }
+        if let Some(client) = client {
+            record_explicit(&mut edge.client, client.seconds);
+        }
+        if let Some(server) = server {
+            record_explicit(&mut edge.server, server.seconds);
+        }
+    }
+
+    /// Pairs the client and server spans of a request with those seen before
+    pub fn consume(&self, request: &ExportTraceServiceRequest, now: SystemTime) {
+        let now = nanos(now);
+        let expires_unix_nano =
+            now.saturating_add(u64::try_from(self.wait.as_nanos()).unwrap_or(u64::MAX));
+        let mut state = self.state();
+        for (resource, _, span) in request.spans() {
+            let (key, is_client) = match span.kind() {
+                SpanKind::Client | SpanKind::Producer => {
+                    ((span.trace_id.clone(), span.span_id.clone()), true)
+                }
+                SpanKind::Server | SpanKind::Consumer => {
+                    if span.parent_span_id.is_empty() {
+                        if self.virtual_nodes {
+                            let server = self.half(service_name(resource), span);
+                            self.record(&mut state, None, Some(&server), now);
+                        }
+                        continue;
+                    }
+                    ((span.trace_id.clone(), span.parent_span_id.clone()), false)
+                }
+                SpanKind::Unspecified | SpanKind::Internal => continue,
+            };
+            let half = self.half(service_name(resource), span);
+            if !state.pending.contains_key(&key) && state.pending.len() >= self.max_pending {
+                state.dropped += 1;
+                continue;
+            }
+            let pending = state.pending.entry(key.clone()).or_insert_with(|| Pending {
+                expires_unix_nano,
+                ..Pending::default()
+            });
+            if is_client {
+                pending.client = Some(half);
+            } else {
+                pending.server = Some(half);
+            }
+            if pending.client.is_some() && pending.server.is_some() {
+                if let Some(pending) = state.pending.remove(&key) {
+                    self.record(
+                        &mut state,
+                        pending.client.as_ref(),
+                        pending.server.as_ref(),
+                        now,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Expires the halves that waited for their match for too long
+    pub fn expire(&self, now: SystemTime) {
+        let now = nanos(now);
+        let mut state = self.state();
+        let (expired, pending): (HashMap<_, _>, HashMap<_, _>) = std::mem::take(&mut state.pending)
+            .into_iter()
+            .partition(|(_, pending)| pending.expires_unix_nano <= now);
+        state.pending = pending;
+        for pending in expired.into_values() {
+            state.expired += 1;
+            if self.virtual_nodes && pending.server.is_none() {
+                self.record(&mut state, pending.client.as_ref(), None, now);
+            }
+        }
+    }
+
+    /// Expires unmatched halves and produces the metrics of the edges
+    /// aggregated so far, delta edges are reset
+    pub fn flush(&self, now: SystemTime) -> Option<ExportMetricsServiceRequest> {
+        self.expire(now);
+        let now = nanos(now);
+        let mut state = self.state();
+        if state.edges.is_empty() {
+            return None;
+        }
+        let edges = if self.temporality == AggregationTemporality::Delta {
+            std::mem::take(&mut state.edges)
+        } else {
+            state.edges.clone()
+        };
+        drop(state);
+
+        let attributes = |(client, server, connection_type): &EdgeKey| {
+            let mut attributes = vec![
+                string_value("client", client),
+                string_value("server", server),
+            ];
+            if let Some(connection_type) = connection_type {
+                attributes.push(string_value("connection_type", connection_type));
+            }
+            attributes
+        };
+        let temporality = self.temporality as i32;
+        let sum = |name: &str, description: &str, counter: fn(&Edge) -> u64| Metric {
+            name: name.to_string(),
+            description: description.to_string(),
+            unit: "{request}".to_string(),
+            data: Some(Data::Sum(Sum {
+                data_points: edges
+                    .iter()
+                    .filter(|(_, edge)| counter(edge) > 0)
+                    .map(|(key, edge)| NumberDataPoint {
+                        attributes: attributes(key),
+                        start_time_unix_nano: edge.start_time_unix_nano,
+                        time_unix_nano: now,
+                        #[allow(clippy::cast_possible_wrap)]
+                        value: Some(number_data_point::Value::AsInt(counter(edge) as i64)),
+                        ..NumberDataPoint::default()
+                    })
+                    .collect(),
+                aggregation_temporality: temporality,
+                is_monotonic: true,
+            })),
+            ..Metric::default()
+        };
+        let histogram =
+            |name: &str, description: &str, side: fn(&Edge) -> &HistogramDataPoint| Metric {
+                name: name.to_string(),
+                description: description.to_string(),
+                unit: "s".to_string(),
+                data: Some(Data::Histogram(Histogram {
+                    data_points: edges
+                        .iter()
+                        .filter(|(_, edge)| side(edge).count > 0)
+                        .map(|(key, edge)| HistogramDataPoint {
+                            attributes: attributes(key),
+                            start_time_unix_nano: edge.start_time_unix_nano,
+                            time_unix_nano: now,
+                            ..side(edge).clone()
+                        })
+                        .collect(),
+                    aggregation_temporality: temporality,
+                })),
+                ..Metric::default()
+            };
+        let metrics: Vec<Metric> = vec![
+            sum(
+                REQUEST_TOTAL_METRIC,
+                "The number of requests between two services",
+                |edge| edge.total,
+            ),
+            sum(
+                REQUEST_FAILED_METRIC,
+                "The number of failed requests between two services",
+                |edge| edge.failed,
+            ),
+            histogram(
+                REQUEST_SERVER_METRIC,
+                "The latency of requests between two services as seen by the server",
+                |edge| &edge.server,
+            ),
+            histogram(
+                REQUEST_CLIENT_METRIC,
+                "The latency of requests between two services as seen by the client",
+                |edge| &edge.client,
+            ),
+        ]
+        .into_iter()
+        .filter(|metric| match &metric.data {
+            Some(Data::Sum(sum)) => !sum.data_points.is_empty(),
+            Some(Data::Histogram(histogram)) => !histogram.data_points.is_empty(),
+            _ => false,
+        })
+        .collect();
+
+        Some(ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: None,
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: "servicegraph".to_string(),
+                        ..InstrumentationScope::default()
+                    }),
+                    metrics,
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        })
+    }
+
+    /// Flushes the edges aggregated so far and delivers their metrics through
+    /// a metrics service
+    pub async fn export<S: MetricsService>(&self, service: &S) -> Result<(), tonic::Status> {
+        if let Some(request) = self.flush(SystemTime::now()) {
+            service.export(tonic::Request::new(request)).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Pairs the spans passing through a trace processor chain, leaving them as
+/// they are
+impl Processor<ExportTraceServiceRequest> for ServiceGraph {
+    fn process(
+        &self,
+        request: &mut ExportTraceServiceRequest,
+    ) -> Result<Disposition, tonic::Status> {
+        self.consume(request, SystemTime::now());
+        Ok(Disposition::accepted())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opentelemetry::proto::resource::v1::Resource;
+    use crate::opentelemetry::proto::trace::v1::{ResourceSpans, ScopeSpans, Status};
+    use std::time::UNIX_EPOCH;
+
+    fn request(service: &str, spans: Vec<Span>) -> ExportTraceServiceRequest {
+        ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: Some(Resource {
+                    attributes: vec![string_value("service.name", service)],
+                    dropped_attributes_count: 0,
+                }),
+                scope_spans: vec![ScopeSpans {
+                    spans,
+                    ..ScopeSpans::default()
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    fn span(kind: SpanKind, span_id: u8, parent_span_id: Option<u8>, error: bool) -> Span {
+        let mut span = Span {
+            trace_id: vec![1; 16],
+            span_id: vec![span_id; 8],
+            parent_span_id: parent_span_id.map(|id| vec![id; 8]).unwrap_or_default(),
+            start_time_unix_nano: 0,
+            end_time_unix_nano: 30_000_000,
+            ..Span::default()
+        };
+        span.set_kind(kind);
+        if error {
+            let mut status = Status::default();
+            status.set_code(StatusCode::Error);
+            span.status = Some(status);
+        }
+        span
+    }
+
+    fn edges(request: &ExportMetricsServiceRequest, name: &str) -> Vec<(Vec<String>, i64)> {
+        let metric = request
+            .resource_metrics
+            .iter()
+            .flat_map(|rm| &rm.scope_metrics)
+            .flat_map(|sm| &sm.metrics)
+            .find(|m| m.name == name);
+        let Some(Data::Sum(sum)) = metric.and_then(|m| m.data.as_ref()) else {
+            return Vec::new();
+        };
+        sum.data_points
+            .iter()
+            .map(|point| {
+                let attributes = point
+                    .attributes
+                    .iter()
+                    .filter_map(|kv| kv.value.as_ref().map(any_value_to_string))
+                    .collect();
+                let value = match point.value {
+                    Some(number_data_point::Value::AsInt(value)) => value,
+                    _ => 0,
+                };
+                (attributes, value)
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn pairs_client_and_server_spans() -> Result<(), Box<dyn std::error::Error>> {
+        let graph = ServiceGraph::new().with_virtual_nodes(true);
+        let mut payments = span(SpanKind::Client, 3, Some(1), false);
+        payments
+            .attributes
+            .push(string_value("peer.service", "payments"));
+        graph.consume(
+            &request(
+                "frontend",
+                vec![
+                    span(SpanKind::Server, 1, None, false),
+                    span(SpanKind::Client, 2, Some(1), false),
+                    payments,
+                ],
+            ),
+            UNIX_EPOCH,
+        );
+        assert_eq!(graph.pending(), 2);
+        // the server half arrives later from another service
+        graph.consume(
+            &request("backend", vec![span(SpanKind::Server, 4, Some(2), true)]),
+            UNIX_EPOCH,
+        );
+        graph.consume(
+            &request("orphan", vec![span(SpanKind::Server, 5, Some(9), false)]),
+            UNIX_EPOCH,
+        );
+        assert_eq!(graph.pending(), 2);
+
+        let flushed = graph.flush(UNIX_EPOCH).ok_or("nothing flushed")?;
+        assert_eq!(
+            edges(&flushed, REQUEST_TOTAL_METRIC),
+            vec![
+                (vec!["frontend".into(), "backend".into()], 1),
+                (
+                    vec!["user".into(), "frontend".into(), "virtual_node".into()],
+                    1
+                )
+            ]
+        );
+        assert_eq!(
+            edges(&flushed, REQUEST_FAILED_METRIC),
+            vec![(vec!["frontend".into(), "backend".into()], 1)]
+        );
+
+        // unmatched halves expire, the client half naming its peer becomes an
+        // edge to a virtual node
+        let later = UNIX_EPOCH + DEFAULT_WAIT;
+        let flushed = graph.flush(later).ok_or("nothing flushed")?;
+        assert_eq!(graph.pending(), 0);
+        assert_eq!(graph.expired_halves(), 2);
+        assert!(edges(&flushed, REQUEST_TOTAL_METRIC).contains(&(
+            vec!["frontend".into(), "payments".into(), "virtual_node".into()],
+            1
+        )));
+        Ok(())
+    }
+}
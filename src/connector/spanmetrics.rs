@@ -0,0 +1,591 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{duration_millis, record_explicit, service_name};
+use crate::common::{any_value_to_string, nanos, string_value};
+use crate::metrics::MetricsService;
+use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest;
+use crate::opentelemetry::proto::common::v1::{
+    any_value, AnyValue, InstrumentationScope, KeyValue,
+};
+use crate::opentelemetry::proto::metrics::v1::{
+    metric::Data, number_data_point, AggregationTemporality, ExponentialHistogram,
+    ExponentialHistogramDataPoint, Histogram, HistogramDataPoint, Metric, NumberDataPoint,
+    ResourceMetrics, ScopeMetrics, Sum,
+};
+use crate::opentelemetry::proto::resource::v1::Resource;
+use crate::opentelemetry::proto::trace::v1::{status::StatusCode, Span};
+use crate::processor::{Disposition, Processor};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// The name of the metric counting spans
+pub const CALLS_METRIC: &str = "traces.span.metrics.calls";
+/// The name of the metric counting spans with an error status
+pub const ERRORS_METRIC: &str = "traces.span.metrics.errors";
+/// The name of the metric of span durations
+pub const DURATION_METRIC: &str = "traces.span.metrics.duration";
+
+/// The bucket bounds of explicit duration histograms unless configured
+/// otherwise, in milliseconds
+pub const DEFAULT_DURATION_BOUNDS: [f64; 16] = [
+    2.0, 4.0, 6.0, 8.0, 10.0, 50.0, 100.0, 200.0, 400.0, 800.0, 1000.0, 1400.0, 2000.0, 5000.0,
+    10_000.0, 15_000.0,
+];
+
+/// The attribute marking the series that spans beyond the cardinality limit
+/// are counted in
+const OVERFLOW_ATTRIBUTE: &str = "otel.metric.overflow";
+
+/// The scale exponential duration histograms start out at before being
+/// downscaled to fit
+const EXPONENTIAL_SCALE: i32 = 20;
+
+/// The kind of histogram span durations are recorded in
+#[derive(Debug, Clone, PartialEq)]
+pub enum DurationHistogram {
+    /// A histogram with the given bucket bounds in milliseconds
+    Explicit(Vec<f64>),
+    /// An exponential histogram, downscaled to at most 160 buckets
+    Exponential,
+}
+
+/// The durations of a series
+#[derive(Debug, Clone)]
+enum Durations {
+    Explicit(HistogramDataPoint),
+    Exponential(ExponentialHistogramDataPoint),
+}
+
+/// The aggregates of the spans sharing a service and dimensions
+#[derive(Debug, Clone)]
+struct Series {
+    attributes: Vec<KeyValue>,
+    start_time_unix_nano: u64,
+    calls: u64,
+    errors: u64,
+    durations: Durations,
+}
+
+/// The service name and the dimension values identifying a series
+type SeriesKey = (String, Vec<(String, String)>);
+
+#[derive(Debug, Default)]
+struct State {
+    series: BTreeMap<SeriesKey, Series>,
+    limited: usize,
+    overflowed: u64,
+}
+
+/// Derives request, error and duration metrics, also known as RED metrics,
+/// from spans.
+///
+/// Spans are counted per `service.name`, span name, span kind and status code,
+/// plus any extra dimensions looked up in the span attributes and then the
+/// resource attributes. Every span is counted in the calls metric, spans with
+/// an error status in the errors metric too, and its duration is recorded in
+/// milliseconds in an explicit or exponential histogram.
+///
+/// Once the number of series reaches the cardinality limit, spans of new
+/// series are counted in a series per service marked with the
+/// `otel.metric.overflow` attribute instead.
+pub struct SpanMetrics {
+    histogram: DurationHistogram,
+    dimensions: Vec<(String, Option<String>)>,
+    max_series: Option<usize>,
+    temporality: AggregationTemporality,
+    state: Mutex<State>,
+}
+
+impl Default for SpanMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpanMetrics {
+    /// Creates a connector producing cumulative metrics with explicit duration
+    /// histograms and no cardinality limit
+    pub fn new() -> Self {
+        SpanMetrics {
+            histogram: DurationHistogram::Explicit(DEFAULT_DURATION_BOUNDS.to_vec()),
+            dimensions: Vec::new(),
+            max_series: None,
+            temporality: AggregationTemporality::Cumulative,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Sets the histogram durations are recorded in
+    pub fn with_histogram(mut self, histogram: DurationHistogram) -> Self {
+        self.histogram = histogram;
+        self
+    }
+
+    /// Adds a dimension taken from the span or resource attribute of the same
+    /// name. Spans without the attribute get the default value or, if there is
+    /// none, go without the dimension.
+    pub fn with_dimension(mut self, name: &str, default: Option<&str>) -> Self {
+        self.dimensions
+            .push((name.to_string(), default.map(ToString::to_string)));
+        self
+    }
+
+    /// Limits the number of series, the overflow series are not counted
+    pub fn with_max_series(mut self, max_series: usize) -> Self {
+        self.max_series = Some(max_series);
+        self
+    }
+
+    /// Sets whether the metrics are cumulative or reset on every flush
+    pub fn with_temporality(mut self, temporality: AggregationTemporality) -> Self {
+        self.temporality = temporality;
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// The number of series aggregated
+    pub fn series_count(&self) -> usize {
+        self.state().series.len()
+    }
+
+    /// The number of spans counted in overflow series
+    pub fn overflowed_spans(&self) -> u64 {
+        self.state().overflowed
+    }
+
+    fn dimensions(&self, resource: &Resource, span: &Span) -> Vec<(String, String)> {
+        let mut dimensions = vec![
+            ("span.name".to_string(), span.name.clone()),
+            (
+                "span.kind".to_string(),
+                span.kind().as_str_name().to_string(),
+            ),
+            (
+                "status.code".to_string(),
+                span.status
+                    .as_ref()
+                    .map_or(StatusCode::Unset, |status| status.code())
+                    .as_str_name()
+                    .to_string(),
+            ),
+        ];
+        for (name, default) in &self.dimensions {
+            let value = span
+                .attributes
+                .iter()
+                .chain(&resource.attributes)
+                .find(|kv| kv.key == *name)
+                .and_then(|kv| kv.value.as_ref())
+                .map(any_value_to_string)
+                .or_else(|| default.clone());
+            if let Some(value) = value {
+                dimensions.push((name.clone(), value));
+            }
+        }
+        dimensions
+    }
+
+    fn new_series(&self, attributes: Vec<KeyValue>, now: u64) -> Series {
+        let durations = match &self.histogram {
+            DurationHistogram::Explicit(bounds) => Durations::Explicit(HistogramDataPoint {
+                bucket_counts: vec![0; bounds.len() + 1],
+                explicit_bounds: bounds.clone(),
+                ..HistogramDataPoint::default()
+            }),
+            DurationHistogram::Exponential => {
+                Durations::Exponential(ExponentialHistogramDataPoint {
+                    scale: EXPONENTIAL_SCALE,
+                    ..ExponentialHistogramDataPoint::default()
+                })
+            }
+        };
+        Series {
+            attributes,
+            start_time_unix_nano: now,
+            calls: 0,
+            errors: 0,
+            durations,
+        }
+    }
+
+    /// Counts the spans of a request
+    pub fn consume(&self, request: &ExportTraceServiceRequest, now: SystemTime) {
+        let now = nanos(now);
+        let mut state = self.state();
+        for (resource, _, span) in request.spans() {
+            let mut key = (service_name(resource), self.dimensions(resource, span));
+            if !state.series.contains_key(&key) {
+                if self.max_series.is_some_and(|max| state.limited >= max) {
+                    key.1 = vec![(OVERFLOW_ATTRIBUTE.to_string(), "true".to_string())];
+                    state.overflowed += 1;
+                } else {
+                    state.limited += 1;
+                }
+            }
+            let series = state
+                .series
+                .entry(key)
+                .or_insert_with_key(|(service, dimensions)| {
+                    let mut attributes = vec![string_value("service.name", service)];
+                    for (name, value) in dimensions {
+                        if name == OVERFLOW_ATTRIBUTE {
+                            attributes.push(KeyValue {
+                                key: name.clone(),
+                                value: Some(AnyValue {
+                                    value: Some(any_value::Value::BoolValue(true)),
+                                }),
+                            });
+                        } else {
+                            attributes.push(string_value(name, value));
+                        }
+                    }
+                    self.new_series(attributes, now)
+                });
+
+            series.calls += 1;
+            if span.status.as_ref().map(|status| status.code()) == Some(StatusCode::Error) {
+                series.errors += 1;
+            }
+            let millis = duration_millis(span);
+            match &mut series.durations {
+                Durations::Explicit(point) => record_explicit(point, millis),
+                Durations::Exponential(point) => point.record(millis, 1),
+            }
+        }
+    }
+
+    /// Produces the metrics of the series aggregated so far, delta series are
+    /// reset
+    pub fn flush(&self, now: SystemTime) -> Option<ExportMetricsServiceRequest> {
+        let now = nanos(now);
+        let mut state = self.state();
+        if state.series.is_empty() {
+            return None;
+        }
+        let delta = self.temporality == AggregationTemporality::Delta;
+        let series = if delta {
+            state.limited = 0;
+            std::mem::take(&mut state.series)
+        } else {
+            state.series.clone()
+        };
+        drop(state);
+
+        let mut services: BTreeMap<String, Vec<Series>> = BTreeMap::new();
+        for ((service, _), series) in series {
+            services.entry(service).or_default().push(series);
+        }
+        let temporality = self.temporality as i32;
+        let resource_metrics = services
+            .into_iter()
+            .map(|(service, series)| {
+                let number = |series: &Series, value: u64| NumberDataPoint {
+                    attributes: series.attributes.clone(),
+                    start_time_unix_nano: series.start_time_unix_nano,
+                    time_unix_nano: now,
+                    #[allow(clippy::cast_possible_wrap)]
+                    value: Some(number_data_point::Value::AsInt(value as i64)),
+                    ..NumberDataPoint::default()
+                };
+                let sum =
+                    |name: &str, description: &str, data_points: Vec<NumberDataPoint>| Metric {
+                        name: name.to_string(),
+                        description: description.to_string(),
+                        unit: "{span}".to_string(),
+                        data: Some(Data::Sum(Sum {
+                            data_points,
+                            aggregation_temporality: temporality,
+                            is_monotonic: true,
+                        })),
+                        ..Metric::default()
+                    };
+                let mut metrics = vec![sum(
+                    CALLS_METRIC,
+                    "The number of spans",
+                    series.iter().map(|s| number(s, s.calls)).collect(),
+                )];
+                let errors: Vec<NumberDataPoint> = series
+                    .iter()
+                    .filter(|s| s.errors > 0)
+                    .map(|s| number(s, s.errors))
+                    .collect();
+                if !errors.is_empty() {
+                    metrics.push(sum(
+                        ERRORS_METRIC,
+                        "The number of spans with an error status",
+                        errors,
+                    ));
+                }
+                let data = match self.histogram {
+                    DurationHistogram::Explicit(_) => Data::Histogram(Histogram {
+                        data_points: series
+                            .iter()
+                            .filter_map(|s| match &s.durations {
+                                Durations::Explicit(point) => Some(HistogramDataPoint {
+                                    attributes: s.attributes.clone(),
+                                    start_time_unix_nano: s.start_time_unix_nano,
+                                    time_unix_nano: now,
+                                    ..point.clone()
+                                }),
+                                Durations::Exponential(_) => None,
+                            })
+                            .collect(),
+                        aggregation_temporality: temporality,
+                    }),
+                    DurationHistogram::Exponential => {
+                        Data::ExponentialHistogram(ExponentialHistogram {
+                            data_points: series
+                                .iter()
+                                .filter_map(|s| match &s.durations {
+                                    Durations::Exponential(point) => {
+                                        Some(ExponentialHistogramDataPoint {
+                                            attributes: s.attributes.clone(),
+                                            start_time_unix_nano: s.start_time_unix_nano,
+                                            time_unix_nano: now,
+                                            ..point.clone()
+                                        })
+                                    }
+                                    Durations::Explicit(_) => None,
+                                })
+                                .collect(),
+                            aggregation_temporality: temporality,
+                        })
+                    }
+                };
+                metrics.push(Metric {
+                    name: DURATION_METRIC.to_string(),
+                    description: "The duration of spans".to_string(),
+                    unit: "ms".to_string(),
+                    data: Some(data),
+                    ..Metric::default()
+                });
+                ResourceMetrics {
+                    resource: Some(Resource {
+                        attributes: vec![string_value("service.name", &service)],
+                        dropped_attributes_count: 0,
+                    }),
+                    scope_metrics: vec![ScopeMetrics {
+                        scope: Some(InstrumentationScope {
+                            name: "spanmetrics".to_string(),
+                            ..InstrumentationScope::default()
+                        }),
+                        metrics,
+                        schema_url: String::new(),
+                    }],
+                    schema_url: String::new(),
+                }
+            })
+            .collect();
+        Some(ExportMetricsServiceRequest { resource_metrics })
+    }
+
+    /// Flushes the metrics aggregated so far and delivers them through a
+    /// metrics service
+    pub async fn export<S: MetricsService>(&self, service: &S) -> Result<(), tonic::Status> {
+        if let Some(request) = self.flush(SystemTime::now()) {
+            service.export(tonic::Request::new(request)).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the spans passing through a trace processor chain, leaving them as
+/// they are
+impl Processor<ExportTraceServiceRequest> for SpanMetrics {
+    fn process(
+        &self,
+        request: &mut ExportTraceServiceRequest,
+    ) -> Result<Disposition, tonic::Status> {
+        self.consume(request, SystemTime::now());
+        Ok(Disposition::accepted())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opentelemetry::proto::trace::v1::{
+        span::SpanKind, ResourceSpans, ScopeSpans, Status,
+    };
+    use std::time::UNIX_EPOCH;
+
+    fn request(service: &str, spans: Vec<Span>) -> ExportTraceServiceRequest {
+        ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: Some(Resource {
+                    attributes: vec![string_value("service.name", service)],
+                    dropped_attributes_count: 0,
+                }),
+                scope_spans: vec![ScopeSpans {
+                    spans,
+                    ..ScopeSpans::default()
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    fn span(name: &str, millis: u64, error: bool) -> Span {
+        let mut span = Span {
+            name: name.to_string(),
+            start_time_unix_nano: 1_000_000_000,
+            end_time_unix_nano: 1_000_000_000 + millis * 1_000_000,
+            attributes: vec![string_value("http.method", "GET")],
+            ..Span::default()
+        };
+        span.set_kind(SpanKind::Server);
+        if error {
+            let mut status = Status::default();
+            status.set_code(StatusCode::Error);
+            span.status = Some(status);
+        }
+        span
+    }
+
+    fn metric<'a>(request: &'a ExportMetricsServiceRequest, name: &str) -> Option<&'a Metric> {
+        request
+            .resource_metrics
+            .first()?
+            .scope_metrics
+            .first()?
+            .metrics
+            .iter()
+            .find(|m| m.name == name)
+    }
+
+    #[test]
+    pub fn durations_on_bucket_bounds() -> Result<(), Box<dyn std::error::Error>> {
+        let connector =
+            SpanMetrics::new().with_histogram(DurationHistogram::Explicit(vec![4001.0]));
+        let spans = vec![span("GET /", 4001, false), span("GET /", 4002, false)];
+        connector.consume(&request("web", spans), UNIX_EPOCH);
+        let flushed = connector.flush(UNIX_EPOCH).ok_or("nothing flushed")?;
+        let Some(Data::Histogram(durations)) =
+            metric(&flushed, DURATION_METRIC).and_then(|m| m.data.clone())
+        else {
+            return Err("no durations".into());
+        };
+        let point = durations.data_points.first().ok_or("no point")?;
+        // 4001 ms falls in the bucket up to 4001, 4002 ms in the one after
+        assert_eq!(point.bucket_counts, vec![1, 1]);
+        assert_eq!(point.sum, Some(8003.0));
+        Ok(())
+    }
+
+    #[test]
+    pub fn red_metrics() -> Result<(), Box<dyn std::error::Error>> {
+        let connector = SpanMetrics::new()
+            .with_dimension("http.method", None)
+            .with_dimension("deployment.environment", Some("none"))
+            .with_max_series(2);
+        let spans = vec![
+            span("GET /", 3, false),
+            span("GET /", 30, true),
+            span("POST /", 1, false),
+        ];
+        connector.consume(&request("web", spans), UNIX_EPOCH);
+        assert_eq!(connector.series_count(), 3);
+        connector.consume(&request("db", vec![span("query", 1, false)]), UNIX_EPOCH);
+        assert_eq!(connector.series_count(), 4);
+        assert_eq!(connector.overflowed_spans(), 2);
+
+        let flushed = connector.flush(UNIX_EPOCH).ok_or("nothing flushed")?;
+        // services are ordered by name, `db` only has an overflow series
+        let web = ExportMetricsServiceRequest {
+            resource_metrics: flushed
+                .resource_metrics
+                .get(1)
+                .cloned()
+                .into_iter()
+                .collect(),
+        };
+        let Some(Data::Sum(calls)) = metric(&web, CALLS_METRIC).and_then(|m| m.data.clone()) else {
+            return Err("no calls".into());
+        };
+        let calls: Vec<Option<number_data_point::Value>> =
+            calls.data_points.iter().map(|p| p.value).collect();
+        assert_eq!(
+            calls,
+            vec![
+                Some(number_data_point::Value::AsInt(1)),
+                Some(number_data_point::Value::AsInt(1)),
+                Some(number_data_point::Value::AsInt(1))
+            ]
+        );
+        let Some(Data::Histogram(durations)) =
+            metric(&web, DURATION_METRIC).and_then(|m| m.data.clone())
+        else {
+            return Err("no durations".into());
+        };
+        // the overflow series sorts first
+        let keys: Vec<&str> = durations
+            .data_points
+            .get(1)
+            .map(|p| p.attributes.iter().map(|kv| kv.key.as_str()).collect())
+            .unwrap_or_default();
+        assert_eq!(
+            keys,
+            vec![
+                "service.name",
+                "span.name",
+                "span.kind",
+                "status.code",
+                "http.method",
+                "deployment.environment"
+            ]
+        );
+        assert!(metric(&web, ERRORS_METRIC).is_some());
+
+        // cumulative series keep counting
+        connector.consume(&request("web", vec![span("GET /", 3, false)]), UNIX_EPOCH);
+        let flushed = connector.flush(UNIX_EPOCH).ok_or("nothing flushed")?;
+        let web = ExportMetricsServiceRequest {
+            resource_metrics: flushed
+                .resource_metrics
+                .get(1)
+                .cloned()
+                .into_iter()
+                .collect(),
+        };
+        let Some(Data::Histogram(durations)) =
+            metric(&web, DURATION_METRIC).and_then(|m| m.data.clone())
+        else {
+            return Err("no durations".into());
+        };
+        assert_eq!(
+            durations.data_points.iter().map(|p| p.count).sum::<u64>(),
+            4
+        );
+
+        let connector = SpanMetrics::new()
+            .with_histogram(DurationHistogram::Exponential)
+            .with_temporality(AggregationTemporality::Delta);
+        connector.consume(&request("web", vec![span("GET /", 3, false)]), UNIX_EPOCH);
+        let flushed = connector.flush(UNIX_EPOCH).ok_or("nothing flushed")?;
+        assert!(matches!(
+            metric(&flushed, DURATION_METRIC).and_then(|m| m.data.as_ref()),
+            Some(Data::ExponentialHistogram(h)) if h.data_points.len() == 1
+        ));
+        assert_eq!(connector.flush(UNIX_EPOCH), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,684 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tailing of local log files into OTLP logs.
+//!
+//! A [`FileTailer`] follows the files matching a set of glob patterns and turns
+//! every line, or every multiline record when a start pattern is configured,
+//! into a log record with the `log.file.path` and `log.file.name` attributes.
+//!
+//! Files are followed by identity, the device and inode on unix, so a file
+//! rotated by renaming is read to its end under its new name while the new
+//! file under the old name is read from its start. A file that shrinks was
+//! rotated by copying and truncating it and is read again from its start.
+//! Records are read again after a [`FileTailer::rewind`] until they are
+//! marked delivered with [`FileTailer::commit`], and the offsets of the
+//! records emitted so far can be checkpointed to disk and are resumed from
+//! when the tailer is created again.
+//!
+//! A [`FileLogReceiver`] polls a tailer on an interval and delivers the logs
+//! through any logs service, such as the logs forwarders of the `logs` and
+//! `all` channels. The files are read on the blocking thread pool and their
+//! offsets are only committed once the logs were delivered.
+
+use crate::common::receiver::ReceiverError;
+use crate::common::{nanos, string_value};
+use crate::logs::LogsService;
+use crate::opentelemetry::proto::collector::logs::v1::ExportLogsServiceRequest;
+use crate::opentelemetry::proto::common::v1::{any_value, AnyValue, InstrumentationScope};
+use crate::opentelemetry::proto::logs::v1::{LogRecord, ResourceLogs, ScopeLogs};
+use crate::opentelemetry::proto::resource::v1::Resource;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+
+/// The interval files are polled on unless configured otherwise
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The most read from a single file in one poll
+const MAX_READ: u64 = 1024 * 1024;
+
+/// The longest line kept, longer lines are split
+const MAX_LINE: usize = 1024 * 1024;
+
+/// The identity of a file that survives renaming it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum FileId {
+    #[cfg(unix)]
+    Inode { device: u64, inode: u64 },
+    #[cfg(not(unix))]
+    Path(PathBuf),
+}
+
+impl std::fmt::Display for FileId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(unix)]
+            FileId::Inode { device, inode } => write!(f, "{device}:{inode}"),
+            #[cfg(not(unix))]
+            FileId::Path(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn file_id(_path: &Path, metadata: &std::fs::Metadata) -> FileId {
+    use std::os::unix::fs::MetadataExt;
+    FileId::Inode {
+        device: metadata.dev(),
+        inode: metadata.ino(),
+    }
+}
+
+#[cfg(not(unix))]
+fn file_id(path: &Path, _metadata: &std::fs::Metadata) -> FileId {
+    FileId::Path(path.to_path_buf())
+}
+
+/// A file being followed
+struct TailedFile {
+    path: PathBuf,
+    file: File,
+    /// The offset read up to
+    offset: u64,
+    /// The offset up to which records were emitted
+    committed: u64,
+    /// The offset up to which records were delivered
+    delivered: u64,
+    /// The incomplete line at the end of what was read
+    partial: Vec<u8>,
+    /// The multiline record being joined and the offset it ends at
+    pending: Option<(String, u64)>,
+}
+
+impl TailedFile {
+    fn record(&self, body: String, observed_time_unix_nano: u64) -> LogRecord {
+        let name = self
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        LogRecord {
+            observed_time_unix_nano,
+            body: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(body)),
+            }),
+            attributes: vec![
+                string_value("log.file.path", &self.path.to_string_lossy()),
+                string_value("log.file.name", &name),
+            ],
+            ..LogRecord::default()
+        }
+    }
+
+    /// Emits the pending multiline record, if any
+    fn flush_pending(&mut self, now: u64, records: &mut Vec<LogRecord>) {
+        if let Some((body, end)) = self.pending.take() {
+            records.push(self.record(body, now));
+            self.committed = end;
+        }
+    }
+
+    /// Adds a complete line ending at `end`, joining it with the pending
+    /// record unless it starts a new one
+    fn push_line(
+        &mut self,
+        line: String,
+        end: u64,
+        multiline: Option<&Regex>,
+        now: u64,
+        records: &mut Vec<LogRecord>,
+    ) {
+        let Some(start) = multiline else {
+            records.push(self.record(line, now));
+            self.committed = end;
+            return;
+        };
+        if start.is_match(&line) {
+            self.flush_pending(now, records);
+        }
+        match &mut self.pending {
+            Some((body, pending_end)) => {
+                body.push('\n');
+                body.push_str(&line);
+                *pending_end = end;
+            }
+            None => self.pending = Some((line, end)),
+        }
+    }
+
+    /// Reads what was appended since the last read, returning how much was
+    /// read. At the end of a file, the incomplete last line and the pending
+    /// record are emitted too.
+    fn read(
+        &mut self,
+        multiline: Option<&Regex>,
+        end_of_file: bool,
+        now: u64,
+        records: &mut Vec<LogRecord>,
+    ) -> std::io::Result<usize> {
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        let mut data = Vec::new();
+        let read = (&self.file).take(MAX_READ).read_to_end(&mut data)?;
+        let start = self.offset - self.partial.len() as u64;
+        self.offset += read as u64;
+        let mut buffer = std::mem::take(&mut self.partial);
+        buffer.extend_from_slice(&data);
+
+        let mut line_start = 0;
+        while let Some(newline) = buffer
+            .get(line_start..)
+            .and_then(|rest| rest.iter().position(|b| *b == b'\n'))
+        {
+            let line_end = line_start + newline;
+            let line = buffer.get(line_start..line_end).unwrap_or_default();
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let line = String::from_utf8_lossy(line).into_owned();
+            line_start = line_end + 1;
+            self.push_line(line, start + line_start as u64, multiline, now, records);
+        }
+        let rest = buffer.get(line_start..).unwrap_or_default();
+        if !rest.is_empty() && (rest.len() >= MAX_LINE || (end_of_file && read == 0)) {
+            let line = String::from_utf8_lossy(rest).into_owned();
+            self.push_line(line, self.offset, multiline, now, records);
+        } else {
+            self.partial = rest.to_vec();
+        }
+        // a record is complete once nothing more was appended to its file
+        if read == 0 {
+            self.flush_pending(now, records);
+        }
+        Ok(read)
+    }
+
+    /// Forgets what was read past the delivered records
+    fn rewind(&mut self) {
+        self.offset = self.delivered;
+        self.committed = self.delivered;
+        self.partial.clear();
+        self.pending = None;
+    }
+}
+
+/// Follows the files matching glob patterns and turns what is appended to
+/// them into log records
+pub struct FileTailer {
+    patterns: Vec<String>,
+    multiline: Option<Regex>,
+    checkpoint: Option<PathBuf>,
+    checkpointed: HashMap<String, u64>,
+    start_at_beginning: bool,
+    scanned: bool,
+    resource: Resource,
+    files: BTreeMap<FileId, TailedFile>,
+    /// Files dropped since the last commit, kept to be read again on a rewind
+    retired: Vec<(FileId, TailedFile)>,
+}
+
+impl FileTailer {
+    /// Creates a tailer following the files matching the glob patterns. Files
+    /// present when the first poll happens are read from their end, files
+    /// appearing later from their start.
+    pub fn new<I, P>(patterns: I) -> Result<Self, glob::PatternError>
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        let patterns: Vec<String> = patterns.into_iter().map(Into::into).collect();
+        for pattern in &patterns {
+            glob::Pattern::new(pattern)?;
+        }
+        Ok(FileTailer {
+            patterns,
+            multiline: None,
+            checkpoint: None,
+            checkpointed: HashMap::new(),
+            start_at_beginning: false,
+            scanned: false,
+            resource: Resource::default(),
+            files: BTreeMap::new(),
+            retired: Vec::new(),
+        })
+    }
+
+    /// Sets whether the files present at the first poll are read from their
+    /// start rather than their end
+    pub fn with_start_at_beginning(mut self, start_at_beginning: bool) -> Self {
+        self.start_at_beginning = start_at_beginning;
+        self
+    }
+
+    /// Joins lines into multiline records, every line matching the pattern
+    /// starts a new record
+    pub fn with_multiline(mut self, start_pattern: &str) -> Result<Self, regex::Error> {
+        self.multiline = Some(Regex::new(start_pattern)?);
+        Ok(self)
+    }
+
+    /// Sets the resource the logs are reported for
+    pub fn with_resource(mut self, resource: Resource) -> Self {
+        self.resource = resource;
+        self
+    }
+
+    /// Checkpoints offsets to a file, resuming from the offsets already in it.
+    /// A missing file has no offsets.
+    pub fn with_checkpoint<P: Into<PathBuf>>(mut self, path: P) -> std::io::Result<Self> {
+        let path = path.into();
+        self.checkpointed = match std::fs::read_to_string(&path) {
+            Ok(text) => text
+                .lines()
+                .filter_map(|line| {
+                    let (offset, rest) = line.split_once('\t')?;
+                    let (id, _path) = rest.split_once('\t')?;
+                    Some((id.to_string(), offset.parse().ok()?))
+                })
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        self.checkpoint = Some(path);
+        Ok(self)
+    }
+
+    /// The paths of the files being followed
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.values().map(|file| file.path.as_path())
+    }
+
+    /// Writes the offsets up to which records were emitted to the checkpoint
+    /// file, if one is configured
+    pub fn save_checkpoint(&self) -> std::io::Result<()> {
+        let Some(path) = &self.checkpoint else {
+            return Ok(());
+        };
+        let mut text = String::new();
+        for (id, file) in &self.files {
+            text.push_str(&format!(
+                "{}\t{id}\t{}\n",
+                file.committed,
+                file.path.display()
+            ));
+        }
+        // replaced atomically so a crash never leaves a partial checkpoint
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, path)
+    }
+
+    /// Marks the records emitted so far as delivered
+    pub fn commit(&mut self) {
+        for file in self.files.values_mut() {
+            file.delivered = file.committed;
+        }
+        self.retired.clear();
+    }
+
+    /// Rewinds the files to the records last marked delivered, so the records
+    /// emitted since are read again by the next poll
+    pub fn rewind(&mut self) {
+        for (id, file) in self.retired.drain(..) {
+            self.files.entry(id).or_insert(file);
+        }
+        for file in self.files.values_mut() {
+            file.rewind();
+        }
+    }
+
+    /// Reads what was appended to the files since the last poll, returning
+    /// the records as a request if there are any
+    pub fn poll(&mut self, now: SystemTime) -> Option<ExportLogsServiceRequest> {
+        let now = nanos(now);
+        let mut found = BTreeMap::new();
+        for pattern in &self.patterns {
+            // patterns were validated when the tailer was created
+            let Ok(paths) = glob::glob(pattern) else {
+                continue;
+            };
+            for path in paths.flatten() {
+                if let Ok(metadata) = std::fs::metadata(&path) {
+                    if metadata.is_file() {
+                        found.insert(file_id(&path, &metadata), (path, metadata.len()));
+                    }
+                }
+            }
+        }
+
+        let mut records = Vec::new();
+        let multiline = self.multiline.as_ref();
+        // files no longer matching were removed or rotated away, they are
+        // read to their end and dropped
+        let gone: Vec<FileId> = self
+            .files
+            .keys()
+            .filter(|id| !found.contains_key(*id))
+            .cloned()
+            .collect();
+        for id in gone {
+            if let Some(mut file) = self.files.remove(&id) {
+                while let Ok(read) = file.read(multiline, true, now, &mut records) {
+                    if read == 0 {
+                        break;
+                    }
+                }
+                self.retired.push((id, file));
+            }
+        }
+
+        for (id, (path, length)) in found {
+            let file = match self.files.get_mut(&id) {
+                Some(file) => file,
+                None => {
+                    let Ok(handle) = File::open(&path) else {
+                        continue;
+                    };
+                    let offset = match self.checkpointed.get(&id.to_string()) {
+                        Some(offset) if *offset <= length => *offset,
+                        _ if self.scanned || self.start_at_beginning => 0,
+                        _ => length,
+                    };
+                    self.files.entry(id).or_insert(TailedFile {
+                        path: path.clone(),
+                        file: handle,
+                        offset,
+                        committed: offset,
+                        delivered: offset,
+                        partial: Vec::new(),
+                        pending: None,
+                    })
+                }
+            };
+            // renamed files keep being followed under their new name
+            file.path = path;
+            if length < file.offset {
+                file.flush_pending(now, &mut records);
+                file.offset = 0;
+                file.committed = 0;
+                file.delivered = 0;
+                file.partial.clear();
+            }
+            // unreadable files are retried on the next poll
+            file.read(multiline, false, now, &mut records).ok();
+        }
+        self.scanned = true;
+
+        if records.is_empty() {
+            return None;
+        }
+        Some(ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: Some(self.resource.clone()),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(InstrumentationScope {
+                        name: "filelog".to_string(),
+                        ..InstrumentationScope::default()
+                    }),
+                    log_records: records,
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        })
+    }
+}
+
+/// An error receiving file logs, the files could not be read, the checkpoint
+/// could not be written or the logs could not be delivered
+pub type FileLogError = ReceiverError;
+
+fn lock(tailer: &Mutex<FileTailer>) -> MutexGuard<'_, FileTailer> {
+    tailer.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Polls a file tailer on an interval and delivers the logs through a logs
+/// service. Offsets are committed and checkpointed once the logs were
+/// delivered, and rewound when delivering them fails.
+pub struct FileLogReceiver<S> {
+    tailer: Arc<Mutex<FileTailer>>,
+    service: S,
+    interval: Duration,
+}
+
+impl<S: LogsService> FileLogReceiver<S> {
+    /// Creates a receiver delivering what a tailer reads through a logs service
+    pub fn new(tailer: FileTailer, service: S) -> Self {
+        FileLogReceiver {
+            tailer: Arc::new(Mutex::new(tailer)),
+            service,
+            interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    /// Sets the interval files are polled on
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Runs blocking file work on the tailer off the async runtime
+    async fn with_tailer<T, F>(&self, work: F) -> Result<T, FileLogError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut FileTailer) -> T + Send + 'static,
+    {
+        let tailer = Arc::clone(&self.tailer);
+        tokio::task::spawn_blocking(move || work(&mut lock(&tailer)))
+            .await
+            .map_err(|e| FileLogError::Io(std::io::Error::other(e)))
+    }
+
+    /// Polls the files once, delivering and checkpointing what was read. When
+    /// delivery fails the files are rewound, so the next poll reads the same
+    /// records again.
+    pub async fn poll(&self) -> Result<(), FileLogError> {
+        let request = self
+            .with_tailer(|tailer| tailer.poll(SystemTime::now()))
+            .await?;
+        if let Some(request) = request {
+            if let Err(status) = self.service.export(tonic::Request::new(request)).await {
+                lock(&self.tailer).rewind();
+                return Err(status.into());
+            }
+            self.with_tailer(|tailer| {
+                tailer.commit();
+                tailer.save_checkpoint()
+            })
+            .await??;
+        }
+        Ok(())
+    }
+
+    /// Polls the files on every interval. Returns once a poll fails to read
+    /// the files, to write the checkpoint or to deliver the logs, as happens
+    /// when the logs channel was closed.
+    pub async fn run(&self) -> Result<Infallible, FileLogError> {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.poll().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::logs::OtelLogsServiceForwarder;
+    use std::fs::OpenOptions;
+    use std::time::UNIX_EPOCH;
+
+    /// A fresh directory under the temporary directory
+    fn directory(name: &str) -> std::io::Result<PathBuf> {
+        let directory =
+            std::env::temp_dir().join(format!("otelapis-filelog-{name}-{}", std::process::id()));
+        if directory.exists() {
+            std::fs::remove_dir_all(&directory)?;
+        }
+        std::fs::create_dir_all(&directory)?;
+        Ok(directory)
+    }
+
+    fn append(path: &Path, text: &str) -> std::io::Result<()> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(text.as_bytes())
+    }
+
+    fn bodies(request: Option<ExportLogsServiceRequest>) -> Vec<String> {
+        request
+            .iter()
+            .flat_map(|r| r.resource_logs.iter())
+            .flat_map(|rl| rl.scope_logs.iter())
+            .flat_map(|sl| sl.log_records.iter())
+            .filter_map(|record| match record.body.as_ref()?.value.as_ref()? {
+                any_value::Value::StringValue(s) => Some(s.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn tail_rotate_and_resume() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = directory("tail")?;
+        let log = directory.join("app.log");
+        let checkpoint = directory.join("checkpoint");
+        let pattern = directory.join("*.log*").to_string_lossy().into_owned();
+        append(&log, "old\n")?;
+
+        let mut tailer = FileTailer::new([pattern.as_str()])?
+            .with_multiline("^\\S")?
+            .with_checkpoint(&checkpoint)?;
+        // existing content is skipped
+        assert_eq!(bodies(tailer.poll(UNIX_EPOCH)), Vec::<String>::new());
+
+        append(&log, "first\n  at frame\nsecond\npart")?;
+        assert_eq!(bodies(tailer.poll(UNIX_EPOCH)), vec!["first\n  at frame"]);
+        // the pending record completes once its file is idle
+        assert_eq!(bodies(tailer.poll(UNIX_EPOCH)), vec!["second"]);
+        tailer.save_checkpoint()?;
+
+        // rotation by renaming reads the rest of the old file first
+        append(&log, "ial\n")?;
+        std::fs::rename(&log, directory.join("app.log.1"))?;
+        append(&log, "new\n")?;
+        assert_eq!(bodies(tailer.poll(UNIX_EPOCH)), Vec::<String>::new());
+        let mut records = bodies(tailer.poll(UNIX_EPOCH));
+        records.sort();
+        assert_eq!(records, vec!["new", "partial"]);
+
+        // rotation by copying and truncating starts over
+        std::fs::write(&log, "")?;
+        append(&log, "x\n")?;
+        tailer.poll(UNIX_EPOCH);
+        assert_eq!(bodies(tailer.poll(UNIX_EPOCH)), vec!["x"]);
+
+        // a new tailer resumes from the checkpoint
+        let mut resumed = FileTailer::new([directory.join("app.log.1").to_string_lossy()])?
+            .with_checkpoint(&checkpoint)?;
+        assert_eq!(bodies(resumed.poll(UNIX_EPOCH)), vec!["partial"]);
+        std::fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn deliver_file_logs() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = directory("deliver")?;
+        let log = directory.join("app.log");
+        append(&log, "hello\n")?;
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        let tailer = FileTailer::new([directory.join("*.log").to_string_lossy()])?
+            .with_start_at_beginning(true)
+            .with_checkpoint(directory.join("checkpoint"))?;
+        let receiver = FileLogReceiver::new(tailer, OtelLogsServiceForwarder::with_sender(tx));
+        receiver.poll().await?;
+
+        let request = rx.recv().await.ok_or("nothing delivered")?;
+        let record = request
+            .resource_logs
+            .first()
+            .and_then(|rl| rl.scope_logs.first())
+            .and_then(|sl| sl.log_records.first())
+            .ok_or("no record")?;
+        let attributes: Vec<(&str, String)> = record
+            .attributes
+            .iter()
+            .map(|kv| {
+                let value = kv
+                    .value
+                    .as_ref()
+                    .map(crate::common::any_value_to_string)
+                    .unwrap_or_default();
+                (kv.key.as_str(), value)
+            })
+            .collect();
+        assert_eq!(
+            attributes,
+            vec![
+                ("log.file.path", log.to_string_lossy().into_owned()),
+                ("log.file.name", "app.log".to_string())
+            ]
+        );
+        let checkpoint = std::fs::read_to_string(directory.join("checkpoint"))?;
+        assert!(checkpoint.starts_with("6\t"));
+        std::fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn rewind_undelivered() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = directory("rewind")?;
+        let log = directory.join("app.log");
+        let checkpoint = directory.join("checkpoint");
+        append(&log, "hello\n")?;
+        let (tx, rx) = tokio::sync::mpsc::channel(4);
+        drop(rx);
+        let tailer = FileTailer::new([directory.join("*.log*").to_string_lossy()])?
+            .with_start_at_beginning(true)
+            .with_checkpoint(&checkpoint)?;
+        let receiver = FileLogReceiver::new(tailer, OtelLogsServiceForwarder::with_sender(tx));
+        assert!(matches!(
+            receiver.poll().await,
+            Err(FileLogError::Deliver(_))
+        ));
+        // nothing was checkpointed and the lines are read again
+        assert!(!checkpoint.exists());
+        let mut tailer = lock(&receiver.tailer);
+        assert_eq!(bodies(tailer.poll(UNIX_EPOCH)), vec!["hello"]);
+
+        // rotated files are read again too, until committed
+        append(&log, "world\n")?;
+        std::fs::rename(&log, directory.join("app.log.1"))?;
+        std::fs::remove_file(directory.join("app.log.1"))?;
+        assert_eq!(bodies(tailer.poll(UNIX_EPOCH)), vec!["world"]);
+        tailer.rewind();
+        assert_eq!(bodies(tailer.poll(UNIX_EPOCH)), vec!["hello", "world"]);
+        tailer.commit();
+        tailer.rewind();
+        assert_eq!(bodies(tailer.poll(UNIX_EPOCH)), Vec::<String>::new());
+        drop(tailer);
+        std::fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Receiving Jaeger spans as OTLP traces.
+//!
+//! Jaeger agents and clients report batches of spans either to the
+//! `api_v2` `CollectorService` over gRPC or as Thrift binary encoded batches
+//! posted to `/api/traces`. Both decode to an [`api_v2::Batch`], which is
+//! converted following the OpenTelemetry Jaeger mapping. The process becomes
+//! the resource, tags become attributes, logs become events and references
+//! other than the parent become links. The `span.kind`, `otel.status_code`,
+//! `error` and `otel.scope.*` tags carry the span kind, the span status and
+//! the instrumentation scope.
+
+use crate::common::receiver::ReceiverError;
+
+mod convert;
+mod receiver;
+mod thrift;
+
+pub use api_v2::collector_service_server::{CollectorService, CollectorServiceServer};
+pub use convert::from_batch;
+pub use receiver::{JaegerReceiver, TRACES_PATH};
+pub use thrift::decode_thrift_batch;
+
+/// The Jaeger `api_v2` protocol buffers
+#[allow(
+    clippy::all,
+    clippy::unwrap_used,
+    clippy::unnecessary_unwrap,
+    clippy::pedantic,
+    missing_docs
+)]
+pub mod api_v2 {
+    tonic::include_proto!("jaeger.api_v2");
+}
+
+/// An error decoding or delivering Jaeger spans
+#[derive(Debug)]
+pub enum JaegerError {
+    /// The body is not a valid Thrift binary encoded batch
+    Thrift {
+        /// What is invalid about the encoding
+        reason: &'static str,
+    },
+    /// A span is not a valid Jaeger span
+    Invalid {
+        /// What is invalid about the span
+        reason: &'static str,
+    },
+    /// The spans could not be delivered to the channel
+    Receive(ReceiverError),
+}
+
+impl std::fmt::Display for JaegerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JaegerError::Thrift { reason } => write!(f, "invalid Thrift batch: {reason}"),
+            JaegerError::Invalid { reason } => write!(f, "invalid Jaeger span: {reason}"),
+            JaegerError::Receive(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for JaegerError {}
+
+impl From<tonic::Status> for JaegerError {
+    fn from(status: tonic::Status) -> Self {
+        JaegerError::Receive(ReceiverError::from(status))
+    }
+}
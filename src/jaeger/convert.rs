@@ -0,0 +1,246 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::api_v2::{self, Batch, Process, SpanRefType, ValueType};
+use super::JaegerError;
+use crate::common::{find_or_push, to_nanos};
+use crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest;
+use crate::opentelemetry::proto::common::v1::{
+    any_value, AnyValue, InstrumentationScope, KeyValue,
+};
+use crate::opentelemetry::proto::resource::v1::Resource;
+use crate::opentelemetry::proto::trace::v1::{
+    span::{Event, Link, SpanKind},
+    status::StatusCode,
+    ResourceSpans, ScopeSpans, Span, Status,
+};
+
+const SERVICE_NAME: &str = "service.name";
+
+const SPAN_KIND_TAG: &str = "span.kind";
+const STATUS_CODE_TAG: &str = "otel.status_code";
+const STATUS_DESCRIPTION_TAG: &str = "otel.status_description";
+const ERROR_TAG: &str = "error";
+const SCOPE_NAME_TAG: &str = "otel.scope.name";
+const SCOPE_VERSION_TAG: &str = "otel.scope.version";
+const LIBRARY_NAME_TAG: &str = "otel.library.name";
+const LIBRARY_VERSION_TAG: &str = "otel.library.version";
+const TRACE_STATE_TAG: &str = "w3c.tracestate";
+
+/// The log field naming the event
+const EVENT_FIELD: &str = "event";
+
+fn invalid(reason: &'static str) -> JaegerError {
+    JaegerError::Invalid { reason }
+}
+
+fn attribute(tag: &api_v2::KeyValue) -> KeyValue {
+    let value = match tag.v_type() {
+        ValueType::String => any_value::Value::StringValue(tag.v_str.clone()),
+        ValueType::Bool => any_value::Value::BoolValue(tag.v_bool),
+        ValueType::Int64 => any_value::Value::IntValue(tag.v_int64),
+        ValueType::Float64 => any_value::Value::DoubleValue(tag.v_float64),
+        ValueType::Binary => any_value::Value::BytesValue(tag.v_binary.clone()),
+    };
+    KeyValue {
+        key: tag.key.clone(),
+        value: Some(AnyValue { value: Some(value) }),
+    }
+}
+
+/// Renders a tag as a string, for tags carrying the span kind or status
+fn tag_string(tag: &api_v2::KeyValue) -> String {
+    match tag.v_type() {
+        ValueType::String => tag.v_str.clone(),
+        ValueType::Bool => tag.v_bool.to_string(),
+        ValueType::Int64 => tag.v_int64.to_string(),
+        ValueType::Float64 => tag.v_float64.to_string(),
+        ValueType::Binary => String::from_utf8_lossy(&tag.v_binary).into_owned(),
+    }
+}
+
+/// Pads 8 byte trace ids to the 16 bytes of OTLP trace ids
+fn padded_trace_id(id: &[u8]) -> Result<Vec<u8>, JaegerError> {
+    match id.len() {
+        16 => Ok(id.to_vec()),
+        8 => Ok([0; 8].iter().chain(id).copied().collect()),
+        _ => Err(invalid("trace id is not 8 or 16 bytes")),
+    }
+}
+
+fn resource(process: Option<&Process>) -> Resource {
+    let mut attributes = Vec::new();
+    if let Some(process) = process {
+        if !process.service_name.is_empty() {
+            attributes.push(KeyValue {
+                key: SERVICE_NAME.to_string(),
+                value: Some(AnyValue {
+                    value: Some(any_value::Value::StringValue(process.service_name.clone())),
+                }),
+            });
+        }
+        attributes.extend(process.tags.iter().map(attribute));
+    }
+    Resource {
+        attributes,
+        dropped_attributes_count: 0,
+    }
+}
+
+fn span(jaeger: &api_v2::Span) -> Result<(InstrumentationScope, Span), JaegerError> {
+    let trace_id = padded_trace_id(&jaeger.trace_id)?;
+    if jaeger.span_id.len() != 8 {
+        return Err(invalid("span id is not 8 bytes"));
+    }
+
+    let mut scope = InstrumentationScope::default();
+    let mut kind = SpanKind::Internal;
+    let mut code = None;
+    let mut error = false;
+    let mut message = String::new();
+    let mut trace_state = String::new();
+    let mut attributes = Vec::new();
+    for tag in &jaeger.tags {
+        match tag.key.as_str() {
+            SPAN_KIND_TAG => {
+                kind = match tag_string(tag).as_str() {
+                    "client" => SpanKind::Client,
+                    "server" => SpanKind::Server,
+                    "producer" => SpanKind::Producer,
+                    "consumer" => SpanKind::Consumer,
+                    _ => SpanKind::Internal,
+                };
+            }
+            STATUS_CODE_TAG => {
+                code = match tag_string(tag).as_str() {
+                    "OK" => Some(StatusCode::Ok),
+                    "ERROR" => Some(StatusCode::Error),
+                    _ => None,
+                };
+            }
+            STATUS_DESCRIPTION_TAG => message = tag_string(tag),
+            ERROR_TAG => error = tag_string(tag) == "true",
+            SCOPE_NAME_TAG | LIBRARY_NAME_TAG => scope.name = tag_string(tag),
+            SCOPE_VERSION_TAG | LIBRARY_VERSION_TAG => scope.version = tag_string(tag),
+            TRACE_STATE_TAG => trace_state = tag_string(tag),
+            _ => attributes.push(attribute(tag)),
+        }
+    }
+    let status = code.or(error.then_some(StatusCode::Error)).map(|code| {
+        let mut status = Status {
+            message,
+            ..Status::default()
+        };
+        status.set_code(code);
+        status
+    });
+
+    // the parent is the first span this one is a child of in the same trace,
+    // any other reference is a link
+    let mut parent_span_id = Vec::new();
+    let mut links = Vec::new();
+    for reference in &jaeger.references {
+        let reference_trace_id = padded_trace_id(&reference.trace_id)?;
+        if parent_span_id.is_empty()
+            && reference.ref_type() == SpanRefType::ChildOf
+            && reference_trace_id == trace_id
+        {
+            parent_span_id.clone_from(&reference.span_id);
+        } else {
+            links.push(Link {
+                trace_id: reference_trace_id,
+                span_id: reference.span_id.clone(),
+                ..Link::default()
+            });
+        }
+    }
+
+    let start_time_unix_nano = jaeger
+        .start_time
+        .as_ref()
+        .and_then(|t| to_nanos(t.seconds, i64::from(t.nanos)))
+        .unwrap_or_default();
+    let duration = jaeger
+        .duration
+        .as_ref()
+        .and_then(|d| to_nanos(d.seconds, i64::from(d.nanos)))
+        .unwrap_or_default();
+    let events = jaeger
+        .logs
+        .iter()
+        .map(|log| {
+            let mut event = Event {
+                time_unix_nano: log
+                    .timestamp
+                    .as_ref()
+                    .and_then(|t| to_nanos(t.seconds, i64::from(t.nanos)))
+                    .unwrap_or_default(),
+                ..Event::default()
+            };
+            for field in &log.fields {
+                if field.key == EVENT_FIELD && event.name.is_empty() {
+                    event.name = tag_string(field);
+                } else {
+                    event.attributes.push(attribute(field));
+                }
+            }
+            event
+        })
+        .collect();
+
+    let mut span = Span {
+        trace_id,
+        span_id: jaeger.span_id.clone(),
+        trace_state,
+        parent_span_id,
+        name: jaeger.operation_name.clone(),
+        start_time_unix_nano,
+        end_time_unix_nano: start_time_unix_nano.saturating_add(duration),
+        attributes,
+        events,
+        links,
+        status,
+        ..Span::default()
+    };
+    span.set_kind(kind);
+    Ok((scope, span))
+}
+
+/// Converts a Jaeger batch into a trace request. Spans carrying their own
+/// process are grouped under a resource of their own.
+pub fn from_batch(batch: &Batch) -> Result<ExportTraceServiceRequest, JaegerError> {
+    let mut resource_spans: Vec<ResourceSpans> = Vec::new();
+    for jaeger in &batch.spans {
+        let resource = resource(jaeger.process.as_ref().or(batch.process.as_ref()));
+        let (scope, span) = span(jaeger)?;
+        let rs = find_or_push(
+            &mut resource_spans,
+            |rs| rs.resource.as_ref() == Some(&resource),
+            || ResourceSpans {
+                resource: Some(resource.clone()),
+                ..ResourceSpans::default()
+            },
+        );
+        let ss = find_or_push(
+            &mut rs.scope_spans,
+            |ss| ss.scope.as_ref() == Some(&scope),
+            || ScopeSpans {
+                scope: Some(scope.clone()),
+                ..ScopeSpans::default()
+            },
+        );
+        ss.spans.push(span);
+    }
+    Ok(ExportTraceServiceRequest { resource_spans })
+}
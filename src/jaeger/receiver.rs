@@ -0,0 +1,284 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::api_v2::collector_service_server::CollectorService;
+use super::api_v2::{Batch, PostSpansRequest, PostSpansResponse};
+use super::{decode_thrift_batch, from_batch, JaegerError};
+use crate::common::receiver::ReceiverError;
+use crate::common::DEFAULT_MAX_MESSAGE_SIZE;
+use crate::trace::TraceService;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// The path Jaeger clients post Thrift batches to
+pub const TRACES_PATH: &str = "/api/traces";
+
+/// The content types of Thrift binary encoded batches
+const THRIFT_CONTENT_TYPES: [&str; 2] = [
+    "application/x-thrift",
+    "application/vnd.apache.thrift.binary",
+];
+
+fn respond(status: StatusCode, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(body.into()));
+    *response.status_mut() = status;
+    response
+}
+
+/// Receives Jaeger batches and delivers them as traces through a trace
+/// service. Batches arrive through the `CollectorService` gRPC service, which
+/// is served by wrapping the receiver in a [`super::CollectorServiceServer`],
+/// or as Thrift posted to `/api/traces`. Batches that cannot be converted are
+/// rejected and counted.
+pub struct JaegerReceiver<S> {
+    service: S,
+    max_body_size: usize,
+    rejected: AtomicU64,
+}
+
+impl<S: TraceService> JaegerReceiver<S> {
+    /// Creates a receiver delivering through a trace service
+    pub fn new(service: S) -> Self {
+        JaegerReceiver {
+            service,
+            max_body_size: DEFAULT_MAX_MESSAGE_SIZE,
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Limits the size of Thrift request bodies
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// The number of batches rejected because they could not be decoded or
+    /// converted
+    pub fn rejected_batches(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Converts a batch and delivers its spans
+    pub async fn ingest(&self, batch: &Batch) -> Result<(), JaegerError> {
+        let request = match from_batch(batch) {
+            Ok(request) => request,
+            Err(e) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+        if request.resource_spans.is_empty() {
+            return Ok(());
+        }
+        self.service.export(tonic::Request::new(request)).await?;
+        Ok(())
+    }
+
+    async fn handle(&self, request: Request<Incoming>) -> Response<Full<Bytes>> {
+        if request.uri().path() != TRACES_PATH {
+            return respond(StatusCode::NOT_FOUND, "not found");
+        }
+        if request.method() != Method::POST {
+            return respond(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+        }
+        let thrift = request
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .is_some_and(|mime| {
+                THRIFT_CONTENT_TYPES
+                    .iter()
+                    .any(|thrift| mime.trim().eq_ignore_ascii_case(thrift))
+            });
+        if !thrift {
+            return respond(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported content type",
+            );
+        }
+        let body = match Limited::new(request.into_body(), self.max_body_size)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(e) if e.is::<LengthLimitError>() => {
+                return respond(StatusCode::PAYLOAD_TOO_LARGE, "body too large");
+            }
+            Err(e) => return respond(StatusCode::BAD_REQUEST, e.to_string()),
+        };
+        let batch = match decode_thrift_batch(&body) {
+            Ok(batch) => batch,
+            Err(e) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return respond(StatusCode::BAD_REQUEST, e.to_string());
+            }
+        };
+        match self.ingest(&batch).await {
+            Ok(()) => respond(StatusCode::ACCEPTED, Bytes::new()),
+            Err(e @ JaegerError::Receive(_)) => {
+                respond(StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+            }
+            Err(e) => respond(StatusCode::BAD_REQUEST, e.to_string()),
+        }
+    }
+
+    /// Accepts connections and serves Thrift HTTP/1.1 requests on each until
+    /// accepting fails
+    pub async fn serve_http(self: Arc<Self>, listener: TcpListener) -> std::io::Result<Infallible> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let receiver = self.clone();
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(move |request| {
+                    let receiver = receiver.clone();
+                    async move { Ok::<_, Infallible>(receiver.handle(request).await) }
+                });
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+            });
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<S: TraceService> CollectorService for JaegerReceiver<S> {
+    async fn post_spans(
+        &self,
+        request: tonic::Request<PostSpansRequest>,
+    ) -> Result<tonic::Response<PostSpansResponse>, tonic::Status> {
+        let Some(batch) = request.into_inner().batch else {
+            return Ok(tonic::Response::new(PostSpansResponse {}));
+        };
+        match self.ingest(&batch).await {
+            Ok(()) => Ok(tonic::Response::new(PostSpansResponse {})),
+            Err(JaegerError::Receive(ReceiverError::Deliver(status))) => Err(*status),
+            Err(e) => Err(tonic::Status::invalid_argument(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::all::{OpenTelemetryEvents, TraceServiceForwarder};
+    use crate::opentelemetry::proto::trace::v1::{span::SpanKind, status::StatusCode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn post(address: std::net::SocketAddr, body: &[u8]) -> std::io::Result<String> {
+        let mut stream = tokio::net::TcpStream::connect(address).await?;
+        let head = format!(
+            "POST {TRACES_PATH} HTTP/1.1\r\nhost: localhost\r\ncontent-type: application/x-thrift\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    pub async fn receive_batches() -> Result<(), Box<dyn std::error::Error>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let receiver = Arc::new(JaegerReceiver::new(TraceServiceForwarder::with_sender(tx)));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(receiver.clone().serve_http(listener));
+
+        let body = super::super::thrift::test::batch();
+        let response = post(address, &body).await?;
+        assert!(response.starts_with("HTTP/1.1 202"), "{response}");
+
+        let Some(OpenTelemetryEvents::Trace(request, _)) = rx.recv().await else {
+            return Err("no trace delivered".into());
+        };
+        let resource = request
+            .resource_spans
+            .first()
+            .and_then(|rs| rs.resource.clone())
+            .unwrap_or_default();
+        let keys: Vec<&str> = resource
+            .attributes
+            .iter()
+            .map(|kv| kv.key.as_str())
+            .collect();
+        assert_eq!(keys, vec!["service.name", "hostname"]);
+        let spans: Vec<_> = request.spans().map(|(_, _, span)| span.clone()).collect();
+        let (server, child) = match spans.as_slice() {
+            [server, child] => (server, child),
+            _ => return Err("expected two spans".into()),
+        };
+        assert_eq!(server.kind(), SpanKind::Server);
+        assert_eq!(server.start_time_unix_nano, 1_500_000_000);
+        assert_eq!(server.end_time_unix_nano, 1_502_500_000);
+        assert_eq!(
+            server
+                .attributes
+                .iter()
+                .map(|kv| kv.key.as_str())
+                .collect::<Vec<_>>(),
+            vec!["http.status_code"]
+        );
+        assert_eq!(
+            server.events.first().map(|e| e.name.as_str()),
+            Some("cache miss")
+        );
+        assert_eq!(child.parent_span_id, server.span_id);
+        assert_eq!(
+            child.status.as_ref().map(|s| s.code()),
+            Some(StatusCode::Error)
+        );
+
+        // the same batch over gRPC
+        let batch = decode_thrift_batch(&body)?;
+        receiver
+            .post_spans(tonic::Request::new(PostSpansRequest { batch: Some(batch) }))
+            .await?;
+        assert!(matches!(
+            rx.recv().await,
+            Some(OpenTelemetryEvents::Trace(..))
+        ));
+
+        let invalid = Batch {
+            spans: vec![super::super::api_v2::Span::default()],
+            process: None,
+        };
+        let result = receiver
+            .post_spans(tonic::Request::new(PostSpansRequest {
+                batch: Some(invalid),
+            }))
+            .await;
+        assert_eq!(
+            result.err().map(|s| s.code()),
+            Some(tonic::Code::InvalidArgument)
+        );
+        assert_eq!(receiver.rejected_batches(), 1);
+
+        // a batch cut off within its list of spans is rejected
+        let truncated = body.get(..body.len() / 2).unwrap_or_default();
+        let response = post(address, truncated).await?;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        assert_eq!(receiver.rejected_batches(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,443 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decoding of `jaeger.thrift` batches in the Thrift binary protocol.
+
+use super::api_v2::{Batch, KeyValue, Log, Process, Span, SpanRef, SpanRefType, ValueType};
+use super::JaegerError;
+
+const STOP: u8 = 0;
+const BOOL: u8 = 2;
+const BYTE: u8 = 3;
+const DOUBLE: u8 = 4;
+const I16: u8 = 6;
+const I32: u8 = 8;
+const I64: u8 = 10;
+const STRING: u8 = 11;
+const STRUCT: u8 = 12;
+const MAP: u8 = 13;
+const SET: u8 = 14;
+const LIST: u8 = 15;
+
+/// How deeply unknown fields may nest before the batch is rejected
+const MAX_DEPTH: usize = 32;
+
+fn thrift(reason: &'static str) -> JaegerError {
+    JaegerError::Thrift { reason }
+}
+
+/// Reads Thrift binary protocol values from a buffer
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], JaegerError> {
+        if len > self.data.len() {
+            return Err(thrift("unexpected end of batch"));
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], JaegerError> {
+        <[u8; N]>::try_from(self.take(N)?).map_err(|_| thrift("unexpected end of batch"))
+    }
+
+    fn byte(&mut self) -> Result<u8, JaegerError> {
+        Ok(u8::from_be_bytes(self.array()?))
+    }
+
+    fn i16(&mut self) -> Result<i16, JaegerError> {
+        Ok(i16::from_be_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, JaegerError> {
+        Ok(i32::from_be_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, JaegerError> {
+        Ok(i64::from_be_bytes(self.array()?))
+    }
+
+    fn double(&mut self) -> Result<f64, JaegerError> {
+        Ok(f64::from_be_bytes(self.array()?))
+    }
+
+    fn bool(&mut self) -> Result<bool, JaegerError> {
+        Ok(self.byte()? != 0)
+    }
+
+    fn binary(&mut self) -> Result<Vec<u8>, JaegerError> {
+        let len = usize::try_from(self.i32()?).map_err(|_| thrift("negative length"))?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, JaegerError> {
+        String::from_utf8(self.binary()?).map_err(|_| thrift("string is not UTF-8"))
+    }
+
+    /// Reads the header of the next field, or `None` at the end of a struct
+    fn field(&mut self) -> Result<Option<(u8, i16)>, JaegerError> {
+        match self.byte()? {
+            STOP => Ok(None),
+            kind => Ok(Some((kind, self.i16()?))),
+        }
+    }
+
+    /// Reads the header of a list of elements of the given type
+    fn list(&mut self, element: u8) -> Result<usize, JaegerError> {
+        if self.byte()? != element {
+            return Err(thrift("unexpected list element type"));
+        }
+        let len = usize::try_from(self.i32()?).map_err(|_| thrift("negative length"))?;
+        // every element takes at least a byte, which bounds the allocation
+        if len > self.data.len() {
+            return Err(thrift("unexpected end of batch"));
+        }
+        Ok(len)
+    }
+
+    fn list_of<T>(
+        &mut self,
+        element: u8,
+        read: fn(&mut Self) -> Result<T, JaegerError>,
+    ) -> Result<Vec<T>, JaegerError> {
+        let len = self.list(element)?;
+        (0..len).map(|_| read(self)).collect()
+    }
+
+    /// Skips a value of the given type
+    fn skip(&mut self, kind: u8, depth: usize) -> Result<(), JaegerError> {
+        if depth > MAX_DEPTH {
+            return Err(thrift("nested too deeply"));
+        }
+        match kind {
+            BOOL | BYTE => self.take(1).map(|_| ()),
+            I16 => self.take(2).map(|_| ()),
+            I32 => self.take(4).map(|_| ()),
+            DOUBLE | I64 => self.take(8).map(|_| ()),
+            STRING => self.binary().map(|_| ()),
+            STRUCT => {
+                while let Some((kind, _)) = self.field()? {
+                    self.skip(kind, depth + 1)?;
+                }
+                Ok(())
+            }
+            MAP => {
+                let (key, value) = (self.byte()?, self.byte()?);
+                let len = usize::try_from(self.i32()?).map_err(|_| thrift("negative length"))?;
+                for _ in 0..len {
+                    self.skip(key, depth + 1)?;
+                    self.skip(value, depth + 1)?;
+                }
+                Ok(())
+            }
+            SET | LIST => {
+                let element = self.byte()?;
+                let len = usize::try_from(self.i32()?).map_err(|_| thrift("negative length"))?;
+                for _ in 0..len {
+                    self.skip(element, depth + 1)?;
+                }
+                Ok(())
+            }
+            _ => Err(thrift("unknown field type")),
+        }
+    }
+
+    fn tag(&mut self) -> Result<KeyValue, JaegerError> {
+        let mut tag = KeyValue::default();
+        while let Some(field) = self.field()? {
+            match field {
+                (STRING, 1) => tag.key = self.string()?,
+                (I32, 2) => tag.set_v_type(match self.i32()? {
+                    0 => ValueType::String,
+                    1 => ValueType::Float64,
+                    2 => ValueType::Bool,
+                    3 => ValueType::Int64,
+                    4 => ValueType::Binary,
+                    _ => return Err(thrift("unknown tag type")),
+                }),
+                (STRING, 3) => tag.v_str = self.string()?,
+                (DOUBLE, 4) => tag.v_float64 = self.double()?,
+                (BOOL, 5) => tag.v_bool = self.bool()?,
+                (I64, 6) => tag.v_int64 = self.i64()?,
+                (STRING, 7) => tag.v_binary = self.binary()?,
+                (kind, _) => self.skip(kind, 0)?,
+            }
+        }
+        Ok(tag)
+    }
+
+    fn log(&mut self) -> Result<Log, JaegerError> {
+        let mut log = Log::default();
+        while let Some(field) = self.field()? {
+            match field {
+                (I64, 1) => log.timestamp = Some(timestamp(self.i64()?)),
+                (LIST, 2) => log.fields = self.list_of(STRUCT, Self::tag)?,
+                (kind, _) => self.skip(kind, 0)?,
+            }
+        }
+        Ok(log)
+    }
+
+    fn span_ref(&mut self) -> Result<SpanRef, JaegerError> {
+        let (mut low, mut high, mut span_id) = (0, 0, 0);
+        let mut reference = SpanRef::default();
+        while let Some(field) = self.field()? {
+            match field {
+                (I32, 1) => reference.set_ref_type(match self.i32()? {
+                    0 => SpanRefType::ChildOf,
+                    1 => SpanRefType::FollowsFrom,
+                    _ => return Err(thrift("unknown reference type")),
+                }),
+                (I64, 2) => low = self.i64()?,
+                (I64, 3) => high = self.i64()?,
+                (I64, 4) => span_id = self.i64()?,
+                (kind, _) => self.skip(kind, 0)?,
+            }
+        }
+        reference.trace_id = trace_id(high, low);
+        reference.span_id = span_id.to_be_bytes().to_vec();
+        Ok(reference)
+    }
+
+    fn span(&mut self) -> Result<Span, JaegerError> {
+        let (mut low, mut high, mut parent_span_id) = (0, 0, 0);
+        let (mut start, mut duration) = (0, 0);
+        let mut span = Span::default();
+        while let Some(field) = self.field()? {
+            match field {
+                (I64, 1) => low = self.i64()?,
+                (I64, 2) => high = self.i64()?,
+                (I64, 3) => span.span_id = self.i64()?.to_be_bytes().to_vec(),
+                (I64, 4) => parent_span_id = self.i64()?,
+                (STRING, 5) => span.operation_name = self.string()?,
+                (LIST, 6) => span.references = self.list_of(STRUCT, Self::span_ref)?,
+                // the flags are a bit field carried in a signed integer
+                #[allow(clippy::cast_sign_loss)]
+                (I32, 7) => span.flags = self.i32()? as u32,
+                (I64, 8) => start = self.i64()?,
+                (I64, 9) => duration = self.i64()?,
+                (LIST, 10) => span.tags = self.list_of(STRUCT, Self::tag)?,
+                (LIST, 11) => span.logs = self.list_of(STRUCT, Self::log)?,
+                (kind, _) => self.skip(kind, 0)?,
+            }
+        }
+        span.trace_id = trace_id(high, low);
+        span.start_time = Some(timestamp(start));
+        // spans cannot end before they start
+        let duration = duration.max(0);
+        span.duration = Some(prost_types::Duration {
+            seconds: duration / 1_000_000,
+            // the remainder of microseconds is below a second
+            #[allow(clippy::cast_possible_truncation)]
+            nanos: (duration % 1_000_000 * 1000) as i32,
+        });
+        let parent = parent_span_id.to_be_bytes().to_vec();
+        if parent_span_id != 0
+            && !span
+                .references
+                .iter()
+                .any(|r| r.span_id == parent && r.ref_type() == SpanRefType::ChildOf)
+        {
+            span.references.insert(
+                0,
+                SpanRef {
+                    trace_id: span.trace_id.clone(),
+                    span_id: parent,
+                    ref_type: SpanRefType::ChildOf.into(),
+                },
+            );
+        }
+        Ok(span)
+    }
+
+    fn process(&mut self) -> Result<Process, JaegerError> {
+        let mut process = Process::default();
+        while let Some(field) = self.field()? {
+            match field {
+                (STRING, 1) => process.service_name = self.string()?,
+                (LIST, 2) => process.tags = self.list_of(STRUCT, Self::tag)?,
+                (kind, _) => self.skip(kind, 0)?,
+            }
+        }
+        Ok(process)
+    }
+
+    fn batch(&mut self) -> Result<Batch, JaegerError> {
+        let mut batch = Batch::default();
+        while let Some(field) = self.field()? {
+            match field {
+                (STRUCT, 1) => batch.process = Some(self.process()?),
+                (LIST, 2) => batch.spans = self.list_of(STRUCT, Self::span)?,
+                (kind, _) => self.skip(kind, 0)?,
+            }
+        }
+        Ok(batch)
+    }
+}
+
+/// Joins the halves of a trace id into its 16 bytes
+fn trace_id(high: i64, low: i64) -> Vec<u8> {
+    high.to_be_bytes()
+        .iter()
+        .chain(&low.to_be_bytes())
+        .copied()
+        .collect()
+}
+
+/// Converts epoch microseconds into a timestamp
+fn timestamp(micros: i64) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: micros.div_euclid(1_000_000),
+        // the remainder of microseconds is below a second
+        #[allow(clippy::cast_possible_truncation)]
+        nanos: (micros.rem_euclid(1_000_000) * 1000) as i32,
+    }
+}
+
+/// Decodes a Thrift binary encoded `jaeger.thrift` batch
+pub fn decode_thrift_batch(body: &[u8]) -> Result<Batch, JaegerError> {
+    Reader { data: body }.batch()
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Writes Thrift binary protocol values, just enough to encode batches
+    #[derive(Default)]
+    pub(crate) struct Writer(pub(crate) Vec<u8>);
+
+    impl Writer {
+        pub(crate) fn field(&mut self, kind: u8, id: i16) -> &mut Self {
+            self.0.push(kind);
+            self.0.extend_from_slice(&id.to_be_bytes());
+            self
+        }
+        pub(crate) fn i32(&mut self, id: i16, value: i32) -> &mut Self {
+            self.field(I32, id);
+            self.0.extend_from_slice(&value.to_be_bytes());
+            self
+        }
+        pub(crate) fn i64(&mut self, id: i16, value: i64) -> &mut Self {
+            self.field(I64, id);
+            self.0.extend_from_slice(&value.to_be_bytes());
+            self
+        }
+        pub(crate) fn string(&mut self, id: i16, value: &str) -> &mut Self {
+            self.field(STRING, id);
+            self.0
+                .extend_from_slice(&(value.len() as i32).to_be_bytes());
+            self.0.extend_from_slice(value.as_bytes());
+            self
+        }
+        pub(crate) fn list(&mut self, id: i16, element: u8, len: i32) -> &mut Self {
+            self.field(LIST, id);
+            self.0.push(element);
+            self.0.extend_from_slice(&len.to_be_bytes());
+            self
+        }
+        pub(crate) fn stop(&mut self) -> &mut Self {
+            self.0.push(STOP);
+            self
+        }
+    }
+
+    /// Encodes a batch of a `web` process with a server span and a child
+    pub(crate) fn batch() -> Vec<u8> {
+        let mut w = Writer::default();
+        w.field(STRUCT, 1).string(1, "web");
+        w.list(2, STRUCT, 1)
+            .string(1, "hostname")
+            .i32(2, 0)
+            .string(3, "h1")
+            .stop();
+        w.stop();
+        w.list(2, STRUCT, 2);
+        // a server span, with an unknown field to skip
+        w.i64(1, 2).i64(2, 1).i64(3, 10).i64(4, 0).string(5, "get");
+        w.i32(7, 1)
+            .i64(8, 1_500_000)
+            .i64(9, 2_500)
+            .string(99, "unknown");
+        w.list(10, STRUCT, 2);
+        w.string(1, "span.kind")
+            .i32(2, 0)
+            .string(3, "server")
+            .stop();
+        w.string(1, "http.status_code").i32(2, 3).i64(6, 200).stop();
+        w.list(11, STRUCT, 1).i64(1, 1_501_000);
+        w.list(2, STRUCT, 1)
+            .string(1, "event")
+            .i32(2, 0)
+            .string(3, "cache miss")
+            .stop();
+        w.stop();
+        w.stop();
+        // its child
+        w.i64(1, 2)
+            .i64(2, 1)
+            .i64(3, 11)
+            .i64(4, 10)
+            .string(5, "query");
+        w.i32(7, 1).i64(8, 1_500_100).i64(9, 1_000);
+        w.list(10, STRUCT, 1)
+            .string(1, "error")
+            .i32(2, 2)
+            .field(BOOL, 5);
+        w.0.push(1);
+        w.stop();
+        w.stop();
+        w.stop();
+        w.0
+    }
+
+    #[test]
+    pub fn decode_batch() -> Result<(), JaegerError> {
+        let encoded = batch();
+        let batch = decode_thrift_batch(&encoded)?;
+        let process = batch.process.clone().unwrap_or_default();
+        assert_eq!(process.service_name, "web");
+        assert_eq!(process.tags.first().map(|t| t.v_str.as_str()), Some("h1"));
+        assert_eq!(batch.spans.len(), 2);
+        let server = batch.spans.first().cloned().unwrap_or_default();
+        assert_eq!(server.trace_id, trace_id(1, 2));
+        assert_eq!(server.operation_name, "get");
+        assert!(server.references.is_empty());
+        assert_eq!(
+            server.start_time,
+            Some(prost_types::Timestamp {
+                seconds: 1,
+                nanos: 500_000_000
+            })
+        );
+        assert_eq!(server.tags.get(1).map(|t| t.v_int64), Some(200));
+        assert_eq!(server.logs.first().map(|l| l.fields.len()), Some(1));
+        let child = batch.spans.get(1).cloned().unwrap_or_default();
+        assert_eq!(
+            child.references.first().map(|r| r.span_id.clone()),
+            Some(10i64.to_be_bytes().to_vec())
+        );
+        assert_eq!(child.tags.first().map(|t| t.v_bool), Some(true));
+
+        assert!(matches!(
+            decode_thrift_batch(encoded.get(..encoded.len() / 2).unwrap_or_default()),
+            Err(JaegerError::Thrift { .. })
+        ));
+        Ok(())
+    }
+}
@@ -117,6 +117,48 @@ mod otelapis;
 /// Common facilities and conveniences
 pub mod common;
 
+/// Processor pipeline between receivers and channels
+pub mod processor;
+
+/// Consistent probabilistic sampling of traces and correlated logs
+pub mod sampling;
+
+/// Connectors deriving metrics from traces
+#[cfg(all(feature = "otel-trace", feature = "otel-metrics"))]
+pub mod connector;
+
+/// Receiving Jaeger spans over gRPC and Thrift HTTP
+#[cfg(feature = "jaeger")]
+pub mod jaeger;
+
+/// Tailing of local log files
+#[cfg(feature = "filelog")]
+pub mod filelog;
+
+/// Conversions between OTLP metrics and the InfluxDB and Graphite line protocols
+#[cfg(feature = "line-protocol")]
+pub mod line_protocol;
+
+/// Conversions between OTLP metrics and Prometheus
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+
+/// StatsD and DogStatsD ingestion
+#[cfg(feature = "statsd")]
+pub mod statsd;
+
+/// Syslog ingestion as OTLP logs
+#[cfg(feature = "syslog")]
+pub mod syslog;
+
+/// Conversions between OTLP traces and Zipkin v2
+#[cfg(feature = "zipkin")]
+pub mod zipkin;
+
+/// Attribute redaction and PII masking
+#[cfg(feature = "redaction")]
+pub mod redaction;
+
 pub use otelapis::opentelemetry;
 
 #[cfg(feature = "otel-trace")]
@@ -175,6 +217,41 @@ mod test {
         assert!(!e.is_ok());
     }
 
+    #[test]
+    pub fn fallible_from_chunk_responses() {
+        let e: FallibleOtelResponse = vec![
+            FallibleOtelResponse::new(1, 0, 0, "snot".to_string()),
+            FallibleOtelResponse::new(0, 0, 0, String::new()),
+            FallibleOtelResponse::new(2, 0, 0, "badger".to_string()),
+            FallibleOtelResponse::new(0, 0, 3, "snot".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(e.error_message, "snot; badger".to_string());
+        assert_eq!(e.rejected_logs, 3);
+        assert_eq!(e.rejected_metrics, 0);
+        assert_eq!(e.rejected_spans, 3);
+        assert!(!e.is_ok());
+
+        // success messages are only kept while nothing was rejected
+        let e: FallibleOtelResponse = vec![
+            FallibleOtelResponse::new(0, 0, 0, "Ok".to_string()),
+            FallibleOtelResponse::new(0, 0, 0, "Ok".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(e.error_message, "Ok".to_string());
+        let e: FallibleOtelResponse = vec![
+            FallibleOtelResponse::new(0, 0, 0, "Ok".to_string()),
+            FallibleOtelResponse::new(0, 2, 0, "snot".to_string()),
+            FallibleOtelResponse::new(0, 0, 0, "Ok".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(e.error_message, "snot".to_string());
+        assert_eq!(e.rejected_metrics, 2);
+    }
+
     #[test]
     pub fn fallible_from_log_response() {
         let log = ExportLogsServiceResponse {
@@ -219,4 +296,28 @@ mod test {
         assert_eq!(e.rejected_metrics, 0);
         assert_eq!(e.rejected_spans, 1);
     }
+
+    #[test]
+    pub fn same_resource_regardless_of_order() {
+        use crate::opentelemetry::proto::common::v1::{any_value, AnyValue, KeyValue};
+        use crate::opentelemetry::proto::resource::v1::Resource;
+
+        let kv = |key: &str, value: i64| KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::IntValue(value)),
+            }),
+        };
+        let resource = |attributes| Resource {
+            attributes,
+            dropped_attributes_count: 0,
+        };
+        let a = resource(vec![kv("a", 1), kv("a", 1), kv("b", 2)]);
+        let b = resource(vec![kv("b", 2), kv("a", 1), kv("a", 1)]);
+        let c = resource(vec![kv("a", 1), kv("b", 2), kv("b", 2)]);
+        assert!(super::common::same_resource(Some(&a), Some(&b)));
+        assert!(!super::common::same_resource(Some(&a), Some(&c)));
+        assert!(!super::common::same_resource(Some(&a), None));
+        assert!(super::common::same_resource(None, None));
+    }
 }
@@ -0,0 +1,358 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversions between OTLP metrics and the InfluxDB line protocol and Graphite
+//! plaintext protocol.
+//!
+//! Only gauges and sums are converted, the data point attributes together with
+//! the resource attributes become tags. Neither protocol carries resources, so
+//! metrics converted from them have an empty resource.
+//!
+//! A [`LineReceiver`] accepts either protocol over TCP or UDP and delivers the
+//! converted metrics through any metrics service, such as the metrics
+//! forwarders of the `metrics` and `all` channels.
+
+use crate::common::receiver::{ReceiverError, MAX_DATAGRAM};
+use crate::common::{any_value_to_string, nanos, string_value};
+use crate::metrics::MetricsService;
+use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use crate::opentelemetry::proto::common::v1::KeyValue;
+use crate::opentelemetry::proto::metrics::v1::{
+    metric::Data, number_data_point, AggregationTemporality, Gauge, Metric, NumberDataPoint,
+    ResourceMetrics, ScopeMetrics, Sum,
+};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, UdpSocket};
+
+mod graphite;
+mod influx;
+
+pub use graphite::{from_graphite, to_graphite};
+pub use influx::{from_influx, to_influx};
+
+/// An error parsing a line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineParseError {
+    /// The line the error occurred on, starting at 1
+    pub line: usize,
+    /// What is wrong with the line
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for LineParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for LineParseError {}
+
+/// The protocol of a line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineProtocol {
+    /// The InfluxDB line protocol
+    Influx,
+    /// The Graphite plaintext protocol, with optional tags
+    Graphite,
+}
+
+/// A number data point parsed from a line
+#[derive(Debug, Clone, PartialEq)]
+struct Point {
+    name: String,
+    counter: bool,
+    attributes: Vec<KeyValue>,
+    value: number_data_point::Value,
+    time_unix_nano: u64,
+}
+
+/// A number data point of a request with the tags it is exported with
+struct Tagged<'a> {
+    name: &'a str,
+    counter: bool,
+    tags: BTreeMap<String, String>,
+    point: &'a NumberDataPoint,
+}
+
+/// The number data points of the gauges and sums of a request
+fn tagged_points(request: &ExportMetricsServiceRequest) -> impl Iterator<Item = Tagged<'_>> {
+    request.resource_metrics.iter().flat_map(|rm| {
+        let resource = rm.resource.as_ref().map(|r| r.attributes.as_slice());
+        rm.scope_metrics
+            .iter()
+            .flat_map(|sm| &sm.metrics)
+            .flat_map(move |metric| {
+                let (points, counter) = match &metric.data {
+                    Some(Data::Gauge(g)) => (g.data_points.as_slice(), false),
+                    Some(Data::Sum(s)) => (
+                        s.data_points.as_slice(),
+                        s.is_monotonic
+                            && s.aggregation_temporality
+                                == AggregationTemporality::Cumulative as i32,
+                    ),
+                    _ => (&[][..], false),
+                };
+                points.iter().map(move |point| {
+                    let tags = resource
+                        .unwrap_or_default()
+                        .iter()
+                        .chain(&point.attributes)
+                        .map(|kv| {
+                            let value = kv.value.as_ref().map(any_value_to_string);
+                            (kv.key.clone(), value.unwrap_or_default())
+                        })
+                        .collect();
+                    Tagged {
+                        name: &metric.name,
+                        counter,
+                        tags,
+                        point,
+                    }
+                })
+            })
+    })
+}
+
+/// Groups parsed points into gauges and cumulative monotonic sums
+fn to_request(points: Vec<Point>) -> ExportMetricsServiceRequest {
+    let mut metrics: Vec<Metric> = Vec::new();
+    for point in points {
+        let metric = crate::common::find_or_push(
+            &mut metrics,
+            |m| m.name == point.name && matches!(m.data, Some(Data::Sum(_))) == point.counter,
+            || Metric {
+                name: point.name.clone(),
+                data: Some(if point.counter {
+                    Data::Sum(Sum {
+                        data_points: Vec::new(),
+                        aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                        is_monotonic: true,
+                    })
+                } else {
+                    Data::Gauge(Gauge {
+                        data_points: Vec::new(),
+                    })
+                }),
+                ..Metric::default()
+            },
+        );
+        let data_point = NumberDataPoint {
+            attributes: point.attributes,
+            time_unix_nano: point.time_unix_nano,
+            value: Some(point.value),
+            ..NumberDataPoint::default()
+        };
+        match &mut metric.data {
+            Some(Data::Sum(s)) => s.data_points.push(data_point),
+            Some(Data::Gauge(g)) => g.data_points.push(data_point),
+            _ => (),
+        }
+    }
+    if metrics.is_empty() {
+        return ExportMetricsServiceRequest::default();
+    }
+    ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: None,
+            scope_metrics: vec![ScopeMetrics {
+                scope: None,
+                metrics,
+                schema_url: String::new(),
+            }],
+            schema_url: String::new(),
+        }],
+    }
+}
+
+/// Parses the lines of a text, returning the points of the valid lines and
+/// the errors of the invalid ones
+fn parse_lines(
+    protocol: LineProtocol,
+    text: &str,
+    now: SystemTime,
+) -> (Vec<Point>, Vec<LineParseError>) {
+    let now = nanos(now);
+    let mut points = Vec::new();
+    let mut errors = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = match protocol {
+            LineProtocol::Influx => influx::parse_line(line, now),
+            LineProtocol::Graphite => graphite::parse_line(line, now).map(|p| vec![p]),
+        };
+        match parsed {
+            Ok(parsed) => points.extend(parsed),
+            Err(reason) => errors.push(LineParseError {
+                line: i + 1,
+                reason,
+            }),
+        }
+    }
+    (points, errors)
+}
+
+/// An error receiving lines, the socket failed or the converted metrics could
+/// not be delivered
+pub type LineReceiverError = ReceiverError;
+
+/// Receives InfluxDB or Graphite lines and delivers them as metrics through a
+/// metrics service. Every datagram, or every read from a connection, is
+/// delivered as one request. Malformed lines are dropped and counted.
+pub struct LineReceiver<S> {
+    protocol: LineProtocol,
+    service: S,
+    malformed: AtomicU64,
+}
+
+impl<S: MetricsService> LineReceiver<S> {
+    /// Creates a receiver for a protocol delivering through a metrics service
+    pub fn new(protocol: LineProtocol, service: S) -> Self {
+        LineReceiver {
+            protocol,
+            service,
+            malformed: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of lines dropped because they could not be parsed
+    pub fn malformed_lines(&self) -> u64 {
+        self.malformed.load(Ordering::Relaxed)
+    }
+
+    /// Converts lines and delivers the valid ones
+    pub async fn ingest(&self, text: &str) -> Result<(), LineReceiverError> {
+        let (points, errors) = parse_lines(self.protocol, text, SystemTime::now());
+        self.malformed
+            .fetch_add(errors.len() as u64, Ordering::Relaxed);
+        if points.is_empty() {
+            return Ok(());
+        }
+        self.service
+            .export(tonic::Request::new(to_request(points)))
+            .await?;
+        Ok(())
+    }
+
+    /// Receives datagrams, delivering the lines of each as one request. Returns
+    /// once the socket fails or a request is not accepted, as happens when the
+    /// metrics channel was closed.
+    pub async fn serve_udp(&self, socket: UdpSocket) -> Result<Infallible, LineReceiverError> {
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        loop {
+            let (length, _) = socket.recv_from(&mut buffer).await?;
+            let datagram = buffer.get(..length).unwrap_or_default();
+            self.ingest(&String::from_utf8_lossy(datagram)).await?;
+        }
+    }
+
+    /// Receives lines from a connection until it is closed. A connection
+    /// sending more than a datagram without a newline is rejected.
+    pub async fn serve_connection<R>(&self, mut stream: R) -> Result<(), LineReceiverError>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let mut buffer: Vec<u8> = Vec::new();
+        loop {
+            let read = stream.read_buf(&mut buffer).await?;
+            // lines are delivered once complete, the rest waits for the next read
+            let complete = match buffer.iter().rposition(|b| *b == b'\n') {
+                _ if read == 0 => buffer.len(),
+                Some(newline) => newline + 1,
+                // an incomplete line may be as long as a datagram
+                None if buffer.len() > MAX_DATAGRAM => {
+                    self.malformed.fetch_add(1, Ordering::Relaxed);
+                    return Err(LineReceiverError::Io(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "line too long",
+                    )));
+                }
+                None => continue,
+            };
+            let rest = buffer.split_off(complete);
+            self.ingest(&String::from_utf8_lossy(&buffer)).await?;
+            buffer = rest;
+            if read == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Accepts connections and receives lines from each until accepting fails.
+    /// Connections whose metrics cannot be delivered are closed.
+    pub async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> std::io::Result<Infallible> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let receiver = self.clone();
+            tokio::spawn(async move { receiver.serve_connection(stream).await });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::OtelMetricsServiceForwarder;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    pub async fn receive_lines() -> Result<(), Box<dyn std::error::Error>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        let service = OtelMetricsServiceForwarder::with_sender(tx);
+        let receiver = Arc::new(LineReceiver::new(LineProtocol::Graphite, service));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(receiver.clone().serve_tcp(listener));
+
+        let mut client = tokio::net::TcpStream::connect(address).await?;
+        client
+            .write_all(b"servers.a.load 1.5 1700000000\nbroken\n")
+            .await?;
+        client.shutdown().await?;
+        let request = rx.recv().await.ok_or("nothing delivered")?;
+        let metric = request
+            .resource_metrics
+            .first()
+            .and_then(|rm| rm.scope_metrics.first())
+            .and_then(|sm| sm.metrics.first())
+            .ok_or("no metric")?;
+        assert_eq!(metric.name, "servers.a.load");
+        assert_eq!(receiver.malformed_lines(), 1);
+
+        // a closed channel fails delivery
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        drop(rx);
+        let closed = LineReceiver::new(
+            LineProtocol::Influx,
+            OtelMetricsServiceForwarder::with_sender(tx),
+        );
+        assert!(closed.ingest("cpu value=1").await.is_err());
+
+        // a line longer than a datagram rejects the connection
+        let endless = vec![b'a'; 4 * MAX_DATAGRAM];
+        assert!(matches!(
+            receiver.serve_connection(endless.as_slice()).await,
+            Err(LineReceiverError::Io(_))
+        ));
+        assert_eq!(receiver.malformed_lines(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{parse_lines, string_value, tagged_points, to_request, LineParseError, LineProtocol};
+use super::{Point, Tagged};
+use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use crate::opentelemetry::proto::metrics::v1::number_data_point;
+use std::time::SystemTime;
+
+/// Replaces the characters that are not allowed in a path or tag with
+/// underscores
+fn sanitize(s: &str, invalid: &[char]) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_whitespace() || invalid.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// Parses a line into a point
+pub(super) fn parse_line(line: &str, now: u64) -> Result<Point, &'static str> {
+    let mut tokens = line.split_whitespace();
+    let path = tokens.next().ok_or("missing path")?;
+    let value: f64 = tokens
+        .next()
+        .ok_or("missing value")?
+        .parse()
+        .ok()
+        .filter(|value: &f64| value.is_finite())
+        .ok_or("invalid value")?;
+    let time_unix_nano = match tokens.next() {
+        Some(timestamp) => {
+            let seconds: f64 = timestamp.parse().map_err(|_| "invalid timestamp")?;
+            if seconds < 0.0 {
+                // carbon treats -1 as the time of arrival
+                now
+            } else if seconds.is_finite() {
+                // saturates far beyond any valid timestamp
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                let nanos = (seconds * 1e9) as u64;
+                nanos
+            } else {
+                return Err("invalid timestamp");
+            }
+        }
+        None => now,
+    };
+    if tokens.next().is_some() {
+        return Err("unexpected trailing content");
+    }
+    let mut parts = path.split(';');
+    let name = parts.next().unwrap_or_default();
+    if name.is_empty() {
+        return Err("missing path");
+    }
+    let mut attributes = Vec::new();
+    for tag in parts {
+        let (key, value) = tag.split_once('=').ok_or("invalid tag")?;
+        if key.is_empty() || value.is_empty() {
+            return Err("invalid tag");
+        }
+        attributes.push(string_value(key, value));
+    }
+    Ok(Point {
+        name: name.to_string(),
+        counter: false,
+        attributes,
+        value: number_data_point::Value::AsDouble(value),
+        time_unix_nano,
+    })
+}
+
+/// Converts the gauges and sums of a request into Graphite lines. The metric
+/// name becomes the path, with whitespace and `;` replaced by underscores, and
+/// the tags are appended in the Graphite tag syntax. Tags with empty values
+/// and non-finite values cannot be represented and are skipped.
+pub fn to_graphite(request: &ExportMetricsServiceRequest) -> String {
+    let mut out = String::new();
+    for Tagged {
+        name, tags, point, ..
+    } in tagged_points(request)
+    {
+        let value = match point.value {
+            Some(number_data_point::Value::AsInt(i)) => i.to_string(),
+            Some(number_data_point::Value::AsDouble(d)) if d.is_finite() => d.to_string(),
+            _ => continue,
+        };
+        out.push_str(&sanitize(name, &[';']));
+        for (key, value) in tags.iter().filter(|(_, v)| !v.is_empty()) {
+            out.push(';');
+            out.push_str(&sanitize(key, &[';', '!', '^', '=']));
+            out.push('=');
+            out.push_str(&sanitize(value, &[';', '~']));
+        }
+        out.push(' ');
+        out.push_str(&value);
+        out.push(' ');
+        out.push_str(&(point.time_unix_nano / 1_000_000_000).to_string());
+        out.push('\n');
+    }
+    out
+}
+
+/// Converts Graphite lines into gauges named after their paths, tags become
+/// attributes. Lines without a timestamp, or with a negative one, are stamped
+/// with `now`.
+pub fn from_graphite(
+    text: &str,
+    now: SystemTime,
+) -> Result<ExportMetricsServiceRequest, LineParseError> {
+    let (points, errors) = parse_lines(LineProtocol::Graphite, text, now);
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(to_request(points)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opentelemetry::proto::metrics::v1::metric::Data;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    pub fn graphite_round_trip() -> Result<(), LineParseError> {
+        let now = UNIX_EPOCH + Duration::from_secs(5);
+        let text = "servers.a.load;dc=eu;rack=7 0.5 1700000000\nservers.b.load 2 -1\n";
+        let request = from_graphite(text, now)?;
+        let points = request
+            .resource_metrics
+            .first()
+            .and_then(|rm| rm.scope_metrics.first())
+            .map(|sm| sm.metrics.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|m| match m.data {
+                Some(Data::Gauge(g)) => Some((m.name, g.data_points)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(points.len(), 2);
+        assert_eq!(
+            points
+                .last()
+                .and_then(|(_, p)| p.first())
+                .map(|p| p.time_unix_nano),
+            Some(5_000_000_000)
+        );
+
+        let lines = to_graphite(&request);
+        assert_eq!(
+            lines,
+            "servers.a.load;dc=eu;rack=7 0.5 1700000000\nservers.b.load 2 5\n"
+        );
+        assert_eq!(from_graphite(&lines, now)?, request);
+        assert_eq!(
+            from_graphite("a.b x", now),
+            Err(LineParseError {
+                line: 1,
+                reason: "invalid value"
+            })
+        );
+        assert_eq!(
+            from_graphite("a.b 1\na.b nan\na.b -inf", now),
+            Err(LineParseError {
+                line: 2,
+                reason: "invalid value"
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{parse_lines, string_value, tagged_points, to_request, LineParseError, LineProtocol};
+use super::{Point, Tagged};
+use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use crate::opentelemetry::proto::metrics::v1::number_data_point;
+use std::time::SystemTime;
+
+/// The field carrying the value of a gauge
+const GAUGE_FIELD: &str = "gauge";
+/// The field carrying the value of a counter
+const COUNTER_FIELD: &str = "counter";
+/// The field carrying a value without a type
+const VALUE_FIELD: &str = "value";
+
+/// Splits at the separators that are neither escaped nor, if `quotes` is set,
+/// inside a double quoted string
+fn split_unescaped(s: &str, separator: char, quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' if quotes => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(s.get(start..i).unwrap_or_default());
+                start = i + c.len_utf8();
+            }
+            _ => (),
+        }
+    }
+    parts.push(s.get(start..).unwrap_or_default());
+    parts
+}
+
+/// Removes the backslashes escaping the special characters of the protocol
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(next @ (',' | '=' | ' ' | '"' | '\\'))) => {
+                unescaped.push(*next);
+                chars.next();
+            }
+            (c, _) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Escapes the characters that are special in measurements, or in tag keys,
+/// tag values and field keys if `equals` is set
+fn escape(s: &str, equals: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            ',' | ' ' | '\\' => escaped.push('\\'),
+            '=' if equals => escaped.push('\\'),
+            // newlines cannot be escaped and would end the line
+            '\n' | '\r' => {
+                escaped.push(' ');
+                continue;
+            }
+            _ => (),
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Parses a field value, string fields have no numeric value
+fn parse_field(value: &str) -> Result<Option<number_data_point::Value>, &'static str> {
+    if value.starts_with('"') {
+        return Ok(None);
+    }
+    let number = if let Some(int) = value.strip_suffix('i') {
+        number_data_point::Value::AsInt(int.parse().map_err(|_| "invalid integer field")?)
+    } else if let Some(uint) = value.strip_suffix('u') {
+        let uint: u64 = uint.parse().map_err(|_| "invalid unsigned field")?;
+        match i64::try_from(uint) {
+            Ok(int) => number_data_point::Value::AsInt(int),
+            // unsigned values beyond i64 are kept approximately
+            #[allow(clippy::cast_precision_loss)]
+            Err(_) => number_data_point::Value::AsDouble(uint as f64),
+        }
+    } else {
+        match value {
+            "t" | "T" | "true" | "True" | "TRUE" => number_data_point::Value::AsInt(1),
+            "f" | "F" | "false" | "False" | "FALSE" => number_data_point::Value::AsInt(0),
+            _ => number_data_point::Value::AsDouble(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|value: &f64| value.is_finite())
+                    .ok_or("invalid float field")?,
+            ),
+        }
+    };
+    Ok(Some(number))
+}
+
+/// Parses a line into a point per numeric field
+pub(super) fn parse_line(line: &str, now: u64) -> Result<Vec<Point>, &'static str> {
+    let sections = split_unescaped(line, ' ', true);
+    let mut sections = sections.into_iter().filter(|s| !s.is_empty());
+    let key = sections.next().ok_or("missing measurement")?;
+    let fields = sections.next().ok_or("missing fields")?;
+    let time_unix_nano = match sections.next() {
+        Some(timestamp) => {
+            let timestamp: i64 = timestamp.parse().map_err(|_| "invalid timestamp")?;
+            u64::try_from(timestamp).map_err(|_| "timestamp before the epoch")?
+        }
+        None => now,
+    };
+    if sections.next().is_some() {
+        return Err("unexpected trailing content");
+    }
+
+    let mut key = split_unescaped(key, ',', false).into_iter();
+    let measurement = unescape(key.next().unwrap_or_default());
+    if measurement.is_empty() {
+        return Err("missing measurement");
+    }
+    let mut attributes = Vec::new();
+    for tag in key {
+        let parts = split_unescaped(tag, '=', false);
+        let [name, value] = parts.as_slice() else {
+            return Err("invalid tag");
+        };
+        attributes.push(string_value(&unescape(name), &unescape(value)));
+    }
+
+    let mut points = Vec::new();
+    for field in split_unescaped(fields, ',', true) {
+        let parts = split_unescaped(field, '=', true);
+        let [name, value] = parts.as_slice() else {
+            return Err("invalid field");
+        };
+        let name = unescape(name);
+        let Some(value) = parse_field(value)? else {
+            continue;
+        };
+        let (name, counter) = match name.as_str() {
+            COUNTER_FIELD => (measurement.clone(), true),
+            GAUGE_FIELD | VALUE_FIELD => (measurement.clone(), false),
+            field => (format!("{measurement}_{field}"), false),
+        };
+        points.push(Point {
+            name,
+            counter,
+            attributes: attributes.clone(),
+            value,
+            time_unix_nano,
+        });
+    }
+    Ok(points)
+}
+
+/// Converts the gauges and sums of a request into InfluxDB lines. The metric
+/// name becomes the measurement and the value is stored in a `counter` field
+/// for cumulative monotonic sums and in a `gauge` field otherwise. Tags with
+/// empty values and non-finite values cannot be represented and are skipped.
+pub fn to_influx(request: &ExportMetricsServiceRequest) -> String {
+    let mut out = String::new();
+    for Tagged {
+        name,
+        counter,
+        tags,
+        point,
+    } in tagged_points(request)
+    {
+        let value = match point.value {
+            Some(number_data_point::Value::AsInt(i)) => format!("{i}i"),
+            Some(number_data_point::Value::AsDouble(d)) if d.is_finite() => d.to_string(),
+            _ => continue,
+        };
+        out.push_str(&escape(name, false));
+        for (key, value) in tags.iter().filter(|(_, v)| !v.is_empty()) {
+            out.push(',');
+            out.push_str(&escape(key, true));
+            out.push('=');
+            out.push_str(&escape(value, true));
+        }
+        out.push(' ');
+        out.push_str(if counter { COUNTER_FIELD } else { GAUGE_FIELD });
+        out.push('=');
+        out.push_str(&value);
+        if point.time_unix_nano > 0 {
+            out.push(' ');
+            out.push_str(&point.time_unix_nano.to_string());
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Converts InfluxDB lines into metrics. Every numeric field becomes a data
+/// point of a gauge named after the measurement and the field, except for the
+/// `gauge` and `value` fields, which become gauges, and the `counter` field,
+/// which becomes a cumulative monotonic sum, all named after the measurement.
+/// Booleans become `0` and `1`, string fields are skipped. Lines without a
+/// timestamp are stamped with `now`.
+pub fn from_influx(
+    text: &str,
+    now: SystemTime,
+) -> Result<ExportMetricsServiceRequest, LineParseError> {
+    let (points, errors) = parse_lines(LineProtocol::Influx, text, now);
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(to_request(points)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opentelemetry::proto::metrics::v1::metric::Data;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    pub fn influx_round_trip() -> Result<(), LineParseError> {
+        let text = "cpu\\ load,host=a\\,b,region=eu gauge=1.5,idle=3i,up=t,note=\"x y\" 1000\n\
+                    requests,host=a counter=7i 2000\n";
+        let request = from_influx(text, UNIX_EPOCH)?;
+        let metrics = request
+            .resource_metrics
+            .first()
+            .and_then(|rm| rm.scope_metrics.first())
+            .map(|sm| sm.metrics.clone())
+            .unwrap_or_default();
+        let names: Vec<&str> = metrics.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["cpu load", "cpu load_idle", "cpu load_up", "requests"]
+        );
+        assert!(matches!(
+            metrics.last().and_then(|m| m.data.as_ref()),
+            Some(Data::Sum(s)) if s.is_monotonic
+        ));
+
+        let lines = to_influx(&request);
+        assert_eq!(
+            lines.lines().next(),
+            Some("cpu\\ load,host=a\\,b,region=eu gauge=1.5 1000")
+        );
+        assert_eq!(
+            lines.lines().last(),
+            Some("requests,host=a counter=7i 2000")
+        );
+        assert_eq!(from_influx(&lines, UNIX_EPOCH)?, request);
+
+        assert_eq!(
+            from_influx("cpu\nmem value=1", UNIX_EPOCH),
+            Err(LineParseError {
+                line: 1,
+                reason: "missing fields"
+            })
+        );
+        Ok(())
+    }
+}
@@ -15,11 +15,21 @@
 use crate::opentelemetry::proto::collector::logs::v1 as base;
 use crate::opentelemetry::proto::collector::logs::v1::logs_service_server as skel;
 
+#[cfg(feature = "log-parsing")]
+mod body;
 #[cfg(feature = "channels")]
 mod channels;
+mod iter;
+mod merge;
+mod severity;
+mod split;
 
+#[cfg(feature = "log-parsing")]
+pub use body::{BodyFormat, BodyParser, BodyTarget, DEFAULT_FAILURE_ATTRIBUTE};
 #[cfg(feature = "channels")]
 pub use channels::*;
+pub use merge::merge;
+pub use severity::{SeverityNormalizer, SyslogSeverity};
 
 /// Alias tonic request
 pub type OtelLogsRequest = tonic::Request<base::ExportLogsServiceRequest>;
@@ -63,3 +73,11 @@ impl skel::LogsService for OtelLogsService {
 pub fn make_service(handler: Box<OnLogsFn>) -> skel::LogsServiceServer<OtelLogsService> {
     skel::LogsServiceServer::new(OtelLogsService::with_handler(handler))
 }
+
+/// Alias processor chain for logs export requests
+pub type LogsProcessorChain = crate::processor::ProcessorChain<base::ExportLogsServiceRequest>;
+
+crate::processor::partial_success_from_disposition!(
+    base::ExportLogsPartialSuccess,
+    rejected_log_records
+);
@@ -0,0 +1,610 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::common::{any_string, parse_rfc3339};
+use crate::opentelemetry::proto::collector::logs::v1::ExportLogsServiceRequest;
+use crate::opentelemetry::proto::common::v1::{
+    any_value, AnyValue, ArrayValue, KeyValue, KeyValueList,
+};
+use crate::opentelemetry::proto::logs::v1::{LogRecord, SeverityNumber};
+use crate::processor::{Disposition, Processor};
+
+/// The attribute set on records whose body could not be parsed
+pub const DEFAULT_FAILURE_ATTRIBUTE: &str = "log.body.unparsed";
+
+/// A structured format of log bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BodyFormat {
+    /// A JSON object, nested values keep their structure
+    Json,
+    /// Space separated `key=value` pairs with optionally double quoted values,
+    /// keys without a value are `true`
+    Logfmt,
+    /// `key=value` pairs separated by spaces, commas or semicolons with
+    /// optionally single or double quoted values
+    KeyValue,
+}
+
+/// Where the fields of a parsed body go
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BodyTarget {
+    /// The body is replaced by a map of the fields
+    Body,
+    /// The fields become attributes, replacing attributes of the same name,
+    /// and the message field, if any, becomes the body
+    Attributes,
+}
+
+/// Parses string log bodies in structured formats and lifts well known fields
+/// into the log record.
+///
+/// The formats are tried in order and the first one the whole body parses in
+/// is used. The timestamp, severity, trace id and span id fields are moved into
+/// the fields of the log record when their values are valid. Bodies that are
+/// not strings are left alone, string bodies that cannot be parsed are left as
+/// they are and flagged with a boolean attribute.
+#[derive(Debug, Clone)]
+pub struct BodyParser {
+    formats: Vec<BodyFormat>,
+    target: BodyTarget,
+    timestamp_keys: Vec<String>,
+    severity_keys: Vec<String>,
+    trace_id_keys: Vec<String>,
+    span_id_keys: Vec<String>,
+    message_keys: Vec<String>,
+    failure_attribute: Option<String>,
+}
+
+impl Default for BodyParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn strings(keys: &[&str]) -> Vec<String> {
+    keys.iter().map(ToString::to_string).collect()
+}
+
+/// Converts a JSON value, numbers are integers when they fit
+fn from_json(value: serde_json::Value) -> AnyValue {
+    let value = match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::Bool(b) => Some(any_value::Value::BoolValue(b)),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Some(any_value::Value::IntValue(i)),
+            None => n.as_f64().map(any_value::Value::DoubleValue),
+        },
+        serde_json::Value::String(s) => Some(any_value::Value::StringValue(s)),
+        serde_json::Value::Array(values) => Some(any_value::Value::ArrayValue(ArrayValue {
+            values: values.into_iter().map(from_json).collect(),
+        })),
+        serde_json::Value::Object(fields) => Some(any_value::Value::KvlistValue(KeyValueList {
+            values: fields
+                .into_iter()
+                .map(|(key, value)| KeyValue {
+                    key,
+                    value: Some(from_json(value)),
+                })
+                .collect(),
+        })),
+    };
+    AnyValue { value }
+}
+
+fn parse_json(text: &str) -> Option<Vec<KeyValue>> {
+    match serde_json::from_str(text).ok()? {
+        serde_json::Value::Object(fields) => Some(
+            fields
+                .into_iter()
+                .map(|(key, value)| KeyValue {
+                    key,
+                    value: Some(from_json(value)),
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Parses a quoted value up to its closing quote, returning it and the rest
+fn quoted(text: &str, quote: char) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 't')) => value.push('\t'),
+                Some((_, escaped)) => value.push(escaped),
+                None => return None,
+            },
+            c if c == quote => return Some((value, text.get(i + c.len_utf8()..)?)),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Parses `key=value` pairs. `separators` end unquoted values and separate
+/// pairs, `quotes` may enclose values, `bare_keys` allows keys without value.
+fn parse_pairs(
+    mut text: &str,
+    separators: &[char],
+    quotes: &[char],
+    bare_keys: bool,
+) -> Option<Vec<KeyValue>> {
+    let valid_key = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '@' | '/');
+    let mut fields = Vec::new();
+    let mut pairs = 0;
+    loop {
+        text = text.trim_start_matches(|c: char| c.is_whitespace() || separators.contains(&c));
+        if text.is_empty() {
+            break;
+        }
+        let end = text.find(|c: char| !valid_key(c)).unwrap_or(text.len());
+        let (key, rest) = text.split_at(end);
+        if key.is_empty() {
+            return None;
+        }
+        let value = match rest.strip_prefix('=') {
+            Some(rest) => {
+                pairs += 1;
+                let quote = rest.chars().next().filter(|c| quotes.contains(c));
+                let (value, rest) = match quote {
+                    Some(quote) => quoted(rest.get(quote.len_utf8()..)?, quote)?,
+                    None => {
+                        let end = rest
+                            .find(|c: char| c.is_whitespace() || separators.contains(&c))
+                            .unwrap_or(rest.len());
+                        let (value, rest) = rest.split_at(end);
+                        (value.to_string(), rest)
+                    }
+                };
+                text = rest;
+                any_value::Value::StringValue(value)
+            }
+            None if bare_keys => {
+                text = rest;
+                any_value::Value::BoolValue(true)
+            }
+            None => return None,
+        };
+        // a value must be followed by a separator
+        if !text.is_empty()
+            && !text.starts_with(|c: char| c.is_whitespace() || separators.contains(&c))
+        {
+            return None;
+        }
+        fields.push(KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue { value: Some(value) }),
+        });
+    }
+    (pairs > 0).then_some(fields)
+}
+
+impl BodyFormat {
+    /// Parses a body into its top level fields, `None` if it is not in this
+    /// format
+    pub fn parse(self, text: &str) -> Option<Vec<KeyValue>> {
+        match self {
+            BodyFormat::Json => parse_json(text.trim()),
+            BodyFormat::Logfmt => parse_pairs(text, &[], &['"'], true),
+            BodyFormat::KeyValue => parse_pairs(text, &[',', ';'], &['"', '\''], false),
+        }
+    }
+}
+
+/// Parses hex of exactly `length` bytes
+fn parse_hex(text: &str, length: usize) -> Option<Vec<u8>> {
+    if text.len() != 2 * length || !text.is_ascii() {
+        return None;
+    }
+    let bytes = (0..length)
+        .map(|i| {
+            text.get(2 * i..2 * i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    // all zero ids are invalid
+    bytes.iter().any(|b| *b != 0).then_some(bytes)
+}
+
+/// Nanoseconds per unit of numeric epoch timestamps below each magnitude:
+/// seconds below 1e11, milliseconds below 1e14 and microseconds below 1e17.
+/// Larger timestamps are taken to be nanoseconds.
+const EPOCH_UNITS: [(u64, u64); 3] = [
+    (100_000_000_000, 1_000_000_000),
+    (100_000_000_000_000, 1_000_000),
+    (100_000_000_000_000_000, 1_000),
+];
+
+/// Nanoseconds per unit of the first magnitude the timestamp is below
+fn epoch_scale<F: Fn(u64) -> bool>(below: F) -> u64 {
+    EPOCH_UNITS
+        .iter()
+        .find(|(magnitude, _)| below(*magnitude))
+        .map_or(1, |(_, scale)| *scale)
+}
+
+/// Converts an integer epoch timestamp exactly, guessing its unit from its
+/// magnitude
+fn epoch_nanos(value: i64) -> Option<u64> {
+    let value = u64::try_from(value).ok().filter(|v| *v > 0)?;
+    value.checked_mul(epoch_scale(|magnitude| value < magnitude))
+}
+
+/// Converts a fractional epoch timestamp, guessing its unit from its magnitude
+fn fractional_epoch_nanos(value: f64) -> Option<u64> {
+    if !value.is_finite() || value <= 0.0 {
+        return None;
+    }
+    // the magnitudes and scales are powers of ten exactly representable
+    #[allow(clippy::cast_precision_loss)]
+    let scale = epoch_scale(|magnitude| value < magnitude as f64) as f64;
+    // saturates far beyond any valid timestamp
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let nanos = (value * scale) as u64;
+    Some(nanos)
+}
+
+fn parse_timestamp(value: &AnyValue) -> Option<u64> {
+    match value.value.as_ref()? {
+        any_value::Value::StringValue(s) => {
+            let s = s.trim();
+            parse_rfc3339(s).or_else(|| match s.parse() {
+                Ok(i) => epoch_nanos(i),
+                Err(_) => fractional_epoch_nanos(s.parse().ok()?),
+            })
+        }
+        any_value::Value::IntValue(i) => epoch_nanos(*i),
+        any_value::Value::DoubleValue(d) => fractional_epoch_nanos(*d),
+        _ => None,
+    }
+}
+
+fn as_str(value: &AnyValue) -> Option<&str> {
+    match value.value.as_ref()? {
+        any_value::Value::StringValue(s) => Some(s),
+        _ => None,
+    }
+}
+
+/// Removes the first field with one of the keys whose value `extract`
+/// accepts, returning the extracted value
+fn take_field<T>(
+    fields: &mut Vec<KeyValue>,
+    keys: &[String],
+    extract: impl Fn(&AnyValue) -> Option<T>,
+) -> Option<T> {
+    let (index, extracted) = fields.iter().enumerate().find_map(|(i, field)| {
+        if !keys.contains(&field.key) {
+            return None;
+        }
+        Some((i, extract(field.value.as_ref()?)?))
+    })?;
+    fields.remove(index);
+    Some(extracted)
+}
+
+impl BodyParser {
+    /// Creates a parser trying JSON, logfmt and key=value in that order,
+    /// replacing the body with the parsed fields and looking for the common
+    /// names of the well known fields
+    pub fn new() -> Self {
+        BodyParser {
+            formats: vec![BodyFormat::Json, BodyFormat::Logfmt, BodyFormat::KeyValue],
+            target: BodyTarget::Body,
+            timestamp_keys: strings(&["timestamp", "time", "ts", "@timestamp"]),
+            severity_keys: strings(&["level", "severity", "lvl", "loglevel", "log.level"]),
+            trace_id_keys: strings(&["trace_id", "traceId", "traceid", "trace.id"]),
+            span_id_keys: strings(&["span_id", "spanId", "spanid", "span.id"]),
+            message_keys: strings(&["message", "msg"]),
+            failure_attribute: Some(DEFAULT_FAILURE_ATTRIBUTE.to_string()),
+        }
+    }
+
+    /// Sets the formats to try, in order
+    pub fn with_formats<I>(mut self, formats: I) -> Self
+    where
+        I: IntoIterator<Item = BodyFormat>,
+    {
+        self.formats = formats.into_iter().collect();
+        self
+    }
+
+    /// Sets where the parsed fields go
+    pub fn with_target(mut self, target: BodyTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Sets the keys of the timestamp field, RFC 3339 strings and epoch
+    /// seconds, milliseconds, microseconds or nanoseconds are understood
+    pub fn with_timestamp_keys<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.timestamp_keys = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the keys of the severity field, level names are mapped with
+    /// [`SeverityNumber::from_level_name`]
+    pub fn with_severity_keys<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.severity_keys = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the keys of the hex encoded trace id field
+    pub fn with_trace_id_keys<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.trace_id_keys = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the keys of the hex encoded span id field
+    pub fn with_span_id_keys<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.span_id_keys = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the keys of the message field that becomes the body when the
+    /// fields go into attributes
+    pub fn with_message_keys<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.message_keys = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the attribute flagging unparseable bodies, `None` flags nothing
+    pub fn with_failure_attribute(mut self, attribute: Option<&str>) -> Self {
+        self.failure_attribute = attribute.map(ToString::to_string);
+        self
+    }
+
+    /// Moves the well known fields into the record
+    fn extract(&self, record: &mut LogRecord, fields: &mut Vec<KeyValue>) {
+        if let Some(timestamp) = take_field(fields, &self.timestamp_keys, parse_timestamp) {
+            record.time_unix_nano = timestamp;
+        }
+        let severity = take_field(fields, &self.severity_keys, |value| {
+            let text = as_str(value)?;
+            let number = SeverityNumber::from_level_name(text)?;
+            Some((text.to_string(), number))
+        });
+        if let Some((text, number)) = severity {
+            record.severity_text = text;
+            record.set_severity_number(number);
+        }
+        if let Some(trace_id) = take_field(fields, &self.trace_id_keys, |value| {
+            parse_hex(as_str(value)?, 16)
+        }) {
+            record.trace_id = trace_id;
+        }
+        if let Some(span_id) = take_field(fields, &self.span_id_keys, |value| {
+            parse_hex(as_str(value)?, 8)
+        }) {
+            record.span_id = span_id;
+        }
+    }
+
+    /// Parses the body of a log record, returning whether it was parsed
+    pub fn parse_record(&self, record: &mut LogRecord) -> bool {
+        let text = match record.body.as_ref().and_then(|body| body.value.as_ref()) {
+            Some(any_value::Value::StringValue(text)) if !text.trim().is_empty() => text,
+            _ => return false,
+        };
+        let Some(mut fields) = self.formats.iter().find_map(|format| format.parse(text)) else {
+            if let Some(key) = &self.failure_attribute {
+                record.attributes.retain(|attribute| attribute.key != *key);
+                record.attributes.push(KeyValue {
+                    key: key.clone(),
+                    value: Some(AnyValue {
+                        value: Some(any_value::Value::BoolValue(true)),
+                    }),
+                });
+            }
+            return false;
+        };
+        self.extract(record, &mut fields);
+        match self.target {
+            BodyTarget::Body => {
+                record.body = Some(AnyValue {
+                    value: Some(any_value::Value::KvlistValue(KeyValueList {
+                        values: fields,
+                    })),
+                });
+            }
+            BodyTarget::Attributes => {
+                if let Some(message) = take_field(&mut fields, &self.message_keys, |value| {
+                    as_str(value).map(any_string)
+                }) {
+                    record.body = Some(message);
+                }
+                record
+                    .attributes
+                    .retain(|attribute| !fields.iter().any(|field| field.key == attribute.key));
+                record.attributes.extend(fields);
+            }
+        }
+        true
+    }
+
+    /// Parses the bodies of the log records of a request, returning how many
+    /// were parsed
+    pub fn parse(&self, request: &mut ExportLogsServiceRequest) -> usize {
+        let mut parsed = 0;
+        for (_, _, record) in request.log_records_mut() {
+            if self.parse_record(record) {
+                parsed += 1;
+            }
+        }
+        parsed
+    }
+}
+
+impl Processor<ExportLogsServiceRequest> for BodyParser {
+    fn process(
+        &self,
+        request: &mut ExportLogsServiceRequest,
+    ) -> Result<Disposition, tonic::Status> {
+        self.parse(request);
+        Ok(Disposition::accepted())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(body: &str) -> LogRecord {
+        LogRecord {
+            body: Some(any_string(body)),
+            ..LogRecord::default()
+        }
+    }
+
+    fn keys(values: &[KeyValue]) -> Vec<&str> {
+        values.iter().map(|kv| kv.key.as_str()).collect()
+    }
+
+    #[test]
+    pub fn parse_formats() {
+        assert_eq!(
+            BodyFormat::Logfmt
+                .parse("at=info msg=\"hello world\" cached")
+                .map(|f| f.len()),
+            Some(3)
+        );
+        assert_eq!(BodyFormat::Logfmt.parse("just some words"), None);
+        assert_eq!(BodyFormat::Logfmt.parse("failed: error=timeout"), None);
+        assert_eq!(
+            BodyFormat::KeyValue
+                .parse("user=bob, action='log in'; ok=1")
+                .map(|f| f.len()),
+            Some(3)
+        );
+        assert_eq!(BodyFormat::KeyValue.parse("user=bob flag"), None);
+        assert_eq!(BodyFormat::Json.parse("[1, 2]"), None);
+    }
+
+    #[test]
+    pub fn parse_bodies() {
+        let parser = BodyParser::new();
+        let mut json = record(
+            r#"{"time":"2003-10-11T22:14:15.003Z","level":"warning","trace_id":"0102030405060708090a0b0c0d0e0f10","span_id":"0102030405060708","msg":"hi","user":{"id":7}}"#,
+        );
+        assert!(parser.parse_record(&mut json));
+        assert_eq!(json.time_unix_nano, 1_065_910_455_003_000_000);
+        assert_eq!(json.severity_number(), SeverityNumber::Warn);
+        assert_eq!(json.severity_text, "warning");
+        assert_eq!(json.trace_id.len(), 16);
+        assert_eq!(json.span_id.len(), 8);
+        match json.body.and_then(|body| body.value) {
+            Some(any_value::Value::KvlistValue(fields)) => {
+                assert_eq!(keys(&fields.values), vec!["msg", "user"]);
+            }
+            other => panic!("unexpected body {other:?}"),
+        }
+
+        let parser = parser.with_target(BodyTarget::Attributes);
+        let mut logfmt = record("ts=1065910455 level=bogus msg=\"hello world\" user=bob");
+        assert!(parser.parse_record(&mut logfmt));
+        assert_eq!(logfmt.time_unix_nano, 1_065_910_455_000_000_000);
+        // unknown levels stay fields
+        assert_eq!(logfmt.severity_number(), SeverityNumber::Unspecified);
+        assert_eq!(keys(&logfmt.attributes), vec!["level", "user"]);
+        assert_eq!(logfmt.body, Some(any_string("hello world")));
+
+        let mut text = record("nothing to see here");
+        assert!(!parser.parse_record(&mut text));
+        assert_eq!(text.body, Some(any_string("nothing to see here")));
+        assert_eq!(keys(&text.attributes), vec![DEFAULT_FAILURE_ATTRIBUTE]);
+    }
+
+    #[test]
+    pub fn parse_epoch_timestamps() {
+        let int = |i| AnyValue {
+            value: Some(any_value::Value::IntValue(i)),
+        };
+        let double = |d| AnyValue {
+            value: Some(any_value::Value::DoubleValue(d)),
+        };
+        // nanoseconds keep their full precision
+        assert_eq!(
+            parse_timestamp(&int(1_700_000_000_123_456_789)),
+            Some(1_700_000_000_123_456_789)
+        );
+        assert_eq!(
+            parse_timestamp(&any_string("1700000000123456789")),
+            Some(1_700_000_000_123_456_789)
+        );
+        assert_eq!(
+            parse_timestamp(&int(1_700_000_000_123_456)),
+            Some(1_700_000_000_123_456_000)
+        );
+        assert_eq!(
+            parse_timestamp(&int(1_700_000_000_123)),
+            Some(1_700_000_000_123_000_000)
+        );
+        assert_eq!(
+            parse_timestamp(&int(1_700_000_000)),
+            Some(1_700_000_000_000_000_000)
+        );
+        assert_eq!(
+            parse_timestamp(&double(1_700_000_000.5)),
+            Some(1_700_000_000_500_000_000)
+        );
+        assert_eq!(
+            parse_timestamp(&any_string("1700000000.5")),
+            Some(1_700_000_000_500_000_000)
+        );
+        assert_eq!(parse_timestamp(&int(-1)), None);
+        assert_eq!(parse_timestamp(&double(f64::NAN)), None);
+    }
+
+    #[test]
+    pub fn parse_rfc3339_dates() {
+        let parse = |s| parse_timestamp(&any_string(s));
+        assert_eq!(
+            parse("2024-02-29T00:00:00Z"),
+            Some(1_709_164_800_000_000_000)
+        );
+        assert_eq!(parse("2000-02-29T00:00:00Z"), Some(951_782_400_000_000_000));
+        assert_eq!(
+            parse("2023-04-30T00:00:00Z"),
+            Some(1_682_812_800_000_000_000)
+        );
+        assert_eq!(parse("2023-02-29T00:00:00Z"), None);
+        assert_eq!(parse("2100-02-29T00:00:00Z"), None);
+        assert_eq!(parse("2023-02-31T00:00:00Z"), None);
+        assert_eq!(parse("2023-04-31T00:00:00Z"), None);
+    }
+}
@@ -16,7 +16,7 @@ use crate::opentelemetry::proto::collector::logs::v1 as base;
 use crate::opentelemetry::proto::collector::logs::v1::logs_service_server as skel;
 use tokio::sync::mpsc::{Receiver, Sender};
 
-use super::{OtelLogsRequest, OtelLogsResponse};
+use super::{LogsProcessorChain, OtelLogsRequest, OtelLogsResponse};
 
 /// Asynchronous channel sender
 pub type OtelLogsSender = Sender<base::ExportLogsServiceRequest>;
@@ -27,25 +27,39 @@ pub type OtelLogsReceiver = Receiver<base::ExportLogsServiceRequest>;
 /// Logs forwarding agent
 pub struct OtelLogsServiceForwarder {
     channel: OtelLogsSender,
+    processors: LogsProcessorChain,
 }
 
 // Creates a metrics service with the specified asynchronous sender channel
 impl OtelLogsServiceForwarder {
     /// Creates a log forwarding agent with an asynchronous channel sender
     pub fn with_sender(channel: OtelLogsSender) -> Self {
-        OtelLogsServiceForwarder { channel }
+        OtelLogsServiceForwarder {
+            channel,
+            processors: LogsProcessorChain::new(),
+        }
+    }
+
+    /// Runs the processor chain on every request before it is forwarded
+    pub fn with_processors(mut self, processors: LogsProcessorChain) -> Self {
+        self.processors = processors;
+        self
     }
 }
 
 #[tonic::async_trait]
 impl skel::LogsService for OtelLogsServiceForwarder {
     async fn export(&self, request: OtelLogsRequest) -> Result<OtelLogsResponse, tonic::Status> {
-        match self.channel.send(request.into_inner()).await {
+        let mut request = request.into_inner();
+        let disposition = self.processors.process(&mut request).await?;
+        if request.resource_logs.is_empty() {
+            return Ok(tonic::Response::new(base::ExportLogsServiceResponse {
+                partial_success: Some(disposition.into()),
+            }));
+        }
+        match self.channel.send(request).await {
             Ok(()) => Ok(tonic::Response::new(base::ExportLogsServiceResponse {
-                partial_success: Some(base::ExportLogsPartialSuccess {
-                    rejected_log_records: 0,
-                    error_message: "Ok".to_string(),
-                }),
+                partial_success: Some(disposition.into()),
             })),
             Err(e) => Err(tonic::Status::internal(format!(
                 "Logs gRPC forwarder channel sender failed to dispatch {}",
@@ -59,3 +73,14 @@ impl skel::LogsService for OtelLogsServiceForwarder {
 pub fn make_forwarder(sender: OtelLogsSender) -> skel::LogsServiceServer<OtelLogsServiceForwarder> {
     skel::LogsServiceServer::new(OtelLogsServiceForwarder::with_sender(sender))
 }
+
+/// Creates a tonic service handler for open telemetry logs events that runs
+/// the processor chain on every request before it is forwarded
+pub fn make_forwarder_with_processors(
+    sender: OtelLogsSender,
+    processors: LogsProcessorChain,
+) -> skel::LogsServiceServer<OtelLogsServiceForwarder> {
+    skel::LogsServiceServer::new(
+        OtelLogsServiceForwarder::with_sender(sender).with_processors(processors),
+    )
+}
@@ -0,0 +1,150 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::common::{EMPTY_RESOURCE, EMPTY_SCOPE};
+use crate::opentelemetry::proto::collector::logs::v1 as base;
+use crate::opentelemetry::proto::common::v1::InstrumentationScope;
+use crate::opentelemetry::proto::logs::v1::LogRecord;
+use crate::opentelemetry::proto::resource::v1::Resource;
+
+impl base::ExportLogsServiceRequest {
+    /// Iterates over every log record in the request alongside its resource and
+    /// instrumentation scope. Missing resources or scopes are presented as empty ones.
+    pub fn log_records(
+        &self,
+    ) -> impl Iterator<Item = (&Resource, &InstrumentationScope, &LogRecord)> {
+        self.resource_logs.iter().flat_map(|rl| {
+            let resource = rl.resource.as_ref().unwrap_or(&EMPTY_RESOURCE);
+            rl.scope_logs.iter().flat_map(move |sl| {
+                let scope = sl.scope.as_ref().unwrap_or(&EMPTY_SCOPE);
+                sl.log_records
+                    .iter()
+                    .map(move |record| (resource, scope, record))
+            })
+        })
+    }
+
+    /// Mutably iterates over every log record in the request alongside its resource and
+    /// instrumentation scope
+    pub fn log_records_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (&Resource, &InstrumentationScope, &mut LogRecord)> {
+        self.resource_logs.iter_mut().flat_map(|rl| {
+            let resource = rl.resource.as_ref().unwrap_or(&EMPTY_RESOURCE);
+            rl.scope_logs.iter_mut().flat_map(move |sl| {
+                let scope = sl.scope.as_ref().unwrap_or(&EMPTY_SCOPE);
+                sl.log_records
+                    .iter_mut()
+                    .map(move |record| (resource, scope, record))
+            })
+        })
+    }
+
+    /// The total number of log records in the request
+    pub fn log_record_count(&self) -> usize {
+        self.resource_logs
+            .iter()
+            .flat_map(|rl| rl.scope_logs.iter())
+            .map(|sl| sl.log_records.len())
+            .sum()
+    }
+
+    /// Retains only the log records for which the predicate returns true. Scopes and
+    /// resources that are left without log records are removed.
+    ///
+    /// Returns the number of log records that were removed.
+    pub fn retain_log_records<F>(&mut self, mut keep: F) -> usize
+    where
+        F: FnMut(&Resource, &InstrumentationScope, &LogRecord) -> bool,
+    {
+        let before = self.log_record_count();
+        for rl in &mut self.resource_logs {
+            let resource = rl.resource.as_ref().unwrap_or(&EMPTY_RESOURCE);
+            for sl in &mut rl.scope_logs {
+                let scope = sl.scope.as_ref().unwrap_or(&EMPTY_SCOPE);
+                sl.log_records
+                    .retain(|record| keep(resource, scope, record));
+            }
+            rl.scope_logs.retain(|sl| !sl.log_records.is_empty());
+        }
+        self.resource_logs.retain(|rl| !rl.scope_logs.is_empty());
+        before - self.log_record_count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opentelemetry::proto::collector::logs::v1::ExportLogsServiceRequest;
+    use crate::opentelemetry::proto::logs::v1::{LogRecord, ResourceLogs, ScopeLogs};
+
+    fn record(severity: &str) -> LogRecord {
+        LogRecord {
+            severity_text: severity.to_string(),
+            ..LogRecord::default()
+        }
+    }
+
+    fn request() -> ExportLogsServiceRequest {
+        ExportLogsServiceRequest {
+            resource_logs: vec![
+                ResourceLogs {
+                    resource: None,
+                    scope_logs: vec![ScopeLogs {
+                        scope: None,
+                        log_records: vec![record("a"), record("b")],
+                        schema_url: String::new(),
+                    }],
+                    schema_url: String::new(),
+                },
+                ResourceLogs {
+                    resource: None,
+                    scope_logs: vec![ScopeLogs {
+                        scope: None,
+                        log_records: vec![record("c")],
+                        schema_url: String::new(),
+                    }],
+                    schema_url: String::new(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    pub fn iterate_log_records() {
+        let mut req = request();
+        assert_eq!(req.log_record_count(), 3);
+        let severities: Vec<&str> = req
+            .log_records()
+            .map(|(_, _, r)| r.severity_text.as_str())
+            .collect();
+        assert_eq!(severities, vec!["a", "b", "c"]);
+        for (_, _, record) in req.log_records_mut() {
+            record.severity_text.push('!');
+        }
+        let severities: Vec<&str> = req
+            .log_records()
+            .map(|(_, _, r)| r.severity_text.as_str())
+            .collect();
+        assert_eq!(severities, vec!["a!", "b!", "c!"]);
+    }
+
+    #[test]
+    pub fn retain_drops_empty_envelopes() {
+        let mut req = request();
+        assert_eq!(req.retain_log_records(|_, _, r| r.severity_text != "c"), 1);
+        assert_eq!(req.resource_logs.len(), 1);
+        assert_eq!(req.retain_log_records(|_, _, _| false), 2);
+        assert!(req.resource_logs.is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::common::{find_or_push, same_resource, same_scope};
+use crate::opentelemetry::proto::collector::logs::v1 as base;
+use crate::opentelemetry::proto::logs::v1::{ResourceLogs, ScopeLogs};
+
+impl base::ExportLogsServiceRequest {
+    /// Merges another request into this one. Log records are appended to the
+    /// resource and scope envelopes of this request that carry the same resource,
+    /// instrumentation scope and schema URLs, new envelopes are added otherwise.
+    /// Attributes are compared regardless of their order.
+    pub fn merge_request(&mut self, other: Self) {
+        for rl in other.resource_logs {
+            let ResourceLogs {
+                resource,
+                scope_logs,
+                schema_url,
+            } = rl;
+            let target = find_or_push(
+                &mut self.resource_logs,
+                |t| {
+                    same_resource(t.resource.as_ref(), resource.as_ref())
+                        && t.schema_url == schema_url
+                },
+                || ResourceLogs {
+                    resource: resource.clone(),
+                    scope_logs: Vec::new(),
+                    schema_url: schema_url.clone(),
+                },
+            );
+            for sl in scope_logs {
+                let ScopeLogs {
+                    scope,
+                    log_records,
+                    schema_url,
+                } = sl;
+                let target = find_or_push(
+                    &mut target.scope_logs,
+                    |t| same_scope(t.scope.as_ref(), scope.as_ref()) && t.schema_url == schema_url,
+                    || ScopeLogs {
+                        scope: scope.clone(),
+                        log_records: Vec::new(),
+                        schema_url: schema_url.clone(),
+                    },
+                );
+                target.log_records.extend(log_records);
+            }
+        }
+    }
+}
+
+/// Combines several logs export requests into one, deduplicating identical
+/// resources and instrumentation scopes
+pub fn merge<I>(requests: I) -> base::ExportLogsServiceRequest
+where
+    I: IntoIterator<Item = base::ExportLogsServiceRequest>,
+{
+    requests
+        .into_iter()
+        .fold(base::ExportLogsServiceRequest::default(), |mut acc, r| {
+            acc.merge_request(r);
+            acc
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opentelemetry::proto::collector::logs::v1::ExportLogsServiceRequest;
+    use crate::opentelemetry::proto::common::v1::{
+        any_value, AnyValue, InstrumentationScope, KeyValue,
+    };
+    use crate::opentelemetry::proto::logs::v1::{LogRecord, ResourceLogs, ScopeLogs};
+    use crate::opentelemetry::proto::resource::v1::Resource;
+
+    fn attribute(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+        }
+    }
+
+    fn request(attributes: Vec<KeyValue>, scope: &str) -> ExportLogsServiceRequest {
+        ExportLogsServiceRequest {
+            resource_logs: vec![ResourceLogs {
+                resource: Some(Resource {
+                    attributes,
+                    dropped_attributes_count: 0,
+                }),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(InstrumentationScope {
+                        name: scope.to_string(),
+                        ..InstrumentationScope::default()
+                    }),
+                    log_records: vec![LogRecord::default()],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    #[test]
+    pub fn merge_deduplicates_envelopes() {
+        let a = attribute("service.name", "a");
+        let b = attribute("service.name", "b");
+        let host = attribute("host.name", "h");
+        let merged = super::merge(vec![
+            request(vec![a.clone(), host.clone()], "x"),
+            request(vec![b], "x"),
+            request(vec![host, a.clone()], "x"),
+            request(vec![a, attribute("host.name", "h")], "y"),
+        ]);
+        assert_eq!(merged.resource_logs.len(), 2);
+        assert_eq!(
+            merged.resource_logs.first().map(|r| r.scope_logs.len()),
+            Some(2)
+        );
+        assert_eq!(merged.log_record_count(), 4);
+    }
+}
@@ -0,0 +1,444 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::opentelemetry::proto::collector::logs::v1::ExportLogsServiceRequest;
+use crate::opentelemetry::proto::logs::v1::{LogRecord, SeverityNumber};
+use crate::processor::{Disposition, Processor};
+
+/// The severity of a syslog message, as defined by RFC 5424
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SyslogSeverity {
+    /// System is unusable
+    Emergency = 0,
+    /// Action must be taken immediately
+    Alert = 1,
+    /// Critical conditions
+    Critical = 2,
+    /// Error conditions
+    Error = 3,
+    /// Warning conditions
+    Warning = 4,
+    /// Normal but significant condition
+    Notice = 5,
+    /// Informational messages
+    Informational = 6,
+    /// Debug-level messages
+    Debug = 7,
+}
+
+impl SyslogSeverity {
+    /// The severity with the given code, `None` for codes above 7
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(SyslogSeverity::Emergency),
+            1 => Some(SyslogSeverity::Alert),
+            2 => Some(SyslogSeverity::Critical),
+            3 => Some(SyslogSeverity::Error),
+            4 => Some(SyslogSeverity::Warning),
+            5 => Some(SyslogSeverity::Notice),
+            6 => Some(SyslogSeverity::Informational),
+            7 => Some(SyslogSeverity::Debug),
+            _ => None,
+        }
+    }
+
+    /// The numeric code of the severity
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// The keyword of the severity, such as `err` or `notice`
+    pub fn keyword(self) -> &'static str {
+        match self {
+            SyslogSeverity::Emergency => "emerg",
+            SyslogSeverity::Alert => "alert",
+            SyslogSeverity::Critical => "crit",
+            SyslogSeverity::Error => "err",
+            SyslogSeverity::Warning => "warning",
+            SyslogSeverity::Notice => "notice",
+            SyslogSeverity::Informational => "info",
+            SyslogSeverity::Debug => "debug",
+        }
+    }
+}
+
+/// Maps syslog severities as recommended by the OpenTelemetry logs data model
+impl From<SyslogSeverity> for SeverityNumber {
+    fn from(severity: SyslogSeverity) -> Self {
+        match severity {
+            SyslogSeverity::Emergency => SeverityNumber::Fatal,
+            SyslogSeverity::Alert => SeverityNumber::Error3,
+            SyslogSeverity::Critical => SeverityNumber::Error2,
+            SyslogSeverity::Error => SeverityNumber::Error,
+            SyslogSeverity::Warning => SeverityNumber::Warn,
+            SyslogSeverity::Notice => SeverityNumber::Info2,
+            SyslogSeverity::Informational => SeverityNumber::Info,
+            SyslogSeverity::Debug => SeverityNumber::Debug,
+        }
+    }
+}
+
+#[cfg(feature = "log")]
+impl From<log::Level> for SeverityNumber {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => SeverityNumber::Error,
+            log::Level::Warn => SeverityNumber::Warn,
+            log::Level::Info => SeverityNumber::Info,
+            log::Level::Debug => SeverityNumber::Debug,
+            log::Level::Trace => SeverityNumber::Trace,
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl From<tracing_core::Level> for SeverityNumber {
+    fn from(level: tracing_core::Level) -> Self {
+        match level {
+            tracing_core::Level::ERROR => SeverityNumber::Error,
+            tracing_core::Level::WARN => SeverityNumber::Warn,
+            tracing_core::Level::INFO => SeverityNumber::Info,
+            tracing_core::Level::DEBUG => SeverityNumber::Debug,
+            tracing_core::Level::TRACE => SeverityNumber::Trace,
+        }
+    }
+}
+
+/// The range of the 24 defined severities
+const MIN_SEVERITY: i64 = SeverityNumber::Trace as i64;
+const MAX_SEVERITY: i64 = SeverityNumber::Fatal4 as i64;
+
+impl SeverityNumber {
+    /// The severity with the given number, clamped to the defined range
+    fn clamped(number: i64) -> Self {
+        let number = number.clamp(MIN_SEVERITY, MAX_SEVERITY);
+        i32::try_from(number)
+            .ok()
+            .and_then(|n| SeverityNumber::try_from(n).ok())
+            .unwrap_or(SeverityNumber::Unspecified)
+    }
+
+    /// The short name of the severity, such as `INFO` or `ERROR2`, which is
+    /// the recommended `severity_text` for it. Empty for `Unspecified`.
+    pub fn short_name(self) -> &'static str {
+        match self {
+            SeverityNumber::Unspecified => "",
+            other => other
+                .as_str_name()
+                .strip_prefix("SEVERITY_NUMBER_")
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The base severity of the range this severity is in, such as `Info` for
+    /// `Info3`
+    pub fn base(self) -> Self {
+        match self {
+            SeverityNumber::Unspecified => SeverityNumber::Unspecified,
+            other => Self::clamped((other as i64 - 1) / 4 * 4 + 1),
+        }
+    }
+
+    /// The severity of a level name from common logging libraries, case
+    /// insensitively.
+    ///
+    /// The short names of the severities such as `INFO2` are recognized as
+    /// well as the Python names (`CRITICAL`, `WARNING`, `NOTSET`, ...), the
+    /// `java.util.logging` and Log4j names (`SEVERE`, `CONFIG`, `FINE`,
+    /// `FINER`, `FINEST`, ...), the Go names of zap, logrus and slog (`DPANIC`,
+    /// `PANIC`, slog offsets such as `INFO+2`, ...) and the syslog keywords
+    /// (`EMERG`, `ALERT`, `CRIT`, `ERR`, `NOTICE`, ...).
+    pub fn from_level_name(name: &str) -> Option<Self> {
+        let name = name.trim().to_ascii_uppercase();
+        // slog offsets levels by multiples of four, as the severity ranges are
+        if let Some(position) = name.find(['+', '-']) {
+            let (base, offset) = name.split_at(position);
+            let offset: i64 = offset.parse().ok()?;
+            let base = Self::from_level_name(base)?;
+            return Some(Self::clamped(base as i64 + offset));
+        }
+        let severity = match name.as_str() {
+            "TRACE" | "FINEST" | "VERBOSE" => SeverityNumber::Trace,
+            "FINER" => SeverityNumber::Trace4,
+            "DEBUG" | "DBG" | "FINE" => SeverityNumber::Debug,
+            "CONFIG" => SeverityNumber::Debug4,
+            "INFO" | "INFORMATION" | "INFORMATIONAL" => SeverityNumber::Info,
+            "NOTICE" => SeverityNumber::Info2,
+            "WARN" | "WARNING" => SeverityNumber::Warn,
+            "ERROR" | "ERR" | "SEVERE" => SeverityNumber::Error,
+            "CRIT" => SeverityNumber::Error2,
+            "ALERT" => SeverityNumber::Error3,
+            "DPANIC" => SeverityNumber::Error4,
+            "FATAL" | "CRITICAL" | "PANIC" | "EMERG" | "EMERGENCY" => SeverityNumber::Fatal,
+            "NOTSET" | "UNSPECIFIED" => SeverityNumber::Unspecified,
+            // the short names of the higher severities of each range
+            other => {
+                let mut chars = other.chars();
+                let sublevel = match chars.next_back() {
+                    Some('2') => 1,
+                    Some('3') => 2,
+                    Some('4') => 3,
+                    _ => return None,
+                };
+                let base = match chars.as_str() {
+                    "TRACE" => SeverityNumber::Trace,
+                    "DEBUG" => SeverityNumber::Debug,
+                    "INFO" => SeverityNumber::Info,
+                    "WARN" => SeverityNumber::Warn,
+                    "ERROR" => SeverityNumber::Error,
+                    "FATAL" => SeverityNumber::Fatal,
+                    _ => return None,
+                };
+                Self::clamped(base as i64 + sublevel)
+            }
+        };
+        Some(severity)
+    }
+
+    /// The severity of a numeric Python logging level, where `DEBUG` is 10,
+    /// `INFO` 20, `WARNING` 30, `ERROR` 40 and `CRITICAL` 50. Levels between
+    /// those map onto the higher severities of each range.
+    pub fn from_python_level(level: i64) -> Self {
+        if level <= 0 {
+            return SeverityNumber::Unspecified;
+        }
+        if level < 10 {
+            return SeverityNumber::Trace;
+        }
+        let base = match level / 10 {
+            1 => SeverityNumber::Debug,
+            2 => SeverityNumber::Info,
+            3 => SeverityNumber::Warn,
+            4 => SeverityNumber::Error,
+            _ => SeverityNumber::Fatal,
+        };
+        let within = if base == SeverityNumber::Fatal {
+            level - 50
+        } else {
+            level % 10
+        };
+        Self::clamped(base as i64 + (within * 4 / 10).min(3))
+    }
+
+    /// The severity of a numeric slog level, where `DEBUG` is -4, `INFO` 0,
+    /// `WARN` 4 and `ERROR` 8
+    pub fn from_slog_level(level: i64) -> Self {
+        Self::clamped(SeverityNumber::Info as i64 + level)
+    }
+
+    /// The syslog severity closest to this severity, `None` for `Unspecified`
+    pub fn to_syslog(self) -> Option<SyslogSeverity> {
+        let severity = match self {
+            SeverityNumber::Unspecified => return None,
+            SeverityNumber::Trace
+            | SeverityNumber::Trace2
+            | SeverityNumber::Trace3
+            | SeverityNumber::Trace4
+            | SeverityNumber::Debug
+            | SeverityNumber::Debug2
+            | SeverityNumber::Debug3
+            | SeverityNumber::Debug4 => SyslogSeverity::Debug,
+            SeverityNumber::Info => SyslogSeverity::Informational,
+            SeverityNumber::Info2 | SeverityNumber::Info3 | SeverityNumber::Info4 => {
+                SyslogSeverity::Notice
+            }
+            SeverityNumber::Warn
+            | SeverityNumber::Warn2
+            | SeverityNumber::Warn3
+            | SeverityNumber::Warn4 => SyslogSeverity::Warning,
+            SeverityNumber::Error => SyslogSeverity::Error,
+            SeverityNumber::Error2 => SyslogSeverity::Critical,
+            SeverityNumber::Error3 | SeverityNumber::Error4 => SyslogSeverity::Alert,
+            SeverityNumber::Fatal
+            | SeverityNumber::Fatal2
+            | SeverityNumber::Fatal3
+            | SeverityNumber::Fatal4 => SyslogSeverity::Emergency,
+        };
+        Some(severity)
+    }
+
+    /// The `log` level of this severity, fatal severities are errors.
+    /// `None` for `Unspecified`.
+    #[cfg(feature = "log")]
+    pub fn to_log_level(self) -> Option<log::Level> {
+        match self.base() {
+            SeverityNumber::Unspecified => None,
+            SeverityNumber::Trace => Some(log::Level::Trace),
+            SeverityNumber::Debug => Some(log::Level::Debug),
+            SeverityNumber::Info => Some(log::Level::Info),
+            SeverityNumber::Warn => Some(log::Level::Warn),
+            _ => Some(log::Level::Error),
+        }
+    }
+
+    /// The `tracing` level of this severity, fatal severities are errors.
+    /// `None` for `Unspecified`.
+    #[cfg(feature = "tracing")]
+    pub fn to_tracing_level(self) -> Option<tracing_core::Level> {
+        match self.base() {
+            SeverityNumber::Unspecified => None,
+            SeverityNumber::Trace => Some(tracing_core::Level::TRACE),
+            SeverityNumber::Debug => Some(tracing_core::Level::DEBUG),
+            SeverityNumber::Info => Some(tracing_core::Level::INFO),
+            SeverityNumber::Warn => Some(tracing_core::Level::WARN),
+            _ => Some(tracing_core::Level::ERROR),
+        }
+    }
+}
+
+/// Fills in the `severity_number` of log records from their `severity_text`
+/// and the `severity_text` from the `severity_number`, whichever is missing.
+/// Texts that are not a known level name leave the number unspecified.
+#[derive(Debug, Clone)]
+pub struct SeverityNormalizer {
+    number_from_text: bool,
+    text_from_number: bool,
+}
+
+impl Default for SeverityNormalizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SeverityNormalizer {
+    /// Creates a normalizer filling in both the number and the text
+    pub fn new() -> Self {
+        SeverityNormalizer {
+            number_from_text: true,
+            text_from_number: true,
+        }
+    }
+
+    /// Sets whether missing numbers are derived from the text
+    pub fn with_number_from_text(mut self, enabled: bool) -> Self {
+        self.number_from_text = enabled;
+        self
+    }
+
+    /// Sets whether missing texts are set to the short name of the number
+    pub fn with_text_from_number(mut self, enabled: bool) -> Self {
+        self.text_from_number = enabled;
+        self
+    }
+
+    /// Normalizes a log record, returning whether it was changed
+    pub fn normalize_record(&self, record: &mut LogRecord) -> bool {
+        let number = record.severity_number();
+        let text = record.severity_text.trim();
+        if number == SeverityNumber::Unspecified && !text.is_empty() && self.number_from_text {
+            match SeverityNumber::from_level_name(text) {
+                Some(SeverityNumber::Unspecified) | None => false,
+                Some(number) => {
+                    record.set_severity_number(number);
+                    true
+                }
+            }
+        } else if number != SeverityNumber::Unspecified && text.is_empty() && self.text_from_number
+        {
+            record.severity_text = number.short_name().to_string();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Normalizes the log records of a request, returning how many were changed
+    pub fn normalize(&self, request: &mut ExportLogsServiceRequest) -> usize {
+        let mut changed = 0;
+        for (_, _, record) in request.log_records_mut() {
+            if self.normalize_record(record) {
+                changed += 1;
+            }
+        }
+        changed
+    }
+}
+
+impl Processor<ExportLogsServiceRequest> for SeverityNormalizer {
+    fn process(
+        &self,
+        request: &mut ExportLogsServiceRequest,
+    ) -> Result<Disposition, tonic::Status> {
+        self.normalize(request);
+        Ok(Disposition::accepted())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn level_mappings() {
+        assert_eq!(
+            SeverityNumber::from_level_name("warning"),
+            Some(SeverityNumber::Warn)
+        );
+        assert_eq!(
+            SeverityNumber::from_level_name("CRITICAL"),
+            Some(SeverityNumber::Fatal)
+        );
+        assert_eq!(
+            SeverityNumber::from_level_name("Severe"),
+            Some(SeverityNumber::Error)
+        );
+        assert_eq!(
+            SeverityNumber::from_level_name("info+2"),
+            Some(SeverityNumber::Info3)
+        );
+        assert_eq!(
+            SeverityNumber::from_level_name("ERROR4"),
+            Some(SeverityNumber::Error4)
+        );
+        assert_eq!(SeverityNumber::from_level_name("loud"), None);
+        assert_eq!(SeverityNumber::from_python_level(25), SeverityNumber::Info3);
+        assert_eq!(SeverityNumber::from_python_level(50), SeverityNumber::Fatal);
+        assert_eq!(SeverityNumber::from_slog_level(-4), SeverityNumber::Debug);
+        assert_eq!(SeverityNumber::Warn3.short_name(), "WARN3");
+        assert_eq!(SeverityNumber::Warn3.base(), SeverityNumber::Warn);
+
+        for code in 0..8 {
+            let syslog = SyslogSeverity::from_code(code);
+            let number = syslog.map(SeverityNumber::from);
+            assert_eq!(number.and_then(SeverityNumber::to_syslog), syslog);
+        }
+        assert_eq!(SyslogSeverity::from_code(8), None);
+    }
+
+    #[test]
+    pub fn normalize_records() {
+        let record = |number: SeverityNumber, text: &str| LogRecord {
+            severity_number: number as i32,
+            severity_text: text.to_string(),
+            ..LogRecord::default()
+        };
+        let normalizer = SeverityNormalizer::new();
+        let mut from_text = record(SeverityNumber::Unspecified, "warning");
+        assert!(normalizer.normalize_record(&mut from_text));
+        assert_eq!(from_text.severity_number(), SeverityNumber::Warn);
+        assert_eq!(from_text.severity_text, "warning");
+
+        let mut from_number = record(SeverityNumber::Error2, "");
+        assert!(normalizer.normalize_record(&mut from_number));
+        assert_eq!(from_number.severity_text, "ERROR2");
+
+        let mut unknown = record(SeverityNumber::Unspecified, "loud");
+        assert!(!normalizer.normalize_record(&mut unknown));
+
+        let mut disabled = record(SeverityNumber::Info, "");
+        let normalizer = normalizer.with_text_from_number(false);
+        assert!(!normalizer.normalize_record(&mut disabled));
+    }
+}
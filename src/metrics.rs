@@ -17,9 +17,18 @@ use crate::opentelemetry::proto::collector::metrics::v1::metrics_service_server
 
 #[cfg(feature = "channels")]
 mod channels;
+mod exponential;
+pub(crate) mod iter;
+mod merge;
+mod split;
+mod stats;
+pub mod temporality;
 
 #[cfg(feature = "channels")]
 pub use channels::*;
+pub use iter::{DataPointMut, DataPointRef};
+pub use merge::merge;
+pub use stats::DistributionStats;
 
 pub use skel::MetricsService;
 pub use skel::MetricsServiceServer;
@@ -64,3 +73,12 @@ impl skel::MetricsService for OtelMetricsService {
 pub fn make_service(handler: Box<OnMetricsFn>) -> skel::MetricsServiceServer<OtelMetricsService> {
     skel::MetricsServiceServer::new(OtelMetricsService::with_handler(handler))
 }
+
+/// Alias processor chain for metrics export requests
+pub type MetricsProcessorChain =
+    crate::processor::ProcessorChain<base::ExportMetricsServiceRequest>;
+
+crate::processor::partial_success_from_disposition!(
+    base::ExportMetricsPartialSuccess,
+    rejected_data_points
+);
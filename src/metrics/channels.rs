@@ -16,7 +16,7 @@ use crate::opentelemetry::proto::collector::metrics::v1 as base;
 use crate::opentelemetry::proto::collector::metrics::v1::metrics_service_server as skel;
 use tokio::sync::mpsc::{Receiver, Sender};
 
-use super::{OtelMetricsRequest, OtelMetricsResponse};
+use super::{MetricsProcessorChain, OtelMetricsRequest, OtelMetricsResponse};
 /// Asynchronous channel sender
 pub type OtelMetricsSender = Sender<base::ExportMetricsServiceRequest>;
 
@@ -26,12 +26,22 @@ pub type OtelMetricsReceiver = Receiver<base::ExportMetricsServiceRequest>;
 /// Creates a metrics service with the specified asynchronous sender channel
 pub struct OtelMetricsServiceForwarder {
     channel: OtelMetricsSender,
+    processors: MetricsProcessorChain,
 }
 
 impl OtelMetricsServiceForwarder {
     /// Creates a metrics service forwarding agent with an asynchronous channel sender
     pub fn with_sender(channel: OtelMetricsSender) -> Self {
-        OtelMetricsServiceForwarder { channel }
+        OtelMetricsServiceForwarder {
+            channel,
+            processors: MetricsProcessorChain::new(),
+        }
+    }
+
+    /// Runs the processor chain on every request before it is forwarded
+    pub fn with_processors(mut self, processors: MetricsProcessorChain) -> Self {
+        self.processors = processors;
+        self
     }
 }
 
@@ -41,12 +51,16 @@ impl skel::MetricsService for OtelMetricsServiceForwarder {
         &self,
         request: OtelMetricsRequest,
     ) -> Result<OtelMetricsResponse, tonic::Status> {
-        match self.channel.send(request.into_inner()).await {
+        let mut request = request.into_inner();
+        let disposition = self.processors.process(&mut request).await?;
+        if request.resource_metrics.is_empty() {
+            return Ok(tonic::Response::new(base::ExportMetricsServiceResponse {
+                partial_success: Some(disposition.into()),
+            }));
+        }
+        match self.channel.send(request).await {
             Ok(_) => Ok(tonic::Response::new(base::ExportMetricsServiceResponse {
-                partial_success: Some(base::ExportMetricsPartialSuccess {
-                    rejected_data_points: 0,
-                    error_message: "Ok".to_string(),
-                }),
+                partial_success: Some(disposition.into()),
             })),
             Err(e) => Err(tonic::Status::internal(format!(
                 "Metrics gRPC forwarder channel sender failed to dispatch {}",
@@ -62,3 +76,14 @@ pub fn make_forwarder(
 ) -> skel::MetricsServiceServer<OtelMetricsServiceForwarder> {
     skel::MetricsServiceServer::new(OtelMetricsServiceForwarder::with_sender(sender))
 }
+
+/// Creates a tonic service forwarder for open telemetry metrics events that runs
+/// the processor chain on every request before it is forwarded
+pub fn make_forwarder_with_processors(
+    sender: OtelMetricsSender,
+    processors: MetricsProcessorChain,
+) -> skel::MetricsServiceServer<OtelMetricsServiceForwarder> {
+    skel::MetricsServiceServer::new(
+        OtelMetricsServiceForwarder::with_sender(sender).with_processors(processors),
+    )
+}
@@ -0,0 +1,407 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::stats::interpolate;
+use crate::opentelemetry::proto::metrics::v1::{
+    exponential_histogram_data_point::Buckets, ExponentialHistogramDataPoint, HistogramDataPoint,
+};
+
+/// The number of buckets per sign recorded values are kept within
+const MAX_BUCKETS: i64 = 160;
+
+/// The lowest scale of the exponential histogram data model
+const MIN_SCALE: i32 = -10;
+
+/// The index of the bucket holding a positive value at the given scale
+fn bucket_index(scale: i32, value: f64) -> i64 {
+    // bucket indices are far below 2^63 for finite values
+    #[allow(clippy::cast_possible_truncation)]
+    let index = (value.log2() * f64::from(scale).exp2()).ceil() as i64;
+    index - 1
+}
+
+/// The lower boundary of the bucket with the given index at the given scale,
+/// the bucket covers the values in `(base^index, base^(index + 1)]` where
+/// `base = 2^(2^-scale)`
+fn lower_boundary(scale: i32, index: i64) -> f64 {
+    // bucket indices are far below 2^52 in practice
+    #[allow(clippy::cast_precision_loss)]
+    let index = index as f64;
+    (index * (-f64::from(scale)).exp2()).exp2()
+}
+
+/// Adds the bucket counts of two points at the same scale, widening the buckets
+/// to cover both
+pub(crate) fn add_buckets(a: Option<&Buckets>, b: Option<&Buckets>) -> Option<Buckets> {
+    let buckets: Vec<&Buckets> = a
+        .into_iter()
+        .chain(b)
+        .filter(|b| !b.bucket_counts.is_empty())
+        .collect();
+    let start = buckets.iter().map(|b| i64::from(b.offset)).min()?;
+    let end = buckets
+        .iter()
+        .map(|b| i64::from(b.offset) + b.bucket_counts.len() as i64)
+        .max()?;
+    let mut bucket_counts = vec![0; usize::try_from(end - start).ok()?];
+    for b in buckets {
+        let skip = usize::try_from(i64::from(b.offset) - start).ok()?;
+        for (sum, count) in bucket_counts.iter_mut().skip(skip).zip(&b.bucket_counts) {
+            *sum += count;
+        }
+    }
+    Some(Buckets {
+        offset: i32::try_from(start).ok()?,
+        bucket_counts,
+    })
+}
+
+/// Merges every `2^by` adjacent buckets into one
+fn downscale_buckets(buckets: &mut Buckets, by: u32) {
+    if by == 0 || buckets.bucket_counts.is_empty() {
+        return;
+    }
+    let by = by.min(63);
+    let offset = i64::from(buckets.offset);
+    let new_offset = offset >> by;
+    let mut bucket_counts: Vec<u64> = Vec::new();
+    for (i, count) in buckets.bucket_counts.iter().enumerate() {
+        let index = ((offset + i as i64) >> by) - new_offset;
+        let Ok(index) = usize::try_from(index) else {
+            continue;
+        };
+        if bucket_counts.len() <= index {
+            bucket_counts.resize(index + 1, 0);
+        }
+        if let Some(slot) = bucket_counts.get_mut(index) {
+            *slot += count;
+        }
+    }
+    // the new offset is within i32 since it is closer to zero than the old one
+    buckets.offset = i32::try_from(new_offset).unwrap_or(buckets.offset);
+    buckets.bucket_counts = bucket_counts;
+}
+
+impl ExponentialHistogramDataPoint {
+    /// Iterates over the populated ranges of the histogram in ascending order of
+    /// their values as `(lower, upper, count)`. The zero bucket is reported as
+    /// the range `(-zero_threshold, zero_threshold)`.
+    pub(crate) fn ranges(&self) -> impl Iterator<Item = (f64, f64, u64)> + Clone + '_ {
+        let scale = self.scale;
+        let negative = self.negative.iter().flat_map(move |b| {
+            b.bucket_counts
+                .iter()
+                .enumerate()
+                .rev()
+                .map(move |(i, count)| {
+                    let index = i64::from(b.offset) + i as i64;
+                    (
+                        -lower_boundary(scale, index + 1),
+                        -lower_boundary(scale, index),
+                        *count,
+                    )
+                })
+        });
+        let zero = std::iter::once((-self.zero_threshold, self.zero_threshold, self.zero_count));
+        let positive = self.positive.iter().flat_map(move |b| {
+            b.bucket_counts.iter().enumerate().map(move |(i, count)| {
+                let index = i64::from(b.offset) + i as i64;
+                (
+                    lower_boundary(scale, index),
+                    lower_boundary(scale, index + 1),
+                    *count,
+                )
+            })
+        });
+        negative
+            .chain(zero)
+            .chain(positive)
+            .filter(|(_, _, count)| *count > 0)
+    }
+
+    /// Records a value observed `count` times. The histogram is downscaled when
+    /// the buckets of either sign would exceed 160, so points should start out
+    /// at the highest scale wanted. Zero and non-finite values are counted in
+    /// the zero bucket and ignored respectively.
+    pub fn record(&mut self, value: f64, count: u64) {
+        if !value.is_finite() || count == 0 {
+            return;
+        }
+        if value.abs() <= self.zero_threshold {
+            self.zero_count += count;
+        } else {
+            let buckets = if value > 0.0 {
+                self.positive.as_ref()
+            } else {
+                self.negative.as_ref()
+            };
+            // the scale and index are settled before anything is changed, so
+            // a value whose index is out of range leaves the point as it was
+            let mut scale = self.scale;
+            let index = loop {
+                let index = bucket_index(scale, value.abs());
+                let by = i64::from(self.scale) - i64::from(scale);
+                let (low, high) =
+                    buckets
+                        .filter(|b| !b.bucket_counts.is_empty())
+                        .map_or((index, index), |b| {
+                            let offset = i64::from(b.offset);
+                            let last = offset + b.bucket_counts.len() as i64 - 1;
+                            ((offset >> by).min(index), (last >> by).max(index))
+                        });
+                if high - low < MAX_BUCKETS || scale <= MIN_SCALE {
+                    break index;
+                }
+                scale -= 1;
+            };
+            let Ok(offset) = i32::try_from(index) else {
+                return;
+            };
+            self.downscale(scale);
+            let single = Buckets {
+                offset,
+                bucket_counts: vec![count],
+            };
+            if value > 0.0 {
+                self.positive = add_buckets(self.positive.as_ref(), Some(&single));
+            } else {
+                self.negative = add_buckets(self.negative.as_ref(), Some(&single));
+            }
+        }
+        self.count += count;
+        // counts beyond 2^53 lose precision, which is fine for the sum
+        #[allow(clippy::cast_precision_loss)]
+        let total = value * count as f64;
+        self.sum = Some(self.sum.unwrap_or_default() + total);
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+    }
+
+    /// Moves the buckets entirely within the zero threshold into the zero
+    /// bucket
+    fn fold_into_zero(&mut self) {
+        let (scale, threshold) = (self.scale, self.zero_threshold);
+        for buckets in self.positive.iter_mut().chain(self.negative.iter_mut()) {
+            let offset = i64::from(buckets.offset);
+            let covered = (0..buckets.bucket_counts.len())
+                .take_while(|i| lower_boundary(scale, offset + *i as i64 + 1) <= threshold)
+                .count();
+            if covered == 0 {
+                continue;
+            }
+            self.zero_count += buckets.bucket_counts.drain(..covered).sum::<u64>();
+            buckets.offset = i32::try_from(offset + covered as i64).unwrap_or(buckets.offset);
+        }
+    }
+
+    /// Lowers the resolution of the histogram to the given scale by merging
+    /// adjacent buckets. Scales at or above the current one leave it unchanged.
+    pub fn downscale(&mut self, scale: i32) {
+        let Ok(by) = u32::try_from(i64::from(self.scale) - i64::from(scale)) else {
+            return;
+        };
+        if by == 0 {
+            return;
+        }
+        for buckets in self.positive.iter_mut().chain(self.negative.iter_mut()) {
+            downscale_buckets(buckets, by);
+        }
+        self.scale = scale;
+    }
+
+    /// Merges another histogram into this one. Both are downscaled to the lower
+    /// of their scales and the wider zero threshold is kept, with the buckets
+    /// it covers counted in the zero bucket. The merged point covers the time
+    /// ranges of both points.
+    pub fn merge_point(&mut self, other: &Self) {
+        let mut other = other.clone();
+        let scale = self.scale.min(other.scale);
+        self.downscale(scale);
+        other.downscale(scale);
+        // statistics of an empty side do not invalidate those of the other
+        let (mine, theirs) = (self.count, other.count);
+        let combine = |a: Option<f64>, b: Option<f64>, f: fn(f64, f64) -> f64| match (a, b) {
+            (Some(a), Some(b)) => Some(f(a, b)),
+            (a, None) if theirs == 0 => a,
+            (None, b) if mine == 0 => b,
+            _ => None,
+        };
+        self.sum = combine(self.sum, other.sum, |a, b| a + b);
+        self.min = combine(self.min, other.min, f64::min);
+        self.max = combine(self.max, other.max, f64::max);
+        self.count += other.count;
+        self.zero_count += other.zero_count;
+        self.zero_threshold = self.zero_threshold.max(other.zero_threshold);
+        self.positive = add_buckets(self.positive.as_ref(), other.positive.as_ref());
+        self.negative = add_buckets(self.negative.as_ref(), other.negative.as_ref());
+        self.fold_into_zero();
+        self.start_time_unix_nano = match (self.start_time_unix_nano, other.start_time_unix_nano) {
+            (0, start) | (start, 0) => start,
+            (a, b) => a.min(b),
+        };
+        self.time_unix_nano = self.time_unix_nano.max(other.time_unix_nano);
+        self.exemplars.extend(other.exemplars);
+    }
+
+    /// Converts the histogram into one with the given explicit bounds, which
+    /// must be sorted in ascending order. Every exponential bucket is counted in
+    /// the explicit bucket containing its midpoint, so the error of the
+    /// conversion is bounded by the width of the exponential buckets.
+    pub fn to_explicit(&self, explicit_bounds: &[f64]) -> HistogramDataPoint {
+        let mut bucket_counts = vec![0; explicit_bounds.len() + 1];
+        for (lower, upper, count) in self.ranges() {
+            let midpoint = lower + (upper - lower) / 2.0;
+            let index = explicit_bounds.partition_point(|bound| *bound < midpoint);
+            if let Some(slot) = bucket_counts.get_mut(index) {
+                *slot += count;
+            }
+        }
+        HistogramDataPoint {
+            attributes: self.attributes.clone(),
+            start_time_unix_nano: self.start_time_unix_nano,
+            time_unix_nano: self.time_unix_nano,
+            count: self.count,
+            sum: self.sum,
+            bucket_counts,
+            explicit_bounds: explicit_bounds.to_vec(),
+            exemplars: self.exemplars.clone(),
+            flags: self.flags,
+            min: self.min,
+            max: self.max,
+        }
+    }
+
+    /// Estimates the value at quantile `q` in `[0, 1]` by interpolating linearly
+    /// inside the bucket holding it. The estimate is clamped to the recorded
+    /// minimum and maximum when they are known.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        interpolate(self.ranges(), q, self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opentelemetry::proto::metrics::v1::{
+        exponential_histogram_data_point::Buckets, ExponentialHistogramDataPoint,
+    };
+
+    fn point(scale: i32, offset: i32, bucket_counts: Vec<u64>) -> ExponentialHistogramDataPoint {
+        ExponentialHistogramDataPoint {
+            count: bucket_counts.iter().sum(),
+            scale,
+            positive: Some(Buckets {
+                offset,
+                bucket_counts,
+            }),
+            ..ExponentialHistogramDataPoint::default()
+        }
+    }
+
+    #[test]
+    pub fn downscale_and_merge() {
+        let mut p = point(1, -3, vec![1, 2, 3, 4, 5]);
+        p.downscale(0);
+        // indices -3..=1 map to -2, -1, -1, 0, 0
+        assert_eq!(
+            p.positive,
+            Some(Buckets {
+                offset: -2,
+                bucket_counts: vec![1, 5, 9]
+            })
+        );
+        let mut merged = point(0, 0, vec![1]);
+        merged.merge_point(&point(2, 4, vec![2, 2, 2, 2]));
+        assert_eq!(merged.scale, 0);
+        assert_eq!(merged.count, 9);
+        assert_eq!(
+            merged.positive,
+            Some(Buckets {
+                offset: 0,
+                bucket_counts: vec![1, 8]
+            })
+        );
+    }
+
+    #[test]
+    pub fn merge_into_wider_zero_bucket() {
+        // scale 0 buckets: (1, 2], (2, 4], (4, 8]
+        let mut merged = point(0, 0, vec![1, 2, 3]);
+        let wide = ExponentialHistogramDataPoint {
+            count: 1,
+            zero_count: 1,
+            zero_threshold: 4.0,
+            ..ExponentialHistogramDataPoint::default()
+        };
+        merged.merge_point(&wide);
+        assert_eq!(merged.count, 7);
+        assert_eq!(merged.zero_threshold, 4.0);
+        assert_eq!(merged.zero_count, 4);
+        assert_eq!(
+            merged.positive,
+            Some(Buckets {
+                offset: 2,
+                bucket_counts: vec![3]
+            })
+        );
+    }
+
+    #[test]
+    pub fn reject_out_of_range_values() {
+        let mut p = ExponentialHistogramDataPoint {
+            scale: 30,
+            ..ExponentialHistogramDataPoint::default()
+        };
+        p.record(-1.5, 1);
+        let before = p.clone();
+        // the first positive value, 2^1000, has an index at scale 30 that does
+        // not fit the bucket offset
+        p.record(1000.0_f64.exp2(), 1);
+        assert_eq!(p, before);
+    }
+
+    #[test]
+    pub fn record_values() {
+        let mut p = ExponentialHistogramDataPoint {
+            scale: 20,
+            ..ExponentialHistogramDataPoint::default()
+        };
+        p.record(1.5, 2);
+        p.record(0.0, 1);
+        p.record(-3.0, 1);
+        p.record(1000.0, 1);
+        assert_eq!(p.count, 5);
+        assert_eq!(p.zero_count, 1);
+        assert_eq!(p.sum, Some(1000.0));
+        assert_eq!(p.min, Some(-3.0));
+        let positive = p.positive.clone().unwrap_or_default();
+        assert!(positive.bucket_counts.len() <= 160);
+        assert_eq!(positive.bucket_counts.iter().sum::<u64>(), 3);
+        // downscaled until 1.5 and 1000 are at most 160 buckets apart
+        assert_eq!(p.scale, 4);
+        let negative = p.negative.clone().unwrap_or_default();
+        assert_eq!(negative.bucket_counts, vec![1]);
+    }
+
+    #[test]
+    pub fn explicit_buckets_and_quantiles() {
+        // scale 0 buckets: (1, 2], (2, 4], (4, 8]
+        let p = point(0, 0, vec![2, 4, 2]);
+        let explicit = p.to_explicit(&[2.0, 5.0]);
+        assert_eq!(explicit.bucket_counts, vec![2, 4, 2]);
+        assert_eq!(explicit.count, 8);
+        assert_eq!(p.quantile(0.5), Some(3.0));
+        assert_eq!(p.quantile(1.0), Some(8.0));
+        assert_eq!(p.quantile(1.5), None);
+    }
+}
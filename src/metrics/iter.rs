@@ -0,0 +1,372 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::common::{EMPTY_RESOURCE, EMPTY_SCOPE};
+use crate::opentelemetry::proto::collector::metrics::v1 as base;
+use crate::opentelemetry::proto::common::v1::{InstrumentationScope, KeyValue};
+use crate::opentelemetry::proto::metrics::v1::{
+    metric::Data, ExponentialHistogramDataPoint, HistogramDataPoint, Metric, NumberDataPoint,
+    SummaryDataPoint,
+};
+use crate::opentelemetry::proto::resource::v1::Resource;
+
+/// A borrowed data point of any of the metric data types
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataPointRef<'a> {
+    /// A data point of a `Gauge`
+    Gauge(&'a NumberDataPoint),
+    /// A data point of a `Sum`
+    Sum(&'a NumberDataPoint),
+    /// A data point of a `Histogram`
+    Histogram(&'a HistogramDataPoint),
+    /// A data point of an `ExponentialHistogram`
+    ExponentialHistogram(&'a ExponentialHistogramDataPoint),
+    /// A data point of a `Summary`
+    Summary(&'a SummaryDataPoint),
+}
+
+/// A mutably borrowed data point of any of the metric data types
+#[derive(Debug, PartialEq)]
+pub enum DataPointMut<'a> {
+    /// A data point of a `Gauge`
+    Gauge(&'a mut NumberDataPoint),
+    /// A data point of a `Sum`
+    Sum(&'a mut NumberDataPoint),
+    /// A data point of a `Histogram`
+    Histogram(&'a mut HistogramDataPoint),
+    /// A data point of an `ExponentialHistogram`
+    ExponentialHistogram(&'a mut ExponentialHistogramDataPoint),
+    /// A data point of a `Summary`
+    Summary(&'a mut SummaryDataPoint),
+}
+
+impl<'a> DataPointRef<'a> {
+    /// The attributes of the data point
+    pub fn attributes(&self) -> &'a [KeyValue] {
+        match self {
+            Self::Gauge(dp) | Self::Sum(dp) => &dp.attributes,
+            Self::Histogram(dp) => &dp.attributes,
+            Self::ExponentialHistogram(dp) => &dp.attributes,
+            Self::Summary(dp) => &dp.attributes,
+        }
+    }
+
+    /// The start time of the data point in nanoseconds since the unix epoch
+    pub fn start_time_unix_nano(&self) -> u64 {
+        match self {
+            Self::Gauge(dp) | Self::Sum(dp) => dp.start_time_unix_nano,
+            Self::Histogram(dp) => dp.start_time_unix_nano,
+            Self::ExponentialHistogram(dp) => dp.start_time_unix_nano,
+            Self::Summary(dp) => dp.start_time_unix_nano,
+        }
+    }
+
+    /// The time of the data point in nanoseconds since the unix epoch
+    pub fn time_unix_nano(&self) -> u64 {
+        match self {
+            Self::Gauge(dp) | Self::Sum(dp) => dp.time_unix_nano,
+            Self::Histogram(dp) => dp.time_unix_nano,
+            Self::ExponentialHistogram(dp) => dp.time_unix_nano,
+            Self::Summary(dp) => dp.time_unix_nano,
+        }
+    }
+
+    /// The `DataPointFlags` of the data point
+    pub fn flags(&self) -> u32 {
+        match self {
+            Self::Gauge(dp) | Self::Sum(dp) => dp.flags,
+            Self::Histogram(dp) => dp.flags,
+            Self::ExponentialHistogram(dp) => dp.flags,
+            Self::Summary(dp) => dp.flags,
+        }
+    }
+}
+
+impl DataPointMut<'_> {
+    /// The attributes of the data point
+    pub fn attributes_mut(&mut self) -> &mut Vec<KeyValue> {
+        match self {
+            Self::Gauge(dp) | Self::Sum(dp) => &mut dp.attributes,
+            Self::Histogram(dp) => &mut dp.attributes,
+            Self::ExponentialHistogram(dp) => &mut dp.attributes,
+            Self::Summary(dp) => &mut dp.attributes,
+        }
+    }
+
+    /// Reborrows the data point immutably
+    pub fn as_ref(&self) -> DataPointRef<'_> {
+        match self {
+            Self::Gauge(dp) => DataPointRef::Gauge(dp),
+            Self::Sum(dp) => DataPointRef::Sum(dp),
+            Self::Histogram(dp) => DataPointRef::Histogram(dp),
+            Self::ExponentialHistogram(dp) => DataPointRef::ExponentialHistogram(dp),
+            Self::Summary(dp) => DataPointRef::Summary(dp),
+        }
+    }
+}
+
+/// Iterates over the data points of a metric, whatever its data type
+pub(crate) fn data_points(data: Option<&Data>) -> Box<dyn Iterator<Item = DataPointRef<'_>> + '_> {
+    match data {
+        Some(Data::Gauge(g)) => Box::new(g.data_points.iter().map(DataPointRef::Gauge)),
+        Some(Data::Sum(s)) => Box::new(s.data_points.iter().map(DataPointRef::Sum)),
+        Some(Data::Histogram(h)) => Box::new(h.data_points.iter().map(DataPointRef::Histogram)),
+        Some(Data::ExponentialHistogram(h)) => {
+            Box::new(h.data_points.iter().map(DataPointRef::ExponentialHistogram))
+        }
+        Some(Data::Summary(s)) => Box::new(s.data_points.iter().map(DataPointRef::Summary)),
+        None => Box::new(std::iter::empty()),
+    }
+}
+
+/// Mutably iterates over the data points of a metric, whatever its data type
+fn data_points_mut(data: Option<&mut Data>) -> Box<dyn Iterator<Item = DataPointMut<'_>> + '_> {
+    match data {
+        Some(Data::Gauge(g)) => Box::new(g.data_points.iter_mut().map(DataPointMut::Gauge)),
+        Some(Data::Sum(s)) => Box::new(s.data_points.iter_mut().map(DataPointMut::Sum)),
+        Some(Data::Histogram(h)) => Box::new(h.data_points.iter_mut().map(DataPointMut::Histogram)),
+        Some(Data::ExponentialHistogram(h)) => Box::new(
+            h.data_points
+                .iter_mut()
+                .map(DataPointMut::ExponentialHistogram),
+        ),
+        Some(Data::Summary(s)) => Box::new(s.data_points.iter_mut().map(DataPointMut::Summary)),
+        None => Box::new(std::iter::empty()),
+    }
+}
+
+/// The number of data points of a metric, whatever its data type
+pub(crate) fn data_point_count(metric: &Metric) -> usize {
+    match &metric.data {
+        Some(Data::Gauge(g)) => g.data_points.len(),
+        Some(Data::Sum(s)) => s.data_points.len(),
+        Some(Data::Histogram(h)) => h.data_points.len(),
+        Some(Data::ExponentialHistogram(h)) => h.data_points.len(),
+        Some(Data::Summary(s)) => s.data_points.len(),
+        None => 0,
+    }
+}
+
+impl base::ExportMetricsServiceRequest {
+    /// Iterates over every metric in the request alongside its resource and
+    /// instrumentation scope. Missing resources or scopes are presented as empty ones.
+    pub fn metrics(&self) -> impl Iterator<Item = (&Resource, &InstrumentationScope, &Metric)> {
+        self.resource_metrics.iter().flat_map(|rm| {
+            let resource = rm.resource.as_ref().unwrap_or(&EMPTY_RESOURCE);
+            rm.scope_metrics.iter().flat_map(move |sm| {
+                let scope = sm.scope.as_ref().unwrap_or(&EMPTY_SCOPE);
+                sm.metrics
+                    .iter()
+                    .map(move |metric| (resource, scope, metric))
+            })
+        })
+    }
+
+    /// Mutably iterates over every metric in the request alongside its resource and
+    /// instrumentation scope
+    pub fn metrics_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (&Resource, &InstrumentationScope, &mut Metric)> {
+        self.resource_metrics.iter_mut().flat_map(|rm| {
+            let resource = rm.resource.as_ref().unwrap_or(&EMPTY_RESOURCE);
+            rm.scope_metrics.iter_mut().flat_map(move |sm| {
+                let scope = sm.scope.as_ref().unwrap_or(&EMPTY_SCOPE);
+                sm.metrics
+                    .iter_mut()
+                    .map(move |metric| (resource, scope, metric))
+            })
+        })
+    }
+
+    /// Iterates over every data point in the request alongside its resource,
+    /// instrumentation scope and metric
+    pub fn data_points(
+        &self,
+    ) -> impl Iterator<Item = (&Resource, &InstrumentationScope, &Metric, DataPointRef<'_>)> {
+        self.metrics().flat_map(|(resource, scope, metric)| {
+            data_points(metric.data.as_ref()).map(move |dp| (resource, scope, metric, dp))
+        })
+    }
+
+    /// Mutably iterates over every data point in the request alongside its resource
+    /// and instrumentation scope. The owning metric is borrowed by the data point,
+    /// use `metrics_mut` when its name or unit are required.
+    pub fn data_points_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (&Resource, &InstrumentationScope, DataPointMut<'_>)> {
+        self.metrics_mut().flat_map(|(resource, scope, metric)| {
+            data_points_mut(metric.data.as_mut()).map(move |dp| (resource, scope, dp))
+        })
+    }
+
+    /// The total number of metrics in the request
+    pub fn metric_count(&self) -> usize {
+        self.resource_metrics
+            .iter()
+            .flat_map(|rm| rm.scope_metrics.iter())
+            .map(|sm| sm.metrics.len())
+            .sum()
+    }
+
+    /// The total number of data points in the request
+    pub fn data_point_count(&self) -> usize {
+        self.metrics()
+            .map(|(_, _, metric)| data_point_count(metric))
+            .sum()
+    }
+
+    /// Retains only the metrics for which the predicate returns true. Scopes and
+    /// resources that are left without metrics are removed.
+    ///
+    /// Returns the number of data points that were removed.
+    pub fn retain_metrics<F>(&mut self, mut keep: F) -> usize
+    where
+        F: FnMut(&Resource, &InstrumentationScope, &Metric) -> bool,
+    {
+        let before = self.data_point_count();
+        for rm in &mut self.resource_metrics {
+            let resource = rm.resource.as_ref().unwrap_or(&EMPTY_RESOURCE);
+            for sm in &mut rm.scope_metrics {
+                let scope = sm.scope.as_ref().unwrap_or(&EMPTY_SCOPE);
+                sm.metrics.retain(|metric| keep(resource, scope, metric));
+            }
+            rm.scope_metrics.retain(|sm| !sm.metrics.is_empty());
+        }
+        self.resource_metrics
+            .retain(|rm| !rm.scope_metrics.is_empty());
+        before - self.data_point_count()
+    }
+
+    /// Retains only the data points for which the predicate returns true. Metrics,
+    /// scopes and resources that are left without data points are removed.
+    ///
+    /// Returns the number of data points that were removed.
+    pub fn retain_data_points<F>(&mut self, mut keep: F) -> usize
+    where
+        F: FnMut(&Resource, &InstrumentationScope, DataPointRef<'_>) -> bool,
+    {
+        let before = self.data_point_count();
+        for rm in &mut self.resource_metrics {
+            let resource = rm.resource.as_ref().unwrap_or(&EMPTY_RESOURCE);
+            for sm in &mut rm.scope_metrics {
+                let scope = sm.scope.as_ref().unwrap_or(&EMPTY_SCOPE);
+                for metric in &mut sm.metrics {
+                    match &mut metric.data {
+                        Some(Data::Gauge(g)) => g
+                            .data_points
+                            .retain(|dp| keep(resource, scope, DataPointRef::Gauge(dp))),
+                        Some(Data::Sum(s)) => s
+                            .data_points
+                            .retain(|dp| keep(resource, scope, DataPointRef::Sum(dp))),
+                        Some(Data::Histogram(h)) => h
+                            .data_points
+                            .retain(|dp| keep(resource, scope, DataPointRef::Histogram(dp))),
+                        Some(Data::ExponentialHistogram(h)) => h.data_points.retain(|dp| {
+                            keep(resource, scope, DataPointRef::ExponentialHistogram(dp))
+                        }),
+                        Some(Data::Summary(s)) => s
+                            .data_points
+                            .retain(|dp| keep(resource, scope, DataPointRef::Summary(dp))),
+                        None => (),
+                    }
+                }
+                sm.metrics.retain(|metric| data_point_count(metric) > 0);
+            }
+            rm.scope_metrics.retain(|sm| !sm.metrics.is_empty());
+        }
+        self.resource_metrics
+            .retain(|rm| !rm.scope_metrics.is_empty());
+        before - self.data_point_count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DataPointRef;
+    use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+    use crate::opentelemetry::proto::metrics::v1::{
+        metric::Data, Gauge, Histogram, HistogramDataPoint, Metric, NumberDataPoint,
+        ResourceMetrics, ScopeMetrics,
+    };
+
+    fn request() -> ExportMetricsServiceRequest {
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: None,
+                scope_metrics: vec![ScopeMetrics {
+                    scope: None,
+                    metrics: vec![
+                        Metric {
+                            name: "gauge".to_string(),
+                            data: Some(Data::Gauge(Gauge {
+                                data_points: vec![
+                                    NumberDataPoint {
+                                        time_unix_nano: 1,
+                                        ..NumberDataPoint::default()
+                                    },
+                                    NumberDataPoint {
+                                        time_unix_nano: 2,
+                                        ..NumberDataPoint::default()
+                                    },
+                                ],
+                            })),
+                            ..Metric::default()
+                        },
+                        Metric {
+                            name: "histogram".to_string(),
+                            data: Some(Data::Histogram(Histogram {
+                                data_points: vec![HistogramDataPoint {
+                                    time_unix_nano: 3,
+                                    ..HistogramDataPoint::default()
+                                }],
+                                aggregation_temporality: 0,
+                            })),
+                            ..Metric::default()
+                        },
+                    ],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    #[test]
+    pub fn iterate_data_points() {
+        let req = request();
+        assert_eq!(req.metric_count(), 2);
+        assert_eq!(req.data_point_count(), 3);
+        let points: Vec<(&str, u64)> = req
+            .data_points()
+            .map(|(_, _, m, dp)| (m.name.as_str(), dp.time_unix_nano()))
+            .collect();
+        assert_eq!(points, vec![("gauge", 1), ("gauge", 2), ("histogram", 3)]);
+    }
+
+    #[test]
+    pub fn retain_drops_empty_metrics() {
+        let mut req = request();
+        let removed = req.retain_data_points(|_, _, dp| !matches!(dp, DataPointRef::Histogram(_)));
+        assert_eq!(removed, 1);
+        assert_eq!(req.metric_count(), 1);
+        for (_, _, mut dp) in req.data_points_mut() {
+            dp.attributes_mut().clear();
+        }
+        assert_eq!(
+            req.retain_data_points(|_, _, dp| dp.time_unix_nano() > 1),
+            1
+        );
+        assert_eq!(req.data_point_count(), 1);
+    }
+}
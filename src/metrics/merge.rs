@@ -0,0 +1,177 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::common::{find_or_push, same_resource, same_scope};
+use crate::opentelemetry::proto::collector::metrics::v1 as base;
+use crate::opentelemetry::proto::metrics::v1::{
+    metric::Data, Metric, ResourceMetrics, ScopeMetrics,
+};
+
+/// Checks if two metrics describe the same stream so that their data points
+/// can be carried by a single metric
+fn same_stream(a: &Metric, b: &Metric) -> bool {
+    let same_data = match (&a.data, &b.data) {
+        (Some(Data::Gauge(_)), Some(Data::Gauge(_))) => true,
+        (Some(Data::Sum(x)), Some(Data::Sum(y))) => {
+            x.aggregation_temporality == y.aggregation_temporality
+                && x.is_monotonic == y.is_monotonic
+        }
+        (Some(Data::Histogram(x)), Some(Data::Histogram(y))) => {
+            x.aggregation_temporality == y.aggregation_temporality
+        }
+        (Some(Data::ExponentialHistogram(x)), Some(Data::ExponentialHistogram(y))) => {
+            x.aggregation_temporality == y.aggregation_temporality
+        }
+        (Some(Data::Summary(_)), Some(Data::Summary(_))) => true,
+        _ => false,
+    };
+    same_data
+        && a.name == b.name
+        && a.description == b.description
+        && a.unit == b.unit
+        && a.metadata == b.metadata
+}
+
+/// Appends the data points of `from` to `into`, both must describe the same stream
+fn append_data_points(into: &mut Metric, from: Metric) {
+    match (&mut into.data, from.data) {
+        (Some(Data::Gauge(x)), Some(Data::Gauge(y))) => x.data_points.extend(y.data_points),
+        (Some(Data::Sum(x)), Some(Data::Sum(y))) => x.data_points.extend(y.data_points),
+        (Some(Data::Histogram(x)), Some(Data::Histogram(y))) => {
+            x.data_points.extend(y.data_points);
+        }
+        (Some(Data::ExponentialHistogram(x)), Some(Data::ExponentialHistogram(y))) => {
+            x.data_points.extend(y.data_points);
+        }
+        (Some(Data::Summary(x)), Some(Data::Summary(y))) => x.data_points.extend(y.data_points),
+        (_, data) => into.data = data,
+    }
+}
+
+impl base::ExportMetricsServiceRequest {
+    /// Merges another request into this one. Metrics are appended to the
+    /// resource and scope envelopes of this request that carry the same resource,
+    /// instrumentation scope and schema URLs, new envelopes are added otherwise.
+    /// Attributes are compared regardless of their order.
+    /// Metrics describing the same stream are combined into one metric.
+    pub fn merge_request(&mut self, other: Self) {
+        for rm in other.resource_metrics {
+            let ResourceMetrics {
+                resource,
+                scope_metrics,
+                schema_url,
+            } = rm;
+            let target = find_or_push(
+                &mut self.resource_metrics,
+                |t| {
+                    same_resource(t.resource.as_ref(), resource.as_ref())
+                        && t.schema_url == schema_url
+                },
+                || ResourceMetrics {
+                    resource: resource.clone(),
+                    scope_metrics: Vec::new(),
+                    schema_url: schema_url.clone(),
+                },
+            );
+            for sm in scope_metrics {
+                let ScopeMetrics {
+                    scope,
+                    metrics,
+                    schema_url,
+                } = sm;
+                let target = find_or_push(
+                    &mut target.scope_metrics,
+                    |t| same_scope(t.scope.as_ref(), scope.as_ref()) && t.schema_url == schema_url,
+                    || ScopeMetrics {
+                        scope: scope.clone(),
+                        metrics: Vec::new(),
+                        schema_url: schema_url.clone(),
+                    },
+                );
+                for metric in metrics {
+                    match target.metrics.iter_mut().find(|t| same_stream(t, &metric)) {
+                        Some(existing) => append_data_points(existing, metric),
+                        None => target.metrics.push(metric),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Combines several metrics export requests into one, deduplicating identical
+/// resources, instrumentation scopes and metric streams
+pub fn merge<I>(requests: I) -> base::ExportMetricsServiceRequest
+where
+    I: IntoIterator<Item = base::ExportMetricsServiceRequest>,
+{
+    requests.into_iter().fold(
+        base::ExportMetricsServiceRequest::default(),
+        |mut acc, r| {
+            acc.merge_request(r);
+            acc
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+    use crate::opentelemetry::proto::common::v1::{any_value, AnyValue, KeyValue};
+    use crate::opentelemetry::proto::metrics::v1::{
+        metric::Data, Gauge, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+    };
+    use crate::opentelemetry::proto::resource::v1::Resource;
+
+    fn request(service: &str, metric: &str) -> ExportMetricsServiceRequest {
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![KeyValue {
+                        key: "service.name".to_string(),
+                        value: Some(AnyValue {
+                            value: Some(any_value::Value::StringValue(service.to_string())),
+                        }),
+                    }],
+                    dropped_attributes_count: 0,
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: None,
+                    metrics: vec![Metric {
+                        name: metric.to_string(),
+                        data: Some(Data::Gauge(Gauge {
+                            data_points: vec![NumberDataPoint::default()],
+                        })),
+                        ..Metric::default()
+                    }],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    #[test]
+    pub fn merge_deduplicates_envelopes() {
+        let merged = super::merge(vec![
+            request("a", "x"),
+            request("b", "x"),
+            request("a", "x"),
+            request("a", "y"),
+        ]);
+        assert_eq!(merged.resource_metrics.len(), 2);
+        assert_eq!(merged.metric_count(), 3);
+        assert_eq!(merged.data_point_count(), 4);
+    }
+}
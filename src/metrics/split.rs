@@ -0,0 +1,170 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::iter::data_point_count;
+use crate::common::{field_len, split_envelopes, SplitLimits};
+use crate::opentelemetry::proto::collector::metrics::v1 as base;
+use crate::opentelemetry::proto::metrics::v1::{metric::Data, Metric};
+use prost::Message;
+
+/// Upper bound of the overhead of the metric and data type envelopes around
+/// the data points of a metric
+fn metric_overhead(shell: &Metric) -> usize {
+    1 + 10 + 10 + shell.encoded_len()
+}
+
+/// Greedily groups data points into chunks honouring the limits
+fn chunk_points<P: Message>(points: Vec<P>, overhead: usize, limits: &SplitLimits) -> Vec<Vec<P>> {
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut bytes = overhead;
+    for point in points {
+        let cost = field_len(point.encoded_len());
+        if !chunk.is_empty() && limits.exceeded_by(bytes + cost, chunk.len() + 1) {
+            chunks.push(std::mem::take(&mut chunk));
+            bytes = overhead;
+        }
+        bytes += cost;
+        chunk.push(point);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Breaks up a metric whose data points exceed the limits into several metrics
+/// with the same descriptor
+fn split_metric(mut metric: Metric, limits: &SplitLimits) -> Vec<Metric> {
+    let too_large = limits.exceeded_by(field_len(metric.encoded_len()), data_point_count(&metric));
+    if !too_large {
+        return vec![metric];
+    }
+    let data = metric.data.take();
+    macro_rules! split_points {
+        ($variant:ident, $inner:expr) => {{
+            let mut inner = $inner;
+            let points = std::mem::take(&mut inner.data_points);
+            metric.data = Some(Data::$variant(inner.clone()));
+            let overhead = metric_overhead(&metric);
+            chunk_points(points, overhead, limits)
+                .into_iter()
+                .map(|data_points| {
+                    let mut inner = inner.clone();
+                    inner.data_points = data_points;
+                    Metric {
+                        data: Some(Data::$variant(inner)),
+                        ..metric.clone()
+                    }
+                })
+                .collect()
+        }};
+    }
+    match data {
+        Some(Data::Gauge(g)) => split_points!(Gauge, g),
+        Some(Data::Sum(s)) => split_points!(Sum, s),
+        Some(Data::Histogram(h)) => split_points!(Histogram, h),
+        Some(Data::ExponentialHistogram(h)) => split_points!(ExponentialHistogram, h),
+        Some(Data::Summary(s)) => split_points!(Summary, s),
+        None => vec![metric],
+    }
+}
+
+impl base::ExportMetricsServiceRequest {
+    /// Splits the request into requests whose encoded size and number of data
+    /// points stay within the given limits. Resource and scope envelopes are
+    /// repeated in every chunk carrying some of their metrics, and metrics with
+    /// too many data points are broken up into several metrics with the same
+    /// descriptor. A single oversized data point is sent in a chunk of its own.
+    pub fn split(self, limits: &SplitLimits) -> Vec<Self> {
+        split_envelopes(
+            self.resource_metrics,
+            limits,
+            |r| &mut r.scope_metrics,
+            |s| &mut s.metrics,
+            data_point_count,
+            split_metric,
+        )
+        .into_iter()
+        .map(|resource_metrics| Self { resource_metrics })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::SplitLimits;
+    use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+    use crate::opentelemetry::proto::metrics::v1::{
+        metric::Data, number_data_point, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+        Sum,
+    };
+    use prost::Message;
+
+    fn request(points: usize) -> ExportMetricsServiceRequest {
+        let data_points = (0..points)
+            .map(|i| NumberDataPoint {
+                time_unix_nano: i as u64,
+                value: Some(number_data_point::Value::AsInt(i as i64)),
+                ..NumberDataPoint::default()
+            })
+            .collect();
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: None,
+                scope_metrics: vec![ScopeMetrics {
+                    scope: None,
+                    metrics: vec![Metric {
+                        name: "requests".to_string(),
+                        data: Some(Data::Sum(Sum {
+                            data_points,
+                            aggregation_temporality: 2,
+                            is_monotonic: true,
+                        })),
+                        ..Metric::default()
+                    }],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    #[test]
+    pub fn split_by_items() {
+        let chunks = request(10).split(&SplitLimits::with_max_items(3));
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|c| c.data_point_count() <= 3));
+        assert_eq!(
+            chunks.iter().map(|c| c.data_point_count()).sum::<usize>(),
+            10
+        );
+        assert!(chunks
+            .iter()
+            .all(|c| c.metrics().all(|(_, _, m)| m.name == "requests")));
+    }
+
+    #[test]
+    pub fn split_by_bytes() {
+        let req = request(1000);
+        let max = req.encoded_len() / 4;
+        let chunks = req.split(&SplitLimits::with_max_bytes(max));
+        assert!(chunks.len() >= 4);
+        assert!(chunks.iter().all(|c| c.encoded_len() <= max));
+        assert_eq!(
+            chunks.iter().map(|c| c.data_point_count()).sum::<usize>(),
+            1000
+        );
+    }
+}
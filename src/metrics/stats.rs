@@ -0,0 +1,245 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::opentelemetry::proto::metrics::v1::{
+    ExponentialHistogramDataPoint, HistogramDataPoint, SummaryDataPoint,
+};
+
+/// Statistics estimated from the data points of distributions
+pub trait DistributionStats {
+    /// Estimates the value at quantile `q` in `[0, 1]`
+    fn quantile(&self, q: f64) -> Option<f64>;
+
+    /// The arithmetic mean of the recorded values
+    fn mean(&self) -> Option<f64>;
+
+    /// The recorded minimum, or the bucket boundary closest to it
+    fn min_estimate(&self) -> Option<f64>;
+
+    /// The recorded maximum, or the bucket boundary closest to it
+    fn max_estimate(&self) -> Option<f64>;
+
+    /// The estimated median
+    fn p50(&self) -> Option<f64> {
+        self.quantile(0.5)
+    }
+
+    /// The estimated 90th percentile
+    fn p90(&self) -> Option<f64> {
+        self.quantile(0.9)
+    }
+
+    /// The estimated 99th percentile
+    fn p99(&self) -> Option<f64> {
+        self.quantile(0.99)
+    }
+}
+
+/// Divides a sum by a count
+fn mean(sum: f64, count: u64) -> Option<f64> {
+    // counts beyond 2^52 lose precision, which is fine for an estimate
+    #[allow(clippy::cast_precision_loss)]
+    let count = count as f64;
+    (count > 0.0).then(|| sum / count)
+}
+
+/// Interpolates the value at quantile `q` over ascending `(lower, upper, count)`
+/// ranges, clamped to the recorded minimum and maximum when they are known
+pub(crate) fn interpolate<I>(ranges: I, q: f64, min: Option<f64>, max: Option<f64>) -> Option<f64>
+where
+    I: Iterator<Item = (f64, f64, u64)> + Clone,
+{
+    if !(0.0..=1.0).contains(&q) {
+        return None;
+    }
+    let total: u64 = ranges.clone().map(|(_, _, count)| count).sum();
+    if total == 0 {
+        return None;
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let rank = q * total as f64;
+    let mut seen = 0.0;
+    let mut estimate = None;
+    for (lower, upper, count) in ranges.filter(|(_, _, count)| *count > 0) {
+        #[allow(clippy::cast_precision_loss)]
+        let count = count as f64;
+        if seen + count >= rank {
+            estimate = Some(lower + (upper - lower) * (rank - seen) / count);
+            break;
+        }
+        seen += count;
+    }
+    let estimate = estimate?;
+    let estimate = min.map_or(estimate, |min| estimate.max(min));
+    Some(max.map_or(estimate, |max| estimate.min(max)))
+}
+
+impl HistogramDataPoint {
+    /// Iterates over the buckets as `(lower, upper, count)`. The unbounded
+    /// first and last buckets are closed with the recorded minimum and maximum
+    /// when known, the first one at zero if its upper bound is positive and the
+    /// last one at its lower bound otherwise.
+    fn ranges(&self) -> impl Iterator<Item = (f64, f64, u64)> + Clone + '_ {
+        let first = self.explicit_bounds.first().copied().unwrap_or(0.0);
+        let last = self.explicit_bounds.last().copied().unwrap_or(0.0);
+        let low = self.min.unwrap_or(if first > 0.0 { 0.0 } else { first });
+        let high = self.max.unwrap_or(last);
+        let lowers = std::iter::once(low).chain(self.explicit_bounds.iter().copied());
+        let uppers = self
+            .explicit_bounds
+            .iter()
+            .copied()
+            .chain(std::iter::once(high));
+        lowers
+            .zip(uppers)
+            .zip(self.bucket_counts.iter().copied())
+            .map(|((lower, upper), count)| (lower.min(upper), upper, count))
+    }
+}
+
+impl DistributionStats for HistogramDataPoint {
+    /// Estimates the value at quantile `q` by interpolating linearly inside the
+    /// bucket holding it
+    fn quantile(&self, q: f64) -> Option<f64> {
+        interpolate(self.ranges(), q, self.min, self.max)
+    }
+
+    fn mean(&self) -> Option<f64> {
+        mean(self.sum?, self.count)
+    }
+
+    fn min_estimate(&self) -> Option<f64> {
+        if self.min.is_some() {
+            return self.min;
+        }
+        let populated = self.bucket_counts.iter().position(|count| *count > 0)?;
+        match populated.checked_sub(1) {
+            Some(i) => self.explicit_bounds.get(i).copied(),
+            None => self.explicit_bounds.first().copied(),
+        }
+    }
+
+    fn max_estimate(&self) -> Option<f64> {
+        if self.max.is_some() {
+            return self.max;
+        }
+        let populated = self.bucket_counts.iter().rposition(|count| *count > 0)?;
+        self.explicit_bounds
+            .get(populated)
+            .or_else(|| self.explicit_bounds.last())
+            .copied()
+    }
+}
+
+impl DistributionStats for ExponentialHistogramDataPoint {
+    /// Estimates the value at quantile `q` by interpolating linearly inside the
+    /// bucket holding it
+    fn quantile(&self, q: f64) -> Option<f64> {
+        ExponentialHistogramDataPoint::quantile(self, q)
+    }
+
+    fn mean(&self) -> Option<f64> {
+        mean(self.sum?, self.count)
+    }
+
+    fn min_estimate(&self) -> Option<f64> {
+        self.min
+            .or_else(|| self.ranges().next().map(|(lower, _, _)| lower))
+    }
+
+    fn max_estimate(&self) -> Option<f64> {
+        self.max
+            .or_else(|| self.ranges().last().map(|(_, upper, _)| upper))
+    }
+}
+
+impl DistributionStats for SummaryDataPoint {
+    /// The value at quantile `q`, interpolated linearly between the nearest
+    /// quantiles reported. Quantiles outside the reported range are unknown.
+    fn quantile(&self, q: f64) -> Option<f64> {
+        if !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+        let mut known: Vec<(f64, f64)> = self
+            .quantile_values
+            .iter()
+            .map(|v| (v.quantile, v.value))
+            .collect();
+        known.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let below = known.iter().rev().find(|(quantile, _)| *quantile <= q)?;
+        let above = known.iter().find(|(quantile, _)| *quantile >= q)?;
+        if above.0 <= below.0 {
+            return Some(below.1);
+        }
+        Some(below.1 + (above.1 - below.1) * (q - below.0) / (above.0 - below.0))
+    }
+
+    fn mean(&self) -> Option<f64> {
+        mean(self.sum, self.count)
+    }
+
+    fn min_estimate(&self) -> Option<f64> {
+        self.quantile(0.0)
+    }
+
+    fn max_estimate(&self) -> Option<f64> {
+        self.quantile(1.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DistributionStats;
+    use crate::opentelemetry::proto::metrics::v1::{
+        summary_data_point::ValueAtQuantile, HistogramDataPoint, SummaryDataPoint,
+    };
+
+    #[test]
+    pub fn histogram_quantiles() {
+        let point = HistogramDataPoint {
+            count: 10,
+            sum: Some(150.0),
+            bucket_counts: vec![0, 5, 4, 1],
+            explicit_bounds: vec![10.0, 20.0, 30.0],
+            ..HistogramDataPoint::default()
+        };
+        assert_eq!(point.p50(), Some(20.0));
+        assert_eq!(point.p90(), Some(30.0));
+        assert_eq!(point.quantile(0.25), Some(15.0));
+        assert_eq!(point.mean(), Some(15.0));
+        assert_eq!(point.min_estimate(), Some(10.0));
+        assert_eq!(point.max_estimate(), Some(30.0));
+        let bounded = HistogramDataPoint {
+            max: Some(35.0),
+            ..point
+        };
+        assert_eq!(bounded.quantile(1.0), Some(35.0));
+    }
+
+    #[test]
+    pub fn summary_quantiles() {
+        let quantile = |quantile, value| ValueAtQuantile { quantile, value };
+        let point = SummaryDataPoint {
+            count: 4,
+            sum: 10.0,
+            quantile_values: vec![quantile(0.9, 9.0), quantile(0.5, 5.0), quantile(0.0, 1.0)],
+            ..SummaryDataPoint::default()
+        };
+        assert_eq!(point.p50(), Some(5.0));
+        assert!(point.quantile(0.7).is_some_and(|v| (v - 7.0).abs() < 1e-9));
+        assert_eq!(point.p99(), None);
+        assert_eq!(point.mean(), Some(2.5));
+        assert_eq!(point.min_estimate(), Some(1.0));
+    }
+}
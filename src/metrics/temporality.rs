@@ -0,0 +1,1018 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversion of metric streams between cumulative and delta temporality.
+//!
+//! A stream is identified by its resource, instrumentation scope, metric name,
+//! unit and kind, and the attributes of its data points. Attribute order does
+//! not matter. Converters keep the last point of every stream they have seen
+//! and forget streams that stayed idle for longer than their idle timeout.
+
+use super::exponential::add_buckets;
+use crate::common::nanos;
+use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use crate::opentelemetry::proto::common::v1::{InstrumentationScope, KeyValue};
+use crate::opentelemetry::proto::metrics::v1::{
+    exponential_histogram_data_point::Buckets, metric::Data, number_data_point,
+    AggregationTemporality, DataPointFlags, ExponentialHistogramDataPoint, HistogramDataPoint,
+    Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+};
+use crate::opentelemetry::proto::resource::v1::Resource;
+use crate::processor::{Disposition, Processor};
+use prost::Message;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Default time after which a stream that received no points is forgotten
+pub const DEFAULT_MAX_IDLE: Duration = Duration::from_secs(300);
+
+/// Appends a length prefixed string to a stream identity
+fn push_str(identity: &mut Vec<u8>, s: &str) {
+    identity.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    identity.extend_from_slice(s.as_bytes());
+}
+
+/// Appends attributes to a stream identity independently of their order
+fn push_attributes(identity: &mut Vec<u8>, attributes: &[KeyValue]) {
+    let mut sorted: Vec<&KeyValue> = attributes.iter().collect();
+    sorted.sort_by(|a, b| a.key.cmp(&b.key));
+    identity.extend_from_slice(&(sorted.len() as u64).to_le_bytes());
+    for kv in sorted {
+        identity.extend(kv.encode_length_delimited_to_vec());
+    }
+}
+
+/// The identity prefix shared by all streams of a resource and scope
+fn scope_identity(
+    resource: Option<&Resource>,
+    resource_schema_url: &str,
+    scope: Option<&InstrumentationScope>,
+) -> Vec<u8> {
+    let mut identity = Vec::new();
+    push_attributes(&mut identity, resource.map_or(&[], |r| &r.attributes));
+    push_str(&mut identity, resource_schema_url);
+    push_str(&mut identity, scope.map_or("", |s| &s.name));
+    push_str(&mut identity, scope.map_or("", |s| &s.version));
+    push_attributes(&mut identity, scope.map_or(&[], |s| &s.attributes));
+    identity
+}
+
+/// The identity of a single stream of a metric
+fn stream_identity(prefix: &[u8], metric: &Metric, kind: u8, attributes: &[KeyValue]) -> Vec<u8> {
+    let mut identity = prefix.to_vec();
+    push_str(&mut identity, &metric.name);
+    push_str(&mut identity, &metric.unit);
+    identity.push(kind);
+    push_attributes(&mut identity, attributes);
+    identity
+}
+
+fn no_recorded_value(flags: u32) -> bool {
+    flags & DataPointFlags::NoRecordedValueMask as u32 != 0
+}
+
+/// The last point of a stream and when it was seen
+struct Stream<P> {
+    point: P,
+    last_seen: Instant,
+}
+
+/// The streams tracked by a converter, one map per kind of data point
+struct Streams<P> {
+    entries: HashMap<Vec<u8>, Stream<P>>,
+}
+
+impl<P> Default for Streams<P> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<P> Streams<P> {
+    fn expire(&mut self, now: Instant, max_idle: Duration) {
+        self.entries
+            .retain(|_, s| now.saturating_duration_since(s.last_seen) <= max_idle);
+    }
+}
+
+/// The state of a converter
+struct State<N, H, E> {
+    numbers: Streams<N>,
+    histograms: Streams<H>,
+    exponential: Streams<E>,
+    last_sweep: Option<Instant>,
+}
+
+impl<N, H, E> Default for State<N, H, E> {
+    fn default() -> Self {
+        Self {
+            numbers: Streams::default(),
+            histograms: Streams::default(),
+            exponential: Streams::default(),
+            last_sweep: None,
+        }
+    }
+}
+
+impl<N, H, E> State<N, H, E> {
+    fn len(&self) -> usize {
+        self.numbers.entries.len() + self.histograms.entries.len() + self.exponential.entries.len()
+    }
+
+    /// Forgets idle streams, sweeping at most once per second
+    fn expire(&mut self, now: Instant, max_idle: Duration) {
+        let due = self
+            .last_sweep
+            .is_none_or(|last| now.saturating_duration_since(last) >= Duration::from_secs(1));
+        if due {
+            self.numbers.expire(now, max_idle);
+            self.histograms.expire(now, max_idle);
+            self.exponential.expire(now, max_idle);
+            self.last_sweep = Some(now);
+        }
+    }
+}
+
+/// Locks the state of a converter, the state stays consistent even if another
+/// thread panicked while holding the lock
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// A cumulative data point that can be turned into the delta since an earlier point
+trait Cumulative: Clone {
+    fn start(&self) -> u64;
+    fn time(&self) -> u64;
+    fn flags(&self) -> u32;
+    fn set_start(&mut self, start: u64);
+    /// Subtracts an earlier point of the same stream, returning false if the
+    /// stream was reset in between
+    fn subtract(&mut self, earlier: &Self, monotonic: bool) -> bool;
+}
+
+impl Cumulative for NumberDataPoint {
+    fn start(&self) -> u64 {
+        self.start_time_unix_nano
+    }
+    fn time(&self) -> u64 {
+        self.time_unix_nano
+    }
+    fn flags(&self) -> u32 {
+        self.flags
+    }
+    fn set_start(&mut self, start: u64) {
+        self.start_time_unix_nano = start;
+    }
+    fn subtract(&mut self, earlier: &Self, monotonic: bool) -> bool {
+        use number_data_point::Value;
+        let delta = match (self.value, earlier.value) {
+            (Some(Value::AsInt(c)), Some(Value::AsInt(e))) if !monotonic || c >= e => {
+                Value::AsInt(c.saturating_sub(e))
+            }
+            (Some(Value::AsDouble(c)), Some(Value::AsDouble(e))) if !monotonic || c >= e => {
+                Value::AsDouble(c - e)
+            }
+            _ => return false,
+        };
+        self.value = Some(delta);
+        true
+    }
+}
+
+impl Cumulative for HistogramDataPoint {
+    fn start(&self) -> u64 {
+        self.start_time_unix_nano
+    }
+    fn time(&self) -> u64 {
+        self.time_unix_nano
+    }
+    fn flags(&self) -> u32 {
+        self.flags
+    }
+    fn set_start(&mut self, start: u64) {
+        self.start_time_unix_nano = start;
+    }
+    fn subtract(&mut self, earlier: &Self, _monotonic: bool) -> bool {
+        let comparable = self.explicit_bounds == earlier.explicit_bounds
+            && self.bucket_counts.len() == earlier.bucket_counts.len()
+            && self.count >= earlier.count
+            && self
+                .bucket_counts
+                .iter()
+                .zip(&earlier.bucket_counts)
+                .all(|(c, e)| c >= e);
+        if !comparable {
+            return false;
+        }
+        self.count -= earlier.count;
+        for (c, e) in self.bucket_counts.iter_mut().zip(&earlier.bucket_counts) {
+            *c -= e;
+        }
+        self.sum = self.sum.zip(earlier.sum).map(|(c, e)| c - e);
+        self.min = None;
+        self.max = None;
+        true
+    }
+}
+
+/// Subtracts the bucket counts of an earlier point, aligning both on their
+/// offsets. Returns `None` if a count decreased.
+fn subtract_buckets(current: Option<&Buckets>, earlier: Option<&Buckets>) -> Option<Buckets> {
+    let earlier_total: u64 = earlier.map_or(0, |e| e.bucket_counts.iter().sum());
+    let Some(current) = current else {
+        return (earlier_total == 0).then(Buckets::default);
+    };
+    let mut covered = 0;
+    let mut bucket_counts = Vec::with_capacity(current.bucket_counts.len());
+    for (i, count) in current.bucket_counts.iter().enumerate() {
+        let index = i64::from(current.offset) + i as i64;
+        let before = earlier
+            .and_then(|e| {
+                usize::try_from(index - i64::from(e.offset))
+                    .ok()
+                    .and_then(|j| e.bucket_counts.get(j))
+            })
+            .copied()
+            .unwrap_or(0);
+        covered += before;
+        bucket_counts.push(count.checked_sub(before)?);
+    }
+    (covered == earlier_total).then_some(Buckets {
+        offset: current.offset,
+        bucket_counts,
+    })
+}
+
+impl Cumulative for ExponentialHistogramDataPoint {
+    fn start(&self) -> u64 {
+        self.start_time_unix_nano
+    }
+    fn time(&self) -> u64 {
+        self.time_unix_nano
+    }
+    fn flags(&self) -> u32 {
+        self.flags
+    }
+    fn set_start(&mut self, start: u64) {
+        self.start_time_unix_nano = start;
+    }
+    fn subtract(&mut self, earlier: &Self, _monotonic: bool) -> bool {
+        #[allow(clippy::float_cmp)] // the threshold is configured, not computed
+        let comparable = self.scale == earlier.scale
+            && self.zero_threshold == earlier.zero_threshold
+            && self.count >= earlier.count
+            && self.zero_count >= earlier.zero_count;
+        if !comparable {
+            return false;
+        }
+        let positive = subtract_buckets(self.positive.as_ref(), earlier.positive.as_ref());
+        let negative = subtract_buckets(self.negative.as_ref(), earlier.negative.as_ref());
+        let (Some(positive), Some(negative)) = (positive, negative) else {
+            return false;
+        };
+        self.count -= earlier.count;
+        self.zero_count -= earlier.zero_count;
+        self.positive = Some(positive);
+        self.negative = Some(negative);
+        self.sum = self.sum.zip(earlier.sum).map(|(c, e)| c - e);
+        self.min = None;
+        self.max = None;
+        true
+    }
+}
+
+/// Turns a cumulative point into a delta point, returning `None` for points
+/// that carry no new information
+fn to_delta<P: Cumulative>(
+    streams: &mut Streams<P>,
+    identity: Vec<u8>,
+    mut point: P,
+    monotonic: bool,
+    now: Instant,
+) -> Option<P> {
+    let current = point.clone();
+    let Some(stream) = streams.entries.get_mut(&identity) else {
+        // a cumulative point with a known start is the delta since that start
+        let known_start = point.start() != 0;
+        if !no_recorded_value(point.flags()) {
+            streams.entries.insert(
+                identity,
+                Stream {
+                    point: current,
+                    last_seen: now,
+                },
+            );
+        }
+        return known_start.then_some(point);
+    };
+    stream.last_seen = now;
+    let earlier = &stream.point;
+    if point.time() <= earlier.time() {
+        // duplicate or out of order
+        return None;
+    }
+    if no_recorded_value(point.flags()) {
+        point.set_start(earlier.time());
+        return Some(point);
+    }
+    let restarted = point.start() != 0 && earlier.start() != 0 && point.start() != earlier.start();
+    let emitted = if !restarted && point.subtract(earlier, monotonic) {
+        point.set_start(earlier.time());
+        Some(point)
+    } else {
+        (point.start() != 0).then_some(point)
+    };
+    stream.point = current;
+    emitted
+}
+
+/// Converts cumulative sums, histograms and exponential histograms into delta ones.
+///
+/// The first point of a stream is forwarded as the delta since its start time,
+/// or dropped when the start time is unknown. Later points are forwarded as the
+/// difference to the previous point. A changed start time, a decreasing
+/// monotonic value or changed bucket boundaries are treated as a reset of the
+/// stream, after which the point is forwarded as the delta since its new start.
+/// Duplicate and out of order points are dropped. Gauges, summaries and points
+/// that already are deltas pass through unchanged.
+pub struct CumulativeToDelta {
+    max_idle: Duration,
+    state: Mutex<State<NumberDataPoint, HistogramDataPoint, ExponentialHistogramDataPoint>>,
+}
+
+impl Default for CumulativeToDelta {
+    fn default() -> Self {
+        Self {
+            max_idle: DEFAULT_MAX_IDLE,
+            state: Mutex::new(State::default()),
+        }
+    }
+}
+
+impl CumulativeToDelta {
+    /// Creates a converter forgetting streams idle for [`DEFAULT_MAX_IDLE`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the time after which a stream that received no points is forgotten
+    pub fn with_max_idle(mut self, max_idle: Duration) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// The number of streams currently tracked
+    pub fn stream_count(&self) -> usize {
+        lock(&self.state).len()
+    }
+
+    /// Converts the cumulative streams of a request in place. Metrics left
+    /// without data points are removed.
+    ///
+    /// Returns the number of data points that were dropped.
+    pub fn convert(&self, request: &mut ExportMetricsServiceRequest, now: Instant) -> usize {
+        let cumulative = AggregationTemporality::Cumulative as i32;
+        let mut state = lock(&self.state);
+        state.expire(now, self.max_idle);
+        let mut dropped = 0;
+        for rm in &mut request.resource_metrics {
+            for sm in &mut rm.scope_metrics {
+                let prefix =
+                    scope_identity(rm.resource.as_ref(), &rm.schema_url, sm.scope.as_ref());
+                for metric in &mut sm.metrics {
+                    let shell = Metric {
+                        data: None,
+                        ..metric.clone()
+                    };
+                    macro_rules! convert_points {
+                        ($inner:expr, $streams:expr, $kind:expr, $monotonic:expr) => {{
+                            let inner = $inner;
+                            if inner.aggregation_temporality == cumulative {
+                                let points = std::mem::take(&mut inner.data_points);
+                                let total = points.len();
+                                inner.data_points = points
+                                    .into_iter()
+                                    .filter_map(|p| {
+                                        let id =
+                                            stream_identity(&prefix, &shell, $kind, &p.attributes);
+                                        to_delta($streams, id, p, $monotonic, now)
+                                    })
+                                    .collect();
+                                dropped += total - inner.data_points.len();
+                                inner.aggregation_temporality =
+                                    AggregationTemporality::Delta as i32;
+                            }
+                        }};
+                    }
+                    match &mut metric.data {
+                        Some(Data::Sum(s)) => {
+                            let monotonic = s.is_monotonic;
+                            convert_points!(s, &mut state.numbers, 1, monotonic);
+                        }
+                        Some(Data::Histogram(h)) => {
+                            convert_points!(h, &mut state.histograms, 2, true);
+                        }
+                        Some(Data::ExponentialHistogram(h)) => {
+                            convert_points!(h, &mut state.exponential, 3, true);
+                        }
+                        Some(Data::Gauge(_) | Data::Summary(_)) | None => (),
+                    }
+                }
+            }
+        }
+        drop(state);
+        request.retain_metrics(|_, _, metric| super::iter::data_point_count(metric) > 0);
+        dropped
+    }
+}
+
+impl Processor<ExportMetricsServiceRequest> for CumulativeToDelta {
+    fn process(
+        &self,
+        request: &mut ExportMetricsServiceRequest,
+    ) -> Result<Disposition, tonic::Status> {
+        self.convert(request, Instant::now());
+        Ok(Disposition::accepted())
+    }
+}
+
+/// A delta data point that can be accumulated into a cumulative one
+trait Accumulate: Cumulative {
+    /// Adds a later point of the same stream, returning false if the points
+    /// are incompatible
+    fn add(&mut self, later: &Self) -> bool;
+    /// Turns the point into a staleness marker at the given time
+    fn mark_stale(&mut self, time: u64);
+    /// Appends the point to a metric of the matching kind
+    fn push_into(self, metric: &mut Metric);
+}
+
+impl Accumulate for NumberDataPoint {
+    fn add(&mut self, later: &Self) -> bool {
+        use number_data_point::Value;
+        self.value = match (self.value, later.value) {
+            (Some(Value::AsInt(a)), Some(Value::AsInt(b))) => {
+                Some(Value::AsInt(a.saturating_add(b)))
+            }
+            (Some(Value::AsDouble(a)), Some(Value::AsDouble(b))) => Some(Value::AsDouble(a + b)),
+            _ => return false,
+        };
+        self.time_unix_nano = later.time_unix_nano;
+        self.exemplars.clone_from(&later.exemplars);
+        true
+    }
+    fn mark_stale(&mut self, time: u64) {
+        self.time_unix_nano = time;
+        self.flags |= DataPointFlags::NoRecordedValueMask as u32;
+        self.value = None;
+        self.exemplars.clear();
+    }
+    fn push_into(self, metric: &mut Metric) {
+        if let Some(Data::Sum(s)) = &mut metric.data {
+            s.data_points.push(self);
+        }
+    }
+}
+
+impl Accumulate for HistogramDataPoint {
+    fn add(&mut self, later: &Self) -> bool {
+        if self.explicit_bounds != later.explicit_bounds
+            || self.bucket_counts.len() != later.bucket_counts.len()
+        {
+            return false;
+        }
+        self.count += later.count;
+        for (a, b) in self.bucket_counts.iter_mut().zip(&later.bucket_counts) {
+            *a += b;
+        }
+        self.sum = self.sum.zip(later.sum).map(|(a, b)| a + b);
+        self.min = self.min.zip(later.min).map(|(a, b)| a.min(b));
+        self.max = self.max.zip(later.max).map(|(a, b)| a.max(b));
+        self.time_unix_nano = later.time_unix_nano;
+        self.exemplars.clone_from(&later.exemplars);
+        true
+    }
+    fn mark_stale(&mut self, time: u64) {
+        self.time_unix_nano = time;
+        self.flags |= DataPointFlags::NoRecordedValueMask as u32;
+        self.count = 0;
+        self.sum = None;
+        self.bucket_counts.clear();
+        self.explicit_bounds.clear();
+        self.min = None;
+        self.max = None;
+        self.exemplars.clear();
+    }
+    fn push_into(self, metric: &mut Metric) {
+        if let Some(Data::Histogram(h)) = &mut metric.data {
+            h.data_points.push(self);
+        }
+    }
+}
+
+impl Accumulate for ExponentialHistogramDataPoint {
+    fn add(&mut self, later: &Self) -> bool {
+        #[allow(clippy::float_cmp)] // the threshold is configured, not computed
+        let compatible = self.scale == later.scale && self.zero_threshold == later.zero_threshold;
+        if !compatible {
+            return false;
+        }
+        self.count += later.count;
+        self.zero_count += later.zero_count;
+        self.positive = add_buckets(self.positive.as_ref(), later.positive.as_ref());
+        self.negative = add_buckets(self.negative.as_ref(), later.negative.as_ref());
+        self.sum = self.sum.zip(later.sum).map(|(a, b)| a + b);
+        self.min = self.min.zip(later.min).map(|(a, b)| a.min(b));
+        self.max = self.max.zip(later.max).map(|(a, b)| a.max(b));
+        self.time_unix_nano = later.time_unix_nano;
+        self.exemplars.clone_from(&later.exemplars);
+        true
+    }
+    fn mark_stale(&mut self, time: u64) {
+        self.time_unix_nano = time;
+        self.flags |= DataPointFlags::NoRecordedValueMask as u32;
+        self.count = 0;
+        self.zero_count = 0;
+        self.sum = None;
+        self.positive = None;
+        self.negative = None;
+        self.min = None;
+        self.max = None;
+        self.exemplars.clear();
+    }
+    fn push_into(self, metric: &mut Metric) {
+        if let Some(Data::ExponentialHistogram(h)) = &mut metric.data {
+            h.data_points.push(self);
+        }
+    }
+}
+
+/// Where a stream was seen, to emit staleness markers for it
+struct Template {
+    resource: Option<Resource>,
+    resource_schema_url: String,
+    scope: Option<InstrumentationScope>,
+    scope_schema_url: String,
+    /// The metric of the stream without data points
+    metric: Metric,
+}
+
+/// The running total of a delta stream
+struct Accumulated<P> {
+    point: P,
+    template: Template,
+    last_seen: u64,
+}
+
+impl<P: Accumulate> Accumulated<P> {
+    fn into_marker(self, time: u64) -> ExportMetricsServiceRequest {
+        let Self {
+            mut point,
+            template,
+            ..
+        } = self;
+        let mut metric = template.metric;
+        point.mark_stale(time);
+        point.push_into(&mut metric);
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: template.resource,
+                scope_metrics: vec![ScopeMetrics {
+                    scope: template.scope,
+                    metrics: vec![metric],
+                    schema_url: template.scope_schema_url,
+                }],
+                schema_url: template.resource_schema_url,
+            }],
+        }
+    }
+}
+
+/// Removes the streams not seen for longer than `max_stale` and returns their markers
+fn expire_stale<P: Accumulate>(
+    streams: &mut HashMap<Vec<u8>, Accumulated<P>>,
+    now: u64,
+    max_stale: u64,
+) -> Vec<ExportMetricsServiceRequest> {
+    let stale: Vec<Vec<u8>> = streams
+        .iter()
+        .filter(|(_, s)| now.saturating_sub(s.last_seen) > max_stale)
+        .map(|(id, _)| id.clone())
+        .collect();
+    stale
+        .iter()
+        .filter_map(|id| streams.remove(id))
+        .map(|s| s.into_marker(now))
+        .collect()
+}
+
+/// Accumulates a delta point into its stream, returning the cumulative point
+/// to emit or `None` for points that are dropped
+fn to_cumulative<P, T>(
+    streams: &mut HashMap<Vec<u8>, Accumulated<P>>,
+    identity: Vec<u8>,
+    mut point: P,
+    now: u64,
+    template: T,
+) -> Option<P>
+where
+    P: Accumulate,
+    T: FnOnce() -> Template,
+{
+    let Some(stream) = streams.get_mut(&identity) else {
+        if no_recorded_value(point.flags()) {
+            return Some(point);
+        }
+        if point.start() == 0 {
+            point.set_start(point.time());
+        }
+        streams.insert(
+            identity,
+            Accumulated {
+                point: point.clone(),
+                template: template(),
+                last_seen: now,
+            },
+        );
+        return Some(point);
+    };
+    stream.last_seen = now;
+    let total = &mut stream.point;
+    let overlapping = point.start() != 0 && point.start() < total.time();
+    if point.time() <= total.time() || overlapping {
+        // duplicate, out of order or overlapping an interval already counted
+        return None;
+    }
+    if no_recorded_value(point.flags()) {
+        point.set_start(total.start());
+        return Some(point);
+    }
+    if !total.add(&point) {
+        // the stream changed its shape, start over from this point
+        if point.start() == 0 {
+            point.set_start(point.time());
+        }
+        *total = point;
+    }
+    Some(total.clone())
+}
+
+/// The state of a delta to cumulative converter
+#[derive(Default)]
+struct Totals {
+    numbers: HashMap<Vec<u8>, Accumulated<NumberDataPoint>>,
+    histograms: HashMap<Vec<u8>, Accumulated<HistogramDataPoint>>,
+    exponential: HashMap<Vec<u8>, Accumulated<ExponentialHistogramDataPoint>>,
+    last_sweep: u64,
+}
+
+/// Accumulates delta sums, histograms and exponential histograms into cumulative ones.
+///
+/// The cumulative points of a stream keep the start time of its first point.
+/// Gaps between delta points are bridged, while duplicate, out of order and
+/// overlapping points are dropped. When a stream received no points for the
+/// staleness timeout it is forgotten and a point flagged with
+/// `DATA_POINT_FLAGS_NO_RECORDED_VALUE_MASK` is added to the next converted
+/// request, so that downstream storage can mark the series as stale. Gauges,
+/// summaries and points that already are cumulative pass through unchanged.
+pub struct DeltaToCumulative {
+    max_stale: Duration,
+    totals: Mutex<Totals>,
+}
+
+impl Default for DeltaToCumulative {
+    fn default() -> Self {
+        Self {
+            max_stale: DEFAULT_MAX_IDLE,
+            totals: Mutex::new(Totals::default()),
+        }
+    }
+}
+
+impl DeltaToCumulative {
+    /// Creates a converter marking streams stale after [`DEFAULT_MAX_IDLE`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the time after which a stream that received no points is stale
+    pub fn with_max_stale(mut self, max_stale: Duration) -> Self {
+        self.max_stale = max_stale;
+        self
+    }
+
+    /// The number of streams currently tracked
+    pub fn stream_count(&self) -> usize {
+        let totals = lock(&self.totals);
+        totals.numbers.len() + totals.histograms.len() + totals.exponential.len()
+    }
+
+    /// Converts the delta streams of a request in place and adds staleness
+    /// markers for streams that went stale. Metrics left without data points
+    /// are removed.
+    ///
+    /// Returns the number of data points that were dropped.
+    pub fn convert(&self, request: &mut ExportMetricsServiceRequest, now: SystemTime) -> usize {
+        let delta = AggregationTemporality::Delta as i32;
+        let now = nanos(now);
+        let max_stale = u64::try_from(self.max_stale.as_nanos()).unwrap_or(u64::MAX);
+        let mut totals = lock(&self.totals);
+        let mut dropped = 0;
+        for rm in &mut request.resource_metrics {
+            for sm in &mut rm.scope_metrics {
+                let prefix =
+                    scope_identity(rm.resource.as_ref(), &rm.schema_url, sm.scope.as_ref());
+                for metric in &mut sm.metrics {
+                    let shell = Metric {
+                        data: None,
+                        ..metric.clone()
+                    };
+                    macro_rules! accumulate_points {
+                        ($variant:ident, $inner:expr, $streams:expr, $kind:expr) => {{
+                            let inner = $inner;
+                            if inner.aggregation_temporality == delta {
+                                let points = std::mem::take(&mut inner.data_points);
+                                let total = points.len();
+                                inner.aggregation_temporality =
+                                    AggregationTemporality::Cumulative as i32;
+                                let empty = inner.clone();
+                                let template = || Template {
+                                    resource: rm.resource.clone(),
+                                    resource_schema_url: rm.schema_url.clone(),
+                                    scope: sm.scope.clone(),
+                                    scope_schema_url: sm.schema_url.clone(),
+                                    metric: Metric {
+                                        data: Some(Data::$variant(empty.clone())),
+                                        ..shell.clone()
+                                    },
+                                };
+                                inner.data_points = points
+                                    .into_iter()
+                                    .filter_map(|p| {
+                                        let id =
+                                            stream_identity(&prefix, &shell, $kind, &p.attributes);
+                                        to_cumulative($streams, id, p, now, template)
+                                    })
+                                    .collect();
+                                dropped += total - inner.data_points.len();
+                            }
+                        }};
+                    }
+                    match &mut metric.data {
+                        Some(Data::Sum(s)) => {
+                            accumulate_points!(Sum, s, &mut totals.numbers, 1);
+                        }
+                        Some(Data::Histogram(h)) => {
+                            accumulate_points!(Histogram, h, &mut totals.histograms, 2);
+                        }
+                        Some(Data::ExponentialHistogram(h)) => {
+                            accumulate_points!(ExponentialHistogram, h, &mut totals.exponential, 3);
+                        }
+                        Some(Data::Gauge(_) | Data::Summary(_)) | None => (),
+                    }
+                }
+            }
+        }
+        let mut markers = Vec::new();
+        if now.saturating_sub(totals.last_sweep) >= 1_000_000_000 {
+            markers.extend(expire_stale(&mut totals.numbers, now, max_stale));
+            markers.extend(expire_stale(&mut totals.histograms, now, max_stale));
+            markers.extend(expire_stale(&mut totals.exponential, now, max_stale));
+            totals.last_sweep = now;
+        }
+        drop(totals);
+        request.retain_metrics(|_, _, metric| super::iter::data_point_count(metric) > 0);
+        for marker in markers {
+            request.merge_request(marker);
+        }
+        dropped
+    }
+}
+
+impl Processor<ExportMetricsServiceRequest> for DeltaToCumulative {
+    fn process(
+        &self,
+        request: &mut ExportMetricsServiceRequest,
+    ) -> Result<Disposition, tonic::Status> {
+        self.convert(request, SystemTime::now());
+        Ok(Disposition::accepted())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CumulativeToDelta, DeltaToCumulative};
+    use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+    use crate::opentelemetry::proto::common::v1::{any_value, AnyValue, KeyValue};
+    use crate::opentelemetry::proto::metrics::v1::{
+        exponential_histogram_data_point::Buckets, metric::Data, number_data_point,
+        ExponentialHistogram, ExponentialHistogramDataPoint, Metric, NumberDataPoint,
+        ResourceMetrics, ScopeMetrics, Sum,
+    };
+    use std::time::{Duration, Instant, SystemTime};
+
+    fn kv(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+        }
+    }
+
+    fn request(data: Data) -> ExportMetricsServiceRequest {
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: None,
+                scope_metrics: vec![ScopeMetrics {
+                    scope: None,
+                    metrics: vec![Metric {
+                        name: "requests".to_string(),
+                        data: Some(data),
+                        ..Metric::default()
+                    }],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    fn sum(points: &[(u64, u64, i64)], attributes: Vec<KeyValue>) -> ExportMetricsServiceRequest {
+        let data_points = points
+            .iter()
+            .map(|(start, time, value)| NumberDataPoint {
+                attributes: attributes.clone(),
+                start_time_unix_nano: *start,
+                time_unix_nano: *time,
+                value: Some(number_data_point::Value::AsInt(*value)),
+                ..NumberDataPoint::default()
+            })
+            .collect();
+        request(Data::Sum(Sum {
+            data_points,
+            aggregation_temporality: 2,
+            is_monotonic: true,
+        }))
+    }
+
+    fn with_temporality(
+        mut request: ExportMetricsServiceRequest,
+        temporality: i32,
+    ) -> ExportMetricsServiceRequest {
+        for (_, _, metric) in request.metrics_mut() {
+            if let Some(Data::Sum(s)) = &mut metric.data {
+                s.aggregation_temporality = temporality;
+            }
+        }
+        request
+    }
+
+    fn deltas(request: &ExportMetricsServiceRequest) -> Vec<(u64, u64, i64)> {
+        request
+            .metrics()
+            .filter_map(|(_, _, m)| match &m.data {
+                Some(Data::Sum(s)) if s.aggregation_temporality == 1 => Some(s),
+                _ => None,
+            })
+            .flat_map(|s| s.data_points.iter())
+            .filter_map(|p| match p.value {
+                Some(number_data_point::Value::AsInt(v)) => {
+                    Some((p.start_time_unix_nano, p.time_unix_nano, v))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn sums_become_deltas() {
+        let converter = CumulativeToDelta::new();
+        let now = Instant::now();
+        let attributes = vec![kv("a", "1"), kv("b", "2")];
+        let mut first = sum(&[(10, 20, 5), (10, 30, 8)], attributes.clone());
+        assert_eq!(converter.convert(&mut first, now), 0);
+        assert_eq!(deltas(&first), vec![(10, 20, 5), (20, 30, 3)]);
+
+        // same stream with attributes in another order, a duplicate and a reset
+        let reordered = vec![kv("b", "2"), kv("a", "1")];
+        let mut second = sum(&[(10, 30, 8), (10, 40, 12), (45, 50, 2)], reordered);
+        assert_eq!(converter.convert(&mut second, now), 1);
+        assert_eq!(deltas(&second), vec![(30, 40, 4), (45, 50, 2)]);
+        assert_eq!(converter.stream_count(), 1);
+
+        // unknown start times drop the first point of a stream
+        let mut unknown = sum(&[(0, 20, 5), (0, 30, 8)], vec![kv("c", "3")]);
+        assert_eq!(converter.convert(&mut unknown, now), 1);
+        assert_eq!(deltas(&unknown), vec![(20, 30, 3)]);
+
+        let mut later = sum(&[(10, 60, 14)], attributes);
+        converter.convert(&mut later, now + Duration::from_secs(600));
+        assert_eq!(converter.stream_count(), 1);
+        assert_eq!(deltas(&later), vec![(10, 60, 14)]);
+    }
+
+    #[test]
+    pub fn exponential_histograms_align_buckets() {
+        let point = |time: u64, offset: i32, bucket_counts: Vec<u64>| {
+            let count = bucket_counts.iter().sum();
+            ExponentialHistogramDataPoint {
+                start_time_unix_nano: 1,
+                time_unix_nano: time,
+                count,
+                positive: Some(Buckets {
+                    offset,
+                    bucket_counts,
+                }),
+                ..ExponentialHistogramDataPoint::default()
+            }
+        };
+        let mut req = request(Data::ExponentialHistogram(ExponentialHistogram {
+            data_points: vec![point(2, 3, vec![1, 2]), point(3, 2, vec![1, 1, 4, 1])],
+            aggregation_temporality: 2,
+        }));
+        CumulativeToDelta::new().convert(&mut req, Instant::now());
+        let converted: Vec<(u64, Option<Buckets>)> = req
+            .data_points()
+            .filter_map(|(_, _, _, p)| match p {
+                crate::metrics::DataPointRef::ExponentialHistogram(p) => {
+                    Some((p.count, p.positive.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            converted,
+            vec![
+                (
+                    3,
+                    Some(Buckets {
+                        offset: 3,
+                        bucket_counts: vec![1, 2]
+                    })
+                ),
+                (
+                    4,
+                    Some(Buckets {
+                        offset: 2,
+                        bucket_counts: vec![1, 0, 2, 1]
+                    })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn deltas_accumulate() {
+        let converter = DeltaToCumulative::new().with_max_stale(Duration::from_secs(60));
+        let now = SystemTime::now();
+        let cumulative = |request: &ExportMetricsServiceRequest| {
+            request
+                .data_points()
+                .filter_map(|(_, _, _, p)| match p {
+                    crate::metrics::DataPointRef::Sum(p) => {
+                        Some((p.start_time_unix_nano, p.time_unix_nano, p.value, p.flags))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let int = |v| Some(number_data_point::Value::AsInt(v));
+        // a gap between 20 and 25 is bridged, the overlapping point is dropped
+        let mut first = with_temporality(sum(&[(10, 20, 5), (25, 30, 3), (22, 40, 1)], vec![]), 1);
+        assert_eq!(converter.convert(&mut first, now), 1);
+        assert_eq!(
+            cumulative(&first),
+            vec![(10, 20, int(5), 0), (10, 30, int(8), 0)]
+        );
+        let mut late = with_temporality(sum(&[(10, 20, 5)], vec![]), 1);
+        assert_eq!(converter.convert(&mut late, now), 1);
+        assert!(late.resource_metrics.is_empty());
+
+        let mut other = with_temporality(sum(&[(10, 20, 1)], vec![kv("a", "1")]), 1);
+        converter.convert(&mut other, now + Duration::from_secs(120));
+        assert_eq!(converter.stream_count(), 1);
+        let markers: Vec<_> = cumulative(&other)
+            .into_iter()
+            .filter(|(_, _, value, flags)| value.is_none() && *flags == 1)
+            .collect();
+        assert_eq!(markers.len(), 1);
+    }
+}
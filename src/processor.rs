@@ -0,0 +1,265 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Processors mutate, filter or reject the items of an export request after it
+//! was received and before it is forwarded.
+//!
+//! A processor is written against one of the export request types, so a
+//! `Processor<ExportLogsServiceRequest>` is a logs processor. Synchronous
+//! processors implement [`Processor`], processors that need to await implement
+//! [`AsyncProcessor`]. Every synchronous processor is also an asynchronous one,
+//! so both kinds can be composed into a single [`ProcessorChain`].
+
+/// The outcome of processing an export request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Disposition {
+    /// The number of items rejected by the processor, reported to the client in
+    /// the `partial_success` of the export response
+    pub rejected: i64,
+    /// Possibly empty reason for the rejection
+    pub error_message: String,
+}
+
+impl Disposition {
+    /// All items were accepted
+    pub fn accepted() -> Self {
+        Self::default()
+    }
+
+    /// Some items were rejected for the given reason
+    pub fn rejected(rejected: i64, error_message: impl Into<String>) -> Self {
+        Self {
+            rejected,
+            error_message: error_message.into(),
+        }
+    }
+
+    /// Folds the disposition of another processor into this one
+    pub fn merge(&mut self, other: Disposition) {
+        self.rejected += other.rejected;
+        if !other.error_message.is_empty() {
+            if !self.error_message.is_empty() {
+                self.error_message.push_str("; ");
+            }
+            self.error_message.push_str(&other.error_message);
+        }
+    }
+}
+
+/// Reports the items rejected by a processor chain to the client as the partial
+/// success of an export response, naming the field of the rejected count.
+/// Accepted requests are reported as `Ok`.
+macro_rules! partial_success_from_disposition {
+    ($partial_success:ty, $rejected:ident) => {
+        impl From<$crate::processor::Disposition> for $partial_success {
+            fn from(disposition: $crate::processor::Disposition) -> Self {
+                let error_message =
+                    if disposition.rejected == 0 && disposition.error_message.is_empty() {
+                        "Ok".to_string()
+                    } else {
+                        disposition.error_message
+                    };
+                Self {
+                    $rejected: disposition.rejected,
+                    error_message,
+                }
+            }
+        }
+    };
+}
+pub(crate) use partial_success_from_disposition;
+
+/// A synchronous processor of export requests of type `T`
+pub trait Processor<T>: Send + Sync {
+    /// Processes an export request in place. Returning an error rejects the
+    /// request as a whole and the error is returned to the client.
+    #[allow(clippy::result_large_err)] // tonic::Status is what the service handlers return
+    fn process(&self, request: &mut T) -> Result<Disposition, tonic::Status>;
+}
+
+/// An asynchronous processor of export requests of type `T`
+#[tonic::async_trait]
+pub trait AsyncProcessor<T: Send>: Send + Sync {
+    /// Processes an export request in place. Returning an error rejects the
+    /// request as a whole and the error is returned to the client.
+    async fn process(&self, request: &mut T) -> Result<Disposition, tonic::Status>;
+}
+
+#[tonic::async_trait]
+impl<T, P> AsyncProcessor<T> for P
+where
+    T: Send,
+    P: Processor<T>,
+{
+    async fn process(&self, request: &mut T) -> Result<Disposition, tonic::Status> {
+        Processor::process(self, request)
+    }
+}
+
+/// An ordered chain of processors for export requests of type `T`
+pub struct ProcessorChain<T> {
+    processors: Vec<Box<dyn AsyncProcessor<T>>>,
+}
+
+impl<T> Default for ProcessorChain<T> {
+    fn default() -> Self {
+        Self {
+            processors: Vec::new(),
+        }
+    }
+}
+
+impl<T: Send> ProcessorChain<T> {
+    /// Creates an empty processor chain
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a synchronous or asynchronous processor to the chain
+    pub fn with_processor<P>(mut self, processor: P) -> Self
+    where
+        P: AsyncProcessor<T> + 'static,
+    {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    /// Checks if the chain has no processors
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+
+    /// Runs all processors in order, stopping at the first one that rejects the
+    /// request as a whole
+    pub async fn process(&self, request: &mut T) -> Result<Disposition, tonic::Status> {
+        let mut disposition = Disposition::accepted();
+        for processor in &self.processors {
+            disposition.merge(processor.process(request).await?);
+        }
+        Ok(disposition)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Disposition, Processor, ProcessorChain};
+    use crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest;
+    use crate::opentelemetry::proto::trace::v1::{ResourceSpans, ScopeSpans, Span};
+
+    struct DropUnnamed;
+
+    impl Processor<ExportTraceServiceRequest> for DropUnnamed {
+        fn process(
+            &self,
+            request: &mut ExportTraceServiceRequest,
+        ) -> Result<Disposition, tonic::Status> {
+            let dropped = request.retain_spans(|_, _, span| !span.name.is_empty());
+            Ok(Disposition::rejected(dropped as i64, "unnamed span"))
+        }
+    }
+
+    struct Rename;
+
+    impl Processor<ExportTraceServiceRequest> for Rename {
+        fn process(
+            &self,
+            request: &mut ExportTraceServiceRequest,
+        ) -> Result<Disposition, tonic::Status> {
+            for (_, _, span) in request.spans_mut() {
+                span.name = span.name.to_uppercase();
+            }
+            Ok(Disposition::accepted())
+        }
+    }
+
+    #[tokio::test]
+    async fn chain_runs_in_order() {
+        let chain = ProcessorChain::new()
+            .with_processor(DropUnnamed)
+            .with_processor(Rename);
+        let mut request = ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: None,
+                scope_spans: vec![ScopeSpans {
+                    scope: None,
+                    spans: vec![
+                        Span::default(),
+                        Span {
+                            name: "snot".to_string(),
+                            ..Span::default()
+                        },
+                    ],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        };
+        let disposition = chain.process(&mut request).await.ok();
+        assert_eq!(disposition, Some(Disposition::rejected(1, "unnamed span")));
+        let names: Vec<&str> = request.spans().map(|(_, _, s)| s.name.as_str()).collect();
+        assert_eq!(names, vec!["SNOT"]);
+    }
+
+    #[cfg(all(feature = "otel-all", feature = "channels"))]
+    #[tokio::test]
+    async fn forwarder_reports_partial_success() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::all::TraceServiceForwarder;
+        use crate::opentelemetry::proto::collector::trace::v1::trace_service_server::TraceService;
+        use crate::trace::TraceProcessorChain;
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let forwarder = TraceServiceForwarder::with_sender(tx)
+            .with_processors(TraceProcessorChain::new().with_processor(DropUnnamed));
+        let request = |names: &[&str]| ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: None,
+                scope_spans: vec![ScopeSpans {
+                    scope: None,
+                    spans: names
+                        .iter()
+                        .map(|name| Span {
+                            name: name.to_string(),
+                            ..Span::default()
+                        })
+                        .collect(),
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        };
+
+        let response = forwarder
+            .export(tonic::Request::new(request(&["", "snot", ""])))
+            .await?
+            .into_inner();
+        let partial_success = response.partial_success.ok_or("no partial success")?;
+        assert_eq!(partial_success.rejected_spans, 2);
+        assert_eq!(partial_success.error_message, "unnamed span");
+        assert!(rx.recv().await.is_some());
+
+        // nothing rejected is reported as ok
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let forwarder = TraceServiceForwarder::with_sender(tx)
+            .with_processors(TraceProcessorChain::new().with_processor(Rename));
+        let response = forwarder
+            .export(tonic::Request::new(request(&["snot"])))
+            .await?
+            .into_inner();
+        let partial_success = response.partial_success.ok_or("no partial success")?;
+        assert_eq!(partial_success.rejected_spans, 0);
+        assert_eq!(partial_success.error_message, "Ok");
+        assert!(rx.recv().await.is_some());
+        Ok(())
+    }
+}
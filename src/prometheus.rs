@@ -0,0 +1,378 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bridges between OTLP metrics and Prometheus.
+//!
+//! Names and labels follow the OpenTelemetry to Prometheus compatibility
+//! specification. Metric names get their unit appended as a suffix, monotonic
+//! sums are suffixed with `_total`, invalid characters are replaced with
+//! underscores and the `service.name`, `service.namespace` and
+//! `service.instance.id` resource attributes become the `job` and `instance`
+//! labels. The remaining resource attributes are exposed through `target_info`.
+
+use crate::common::any_value_to_string;
+use crate::opentelemetry::proto::common::v1::KeyValue;
+use crate::opentelemetry::proto::metrics::v1::{
+    exemplar, metric::Data, AggregationTemporality, DataPointFlags, Exemplar, Metric,
+};
+use crate::opentelemetry::proto::resource::v1::Resource;
+use std::collections::BTreeMap;
+
+mod parse;
+mod remote_write;
+#[cfg(feature = "prometheus-scrape")]
+mod scrape;
+mod text;
+
+pub use parse::{from_text, TextParseError};
+
+pub use remote_write::{
+    decode_write_request, encode_write_request, from_write_request, to_write_request,
+    RemoteWriteError,
+};
+#[cfg(feature = "prometheus-scrape")]
+pub use scrape::{ScrapeError, Scraper, DEFAULT_SCRAPE_INTERVAL, DEFAULT_SCRAPE_TIMEOUT};
+pub use text::{to_text, TextFormat};
+
+/// The Prometheus remote-write protocol buffers
+#[allow(clippy::all, clippy::pedantic, missing_docs)]
+pub mod prompb {
+    tonic::include_proto!("prometheus");
+}
+
+/// The resource attribute naming the service
+pub const SERVICE_NAME: &str = "service.name";
+/// The resource attribute naming the namespace of the service
+pub const SERVICE_NAMESPACE: &str = "service.namespace";
+/// The resource attribute identifying the instance of the service
+pub const SERVICE_INSTANCE_ID: &str = "service.instance.id";
+/// The name of the metric carrying the remaining resource attributes
+pub const TARGET_INFO: &str = "target_info";
+/// The label carrying the name of the instrumentation scope
+pub const SCOPE_NAME_LABEL: &str = "otel_scope_name";
+/// The label carrying the version of the instrumentation scope
+pub const SCOPE_VERSION_LABEL: &str = "otel_scope_version";
+
+/// Replaces the characters that are not valid in a metric or label name with
+/// underscores and collapses runs of underscores
+fn sanitize(name: &str, allow_colons: bool) -> String {
+    let mut sanitized = String::with_capacity(name.len());
+    for c in name.chars() {
+        let valid = c.is_ascii_alphanumeric() || c == '_' || (allow_colons && c == ':');
+        let c = if valid { c } else { '_' };
+        if !(c == '_' && sanitized.ends_with('_')) {
+            sanitized.push(c);
+        }
+    }
+    sanitized
+}
+
+/// Sanitizes a metric name, names starting with a digit are prefixed with an
+/// underscore
+pub fn sanitize_metric_name(name: &str) -> String {
+    let sanitized = sanitize(name, true);
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{sanitized}")
+    } else {
+        sanitized
+    }
+}
+
+/// Sanitizes a label name, names starting with a digit are prefixed with `key_`
+pub fn sanitize_label_name(name: &str) -> String {
+    let sanitized = sanitize(name, false);
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("key_{sanitized}")
+    } else if sanitized.is_empty() {
+        "_".to_string()
+    } else {
+        sanitized
+    }
+}
+
+/// The Prometheus name of a UCUM unit
+fn unit_name(unit: &str) -> &str {
+    match unit {
+        "d" => "days",
+        "h" => "hours",
+        "min" => "minutes",
+        "s" => "seconds",
+        "ms" => "milliseconds",
+        "us" => "microseconds",
+        "ns" => "nanoseconds",
+        "By" => "bytes",
+        "KiBy" => "kibibytes",
+        "MiBy" => "mebibytes",
+        "GiBy" => "gibibytes",
+        "TiBy" => "tebibytes",
+        "KBy" => "kilobytes",
+        "MBy" => "megabytes",
+        "GBy" => "gigabytes",
+        "TBy" => "terabytes",
+        "m" => "meters",
+        "V" => "volts",
+        "A" => "amperes",
+        "J" => "joules",
+        "W" => "watts",
+        "g" => "grams",
+        "Cel" => "celsius",
+        "Hz" => "hertz",
+        "%" => "percent",
+        other => other,
+    }
+}
+
+/// The Prometheus name of the denominator of a UCUM unit
+fn per_unit_name(unit: &str) -> &str {
+    match unit {
+        "s" => "second",
+        "m" => "minute",
+        "h" => "hour",
+        "d" => "day",
+        "w" => "week",
+        "mo" => "month",
+        "y" => "year",
+        other => other,
+    }
+}
+
+/// The name suffix for a UCUM unit, annotations in curly braces are dropped
+pub fn unit_suffix(unit: &str, is_gauge: bool) -> String {
+    let unit = unit.split_once('{').map_or(unit, |(unit, _)| unit).trim();
+    if unit == "1" {
+        return if is_gauge {
+            "ratio".to_string()
+        } else {
+            String::new()
+        };
+    }
+    let (main, per) = match unit.split_once('/') {
+        Some((main, per)) => (main.trim(), Some(per.trim())),
+        None => (unit, None),
+    };
+    let mut suffix = sanitize_label_name(unit_name(main));
+    if main.is_empty() {
+        suffix.clear();
+    }
+    if let Some(per) = per.filter(|p| !p.is_empty()) {
+        if !suffix.is_empty() {
+            suffix.push('_');
+        }
+        suffix.push_str("per_");
+        suffix.push_str(&sanitize_label_name(per_unit_name(per)));
+    }
+    suffix.trim_matches('_').to_string()
+}
+
+/// Checks if a metric is a monotonic cumulative sum, a Prometheus counter
+pub fn is_counter(metric: &Metric) -> bool {
+    matches!(&metric.data, Some(Data::Sum(s))
+        if s.is_monotonic
+            && s.aggregation_temporality == AggregationTemporality::Cumulative as i32)
+}
+
+/// The Prometheus name of a metric, with its unit suffix and `_total` for counters
+pub fn metric_name(metric: &Metric) -> String {
+    let is_gauge = matches!(&metric.data, Some(Data::Gauge(_)));
+    let counter = is_counter(metric);
+    let mut name = sanitize_metric_name(&metric.name);
+    if counter {
+        if let Some(stripped) = name.strip_suffix("_total") {
+            name = stripped.to_string();
+        }
+    }
+    let suffix = unit_suffix(&metric.unit, is_gauge);
+    if !suffix.is_empty() && !name.ends_with(&format!("_{suffix}")) && name != suffix {
+        name.push('_');
+        name.push_str(&suffix);
+    }
+    if counter {
+        name.push_str("_total");
+    }
+    name
+}
+
+/// Checks if an aggregation temporality is cumulative
+pub(crate) fn is_cumulative(temporality: i32) -> bool {
+    temporality == AggregationTemporality::Cumulative as i32
+}
+
+/// Checks if a data point carries a value
+pub(crate) fn has_value(flags: u32) -> bool {
+    flags & DataPointFlags::NoRecordedValueMask as u32 == 0
+}
+
+/// The value of an exemplar as a float
+pub(crate) fn exemplar_value(exemplar: &Exemplar) -> Option<f64> {
+    match exemplar.value? {
+        exemplar::Value::AsDouble(d) => Some(d),
+        // exemplar values beyond 2^53 lose precision
+        #[allow(clippy::cast_precision_loss)]
+        exemplar::Value::AsInt(i) => Some(i as f64),
+    }
+}
+
+/// The value of a resource attribute, if it is not empty
+fn resource_attribute(resource: &Resource, key: &str) -> Option<String> {
+    resource
+        .attributes
+        .iter()
+        .find(|kv| kv.key == key)
+        .and_then(|kv| kv.value.as_ref())
+        .map(any_value_to_string)
+        .filter(|v| !v.is_empty())
+}
+
+/// The `job` and `instance` labels of a resource
+pub fn job_and_instance(resource: &Resource) -> (Option<String>, Option<String>) {
+    let job = match (
+        resource_attribute(resource, SERVICE_NAMESPACE),
+        resource_attribute(resource, SERVICE_NAME),
+    ) {
+        (Some(namespace), Some(name)) => Some(format!("{namespace}/{name}")),
+        (None, name) => name,
+        (Some(_), None) => None,
+    };
+    (job, resource_attribute(resource, SERVICE_INSTANCE_ID))
+}
+
+/// Sanitized and sorted labels. Attributes whose names collide after
+/// sanitization have their values joined with `;`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Labels(BTreeMap<String, String>);
+
+impl Labels {
+    /// Adds a label, joining the value with an existing one of the same name
+    pub fn add(&mut self, name: &str, value: String) {
+        let name = sanitize_label_name(name);
+        match self.0.get_mut(&name) {
+            Some(existing) if *existing != value => {
+                existing.push(';');
+                existing.push_str(&value);
+            }
+            Some(_) => (),
+            None => {
+                self.0.insert(name, value);
+            }
+        }
+    }
+
+    /// Sets a label, replacing any existing value
+    pub fn set(&mut self, name: &str, value: String) {
+        self.0.insert(sanitize_label_name(name), value);
+    }
+
+    /// Adds attributes as labels
+    pub fn add_attributes(&mut self, attributes: &[KeyValue]) {
+        for kv in attributes {
+            let value = kv
+                .value
+                .as_ref()
+                .map(any_value_to_string)
+                .unwrap_or_default();
+            self.add(&kv.key, value);
+        }
+    }
+
+    /// Iterates over the labels in the order of their names
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// The value of a label
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+}
+
+/// The labels identifying the target of a resource
+pub(crate) fn target_labels(resource: &Resource) -> Labels {
+    let mut labels = Labels::default();
+    let (job, instance) = job_and_instance(resource);
+    if let Some(job) = job {
+        labels.set("job", job);
+    }
+    if let Some(instance) = instance {
+        labels.set("instance", instance);
+    }
+    labels
+}
+
+/// The labels of the `target_info` series of a resource, `None` if the resource
+/// has no attributes besides those mapped onto `job` and `instance`
+pub(crate) fn target_info_labels(resource: &Resource) -> Option<Labels> {
+    let mut labels = target_labels(resource);
+    let mut any = false;
+    for kv in &resource.attributes {
+        if !matches!(
+            kv.key.as_str(),
+            SERVICE_NAME | SERVICE_NAMESPACE | SERVICE_INSTANCE_ID
+        ) {
+            labels.add(
+                &kv.key,
+                kv.value
+                    .as_ref()
+                    .map(any_value_to_string)
+                    .unwrap_or_default(),
+            );
+            any = true;
+        }
+    }
+    any.then_some(labels)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opentelemetry::proto::metrics::v1::{Gauge, Sum};
+
+    fn metric(name: &str, unit: &str, data: Data) -> Metric {
+        Metric {
+            name: name.to_string(),
+            unit: unit.to_string(),
+            data: Some(data),
+            ..Metric::default()
+        }
+    }
+
+    #[test]
+    pub fn metric_names() {
+        let counter = Data::Sum(Sum {
+            data_points: Vec::new(),
+            aggregation_temporality: AggregationTemporality::Cumulative as i32,
+            is_monotonic: true,
+        });
+        let gauge = Data::Gauge(Gauge {
+            data_points: Vec::new(),
+        });
+        assert_eq!(
+            metric_name(&metric("http.server.duration", "s", gauge.clone())),
+            "http_server_duration_seconds"
+        );
+        assert_eq!(
+            metric_name(&metric("requests_total", "{requests}", counter.clone())),
+            "requests_total"
+        );
+        assert_eq!(metric_name(&metric("io", "By", counter)), "io_bytes_total");
+        assert_eq!(
+            metric_name(&metric("cpu.utilization", "1", gauge.clone())),
+            "cpu_utilization_ratio"
+        );
+        assert_eq!(
+            metric_name(&metric("speed", "m/s", gauge)),
+            "speed_meters_per_second"
+        );
+        assert_eq!(sanitize_label_name("0.key-name"), "key_0_key_name");
+    }
+}
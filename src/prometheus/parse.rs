@@ -0,0 +1,457 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::prompb::{
+    metric_metadata::MetricType, Exemplar, Label, MetricMetadata, Sample, TimeSeries, WriteRequest,
+};
+use super::{from_write_request, TextFormat};
+use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An error parsing a text exposition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextParseError {
+    /// The line the error occurred on, starting at 1
+    pub line: usize,
+    /// What is wrong with the line
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for TextParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for TextParseError {}
+
+fn is_name_char(c: char, allow_colons: bool) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || (allow_colons && c == ':')
+}
+
+/// Splits a metric or label name off the start of a string
+fn parse_name(s: &str, allow_colons: bool) -> Result<(&str, &str), &'static str> {
+    let end = s
+        .find(|c: char| !is_name_char(c, allow_colons))
+        .unwrap_or(s.len());
+    let (name, rest) = s.split_at(end);
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err("invalid name");
+    }
+    Ok((name, rest))
+}
+
+fn skip_whitespace(s: &str) -> &str {
+    s.trim_start_matches([' ', '\t'])
+}
+
+/// Splits a quoted and escaped label value off the start of a string
+fn parse_quoted(s: &str) -> Result<(String, &str), &'static str> {
+    let s = s.strip_prefix('"').ok_or("expected a quoted label value")?;
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, s.get(i + 1..).unwrap_or_default())),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, c @ ('\\' | '"'))) => value.push(c),
+                _ => return Err("invalid escape sequence"),
+            },
+            c => value.push(c),
+        }
+    }
+    Err("unterminated label value")
+}
+
+/// Splits a label set in curly braces off the start of a string
+fn parse_labels(s: &str) -> Result<(Vec<Label>, &str), &'static str> {
+    let mut rest = s.strip_prefix('{').ok_or("expected labels")?;
+    let mut labels: Vec<Label> = Vec::new();
+    loop {
+        rest = skip_whitespace(rest);
+        if let Some(after) = rest.strip_prefix('}') {
+            return Ok((labels, after));
+        }
+        let (name, after) = parse_name(rest, false)?;
+        let after = skip_whitespace(after);
+        let after = skip_whitespace(after.strip_prefix('=').ok_or("expected `=`")?);
+        let (value, after) = parse_quoted(after)?;
+        if labels.iter().any(|l| l.name == name) {
+            return Err("duplicate label");
+        }
+        labels.push(Label {
+            name: name.to_string(),
+            value,
+        });
+        rest = skip_whitespace(after);
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after;
+        } else if !rest.starts_with('}') {
+            return Err("expected `,` or `}`");
+        }
+    }
+}
+
+fn parse_value(token: &str) -> Result<f64, &'static str> {
+    token.parse().map_err(|_| "invalid value")
+}
+
+/// Timestamps are milliseconds in the Prometheus format and seconds in OpenMetrics
+fn parse_timestamp(token: &str, format: TextFormat) -> Result<i64, &'static str> {
+    match format {
+        TextFormat::Prometheus => token.parse().map_err(|_| "invalid timestamp"),
+        TextFormat::OpenMetrics => {
+            let seconds: f64 = token.parse().map_err(|_| "invalid timestamp")?;
+            if !seconds.is_finite() {
+                return Err("invalid timestamp");
+            }
+            // saturates at the bounds of i64, far beyond any valid timestamp
+            #[allow(clippy::cast_possible_truncation)]
+            let millis = (seconds * 1000.0).round() as i64;
+            Ok(millis)
+        }
+    }
+}
+
+/// Parses the value and optional timestamp of a sample or exemplar
+fn parse_sample(s: &str, format: TextFormat, default: i64) -> Result<Sample, &'static str> {
+    let mut tokens = s.split_whitespace();
+    let value = parse_value(tokens.next().ok_or("missing value")?)?;
+    let timestamp = match tokens.next() {
+        Some(token) => parse_timestamp(token, format)?,
+        None => default,
+    };
+    if tokens.next().is_some() {
+        return Err("unexpected trailing content");
+    }
+    Ok(Sample { value, timestamp })
+}
+
+fn metric_type(name: &str) -> MetricType {
+    match name {
+        "counter" => MetricType::Counter,
+        "gauge" => MetricType::Gauge,
+        "histogram" => MetricType::Histogram,
+        "gaugehistogram" => MetricType::Gaugehistogram,
+        "summary" => MetricType::Summary,
+        "info" => MetricType::Info,
+        "stateset" => MetricType::Stateset,
+        _ => MetricType::Unknown,
+    }
+}
+
+/// Unescapes the text of a `HELP` line
+fn unescape_help(help: &str) -> String {
+    let mut unescaped = String::with_capacity(help.len());
+    let mut chars = help.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some(c @ ('\\' | '"'))) => {
+                unescaped.push(c);
+                chars.next();
+            }
+            (c, _) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Collects series by label set and the metadata of their families
+#[derive(Default)]
+struct Exposition {
+    series: Vec<TimeSeries>,
+    index: HashMap<Vec<(String, String)>, usize>,
+    metadata: Vec<MetricMetadata>,
+    families: HashMap<String, usize>,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str) -> Option<&mut MetricMetadata> {
+        let index = match self.families.get(name) {
+            Some(index) => *index,
+            None => {
+                self.metadata.push(MetricMetadata {
+                    r#type: MetricType::Unknown as i32,
+                    metric_family_name: name.to_string(),
+                    help: String::new(),
+                    unit: String::new(),
+                });
+                self.families
+                    .insert(name.to_string(), self.metadata.len() - 1);
+                self.metadata.len() - 1
+            }
+        };
+        self.metadata.get_mut(index)
+    }
+
+    fn comment(&mut self, comment: &str) -> Result<(), &'static str> {
+        let mut parts = skip_whitespace(comment).splitn(3, [' ', '\t']);
+        let (Some(keyword), Some(name)) = (parts.next(), parts.next()) else {
+            return Ok(());
+        };
+        let text = parts.next().unwrap_or_default();
+        match keyword {
+            "HELP" => {
+                parse_name(name, true)?;
+                if let Some(family) = self.family(name) {
+                    family.help = unescape_help(text);
+                }
+            }
+            "TYPE" => {
+                parse_name(name, true)?;
+                if let Some(family) = self.family(name) {
+                    family.r#type = metric_type(text.trim()) as i32;
+                }
+            }
+            "UNIT" => {
+                parse_name(name, true)?;
+                if let Some(family) = self.family(name) {
+                    family.unit = text.trim().to_string();
+                }
+            }
+            // anything else is a comment
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn sample(&mut self, line: &str, format: TextFormat, default: i64) -> Result<(), &'static str> {
+        let (name, rest) = parse_name(line, true)?;
+        let (mut labels, rest) = if rest.starts_with('{') {
+            parse_labels(rest)?
+        } else {
+            (Vec::new(), rest)
+        };
+        if !rest.starts_with([' ', '\t']) {
+            return Err("expected whitespace after the series");
+        }
+        let (sample, exemplar) = match rest.split_once('#') {
+            Some((sample, exemplar)) => (sample, Some(exemplar)),
+            None => (rest, None),
+        };
+        let sample = parse_sample(sample, format, default)?;
+        let exemplar = match exemplar {
+            Some(exemplar) => {
+                let (labels, rest) = parse_labels(skip_whitespace(exemplar))?;
+                let Sample { value, timestamp } = parse_sample(rest, format, sample.timestamp)?;
+                Some(Exemplar {
+                    labels,
+                    value,
+                    timestamp,
+                })
+            }
+            None => None,
+        };
+
+        labels.push(Label {
+            name: "__name__".to_string(),
+            value: name.to_string(),
+        });
+        labels.sort_by(|a, b| a.name.cmp(&b.name));
+        let key: Vec<(String, String)> = labels
+            .iter()
+            .map(|l| (l.name.clone(), l.value.clone()))
+            .collect();
+        let index = match self.index.get(&key) {
+            Some(index) => *index,
+            None => {
+                self.series.push(TimeSeries {
+                    labels,
+                    ..TimeSeries::default()
+                });
+                self.index.insert(key, self.series.len() - 1);
+                self.series.len() - 1
+            }
+        };
+        if let Some(series) = self.series.get_mut(index) {
+            series.samples.push(sample);
+            series.exemplars.extend(exemplar);
+        }
+        Ok(())
+    }
+
+    /// Drops the `_created` series of counters, histograms and summaries,
+    /// which carry start times rather than values
+    fn drop_created(&mut self) {
+        let families = &self.families;
+        let metadata = &self.metadata;
+        let is_created = |series: &TimeSeries| {
+            let Some(name) = series.labels.iter().find(|l| l.name == "__name__") else {
+                return false;
+            };
+            let Some(family) = name.value.strip_suffix("_created") else {
+                return false;
+            };
+            let kind = families
+                .get(family)
+                .and_then(|i| metadata.get(*i))
+                .map(|m| m.r#type);
+            [
+                MetricType::Counter,
+                MetricType::Histogram,
+                MetricType::Gaugehistogram,
+                MetricType::Summary,
+            ]
+            .iter()
+            .any(|t| kind == Some(*t as i32))
+        };
+        self.series.retain(|series| !is_created(series));
+    }
+}
+
+/// Parses a Prometheus or OpenMetrics text exposition into remote-write time
+/// series and metadata. Samples without a timestamp are stamped with `now`, in
+/// milliseconds since the epoch.
+pub(crate) fn parse_text(
+    text: &str,
+    format: TextFormat,
+    now: i64,
+) -> Result<WriteRequest, TextParseError> {
+    let mut exposition = Exposition::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        let result = if let Some(comment) = line.strip_prefix('#') {
+            if comment.trim() == "EOF" {
+                break;
+            }
+            exposition.comment(comment)
+        } else if line.trim().is_empty() {
+            Ok(())
+        } else {
+            exposition.sample(skip_whitespace(line), format, now)
+        };
+        result.map_err(|reason| TextParseError {
+            line: i + 1,
+            reason,
+        })?;
+    }
+    exposition.drop_created();
+    Ok(WriteRequest {
+        timeseries: exposition.series,
+        metadata: exposition.metadata,
+    })
+}
+
+/// Converts a Prometheus or OpenMetrics text exposition into OTLP metrics.
+///
+/// Counters become monotonic cumulative sums, gauges and untyped metrics
+/// become gauges and classic histograms and summaries are reassembled from
+/// their series, the same way [`from_write_request`] converts remote-write
+/// series. Samples without a timestamp are stamped with `now`.
+pub fn from_text(
+    text: &str,
+    format: TextFormat,
+    now: SystemTime,
+) -> Result<ExportMetricsServiceRequest, TextParseError> {
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| i64::try_from(d.as_millis()).unwrap_or(i64::MAX));
+    Ok(from_write_request(&parse_text(text, format, now)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opentelemetry::proto::metrics::v1::{metric::Data, number_data_point};
+    use std::time::Duration;
+
+    const EXPOSITION: &str = r#"# HELP http_requests_total Requests served
+# TYPE http_requests_total counter
+http_requests_total{method="GET",path="/a \"b\""} 7 1000
+http_requests_total{method="POST"} 2 1000
+# TYPE temperature gauge
+temperature 21.5
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le="0.5"} 1
+latency_seconds_bucket{le="+Inf"} 3 # {trace_id="0102"} 0.7
+latency_seconds_sum 4
+latency_seconds_count 3
+latency_seconds_created 1
+# TYPE rpc summary
+rpc{quantile="0.5"} 0.2
+rpc{quantile="0.9"} 0.8
+rpc_sum 5
+rpc_count 10
+"#;
+
+    #[test]
+    pub fn text_exposition() -> Result<(), TextParseError> {
+        let now = UNIX_EPOCH + Duration::from_secs(2);
+        let request = from_text(EXPOSITION, TextFormat::Prometheus, now)?;
+        let metrics: Vec<_> = request
+            .resource_metrics
+            .iter()
+            .flat_map(|rm| &rm.scope_metrics)
+            .flat_map(|sm| &sm.metrics)
+            .collect();
+        assert_eq!(metrics.len(), 4);
+        for metric in metrics {
+            match (metric.name.as_str(), &metric.data) {
+                ("http_requests", Some(Data::Sum(s))) => {
+                    assert!(s.is_monotonic);
+                    assert_eq!(metric.description, "Requests served");
+                    assert_eq!(s.data_points.len(), 2);
+                    let get = s.data_points.iter().find(|p| {
+                        p.attributes
+                            .iter()
+                            .any(|kv| kv.key == "path" && format!("{:?}", kv.value).contains("/a"))
+                    });
+                    assert_eq!(
+                        get.and_then(|p| p.value),
+                        Some(number_data_point::Value::AsDouble(7.0))
+                    );
+                    assert_eq!(get.map(|p| p.time_unix_nano), Some(1_000_000_000));
+                }
+                ("temperature", Some(Data::Gauge(g))) => {
+                    let point = g.data_points.first();
+                    assert_eq!(point.map(|p| p.time_unix_nano), Some(2_000_000_000));
+                }
+                ("latency_seconds", Some(Data::Histogram(h))) => {
+                    let point = h.data_points.first();
+                    assert_eq!(point.map(|p| p.bucket_counts.clone()), Some(vec![1, 2]));
+                    assert_eq!(point.map(|p| p.explicit_bounds.clone()), Some(vec![0.5]));
+                    assert_eq!(point.and_then(|p| p.sum), Some(4.0));
+                    assert_eq!(
+                        point
+                            .and_then(|p| p.exemplars.first())
+                            .map(|e| e.trace_id.clone()),
+                        Some(vec![1, 2])
+                    );
+                }
+                ("rpc", Some(Data::Summary(s))) => {
+                    let point = s.data_points.first();
+                    assert_eq!(point.map(|p| p.count), Some(10));
+                    assert_eq!(point.map(|p| p.quantile_values.len()), Some(2));
+                }
+                (name, data) => panic!("unexpected metric {name}: {data:?}"),
+            }
+        }
+
+        let error = from_text("up{job=\"a\" 1\n", TextFormat::Prometheus, now);
+        assert_eq!(
+            error,
+            Err(TextParseError {
+                line: 1,
+                reason: "expected `,` or `}`"
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,1169 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::prompb::{
+    self, histogram, metric_metadata::MetricType, BucketSpan, Label, MetricMetadata, Sample,
+    TimeSeries, WriteRequest,
+};
+use super::{
+    exemplar_value, has_value, is_counter, is_cumulative, metric_name, target_info_labels,
+    target_labels, Labels, SCOPE_NAME_LABEL, SCOPE_VERSION_LABEL, SERVICE_INSTANCE_ID,
+    SERVICE_NAME, SERVICE_NAMESPACE, TARGET_INFO,
+};
+use crate::common::{find_or_push, hex, string_value};
+use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use crate::opentelemetry::proto::common::v1::{InstrumentationScope, KeyValue};
+use crate::opentelemetry::proto::metrics::v1::{
+    exponential_histogram_data_point::Buckets, metric::Data, number_data_point,
+    summary_data_point::ValueAtQuantile, AggregationTemporality, DataPointFlags, Exemplar,
+    ExponentialHistogram, ExponentialHistogramDataPoint, Gauge, Histogram, HistogramDataPoint,
+    Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum, Summary, SummaryDataPoint,
+};
+use crate::opentelemetry::proto::resource::v1::Resource;
+use prost::Message;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The value Prometheus uses to mark a series as stale
+const STALE_NAN: u64 = 0x7ff0_0000_0000_0002;
+
+/// The range of native histogram schemas Prometheus supports
+const MIN_SCHEMA: i32 = -4;
+const MAX_SCHEMA: i32 = 8;
+
+/// An error decoding a remote-write request
+#[derive(Debug)]
+pub enum RemoteWriteError {
+    /// The body is not valid snappy block compressed data
+    Snappy(snap::Error),
+    /// The body is not a valid `WriteRequest`
+    Decode(prost::DecodeError),
+}
+
+impl std::fmt::Display for RemoteWriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemoteWriteError::Snappy(e) => write!(f, "invalid snappy compression: {e}"),
+            RemoteWriteError::Decode(e) => write!(f, "invalid write request: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for RemoteWriteError {}
+
+impl From<snap::Error> for RemoteWriteError {
+    fn from(e: snap::Error) -> Self {
+        RemoteWriteError::Snappy(e)
+    }
+}
+
+impl From<prost::DecodeError> for RemoteWriteError {
+    fn from(e: prost::DecodeError) -> Self {
+        RemoteWriteError::Decode(e)
+    }
+}
+
+/// Encodes a write request as a snappy block compressed remote-write body
+pub fn encode_write_request(request: &WriteRequest) -> Result<Vec<u8>, RemoteWriteError> {
+    Ok(snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?)
+}
+
+/// Decodes a snappy block compressed remote-write body
+pub fn decode_write_request(body: &[u8]) -> Result<WriteRequest, RemoteWriteError> {
+    let decoded = snap::raw::Decoder::new().decompress_vec(body)?;
+    Ok(WriteRequest::decode(decoded.as_slice())?)
+}
+
+fn millis(nanos: u64) -> i64 {
+    i64::try_from(nanos / 1_000_000).unwrap_or(i64::MAX)
+}
+
+fn millis_to_nanos(millis: i64) -> u64 {
+    u64::try_from(millis).map_or(0, |m| m.saturating_mul(1_000_000))
+}
+
+// counts beyond 2^53 lose precision in Prometheus as well
+#[allow(clippy::cast_precision_loss)]
+fn float(count: u64) -> f64 {
+    count as f64
+}
+
+// float counts of remote-write histograms are rounded to whole observations
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn count(value: f64) -> u64 {
+    value.max(0.0).round() as u64
+}
+
+fn is_stale(value: f64) -> bool {
+    value.to_bits() == STALE_NAN
+}
+
+/// Collects series by label set and the metadata of their families
+#[derive(Default)]
+struct Writer {
+    series: Vec<TimeSeries>,
+    index: HashMap<Vec<(String, String)>, usize>,
+    metadata: Vec<MetricMetadata>,
+    families: HashSet<String>,
+}
+
+impl Writer {
+    fn series(
+        &mut self,
+        name: &str,
+        labels: &Labels,
+        extra: Option<(&str, &str)>,
+    ) -> Option<&mut TimeSeries> {
+        let mut key: Vec<(String, String)> = labels
+            .iter()
+            .chain(extra)
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect();
+        key.push(("__name__".to_string(), name.to_string()));
+        key.sort();
+        let index = match self.index.get(&key) {
+            Some(index) => *index,
+            None => {
+                let labels = key
+                    .iter()
+                    .map(|(name, value)| Label {
+                        name: name.clone(),
+                        value: value.clone(),
+                    })
+                    .collect();
+                self.series.push(TimeSeries {
+                    labels,
+                    ..TimeSeries::default()
+                });
+                self.index.insert(key, self.series.len() - 1);
+                self.series.len() - 1
+            }
+        };
+        self.series.get_mut(index)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn sample(
+        &mut self,
+        name: &str,
+        labels: &Labels,
+        extra: Option<(&str, &str)>,
+        value: f64,
+        time_unix_nano: u64,
+        exemplar: Option<prompb::Exemplar>,
+    ) {
+        if let Some(series) = self.series(name, labels, extra) {
+            series.samples.push(Sample {
+                value,
+                timestamp: millis(time_unix_nano),
+            });
+            series.exemplars.extend(exemplar);
+        }
+    }
+
+    fn metadata(&mut self, family: &str, kind: MetricType, metric: &Metric) {
+        if self.families.insert(family.to_string()) {
+            self.metadata.push(MetricMetadata {
+                r#type: kind as i32,
+                metric_family_name: family.to_string(),
+                help: metric.description.clone(),
+                unit: String::new(),
+            });
+        }
+    }
+}
+
+fn to_exemplar(exemplar: &Exemplar) -> Option<prompb::Exemplar> {
+    let value = exemplar_value(exemplar)?;
+    let mut labels = Labels::default();
+    labels.add_attributes(&exemplar.filtered_attributes);
+    if !exemplar.trace_id.is_empty() {
+        labels.set("trace_id", hex(&exemplar.trace_id));
+    }
+    if !exemplar.span_id.is_empty() {
+        labels.set("span_id", hex(&exemplar.span_id));
+    }
+    Some(prompb::Exemplar {
+        labels: labels
+            .iter()
+            .map(|(name, value)| Label {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect(),
+        value,
+        timestamp: millis(exemplar.time_unix_nano),
+    })
+}
+
+/// The spans and count deltas of native histogram buckets. Prometheus bucket
+/// `i` covers `(base^(i-1), base^i]`, so OTLP indices are shifted by one.
+fn native_buckets(buckets: Option<&Buckets>) -> (Vec<BucketSpan>, Vec<i64>) {
+    let Some(buckets) = buckets.filter(|b| !b.bucket_counts.is_empty()) else {
+        return (Vec::new(), Vec::new());
+    };
+    let span = BucketSpan {
+        offset: buckets.offset.saturating_add(1),
+        length: u32::try_from(buckets.bucket_counts.len()).unwrap_or(u32::MAX),
+    };
+    let mut previous = 0_i64;
+    let deltas = buckets
+        .bucket_counts
+        .iter()
+        .map(|count| {
+            let count = i64::try_from(*count).unwrap_or(i64::MAX);
+            let delta = count - previous;
+            previous = count;
+            delta
+        })
+        .collect();
+    (vec![span], deltas)
+}
+
+fn to_native(point: &ExponentialHistogramDataPoint) -> Option<prompb::Histogram> {
+    let mut point = point.clone();
+    point.downscale(MAX_SCHEMA);
+    if point.scale < MIN_SCHEMA {
+        return None;
+    }
+    let (negative_spans, negative_deltas) = native_buckets(point.negative.as_ref());
+    let (positive_spans, positive_deltas) = native_buckets(point.positive.as_ref());
+    let sum = if has_value(point.flags) {
+        point.sum.unwrap_or_default()
+    } else {
+        f64::from_bits(STALE_NAN)
+    };
+    Some(prompb::Histogram {
+        count: Some(histogram::Count::CountInt(point.count)),
+        sum,
+        schema: point.scale,
+        zero_threshold: point.zero_threshold,
+        zero_count: Some(histogram::ZeroCount::ZeroCountInt(point.zero_count)),
+        negative_spans,
+        negative_deltas,
+        negative_counts: Vec::new(),
+        positive_spans,
+        positive_deltas,
+        positive_counts: Vec::new(),
+        reset_hint: histogram::ResetHint::Unknown as i32,
+        timestamp: millis(point.time_unix_nano),
+    })
+}
+
+fn write_metric(writer: &mut Writer, metric: &Metric, base: &Labels) {
+    let name = metric_name(metric);
+    let labels_of = |attributes: &[KeyValue]| {
+        let mut labels = base.clone();
+        labels.add_attributes(attributes);
+        labels
+    };
+    let stale = f64::from_bits(STALE_NAN);
+    match &metric.data {
+        Some(Data::Gauge(Gauge { data_points })) => {
+            writer.metadata(&name, MetricType::Gauge, metric);
+            write_numbers(writer, &name, data_points, &labels_of);
+        }
+        Some(Data::Sum(s)) if is_cumulative(s.aggregation_temporality) => {
+            let kind = if is_counter(metric) {
+                MetricType::Counter
+            } else {
+                MetricType::Gauge
+            };
+            writer.metadata(&name, kind, metric);
+            write_numbers(writer, &name, &s.data_points, &labels_of);
+        }
+        Some(Data::Histogram(h)) if is_cumulative(h.aggregation_temporality) => {
+            writer.metadata(&name, MetricType::Histogram, metric);
+            let bucket = format!("{name}_bucket");
+            for point in &h.data_points {
+                let labels = labels_of(&point.attributes);
+                let valid = has_value(point.flags);
+                let value = |v: f64| if valid { v } else { stale };
+                let time = point.time_unix_nano;
+                let mut cumulative = 0;
+                let mut lower = f64::NEG_INFINITY;
+                let uppers = point
+                    .explicit_bounds
+                    .iter()
+                    .copied()
+                    .chain(std::iter::once(f64::INFINITY));
+                for (upper, bucket_count) in uppers.zip(&point.bucket_counts) {
+                    cumulative += bucket_count;
+                    let le = if upper.is_infinite() {
+                        "+Inf".to_string()
+                    } else {
+                        upper.to_string()
+                    };
+                    let exemplar = point
+                        .exemplars
+                        .iter()
+                        .find(|e| exemplar_value(e).is_some_and(|v| v > lower && v <= upper))
+                        .and_then(to_exemplar);
+                    let v = value(float(cumulative));
+                    writer.sample(&bucket, &labels, Some(("le", &le)), v, time, exemplar);
+                    lower = upper;
+                }
+                if let Some(sum) = point.sum {
+                    writer.sample(
+                        &format!("{name}_sum"),
+                        &labels,
+                        None,
+                        value(sum),
+                        time,
+                        None,
+                    );
+                }
+                let count = value(float(point.count));
+                writer.sample(&format!("{name}_count"), &labels, None, count, time, None);
+            }
+        }
+        Some(Data::ExponentialHistogram(h)) if is_cumulative(h.aggregation_temporality) => {
+            writer.metadata(&name, MetricType::Histogram, metric);
+            for point in &h.data_points {
+                let labels = labels_of(&point.attributes);
+                let Some(native) = to_native(point) else {
+                    continue;
+                };
+                if let Some(series) = writer.series(&name, &labels, None) {
+                    series.histograms.push(native);
+                    series
+                        .exemplars
+                        .extend(point.exemplars.iter().filter_map(to_exemplar));
+                }
+            }
+        }
+        Some(Data::Summary(s)) => {
+            writer.metadata(&name, MetricType::Summary, metric);
+            for point in &s.data_points {
+                let labels = labels_of(&point.attributes);
+                let valid = has_value(point.flags);
+                let value = |v: f64| if valid { v } else { stale };
+                let time = point.time_unix_nano;
+                for q in &point.quantile_values {
+                    let quantile = q.quantile.to_string();
+                    let extra = Some(("quantile", quantile.as_str()));
+                    writer.sample(&name, &labels, extra, value(q.value), time, None);
+                }
+                let sum = value(point.sum);
+                writer.sample(&format!("{name}_sum"), &labels, None, sum, time, None);
+                let count = value(float(point.count));
+                writer.sample(&format!("{name}_count"), &labels, None, count, time, None);
+            }
+        }
+        // delta streams cannot be represented
+        Some(Data::Sum(_) | Data::Histogram(_) | Data::ExponentialHistogram(_)) | None => (),
+    }
+}
+
+fn write_numbers<F>(writer: &mut Writer, name: &str, points: &[NumberDataPoint], labels_of: &F)
+where
+    F: Fn(&[KeyValue]) -> Labels,
+{
+    for point in points {
+        let value = match point.value {
+            _ if !has_value(point.flags) => f64::from_bits(STALE_NAN),
+            Some(number_data_point::Value::AsDouble(d)) => d,
+            // values beyond 2^53 lose precision in Prometheus as well
+            #[allow(clippy::cast_precision_loss)]
+            Some(number_data_point::Value::AsInt(i)) => i as f64,
+            None => continue,
+        };
+        let exemplar = point.exemplars.last().and_then(to_exemplar);
+        let labels = labels_of(&point.attributes);
+        writer.sample(name, &labels, None, value, point.time_unix_nano, exemplar);
+    }
+}
+
+/// Converts OTLP metrics into remote-write time series.
+///
+/// Series are named and labelled like in the text exposition, with `job` and
+/// `instance` derived from the resource and the remaining resource attributes
+/// on a `target_info` series. Exponential histograms become native histograms,
+/// downscaled to the highest resolution Prometheus supports when needed. Points
+/// without a recorded value become staleness markers. Delta streams cannot be
+/// represented and are skipped.
+pub fn to_write_request(request: &ExportMetricsServiceRequest) -> WriteRequest {
+    let mut writer = Writer::default();
+    let info = Metric {
+        description: "Target metadata".to_string(),
+        ..Metric::default()
+    };
+    for rm in &request.resource_metrics {
+        let resource = rm.resource.clone().unwrap_or_default();
+        let time = rm
+            .scope_metrics
+            .iter()
+            .flat_map(|sm| &sm.metrics)
+            .flat_map(|m| crate::metrics::iter::data_points(m.data.as_ref()))
+            .map(|p| p.time_unix_nano())
+            .max()
+            .unwrap_or_default();
+        if let Some(labels) = target_info_labels(&resource) {
+            writer.metadata(TARGET_INFO, MetricType::Gauge, &info);
+            writer.sample(TARGET_INFO, &labels, None, 1.0, time, None);
+        }
+        let target = target_labels(&resource);
+        for sm in &rm.scope_metrics {
+            let mut labels = target.clone();
+            if let Some(scope) = &sm.scope {
+                if !scope.name.is_empty() {
+                    labels.set(SCOPE_NAME_LABEL, scope.name.clone());
+                }
+                if !scope.version.is_empty() {
+                    labels.set(SCOPE_VERSION_LABEL, scope.version.clone());
+                }
+            }
+            for metric in &sm.metrics {
+                write_metric(&mut writer, metric, &labels);
+            }
+        }
+    }
+    WriteRequest {
+        timeseries: writer.series,
+        metadata: writer.metadata,
+    }
+}
+
+/// The kind of metric a series belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    Gauge,
+    Counter,
+    Histogram,
+    Summary,
+}
+
+/// Identifies the data point a sample belongs to
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct PointKey {
+    target: (Option<String>, Option<String>),
+    scope: (String, String),
+    family: String,
+    kind: Kind,
+    attributes: Vec<(String, String)>,
+    timestamp: i64,
+}
+
+/// The samples collected for a data point
+#[derive(Default)]
+struct PartialPoint {
+    value: Option<f64>,
+    buckets: Vec<(f64, f64)>,
+    quantiles: Vec<(f64, f64)>,
+    sum: Option<f64>,
+    count: Option<f64>,
+    exemplars: Vec<Exemplar>,
+}
+
+fn resource_of(
+    job: Option<&String>,
+    instance: Option<&String>,
+    info: &HashMap<(Option<String>, Option<String>), Vec<KeyValue>>,
+) -> Resource {
+    let mut attributes = Vec::new();
+    match job.map(|job| job.split_once('/')) {
+        Some(Some((namespace, name))) => {
+            attributes.push(string_value(SERVICE_NAMESPACE, namespace));
+            attributes.push(string_value(SERVICE_NAME, name));
+        }
+        Some(None) => attributes.extend(job.map(|job| string_value(SERVICE_NAME, job))),
+        None => (),
+    }
+    attributes.extend(instance.map(|i| string_value(SERVICE_INSTANCE_ID, i)));
+    if let Some(extra) = info.get(&(job.cloned(), instance.cloned())) {
+        attributes.extend(extra.iter().cloned());
+    }
+    Resource {
+        attributes,
+        dropped_attributes_count: 0,
+    }
+}
+
+fn from_exemplar(exemplar: &prompb::Exemplar) -> Exemplar {
+    let parse_hex = |value: &str| -> Vec<u8> {
+        (0..value.len() / 2)
+            .filter_map(|i| value.get(2 * i..2 * i + 2))
+            .filter_map(|byte| u8::from_str_radix(byte, 16).ok())
+            .collect()
+    };
+    let mut result = Exemplar {
+        value: Some(
+            crate::opentelemetry::proto::metrics::v1::exemplar::Value::AsDouble(exemplar.value),
+        ),
+        time_unix_nano: millis_to_nanos(exemplar.timestamp),
+        ..Exemplar::default()
+    };
+    for label in &exemplar.labels {
+        match label.name.as_str() {
+            "trace_id" => result.trace_id = parse_hex(&label.value),
+            "span_id" => result.span_id = parse_hex(&label.value),
+            _ => result
+                .filtered_attributes
+                .push(string_value(&label.name, &label.value)),
+        }
+    }
+    result
+}
+
+/// Expands native histogram spans and deltas into OTLP buckets
+fn from_native_buckets(spans: &[BucketSpan], deltas: &[i64], counts: &[f64]) -> Option<Buckets> {
+    let mut absolute: Vec<(i64, u64)> = Vec::new();
+    let mut index = 0_i64;
+    let mut running = 0_i64;
+    let mut position = 0;
+    // offsets of later spans are relative to the end of the previous span
+    for span in spans {
+        index += i64::from(span.offset);
+        for _ in 0..span.length {
+            let count = match deltas.get(position) {
+                Some(delta) => {
+                    running += delta;
+                    u64::try_from(running).unwrap_or(0)
+                }
+                None => counts.get(position).copied().map_or(0, count),
+            };
+            absolute.push((index, count));
+            index += 1;
+            position += 1;
+        }
+    }
+    let first = absolute.first()?.0;
+    let last = absolute.last()?.0;
+    let mut bucket_counts = vec![0; usize::try_from(last - first + 1).ok()?];
+    for (index, count) in absolute {
+        if let Some(slot) = usize::try_from(index - first)
+            .ok()
+            .and_then(|i| bucket_counts.get_mut(i))
+        {
+            *slot += count;
+        }
+    }
+    Some(Buckets {
+        offset: i32::try_from(first - 1).ok()?,
+        bucket_counts,
+    })
+}
+
+fn from_native(histogram: &prompb::Histogram) -> ExponentialHistogramDataPoint {
+    let stale = is_stale(histogram.sum);
+    ExponentialHistogramDataPoint {
+        time_unix_nano: millis_to_nanos(histogram.timestamp),
+        count: match histogram.count {
+            Some(histogram::Count::CountInt(c)) => c,
+            Some(histogram::Count::CountFloat(c)) => count(c),
+            None => 0,
+        },
+        sum: (!stale).then_some(histogram.sum),
+        scale: histogram.schema,
+        zero_count: match histogram.zero_count {
+            Some(histogram::ZeroCount::ZeroCountInt(c)) => c,
+            Some(histogram::ZeroCount::ZeroCountFloat(c)) => count(c),
+            None => 0,
+        },
+        positive: from_native_buckets(
+            &histogram.positive_spans,
+            &histogram.positive_deltas,
+            &histogram.positive_counts,
+        ),
+        negative: from_native_buckets(
+            &histogram.negative_spans,
+            &histogram.negative_deltas,
+            &histogram.negative_counts,
+        ),
+        flags: if stale {
+            DataPointFlags::NoRecordedValueMask as u32
+        } else {
+            0
+        },
+        zero_threshold: histogram.zero_threshold,
+        ..ExponentialHistogramDataPoint::default()
+    }
+}
+
+/// Finds the metric a data point belongs to, creating the envelopes as needed
+fn metric_for<'a>(
+    request: &'a mut ExportMetricsServiceRequest,
+    resource: Resource,
+    scope: &(String, String),
+    name: &str,
+    data: Data,
+    metadata: Option<&MetricMetadata>,
+) -> &'a mut Metric {
+    let rm = find_or_push(
+        &mut request.resource_metrics,
+        |rm| rm.resource.as_ref() == Some(&resource),
+        || ResourceMetrics {
+            resource: Some(resource.clone()),
+            scope_metrics: Vec::new(),
+            schema_url: String::new(),
+        },
+    );
+    let scope = InstrumentationScope {
+        name: scope.0.clone(),
+        version: scope.1.clone(),
+        attributes: Vec::new(),
+        dropped_attributes_count: 0,
+    };
+    let sm = find_or_push(
+        &mut rm.scope_metrics,
+        |sm| sm.scope.as_ref() == Some(&scope),
+        || ScopeMetrics {
+            scope: Some(scope.clone()),
+            metrics: Vec::new(),
+            schema_url: String::new(),
+        },
+    );
+    find_or_push(
+        &mut sm.metrics,
+        |m| {
+            m.name == name
+                && m.data.as_ref().map(std::mem::discriminant)
+                    == Some(std::mem::discriminant(&data))
+        },
+        || Metric {
+            name: name.to_string(),
+            description: metadata.map(|m| m.help.clone()).unwrap_or_default(),
+            unit: metadata.map(|m| m.unit.clone()).unwrap_or_default(),
+            data: Some(data.clone()),
+            ..Metric::default()
+        },
+    )
+}
+
+/// Classifies a series into the family and kind of its metric
+fn classify<'a>(
+    name: &'a str,
+    has_le: bool,
+    has_quantile: bool,
+    types: &HashMap<&str, i32>,
+    histograms: &HashSet<&str>,
+    summaries: &HashSet<&str>,
+) -> (&'a str, Kind) {
+    let of_type = |family: &str, kind: MetricType| types.get(family) == Some(&(kind as i32));
+    if let Some(family) = name.strip_suffix("_bucket").filter(|_| has_le) {
+        if of_type(family, MetricType::Histogram) || histograms.contains(family) {
+            return (family, Kind::Histogram);
+        }
+    }
+    if has_quantile && (of_type(name, MetricType::Summary) || !types.contains_key(name)) {
+        return (name, Kind::Summary);
+    }
+    for suffix in ["_sum", "_count"] {
+        if let Some(family) = name.strip_suffix(suffix) {
+            if of_type(family, MetricType::Histogram) || histograms.contains(family) {
+                return (family, Kind::Histogram);
+            }
+            if of_type(family, MetricType::Summary) || summaries.contains(family) {
+                return (family, Kind::Summary);
+            }
+        }
+    }
+    let counter = match types.get(name) {
+        Some(t) => *t == MetricType::Counter as i32,
+        None => name.ends_with("_total"),
+    };
+    if counter {
+        (name.strip_suffix("_total").unwrap_or(name), Kind::Counter)
+    } else {
+        (name, Kind::Gauge)
+    }
+}
+
+/// Converts remote-write time series into OTLP metrics.
+///
+/// Series are grouped into resources by their `job` and `instance` labels and
+/// into scopes by their `otel_scope_name` and `otel_scope_version` labels,
+/// `target_info` series become resource attributes. Metric types are taken
+/// from the metadata when present and inferred from the series names
+/// otherwise: counters become monotonic cumulative sums without their `_total`
+/// suffix, classic histograms and summaries are reassembled from their series
+/// and native histograms become exponential histograms. Staleness markers
+/// become points without a recorded value.
+pub fn from_write_request(write: &WriteRequest) -> ExportMetricsServiceRequest {
+    let types: HashMap<&str, i32> = write
+        .metadata
+        .iter()
+        .map(|m| (m.metric_family_name.as_str(), m.r#type))
+        .collect();
+    let families: HashMap<&str, &MetricMetadata> = write
+        .metadata
+        .iter()
+        .map(|m| (m.metric_family_name.as_str(), m))
+        .collect();
+    let label = |series: &'_ TimeSeries, name: &str| -> Option<String> {
+        series
+            .labels
+            .iter()
+            .find(|l| l.name == name)
+            .map(|l| l.value.clone())
+    };
+    let name_of = |series: &TimeSeries| label(series, "__name__").unwrap_or_default();
+    let names: Vec<String> = write.timeseries.iter().map(name_of).collect();
+    let histograms: HashSet<&str> = write
+        .timeseries
+        .iter()
+        .zip(&names)
+        .filter(|(s, _)| s.labels.iter().any(|l| l.name == "le"))
+        .filter_map(|(_, n)| n.strip_suffix("_bucket"))
+        .collect();
+    let summaries: HashSet<&str> = write
+        .timeseries
+        .iter()
+        .zip(&names)
+        .filter(|(s, _)| s.labels.iter().any(|l| l.name == "quantile"))
+        .map(|(_, n)| n.as_str())
+        .collect();
+
+    let mut info: HashMap<(Option<String>, Option<String>), Vec<KeyValue>> = HashMap::new();
+    for (series, name) in write.timeseries.iter().zip(&names) {
+        if name == TARGET_INFO {
+            let target = (label(series, "job"), label(series, "instance"));
+            let attributes = series
+                .labels
+                .iter()
+                .filter(|l| !matches!(l.name.as_str(), "__name__" | "job" | "instance"))
+                .map(|l| string_value(&l.name, &l.value))
+                .collect();
+            info.insert(target, attributes);
+        }
+    }
+
+    let mut points: BTreeMap<PointKey, PartialPoint> = BTreeMap::new();
+    let mut request = ExportMetricsServiceRequest::default();
+    for (series, name) in write.timeseries.iter().zip(&names) {
+        if name.is_empty() || name == TARGET_INFO {
+            continue;
+        }
+        let target = (label(series, "job"), label(series, "instance"));
+        let scope = (
+            label(series, SCOPE_NAME_LABEL).unwrap_or_default(),
+            label(series, SCOPE_VERSION_LABEL).unwrap_or_default(),
+        );
+        let le = label(series, "le").and_then(|le| le.parse::<f64>().ok());
+        let quantile = label(series, "quantile").and_then(|q| q.parse::<f64>().ok());
+        let attributes: Vec<(String, String)> = series
+            .labels
+            .iter()
+            .filter(|l| {
+                !matches!(
+                    l.name.as_str(),
+                    "__name__" | "job" | "instance" | "le" | "quantile"
+                ) && l.name != SCOPE_NAME_LABEL
+                    && l.name != SCOPE_VERSION_LABEL
+            })
+            .map(|l| (l.name.clone(), l.value.clone()))
+            .collect();
+
+        if !series.histograms.is_empty() {
+            let resource = resource_of(target.0.as_ref(), target.1.as_ref(), &info);
+            let metadata = families.get(name.as_str()).copied();
+            let data = Data::ExponentialHistogram(ExponentialHistogram {
+                data_points: Vec::new(),
+                aggregation_temporality: AggregationTemporality::Cumulative as i32,
+            });
+            let metric = metric_for(&mut request, resource, &scope, name, data, metadata);
+            if let Some(Data::ExponentialHistogram(h)) = &mut metric.data {
+                for native in &series.histograms {
+                    let mut point = from_native(native);
+                    point.attributes = attributes.iter().map(|(k, v)| string_value(k, v)).collect();
+                    h.data_points.push(point);
+                }
+            }
+            continue;
+        }
+
+        let (family, kind) = classify(
+            name,
+            le.is_some(),
+            quantile.is_some(),
+            &types,
+            &histograms,
+            &summaries,
+        );
+        for sample in &series.samples {
+            let key = PointKey {
+                target: target.clone(),
+                scope: scope.clone(),
+                family: family.to_string(),
+                kind,
+                attributes: attributes.clone(),
+                timestamp: sample.timestamp,
+            };
+            let point = points.entry(key).or_default();
+            match (kind, le, quantile) {
+                (Kind::Histogram, Some(le), _) => point.buckets.push((le, sample.value)),
+                (Kind::Summary, _, Some(q)) => point.quantiles.push((q, sample.value)),
+                (Kind::Histogram | Kind::Summary, _, _) if name.ends_with("_sum") => {
+                    point.sum = Some(sample.value);
+                }
+                (Kind::Histogram | Kind::Summary, _, _) => point.count = Some(sample.value),
+                (Kind::Gauge | Kind::Counter, _, _) => point.value = Some(sample.value),
+            }
+        }
+        if let Some(exemplar) = series.exemplars.last() {
+            if let Some((_, point)) = points
+                .iter_mut()
+                .rev()
+                .find(|(k, _)| k.family == family && k.attributes == attributes)
+            {
+                point.exemplars.push(from_exemplar(exemplar));
+            }
+        }
+    }
+
+    for (key, point) in points {
+        let resource = resource_of(key.target.0.as_ref(), key.target.1.as_ref(), &info);
+        let family_name = match key.kind {
+            Kind::Counter => format!("{}_total", key.family),
+            _ => key.family.clone(),
+        };
+        let metadata = families
+            .get(family_name.as_str())
+            .or_else(|| families.get(key.family.as_str()))
+            .copied();
+        let attributes: Vec<KeyValue> = key
+            .attributes
+            .iter()
+            .map(|(k, v)| string_value(k, v))
+            .collect();
+        let time_unix_nano = millis_to_nanos(key.timestamp);
+        let samples = point
+            .value
+            .iter()
+            .chain(&point.sum)
+            .chain(&point.count)
+            .chain(point.buckets.iter().map(|(_, v)| v))
+            .chain(point.quantiles.iter().map(|(_, v)| v));
+        let flags = if samples.clone().any(|v| is_stale(*v)) {
+            DataPointFlags::NoRecordedValueMask as u32
+        } else {
+            0
+        };
+        match key.kind {
+            Kind::Gauge | Kind::Counter => {
+                let data = if key.kind == Kind::Counter {
+                    Data::Sum(Sum {
+                        data_points: Vec::new(),
+                        aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                        is_monotonic: true,
+                    })
+                } else {
+                    Data::Gauge(Gauge {
+                        data_points: Vec::new(),
+                    })
+                };
+                let metric = metric_for(
+                    &mut request,
+                    resource,
+                    &key.scope,
+                    &key.family,
+                    data,
+                    metadata,
+                );
+                let number = NumberDataPoint {
+                    attributes,
+                    time_unix_nano,
+                    value: point
+                        .value
+                        .filter(|v| !is_stale(*v))
+                        .map(number_data_point::Value::AsDouble),
+                    exemplars: point.exemplars,
+                    flags,
+                    ..NumberDataPoint::default()
+                };
+                match &mut metric.data {
+                    Some(Data::Sum(s)) => s.data_points.push(number),
+                    Some(Data::Gauge(g)) => g.data_points.push(number),
+                    _ => (),
+                }
+            }
+            Kind::Histogram => {
+                let mut buckets = point.buckets;
+                buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+                let mut previous = 0.0;
+                let bucket_counts = buckets
+                    .iter()
+                    .map(|(_, cumulative)| {
+                        let bucket = count(cumulative - previous);
+                        previous = *cumulative;
+                        bucket
+                    })
+                    .collect();
+                let explicit_bounds = buckets
+                    .iter()
+                    .map(|(le, _)| *le)
+                    .filter(|le| le.is_finite())
+                    .collect();
+                let total = point.count.or(buckets.last().map(|(_, c)| *c));
+                let data = Data::Histogram(Histogram {
+                    data_points: Vec::new(),
+                    aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                });
+                let metric = metric_for(
+                    &mut request,
+                    resource,
+                    &key.scope,
+                    &key.family,
+                    data,
+                    metadata,
+                );
+                if let Some(Data::Histogram(h)) = &mut metric.data {
+                    h.data_points.push(HistogramDataPoint {
+                        attributes,
+                        time_unix_nano,
+                        count: total.map_or(0, count),
+                        sum: point.sum.filter(|v| !is_stale(*v)),
+                        bucket_counts,
+                        explicit_bounds,
+                        exemplars: point.exemplars,
+                        flags,
+                        ..HistogramDataPoint::default()
+                    });
+                }
+            }
+            Kind::Summary => {
+                let mut quantiles = point.quantiles;
+                quantiles.sort_by(|a, b| a.0.total_cmp(&b.0));
+                let data = Data::Summary(Summary {
+                    data_points: Vec::new(),
+                });
+                let metric = metric_for(
+                    &mut request,
+                    resource,
+                    &key.scope,
+                    &key.family,
+                    data,
+                    metadata,
+                );
+                if let Some(Data::Summary(s)) = &mut metric.data {
+                    s.data_points.push(SummaryDataPoint {
+                        attributes,
+                        time_unix_nano,
+                        count: point.count.map_or(0, count),
+                        sum: point.sum.filter(|v| !is_stale(*v)).unwrap_or_default(),
+                        quantile_values: quantiles
+                            .into_iter()
+                            .map(|(quantile, value)| ValueAtQuantile { quantile, value })
+                            .collect(),
+                        flags,
+                        ..SummaryDataPoint::default()
+                    });
+                }
+            }
+        }
+    }
+    request
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kv(key: &str, value: &str) -> KeyValue {
+        string_value(key, value)
+    }
+
+    fn request(metrics: Vec<Metric>) -> ExportMetricsServiceRequest {
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![
+                        kv(SERVICE_NAMESPACE, "shop"),
+                        kv(SERVICE_NAME, "cart"),
+                        kv(SERVICE_INSTANCE_ID, "cart-1"),
+                        kv("host.name", "box"),
+                    ],
+                    dropped_attributes_count: 0,
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: None,
+                    metrics,
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    fn labels(series: &TimeSeries) -> Vec<(&str, &str)> {
+        series
+            .labels
+            .iter()
+            .map(|l| (l.name.as_str(), l.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    pub fn round_trip() -> Result<(), RemoteWriteError> {
+        let counter = Metric {
+            name: "http.requests".to_string(),
+            description: "Requests served".to_string(),
+            data: Some(Data::Sum(Sum {
+                data_points: vec![NumberDataPoint {
+                    attributes: vec![kv("http.method", "GET")],
+                    time_unix_nano: 2_000_000_000,
+                    value: Some(number_data_point::Value::AsInt(7)),
+                    ..NumberDataPoint::default()
+                }],
+                aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                is_monotonic: true,
+            })),
+            ..Metric::default()
+        };
+        let histogram = Metric {
+            name: "latency".to_string(),
+            data: Some(Data::Histogram(Histogram {
+                data_points: vec![HistogramDataPoint {
+                    time_unix_nano: 2_000_000_000,
+                    count: 3,
+                    sum: Some(4.0),
+                    bucket_counts: vec![1, 2],
+                    explicit_bounds: vec![1.0],
+                    ..HistogramDataPoint::default()
+                }],
+                aggregation_temporality: AggregationTemporality::Cumulative as i32,
+            })),
+            ..Metric::default()
+        };
+        let write = to_write_request(&request(vec![counter, histogram]));
+        let write = decode_write_request(&encode_write_request(&write)?)?;
+
+        let requests = write
+            .timeseries
+            .iter()
+            .find(|s| labels(s).contains(&("__name__", "http_requests_total")))
+            .map(labels);
+        assert_eq!(
+            requests,
+            Some(vec![
+                ("__name__", "http_requests_total"),
+                ("http_method", "GET"),
+                ("instance", "cart-1"),
+                ("job", "shop/cart"),
+            ])
+        );
+        assert!(write
+            .timeseries
+            .iter()
+            .any(|s| labels(s).contains(&("host_name", "box"))));
+        assert_eq!(write.timeseries.len(), 6);
+
+        let back = from_write_request(&write);
+        let rm = back.resource_metrics.first();
+        let attributes = rm
+            .and_then(|rm| rm.resource.as_ref())
+            .map(|r| &r.attributes);
+        assert_eq!(
+            attributes,
+            Some(&vec![
+                kv(SERVICE_NAMESPACE, "shop"),
+                kv(SERVICE_NAME, "cart"),
+                kv(SERVICE_INSTANCE_ID, "cart-1"),
+                kv("host_name", "box"),
+            ])
+        );
+        let metrics = rm
+            .and_then(|rm| rm.scope_metrics.first())
+            .map(|sm| sm.metrics.clone())
+            .unwrap_or_default();
+        assert_eq!(metrics.len(), 2);
+        for metric in metrics {
+            match metric.data {
+                Some(Data::Sum(s)) => {
+                    assert_eq!(metric.name, "http_requests");
+                    assert_eq!(metric.description, "Requests served");
+                    assert!(s.is_monotonic);
+                    let point = s.data_points.first();
+                    assert_eq!(
+                        point.and_then(|p| p.value),
+                        Some(number_data_point::Value::AsDouble(7.0))
+                    );
+                    assert_eq!(point.map(|p| p.time_unix_nano), Some(2_000_000_000));
+                }
+                Some(Data::Histogram(h)) => {
+                    assert_eq!(metric.name, "latency");
+                    let point = h.data_points.first();
+                    assert_eq!(point.map(|p| p.bucket_counts.clone()), Some(vec![1, 2]));
+                    assert_eq!(point.map(|p| p.explicit_bounds.clone()), Some(vec![1.0]));
+                    assert_eq!(point.map(|p| p.count), Some(3));
+                    assert_eq!(point.and_then(|p| p.sum), Some(4.0));
+                }
+                other => assert!(other.is_none(), "unexpected metric {other:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn native_histograms() {
+        let point = ExponentialHistogramDataPoint {
+            time_unix_nano: 1_000_000,
+            count: 6,
+            sum: Some(20.0),
+            scale: 10,
+            zero_count: 1,
+            positive: Some(Buckets {
+                offset: 1024,
+                bucket_counts: vec![2, 0, 3],
+            }),
+            ..ExponentialHistogramDataPoint::default()
+        };
+        let metric = Metric {
+            name: "sizes".to_string(),
+            data: Some(Data::ExponentialHistogram(ExponentialHistogram {
+                data_points: vec![point],
+                aggregation_temporality: AggregationTemporality::Cumulative as i32,
+            })),
+            ..Metric::default()
+        };
+        let write = to_write_request(&request(vec![metric]));
+        let native = write
+            .timeseries
+            .iter()
+            .find_map(|s| s.histograms.first())
+            .cloned()
+            .unwrap_or_default();
+        // scale 10 is downscaled to 8, merging four buckets into one
+        assert_eq!(native.schema, 8);
+        assert_eq!(
+            native.positive_spans,
+            vec![BucketSpan {
+                offset: 257,
+                length: 1
+            }]
+        );
+        assert_eq!(native.positive_deltas, vec![5]);
+
+        let back = from_write_request(&write);
+        let data = back
+            .resource_metrics
+            .iter()
+            .flat_map(|rm| &rm.scope_metrics)
+            .flat_map(|sm| &sm.metrics)
+            .find_map(|m| m.data.clone());
+        let Some(Data::ExponentialHistogram(h)) = data else {
+            panic!("expected an exponential histogram, got {data:?}");
+        };
+        let point = h.data_points.first().cloned().unwrap_or_default();
+        assert_eq!(point.scale, 8);
+        assert_eq!(point.count, 6);
+        assert_eq!(point.zero_count, 1);
+        assert_eq!(
+            point.positive,
+            Some(Buckets {
+                offset: 256,
+                bucket_counts: vec![5]
+            })
+        );
+    }
+}
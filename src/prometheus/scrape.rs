@@ -0,0 +1,405 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::parse::parse_text;
+use super::prompb::WriteRequest;
+use super::prompb::{metric_metadata::MetricType, Label, MetricMetadata, Sample, TimeSeries};
+use super::{from_write_request, TextFormat, TextParseError};
+use crate::common::string_value;
+use crate::metrics::{MetricsService, OtelMetricsServiceForwarder};
+use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use crate::opentelemetry::proto::common::v1::{any_value, AnyValue, KeyValue};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE};
+use hyper::{Request, StatusCode, Uri};
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use std::convert::Infallible;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The interval between scrapes unless configured otherwise
+pub const DEFAULT_SCRAPE_INTERVAL: Duration = Duration::from_secs(60);
+/// The time a scrape may take unless configured otherwise
+pub const DEFAULT_SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prefers OpenMetrics, which carries units and exemplars
+const ACCEPT_HEADER: &str =
+    "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+
+/// The metric reporting whether the last scrape succeeded
+const UP: &str = "up";
+
+/// An error scraping or delivering metrics
+#[derive(Debug)]
+pub enum ScrapeError {
+    /// The request could not be sent
+    Request(hyper_util::client::legacy::Error),
+    /// The target did not respond within the scrape timeout
+    Timeout,
+    /// The target responded with an unsuccessful status
+    Status(StatusCode),
+    /// The response body could not be read
+    Body(hyper::Error),
+    /// The response body is not UTF-8
+    Encoding(std::string::FromUtf8Error),
+    /// The response body is not a valid text exposition
+    Parse(TextParseError),
+    /// The target is not an `http` URL
+    Scheme(String),
+    /// The scraped metrics could not be delivered to the channel
+    Deliver(Box<tonic::Status>),
+}
+
+impl std::fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScrapeError::Request(e) => write!(f, "scrape request failed: {e}"),
+            ScrapeError::Timeout => write!(f, "scrape timed out"),
+            ScrapeError::Status(status) => write!(f, "scrape failed with status {status}"),
+            ScrapeError::Body(e) => write!(f, "failed to read scrape response: {e}"),
+            ScrapeError::Encoding(e) => write!(f, "scrape response is not UTF-8: {e}"),
+            ScrapeError::Parse(e) => write!(f, "invalid scrape response: {e}"),
+            ScrapeError::Scheme(scheme) => {
+                write!(f, "only http targets can be scraped, not `{scheme}`")
+            }
+            ScrapeError::Deliver(status) => write!(f, "failed to deliver metrics: {status}"),
+        }
+    }
+}
+
+impl std::error::Error for ScrapeError {}
+
+impl From<TextParseError> for ScrapeError {
+    fn from(e: TextParseError) -> Self {
+        ScrapeError::Parse(e)
+    }
+}
+
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| i64::try_from(d.as_millis()).unwrap_or(i64::MAX))
+}
+
+/// Periodically scrapes a Prometheus or OpenMetrics text endpoint and delivers
+/// the metrics through a metrics forwarder, running its processors.
+///
+/// Every series is labelled with the `job` and `instance` of the target, which
+/// become the `service.name`, `service.namespace` and `service.instance.id`
+/// resource attributes. Conflicting labels exposed by the target are kept as
+/// `exported_job` and `exported_instance`. The resource also carries the
+/// `server.address`, `server.port` and `url.scheme` of the target and any
+/// configured target labels. Each scrape reports an `up` gauge that is `1` if
+/// it succeeded and `0` otherwise.
+pub struct Scraper {
+    url: Uri,
+    job: String,
+    labels: Vec<(String, String)>,
+    interval: Duration,
+    timeout: Duration,
+    forwarder: OtelMetricsServiceForwarder,
+    client: Client<HttpConnector, Empty<Bytes>>,
+}
+
+impl Scraper {
+    /// Creates a scraper for an `http` URL, delivering through the forwarder.
+    /// Other schemes, such as `https`, are rejected.
+    pub fn new(url: Uri, forwarder: OtelMetricsServiceForwarder) -> Result<Self, ScrapeError> {
+        match url.scheme_str() {
+            Some("http") => (),
+            scheme => return Err(ScrapeError::Scheme(scheme.unwrap_or_default().to_string())),
+        }
+        Ok(Scraper {
+            url,
+            job: "prometheus".to_string(),
+            labels: Vec::new(),
+            interval: DEFAULT_SCRAPE_INTERVAL,
+            timeout: DEFAULT_SCRAPE_TIMEOUT,
+            forwarder,
+            client: Client::builder(TokioExecutor::new()).build_http(),
+        })
+    }
+
+    /// Sets the `job` label, `namespace/name` jobs set both `service.namespace`
+    /// and `service.name`
+    pub fn with_job(mut self, job: impl Into<String>) -> Self {
+        self.job = job.into();
+        self
+    }
+
+    /// Adds a target label, which becomes a resource attribute
+    pub fn with_label(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets the interval between scrapes
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the time a scrape may take
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The port of the target, the default `http` port unless given
+    fn port(&self) -> u16 {
+        self.url.port_u16().unwrap_or(80)
+    }
+
+    /// The `instance` label, the host and port of the target
+    fn instance(&self) -> String {
+        let host = self.url.host().unwrap_or_default();
+        format!("{host}:{}", self.port())
+    }
+
+    /// Fetches the exposition and its format
+    async fn fetch(&self) -> Result<(String, TextFormat), ScrapeError> {
+        let mut request = Request::new(Empty::new());
+        *request.uri_mut() = self.url.clone();
+        request
+            .headers_mut()
+            .insert(ACCEPT, HeaderValue::from_static(ACCEPT_HEADER));
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(ScrapeError::Request)?;
+        if !response.status().is_success() {
+            return Err(ScrapeError::Status(response.status()));
+        }
+        let format = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map_or(TextFormat::Prometheus, TextFormat::from_content_type);
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(ScrapeError::Body)?
+            .to_bytes();
+        let text = String::from_utf8(body.to_vec()).map_err(ScrapeError::Encoding)?;
+        Ok((text, format))
+    }
+
+    /// Labels the series with the target and adds the `up` series
+    fn target(&self, mut write: WriteRequest, up: bool, now: i64) -> ExportMetricsServiceRequest {
+        let instance = self.instance();
+        write.timeseries.push(TimeSeries {
+            labels: vec![Label {
+                name: "__name__".to_string(),
+                value: UP.to_string(),
+            }],
+            samples: vec![Sample {
+                value: if up { 1.0 } else { 0.0 },
+                timestamp: now,
+            }],
+            ..TimeSeries::default()
+        });
+        write.metadata.push(MetricMetadata {
+            r#type: MetricType::Gauge as i32,
+            metric_family_name: UP.to_string(),
+            help: "Whether the last scrape of the target succeeded".to_string(),
+            unit: String::new(),
+        });
+        for series in &mut write.timeseries {
+            for label in &mut series.labels {
+                if label.name == "job" || label.name == "instance" {
+                    label.name = format!("exported_{}", label.name);
+                }
+            }
+            series.labels.push(Label {
+                name: "job".to_string(),
+                value: self.job.clone(),
+            });
+            series.labels.push(Label {
+                name: "instance".to_string(),
+                value: instance.clone(),
+            });
+        }
+
+        let mut request = from_write_request(&write);
+        let host = self.url.host().unwrap_or_default().to_string();
+        let port = self.port();
+        for rm in &mut request.resource_metrics {
+            let resource = rm.resource.get_or_insert_with(Default::default);
+            resource
+                .attributes
+                .push(string_value("server.address", &host));
+            resource.attributes.push(KeyValue {
+                key: "server.port".to_string(),
+                value: Some(AnyValue {
+                    value: Some(any_value::Value::IntValue(i64::from(port))),
+                }),
+            });
+            resource.attributes.push(string_value(
+                "url.scheme",
+                self.url.scheme_str().unwrap_or("http"),
+            ));
+            for (name, value) in &self.labels {
+                resource.attributes.push(string_value(name, value));
+            }
+        }
+        request
+    }
+
+    /// Scrapes the target once, returning the metrics without delivering them
+    pub async fn scrape(&self) -> Result<ExportMetricsServiceRequest, ScrapeError> {
+        let now = millis(SystemTime::now());
+        let (text, format) = tokio::time::timeout(self.timeout, self.fetch())
+            .await
+            .map_err(|_| ScrapeError::Timeout)??;
+        let write = parse_text(&text, format, now)?;
+        Ok(self.target(write, true, now))
+    }
+
+    /// Scrapes the target once and delivers the metrics. A failed scrape still
+    /// delivers `up` as `0` before its error is returned.
+    pub async fn scrape_once(&self) -> Result<(), ScrapeError> {
+        let (request, error) = match self.scrape().await {
+            Ok(request) => (request, None),
+            Err(e) => {
+                let now = millis(SystemTime::now());
+                (self.target(WriteRequest::default(), false, now), Some(e))
+            }
+        };
+        self.forwarder
+            .export(tonic::Request::new(request))
+            .await
+            .map_err(|status| ScrapeError::Deliver(Box::new(status)))?;
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Scrapes the target on every interval. Failed scrapes are reported
+    /// through `up` and do not stop the scraper, it only returns once a scrape
+    /// is not accepted by the forwarder, as happens when the metrics channel
+    /// was closed.
+    pub async fn run(&self) -> Result<Infallible, ScrapeError> {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e @ ScrapeError::Deliver(_)) = self.scrape_once().await {
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::opentelemetry::proto::metrics::v1::metric::Data;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves a single HTTP response on a local port
+    async fn stub(content_type: &'static str, body: &'static str) -> std::io::Result<Uri> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move {
+            if let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = vec![0; 4096];
+                let _read = stream.read(&mut buffer).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _written = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        Ok(format!("http://{address}/metrics")
+            .parse()
+            .expect("valid uri"))
+    }
+
+    #[tokio::test]
+    pub async fn scrape_into_channel() -> Result<(), Box<dyn std::error::Error>> {
+        let body = "# TYPE jobs counter\n# UNIT jobs seconds\njobs_total{job=\"x\"} 3 1.5\n# EOF\n";
+        let url = stub("application/openmetrics-text; version=1.0.0", body).await?;
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let scraper = Scraper::new(url, OtelMetricsServiceForwarder::with_sender(tx))?
+            .with_job("shop/cart")
+            .with_label("region", "eu");
+        scraper.scrape_once().await?;
+        let request = rx.recv().await.ok_or("nothing delivered")?;
+
+        let rm = request.resource_metrics.first().ok_or("no resource")?;
+        let attributes: Vec<(String, String)> = rm
+            .resource
+            .iter()
+            .flat_map(|r| &r.attributes)
+            .map(|kv| (kv.key.clone(), format!("{:?}", kv.value)))
+            .collect();
+        let keys: Vec<&str> = attributes.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "service.namespace",
+                "service.name",
+                "service.instance.id",
+                "server.address",
+                "server.port",
+                "url.scheme",
+                "region"
+            ]
+        );
+        let metrics: Vec<_> = rm.scope_metrics.iter().flat_map(|sm| &sm.metrics).collect();
+        let jobs = metrics
+            .iter()
+            .find(|m| m.name == "jobs")
+            .ok_or("no counter")?;
+        let Some(Data::Sum(sum)) = &jobs.data else {
+            panic!("expected a sum, got {:?}", jobs.data);
+        };
+        assert!(sum.is_monotonic);
+        assert_eq!(jobs.unit, "seconds");
+        let point = sum.data_points.first().ok_or("no point")?;
+        assert_eq!(point.time_unix_nano, 1_500_000_000);
+        assert!(point.attributes.iter().any(|kv| kv.key == "exported_job"));
+        assert!(metrics.iter().any(|m| m.name == UP));
+
+        // nothing listens on the port anymore, so the scrape fails with up = 0
+        let error = scraper.scrape_once().await;
+        assert!(matches!(error, Err(ScrapeError::Request(_))));
+        let request = rx.recv().await.ok_or("nothing delivered")?;
+        let up = request
+            .resource_metrics
+            .iter()
+            .flat_map(|rm| &rm.scope_metrics)
+            .flat_map(|sm| &sm.metrics)
+            .find(|m| m.name == UP)
+            .and_then(|m| m.data.clone());
+        let Some(Data::Gauge(gauge)) = up else {
+            panic!("expected a gauge, got {up:?}");
+        };
+        assert_eq!(
+            gauge.data_points.first().and_then(|p| p.value),
+            Some(crate::opentelemetry::proto::metrics::v1::number_data_point::Value::AsDouble(0.0))
+        );
+
+        // https targets cannot be scraped by the plain http client
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let secure = Scraper::new(
+            "https://example.com/metrics".parse()?,
+            OtelMetricsServiceForwarder::with_sender(tx),
+        );
+        assert!(matches!(secure, Err(ScrapeError::Scheme(scheme)) if scheme == "https"));
+        Ok(())
+    }
+}
@@ -0,0 +1,553 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{
+    exemplar_value, has_value, is_counter, is_cumulative, metric_name, target_info_labels,
+    target_labels, unit_suffix, Labels, SCOPE_NAME_LABEL, SCOPE_VERSION_LABEL, TARGET_INFO,
+};
+use crate::common::hex;
+use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use crate::opentelemetry::proto::metrics::v1::{
+    metric::Data, number_data_point, Exemplar, HistogramDataPoint, Metric,
+};
+use std::collections::HashMap;
+
+/// The text format to render
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextFormat {
+    /// The Prometheus text exposition format 0.0.4
+    Prometheus,
+    /// The OpenMetrics text format 1.0.0, which carries units and exemplars
+    OpenMetrics,
+}
+
+impl TextFormat {
+    /// The content type to serve the format with
+    pub fn content_type(self) -> &'static str {
+        match self {
+            TextFormat::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            TextFormat::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+
+    /// The format of a response with the given content type, anything other
+    /// than OpenMetrics is read as the Prometheus format
+    pub fn from_content_type(content_type: &str) -> Self {
+        if content_type
+            .trim_start()
+            .starts_with("application/openmetrics-text")
+        {
+            TextFormat::OpenMetrics
+        } else {
+            TextFormat::Prometheus
+        }
+    }
+}
+
+/// The samples of all metrics sharing a name
+struct Family {
+    name: String,
+    kind: &'static str,
+    help: String,
+    unit: String,
+    samples: String,
+}
+
+/// Families in the order they were first seen
+#[derive(Default)]
+struct Families {
+    families: Vec<Family>,
+    index: HashMap<String, usize>,
+}
+
+impl Families {
+    /// The family of the given name, `None` if it exists with another kind
+    fn get(&mut self, name: &str, kind: &'static str, metric: &Metric) -> Option<&mut Family> {
+        let index = match self.index.get(name) {
+            Some(index) => *index,
+            None => {
+                self.index.insert(name.to_string(), self.families.len());
+                self.families.push(Family {
+                    name: name.to_string(),
+                    kind,
+                    help: metric.description.clone(),
+                    unit: String::new(),
+                    samples: String::new(),
+                });
+                self.families.len() - 1
+            }
+        };
+        self.families.get_mut(index).filter(|f| f.kind == kind)
+    }
+}
+
+fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn escape_help(help: &str, format: TextFormat) -> String {
+    let escaped = help.replace('\\', "\\\\").replace('\n', "\\n");
+    match format {
+        TextFormat::Prometheus => escaped,
+        TextFormat::OpenMetrics => escaped.replace('"', "\\\""),
+    }
+}
+
+/// Renders an exemplar in the OpenMetrics syntax, without its leading space
+fn format_exemplar(exemplar: &Exemplar) -> Option<String> {
+    let value = exemplar_value(exemplar)?;
+    let mut labels = Labels::default();
+    labels.add_attributes(&exemplar.filtered_attributes);
+    if !exemplar.trace_id.is_empty() {
+        labels.set("trace_id", hex(&exemplar.trace_id));
+    }
+    if !exemplar.span_id.is_empty() {
+        labels.set("span_id", hex(&exemplar.span_id));
+    }
+    let mut out = format!("# {} {}", render_labels(&labels, None), format_float(value));
+    if exemplar.time_unix_nano > 0 {
+        // second resolution with nanosecond fraction
+        #[allow(clippy::cast_precision_loss)]
+        let seconds = exemplar.time_unix_nano as f64 / 1e9;
+        out.push_str(&format!(" {seconds}"));
+    }
+    Some(out)
+}
+
+fn render_labels(labels: &Labels, extra: Option<(&str, &str)>) -> String {
+    let mut out = String::from("{");
+    for (i, (name, value)) in labels.iter().chain(extra).enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(name);
+        out.push_str("=\"");
+        out.push_str(&escape_label_value(value));
+        out.push('"');
+    }
+    out.push('}');
+    out
+}
+
+/// Appends a sample line to a family
+fn sample(
+    family: &mut Family,
+    name: &str,
+    labels: &Labels,
+    extra: Option<(&str, &str)>,
+    value: &str,
+    exemplar: Option<String>,
+) {
+    family.samples.push_str(name);
+    family.samples.push_str(&render_labels(labels, extra));
+    family.samples.push(' ');
+    family.samples.push_str(value);
+    if let Some(exemplar) = exemplar {
+        family.samples.push(' ');
+        family.samples.push_str(&exemplar);
+    }
+    family.samples.push('\n');
+}
+
+/// Appends the samples of a classic histogram given its bounds and per bucket counts
+#[allow(clippy::too_many_arguments)]
+fn histogram_samples(
+    family: &mut Family,
+    name: &str,
+    labels: &Labels,
+    bounds: &[f64],
+    bucket_counts: &[u64],
+    count: u64,
+    sum: Option<f64>,
+    exemplars: &[Exemplar],
+    format: TextFormat,
+) {
+    let exemplar_for = |lower: f64, upper: f64| {
+        if format == TextFormat::Prometheus {
+            return None;
+        }
+        exemplars
+            .iter()
+            .find(|e| exemplar_value(e).is_some_and(|v| v > lower && v <= upper))
+            .and_then(format_exemplar)
+    };
+    let bucket = format!("{name}_bucket");
+    let mut cumulative = 0;
+    let mut lower = f64::NEG_INFINITY;
+    for (bound, bucket_count) in bounds.iter().zip(bucket_counts) {
+        cumulative += bucket_count;
+        let le = format_float(*bound);
+        let exemplar = exemplar_for(lower, *bound);
+        sample(
+            family,
+            &bucket,
+            labels,
+            Some(("le", &le)),
+            &cumulative.to_string(),
+            exemplar,
+        );
+        lower = *bound;
+    }
+    let exemplar = exemplar_for(lower, f64::INFINITY);
+    sample(
+        family,
+        &bucket,
+        labels,
+        Some(("le", "+Inf")),
+        &count.to_string(),
+        exemplar,
+    );
+    if let Some(sum) = sum {
+        sample(
+            family,
+            &format!("{name}_sum"),
+            labels,
+            None,
+            &format_float(sum),
+            None,
+        );
+    }
+    sample(
+        family,
+        &format!("{name}_count"),
+        labels,
+        None,
+        &count.to_string(),
+        None,
+    );
+}
+
+fn render_metric(
+    families: &mut Families,
+    metric: &Metric,
+    base_labels: &Labels,
+    format: TextFormat,
+) {
+    let name = metric_name(metric);
+    let labels_of = |attributes| {
+        let mut labels = base_labels.clone();
+        labels.add_attributes(attributes);
+        labels
+    };
+    match &metric.data {
+        Some(Data::Gauge(_)) | Some(Data::Sum(_)) => {
+            let (points, counter) = match &metric.data {
+                Some(Data::Gauge(g)) => (&g.data_points, false),
+                Some(Data::Sum(s)) if is_cumulative(s.aggregation_temporality) => {
+                    (&s.data_points, is_counter(metric))
+                }
+                // delta sums cannot be represented
+                _ => return,
+            };
+            let (family_name, kind) = match (counter, format) {
+                (true, TextFormat::OpenMetrics) => {
+                    (name.strip_suffix("_total").unwrap_or(&name), "counter")
+                }
+                (true, TextFormat::Prometheus) => (name.as_str(), "counter"),
+                (false, _) => (name.as_str(), "gauge"),
+            };
+            let Some(family) = families.get(family_name, kind, metric) else {
+                return;
+            };
+            family.unit = unit_suffix(&metric.unit, !counter);
+            for point in points.iter().filter(|p| has_value(p.flags)) {
+                let value = match point.value {
+                    Some(number_data_point::Value::AsInt(i)) => i.to_string(),
+                    Some(number_data_point::Value::AsDouble(d)) => format_float(d),
+                    None => continue,
+                };
+                let exemplar = if counter && format == TextFormat::OpenMetrics {
+                    point.exemplars.last().and_then(format_exemplar)
+                } else {
+                    None
+                };
+                sample(
+                    family,
+                    &name,
+                    &labels_of(&point.attributes),
+                    None,
+                    &value,
+                    exemplar,
+                );
+            }
+        }
+        Some(Data::Histogram(h)) if is_cumulative(h.aggregation_temporality) => {
+            let Some(family) = families.get(&name, "histogram", metric) else {
+                return;
+            };
+            family.unit = unit_suffix(&metric.unit, false);
+            for point in h.data_points.iter().filter(|p| has_value(p.flags)) {
+                let HistogramDataPoint {
+                    attributes,
+                    count,
+                    sum,
+                    bucket_counts,
+                    explicit_bounds,
+                    exemplars,
+                    ..
+                } = point;
+                let labels = labels_of(attributes);
+                histogram_samples(
+                    family,
+                    &name,
+                    &labels,
+                    explicit_bounds,
+                    bucket_counts,
+                    *count,
+                    *sum,
+                    exemplars,
+                    format,
+                );
+            }
+        }
+        Some(Data::ExponentialHistogram(h)) if is_cumulative(h.aggregation_temporality) => {
+            let Some(family) = families.get(&name, "histogram", metric) else {
+                return;
+            };
+            family.unit = unit_suffix(&metric.unit, false);
+            for point in h.data_points.iter().filter(|p| has_value(p.flags)) {
+                // every exponential bucket becomes a classic bucket bounded by its upper boundary
+                let (bounds, counts): (Vec<f64>, Vec<u64>) = point
+                    .ranges()
+                    .map(|(_, upper, count)| (upper, count))
+                    .unzip();
+                let labels = labels_of(&point.attributes);
+                histogram_samples(
+                    family,
+                    &name,
+                    &labels,
+                    &bounds,
+                    &counts,
+                    point.count,
+                    point.sum,
+                    &point.exemplars,
+                    format,
+                );
+            }
+        }
+        Some(Data::Summary(s)) => {
+            let Some(family) = families.get(&name, "summary", metric) else {
+                return;
+            };
+            family.unit = unit_suffix(&metric.unit, false);
+            for point in s.data_points.iter().filter(|p| has_value(p.flags)) {
+                let labels = labels_of(&point.attributes);
+                for q in &point.quantile_values {
+                    let quantile = format_float(q.quantile);
+                    let value = format_float(q.value);
+                    sample(
+                        family,
+                        &name,
+                        &labels,
+                        Some(("quantile", &quantile)),
+                        &value,
+                        None,
+                    );
+                }
+                let sum = format_float(point.sum);
+                sample(family, &format!("{name}_sum"), &labels, None, &sum, None);
+                let count = point.count.to_string();
+                sample(
+                    family,
+                    &format!("{name}_count"),
+                    &labels,
+                    None,
+                    &count,
+                    None,
+                );
+            }
+        }
+        // delta histograms cannot be represented
+        Some(Data::Histogram(_) | Data::ExponentialHistogram(_)) | None => (),
+    }
+}
+
+/// Renders the metrics of a request in a Prometheus text format.
+///
+/// Only cumulative sums and histograms can be represented, delta streams should
+/// be accumulated first, for instance with a
+/// [`DeltaToCumulative`](crate::metrics::temporality::DeltaToCumulative)
+/// converter. Exponential histograms are rendered as classic histograms with
+/// one bucket per exponential bucket. Metrics whose name collides with a metric
+/// of another type are skipped.
+pub fn to_text(request: &ExportMetricsServiceRequest, format: TextFormat) -> String {
+    let mut families = Families::default();
+    let info = Metric {
+        description: "Target metadata".to_string(),
+        ..Metric::default()
+    };
+    let (info_name, info_kind) = match format {
+        TextFormat::Prometheus => (TARGET_INFO, "gauge"),
+        TextFormat::OpenMetrics => ("target", "info"),
+    };
+    for (resource, rm) in request
+        .resource_metrics
+        .iter()
+        .map(|rm| (rm.resource.clone().unwrap_or_default(), rm))
+    {
+        if let Some(labels) = target_info_labels(&resource) {
+            if let Some(family) = families.get(info_name, info_kind, &info) {
+                sample(family, TARGET_INFO, &labels, None, "1", None);
+            }
+        }
+        let target = target_labels(&resource);
+        for sm in &rm.scope_metrics {
+            let mut labels = target.clone();
+            if let Some(scope) = &sm.scope {
+                if !scope.name.is_empty() {
+                    labels.set(SCOPE_NAME_LABEL, scope.name.clone());
+                }
+                if !scope.version.is_empty() {
+                    labels.set(SCOPE_VERSION_LABEL, scope.version.clone());
+                }
+            }
+            for metric in &sm.metrics {
+                render_metric(&mut families, metric, &labels, format);
+            }
+        }
+    }
+    let mut out = String::new();
+    for family in families.families.iter().filter(|f| !f.samples.is_empty()) {
+        if !family.help.is_empty() {
+            let help = escape_help(&family.help, format);
+            out.push_str(&format!("# HELP {} {help}\n", family.name));
+        }
+        out.push_str(&format!("# TYPE {} {}\n", family.name, family.kind));
+        let has_unit_suffix = family.name.ends_with(&format!("_{}", family.unit));
+        if format == TextFormat::OpenMetrics && !family.unit.is_empty() && has_unit_suffix {
+            out.push_str(&format!("# UNIT {} {}\n", family.name, family.unit));
+        }
+        out.push_str(&family.samples);
+    }
+    if format == TextFormat::OpenMetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_text, TextFormat};
+    use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+    use crate::opentelemetry::proto::common::v1::{any_value, AnyValue, KeyValue};
+    use crate::opentelemetry::proto::metrics::v1::{
+        exemplar, metric::Data, number_data_point, Exemplar, Histogram, HistogramDataPoint, Metric,
+        NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum,
+    };
+    use crate::opentelemetry::proto::resource::v1::Resource;
+
+    fn kv(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+        }
+    }
+
+    fn request() -> ExportMetricsServiceRequest {
+        let counter = Metric {
+            name: "http.requests".to_string(),
+            description: "Handled requests".to_string(),
+            data: Some(Data::Sum(Sum {
+                data_points: vec![NumberDataPoint {
+                    attributes: vec![kv("http.method", "GET")],
+                    value: Some(number_data_point::Value::AsInt(7)),
+                    exemplars: vec![Exemplar {
+                        value: Some(exemplar::Value::AsInt(1)),
+                        trace_id: vec![1; 16],
+                        ..Exemplar::default()
+                    }],
+                    ..NumberDataPoint::default()
+                }],
+                aggregation_temporality: 2,
+                is_monotonic: true,
+            })),
+            ..Metric::default()
+        };
+        let histogram = Metric {
+            name: "latency".to_string(),
+            unit: "s".to_string(),
+            data: Some(Data::Histogram(Histogram {
+                data_points: vec![HistogramDataPoint {
+                    count: 3,
+                    sum: Some(1.5),
+                    bucket_counts: vec![1, 2, 0],
+                    explicit_bounds: vec![0.1, 1.0],
+                    ..HistogramDataPoint::default()
+                }],
+                aggregation_temporality: 2,
+            })),
+            ..Metric::default()
+        };
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![kv("service.name", "shop"), kv("host.name", "a")],
+                    dropped_attributes_count: 0,
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: None,
+                    metrics: vec![counter, histogram],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    #[test]
+    pub fn prometheus_text() {
+        let text = to_text(&request(), TextFormat::Prometheus);
+        let expected = "# HELP target_info Target metadata
+# TYPE target_info gauge
+target_info{host_name=\"a\",job=\"shop\"} 1
+# HELP http_requests_total Handled requests
+# TYPE http_requests_total counter
+http_requests_total{http_method=\"GET\",job=\"shop\"} 7
+# TYPE latency_seconds histogram
+latency_seconds_bucket{job=\"shop\",le=\"0.1\"} 1
+latency_seconds_bucket{job=\"shop\",le=\"1\"} 3
+latency_seconds_bucket{job=\"shop\",le=\"+Inf\"} 3
+latency_seconds_sum{job=\"shop\"} 1.5
+latency_seconds_count{job=\"shop\"} 3
+";
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    pub fn open_metrics_text() {
+        let text = to_text(&request(), TextFormat::OpenMetrics);
+        assert!(text.contains("# TYPE target info\n"));
+        assert!(text.contains("# TYPE http_requests counter\n"));
+        assert!(text.contains(
+            "http_requests_total{http_method=\"GET\",job=\"shop\"} 7 # {trace_id=\"01010101010101010101010101010101\"} 1\n"
+        ));
+        assert!(text.contains("# UNIT latency_seconds seconds\n"));
+        assert!(text.ends_with("# EOF\n"));
+    }
+}
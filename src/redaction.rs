@@ -0,0 +1,617 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scrubs sensitive data from spans and log records before it leaves the receiver.
+//!
+//! A [`Redactor`] removes attributes whose keys are not on its allow-list, redacts
+//! the values of attributes whose keys are on its block-list and redacts matches
+//! of its patterns in string values, wherever they are nested, and in span
+//! names and status messages. Items that had something redacted are annotated
+//! with the number of redactions and the affected attribute keys. Redacting an
+//! item again adds to this summary, values redacted before are left alone.
+
+use crate::common::find_or_push;
+use crate::opentelemetry::proto::common::v1::{any_value::Value, AnyValue, KeyValue};
+#[cfg(feature = "otel-logs")]
+use crate::opentelemetry::proto::logs::v1::LogRecord;
+#[cfg(feature = "otel-trace")]
+use crate::opentelemetry::proto::trace::v1::Span;
+use crate::processor::{Disposition, Processor};
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
+
+/// Attribute recording the number of redactions applied to an item
+pub const REDACTED_COUNT_KEY: &str = "redaction.redacted.count";
+
+/// Attribute recording the keys of the attributes that had values redacted
+pub const REDACTED_KEYS_KEY: &str = "redaction.redacted.keys";
+
+/// Key under which redactions of a log record body are reported
+pub const BODY_KEY: &str = "body";
+
+/// Key under which redactions of a span name are reported
+pub const SPAN_NAME_KEY: &str = "name";
+
+/// Key under which redactions of a span status message are reported
+pub const STATUS_MESSAGE_KEY: &str = "status.message";
+
+/// The text replacing masked values
+pub const MASK: &str = "****";
+
+/// How redacted values are treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RedactionAction {
+    /// Replace the value with a fixed mask
+    #[default]
+    Mask,
+    /// Replace the value with the hex encoded SHA-256 hash of the value, so that
+    /// equal values can still be correlated
+    Hash,
+    /// Remove the attribute, or the matched text in string values
+    Delete,
+}
+
+/// A named pattern matched against string values
+struct Pattern {
+    name: String,
+    regex: Regex,
+    validate: Option<fn(&str) -> bool>,
+}
+
+/// The redactions applied to a single item
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RedactionSummary {
+    /// Number of redacted values and matches
+    pub count: usize,
+    /// Keys of the attributes that had values redacted
+    pub keys: BTreeSet<String>,
+}
+
+impl RedactionSummary {
+    fn record(&mut self, key: &str, count: usize) {
+        if count > 0 {
+            self.count += count;
+            self.keys.insert(key.to_string());
+        }
+    }
+
+    /// Removes the summary of an earlier redaction from the attributes, so
+    /// that it is neither redacted nor removed by the allow-list
+    fn take(attributes: &mut Vec<KeyValue>) -> Self {
+        let mut summary = Self::default();
+        attributes.retain(|kv| {
+            let value = kv.value.as_ref().and_then(|v| v.value.as_ref());
+            match (kv.key.as_str(), value) {
+                (REDACTED_COUNT_KEY, Some(Value::IntValue(count))) => {
+                    summary.count = usize::try_from(*count).unwrap_or_default();
+                }
+                (REDACTED_KEYS_KEY, Some(Value::ArrayValue(keys))) => {
+                    summary.keys = keys
+                        .values
+                        .iter()
+                        .filter_map(|key| match &key.value {
+                            Some(Value::StringValue(key)) => Some(key.clone()),
+                            _ => None,
+                        })
+                        .collect();
+                }
+                (REDACTED_COUNT_KEY | REDACTED_KEYS_KEY, _) => (),
+                _ => return true,
+            }
+            false
+        });
+        summary
+    }
+
+    /// Adds the summary to the one of an earlier redaction and records both as
+    /// attributes. Returns the number of redactions of this summary.
+    fn annotate_after(self, mut earlier: Self, attributes: &mut Vec<KeyValue>) -> usize {
+        let count = self.count;
+        earlier.count += self.count;
+        earlier.keys.extend(self.keys);
+        earlier.annotate(attributes);
+        count
+    }
+
+    /// Records the summary as attributes, if anything was redacted. The
+    /// summary of an earlier redaction is replaced.
+    fn annotate(self, attributes: &mut Vec<KeyValue>) {
+        if self.count == 0 {
+            return;
+        }
+        set_attribute(
+            attributes,
+            REDACTED_COUNT_KEY,
+            Value::IntValue(i64::try_from(self.count).unwrap_or(i64::MAX)),
+        );
+        set_attribute(
+            attributes,
+            REDACTED_KEYS_KEY,
+            Value::ArrayValue(crate::opentelemetry::proto::common::v1::ArrayValue {
+                values: self
+                    .keys
+                    .into_iter()
+                    .map(|k| AnyValue {
+                        value: Some(Value::StringValue(k)),
+                    })
+                    .collect(),
+            }),
+        );
+    }
+}
+
+/// Sets an attribute, replacing the value of an existing one with the same key
+fn set_attribute(attributes: &mut Vec<KeyValue>, key: &str, value: Value) {
+    find_or_push(
+        attributes,
+        |kv| kv.key == key,
+        || KeyValue {
+            key: key.to_string(),
+            value: None,
+        },
+    )
+    .value = Some(AnyValue { value: Some(value) });
+}
+
+/// Checks a candidate credit card number with the Luhn algorithm
+fn luhn(candidate: &str) -> bool {
+    let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| {
+            if i % 2 == 1 {
+                let doubled = d * 2;
+                if doubled > 9 {
+                    doubled - 9
+                } else {
+                    doubled
+                }
+            } else {
+                *d
+            }
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// The bytes of a value that are hashed, strings are hashed as is so that their
+/// hashes match the hashes of pattern matches
+fn value_bytes(value: &AnyValue) -> Vec<u8> {
+    match &value.value {
+        Some(Value::StringValue(s)) => s.as_bytes().to_vec(),
+        _ => prost::Message::encode_to_vec(value),
+    }
+}
+
+/// Hex encoded SHA-256 hash of a value
+fn hash(value: &[u8]) -> String {
+    Sha256::digest(value)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Redacts attributes and string values of spans and log records
+#[derive(Default)]
+pub struct Redactor {
+    allowed_keys: HashSet<String>,
+    blocked_keys: HashSet<String>,
+    patterns: Vec<Pattern>,
+    action: RedactionAction,
+}
+
+impl Redactor {
+    /// Creates a redactor that redacts nothing until configured
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only attributes with these keys are kept, all others are removed. An
+    /// empty allow-list keeps all attributes.
+    pub fn with_allowed_keys<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.allowed_keys.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// Attributes with these keys have their values redacted
+    pub fn with_blocked_keys<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.blocked_keys.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// Matches of the regular expression in string values are redacted
+    pub fn with_pattern(mut self, name: &str, pattern: &str) -> Result<Self, regex::Error> {
+        self.patterns.push(Pattern {
+            name: name.to_string(),
+            regex: Regex::new(pattern)?,
+            validate: None,
+        });
+        Ok(self)
+    }
+
+    /// Redacts credit card numbers, email addresses, bearer and basic
+    /// authorization credentials and JSON web tokens
+    pub fn with_default_patterns(mut self) -> Result<Self, regex::Error> {
+        self.patterns.push(Pattern {
+            name: "credit_card".to_string(),
+            regex: Regex::new(r"\b(?:[0-9][ -]?){12,18}[0-9]\b")?,
+            validate: Some(luhn),
+        });
+        self = self
+            .with_pattern("email", r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}")?
+            .with_pattern("auth", r"(?i)\b(?:bearer|basic)\s+[A-Za-z0-9\-._~+/]+=*")?
+            .with_pattern(
+                "jwt",
+                r"\beyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+",
+            )?;
+        Ok(self)
+    }
+
+    /// Sets how redacted values are treated, defaults to masking
+    pub fn with_action(mut self, action: RedactionAction) -> Self {
+        self.action = action;
+        self
+    }
+
+    /// The names of the configured patterns
+    pub fn pattern_names(&self) -> impl Iterator<Item = &str> {
+        self.patterns.iter().map(|p| p.name.as_str())
+    }
+
+    /// The replacement for a redacted value
+    fn replacement(&self, value: &str) -> String {
+        match self.action {
+            RedactionAction::Mask => MASK.to_string(),
+            RedactionAction::Hash => hash(value.as_bytes()),
+            RedactionAction::Delete => String::new(),
+        }
+    }
+
+    /// Redacts all pattern matches in a string, returning the number of redactions
+    fn redact_str(&self, text: &mut String) -> usize {
+        let mut count = 0;
+        for pattern in &self.patterns {
+            let replaced = pattern.regex.replace_all(text, |caps: &Captures| {
+                let matched = caps.get(0).map_or("", |m| m.as_str());
+                if pattern.validate.is_none_or(|validate| validate(matched)) {
+                    count += 1;
+                    self.replacement(matched)
+                } else {
+                    matched.to_string()
+                }
+            });
+            if let std::borrow::Cow::Owned(replaced) = replaced {
+                *text = replaced;
+            }
+        }
+        count
+    }
+
+    /// Redacts pattern matches in a value, recursing into arrays and key value lists.
+    /// Returns the number of redactions in string values, redactions of nested
+    /// attributes are recorded in the summary under their own keys.
+    pub fn redact_value(&self, value: &mut AnyValue, summary: &mut RedactionSummary) -> usize {
+        self.redact_nested_value(value, summary, &BTreeSet::new())
+    }
+
+    /// Redacts pattern matches in a value, leaving the values of blocked keys
+    /// that were redacted before as they are
+    fn redact_nested_value(
+        &self,
+        value: &mut AnyValue,
+        summary: &mut RedactionSummary,
+        redacted: &BTreeSet<String>,
+    ) -> usize {
+        match &mut value.value {
+            Some(Value::StringValue(s)) => self.redact_str(s),
+            Some(Value::ArrayValue(a)) => a
+                .values
+                .iter_mut()
+                .map(|v| self.redact_nested_value(v, summary, redacted))
+                .sum(),
+            Some(Value::KvlistValue(kvs)) => {
+                self.redact_key_values(&mut kvs.values, summary, false, redacted);
+                0
+            }
+            Some(Value::BytesValue(b)) => match std::str::from_utf8(b) {
+                Ok(s) => {
+                    let mut s = s.to_string();
+                    let count = self.redact_str(&mut s);
+                    if count > 0 {
+                        *b = s.into_bytes();
+                    }
+                    count
+                }
+                Err(_) => 0,
+            },
+            Some(Value::BoolValue(_) | Value::IntValue(_) | Value::DoubleValue(_)) | None => 0,
+        }
+    }
+
+    /// Redacts a list of attributes according to the allow-list, the block-list
+    /// and the patterns
+    pub fn redact_attributes(
+        &self,
+        attributes: &mut Vec<KeyValue>,
+        summary: &mut RedactionSummary,
+    ) {
+        self.redact_key_values(attributes, summary, true, &BTreeSet::new());
+    }
+
+    /// Redacts key value pairs, the allow-list only applies to top level
+    /// attributes. Values of blocked keys in `redacted` were redacted before.
+    fn redact_key_values(
+        &self,
+        key_values: &mut Vec<KeyValue>,
+        summary: &mut RedactionSummary,
+        apply_allow_list: bool,
+        redacted: &BTreeSet<String>,
+    ) {
+        if apply_allow_list && !self.allowed_keys.is_empty() {
+            key_values.retain(|kv| {
+                let allowed = self.allowed_keys.contains(&kv.key);
+                if !allowed {
+                    summary.record(&kv.key, 1);
+                }
+                allowed
+            });
+        }
+        key_values.retain_mut(|kv| {
+            if self.blocked_keys.contains(&kv.key) {
+                if redacted.contains(&kv.key) {
+                    return true;
+                }
+                summary.record(&kv.key, 1);
+                let replacement = match self.action {
+                    RedactionAction::Delete => return false,
+                    RedactionAction::Mask => MASK.to_string(),
+                    RedactionAction::Hash => {
+                        hash(&kv.value.as_ref().map(value_bytes).unwrap_or_default())
+                    }
+                };
+                kv.value = Some(AnyValue {
+                    value: Some(Value::StringValue(replacement)),
+                });
+            } else if let Some(value) = kv.value.as_mut() {
+                let count = self.redact_nested_value(value, summary, redacted);
+                summary.record(&kv.key, count);
+            }
+            true
+        });
+    }
+
+    /// Redacts the name, the status message and the attributes of a span, its
+    /// events and its links. Returns the number of redactions.
+    #[cfg(feature = "otel-trace")]
+    pub fn redact_span(&self, span: &mut Span) -> usize {
+        let earlier = RedactionSummary::take(&mut span.attributes);
+        let mut summary = RedactionSummary::default();
+        let count = self.redact_str(&mut span.name);
+        summary.record(SPAN_NAME_KEY, count);
+        if let Some(status) = span.status.as_mut() {
+            let count = self.redact_str(&mut status.message);
+            summary.record(STATUS_MESSAGE_KEY, count);
+        }
+        let attributes = std::iter::once(&mut span.attributes)
+            .chain(span.events.iter_mut().map(|event| &mut event.attributes))
+            .chain(span.links.iter_mut().map(|link| &mut link.attributes));
+        for attributes in attributes {
+            self.redact_key_values(attributes, &mut summary, true, &earlier.keys);
+        }
+        summary.annotate_after(earlier, &mut span.attributes)
+    }
+
+    /// Redacts the attributes and the body of a log record. Returns the number
+    /// of redactions.
+    #[cfg(feature = "otel-logs")]
+    pub fn redact_log_record(&self, record: &mut LogRecord) -> usize {
+        let earlier = RedactionSummary::take(&mut record.attributes);
+        let mut summary = RedactionSummary::default();
+        self.redact_key_values(&mut record.attributes, &mut summary, true, &earlier.keys);
+        if let Some(body) = record.body.as_mut() {
+            let count = self.redact_nested_value(body, &mut summary, &earlier.keys);
+            summary.record(BODY_KEY, count);
+        }
+        summary.annotate_after(earlier, &mut record.attributes)
+    }
+}
+
+#[cfg(feature = "otel-trace")]
+impl Processor<crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest>
+    for Redactor
+{
+    fn process(
+        &self,
+        request: &mut crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest,
+    ) -> Result<Disposition, tonic::Status> {
+        for (_, _, span) in request.spans_mut() {
+            self.redact_span(span);
+        }
+        Ok(Disposition::accepted())
+    }
+}
+
+#[cfg(feature = "otel-logs")]
+impl Processor<crate::opentelemetry::proto::collector::logs::v1::ExportLogsServiceRequest>
+    for Redactor
+{
+    fn process(
+        &self,
+        request: &mut crate::opentelemetry::proto::collector::logs::v1::ExportLogsServiceRequest,
+    ) -> Result<Disposition, tonic::Status> {
+        for (_, _, record) in request.log_records_mut() {
+            self.redact_log_record(record);
+        }
+        Ok(Disposition::accepted())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        RedactionAction, Redactor, MASK, REDACTED_COUNT_KEY, REDACTED_KEYS_KEY, SPAN_NAME_KEY,
+        STATUS_MESSAGE_KEY,
+    };
+    use crate::common::any_string;
+    use crate::opentelemetry::proto::common::v1::{any_value::Value, AnyValue, KeyValue};
+    use crate::opentelemetry::proto::logs::v1::LogRecord;
+    use crate::opentelemetry::proto::trace::v1::{Span, Status};
+
+    fn string(s: &str) -> Option<AnyValue> {
+        Some(AnyValue {
+            value: Some(Value::StringValue(s.to_string())),
+        })
+    }
+
+    fn kv(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: string(value),
+        }
+    }
+
+    fn attribute<'a>(record: &'a LogRecord, key: &str) -> Option<&'a Value> {
+        record
+            .attributes
+            .iter()
+            .find(|kv| kv.key == key)
+            .and_then(|kv| kv.value.as_ref())
+            .and_then(|v| v.value.as_ref())
+    }
+
+    #[test]
+    pub fn redact_log_record() -> Result<(), regex::Error> {
+        let redactor = Redactor::new()
+            .with_default_patterns()?
+            .with_blocked_keys(["password"]);
+        let mut record = LogRecord {
+            body: string("paid with 4111 1111 1111 1111 by jane@example.com, order 1234567890123"),
+            attributes: vec![kv("password", "hunter2"), kv("user", "jane")],
+            ..LogRecord::default()
+        };
+        assert_eq!(redactor.redact_log_record(&mut record), 3);
+        assert_eq!(
+            record.body.as_ref().and_then(|b| b.value.clone()),
+            Some(Value::StringValue(format!(
+                "paid with {MASK} by {MASK}, order 1234567890123"
+            )))
+        );
+        assert_eq!(
+            attribute(&record, "password"),
+            Some(&Value::StringValue(MASK.to_string()))
+        );
+        assert_eq!(
+            attribute(&record, REDACTED_COUNT_KEY),
+            Some(&Value::IntValue(3))
+        );
+        assert!(attribute(&record, REDACTED_KEYS_KEY).is_some());
+
+        // redacting again finds nothing new and keeps the summary
+        assert_eq!(redactor.redact_log_record(&mut record), 0);
+        let summaries = record
+            .attributes
+            .iter()
+            .filter(|kv| kv.key == REDACTED_COUNT_KEY || kv.key == REDACTED_KEYS_KEY)
+            .count();
+        assert_eq!(summaries, 2);
+        assert_eq!(
+            attribute(&record, REDACTED_COUNT_KEY),
+            Some(&Value::IntValue(3))
+        );
+
+        // new findings are added to the summary
+        record.attributes.push(kv("contact", "joe@example.com"));
+        assert_eq!(redactor.redact_log_record(&mut record), 1);
+        assert_eq!(
+            attribute(&record, REDACTED_COUNT_KEY),
+            Some(&Value::IntValue(4))
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn allow_list_and_hashing() -> Result<(), regex::Error> {
+        let redactor = Redactor::new()
+            .with_allowed_keys(["user", "token"])
+            .with_pattern("token", "secret-[0-9]+")?
+            .with_action(RedactionAction::Hash);
+        let mut record = LogRecord {
+            attributes: vec![
+                kv("user", "jane"),
+                kv("ip", "10.0.0.1"),
+                kv("token", "secret-42"),
+            ],
+            ..LogRecord::default()
+        };
+        assert_eq!(redactor.redact_log_record(&mut record), 2);
+        assert!(attribute(&record, "ip").is_none());
+        assert_eq!(
+            attribute(&record, "token"),
+            Some(&Value::StringValue(super::hash(b"secret-42")))
+        );
+
+        // the summary is kept although its keys are not allowed
+        let redacted = record.clone();
+        assert_eq!(redactor.redact_log_record(&mut record), 0);
+        assert_eq!(record, redacted);
+        Ok(())
+    }
+
+    #[test]
+    pub fn redact_span_name_and_status() -> Result<(), regex::Error> {
+        let redactor = Redactor::new().with_default_patterns()?;
+        let mut span = Span {
+            name: "notify jane@example.com".to_string(),
+            status: Some(Status {
+                message: "rejected Bearer abc.def".to_string(),
+                ..Status::default()
+            }),
+            ..Span::default()
+        };
+        assert_eq!(redactor.redact_span(&mut span), 2);
+        assert_eq!(span.name, format!("notify {MASK}"));
+        assert_eq!(
+            span.status.as_ref().map(|s| s.message.as_str()),
+            Some(format!("rejected {MASK}").as_str())
+        );
+        let keys = span
+            .attributes
+            .iter()
+            .find(|kv| kv.key == REDACTED_KEYS_KEY)
+            .and_then(|kv| kv.value.as_ref())
+            .and_then(|v| v.value.as_ref());
+        let Some(Value::ArrayValue(keys)) = keys else {
+            panic!("expected the redacted keys, got {keys:?}");
+        };
+        assert_eq!(
+            keys.values,
+            vec![any_string(SPAN_NAME_KEY), any_string(STATUS_MESSAGE_KEY)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,285 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Consistent probabilistic sampling keyed on the trace id.
+//!
+//! The decision follows the OpenTelemetry tracestate probability sampling
+//! specification. Every trace carries 56 bits of randomness, either explicitly as
+//! the `rv` sub-key of the `ot` tracestate entry or implicitly as the least
+//! significant 56 bits of its trace id. A trace is kept when its randomness is
+//! at least the rejection threshold derived from the sampling probability, so
+//! every node configured with the same probability makes the same decision for
+//! the same trace. Kept spans have their `th` sub-key updated to the threshold
+//! they were sampled with.
+
+#[cfg(any(feature = "otel-trace", feature = "otel-logs"))]
+use crate::processor::{Disposition, Processor};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "otel-trace")]
+pub mod tail;
+
+/// Number of bits of randomness and precision of the thresholds
+const RANDOMNESS_BITS: u32 = 56;
+
+/// The exclusive upper bound of randomness values, a threshold rejecting everything
+const MAX_THRESHOLD: u64 = 1 << RANDOMNESS_BITS;
+
+/// Number of hex digits of a threshold or randomness value
+const HEX_DIGITS: usize = 14;
+
+/// The value of the `ot` entry of a W3C tracestate
+fn ot_value(trace_state: &str) -> Option<&str> {
+    trace_state
+        .split(',')
+        .filter_map(|member| member.trim().split_once('='))
+        .find(|(key, _)| *key == "ot")
+        .map(|(_, value)| value)
+}
+
+/// The value of a sub-key of the `ot` tracestate entry
+fn ot_sub_value<'a>(ot: &'a str, key: &str) -> Option<&'a str> {
+    ot.split(';')
+        .filter_map(|field| field.split_once(':'))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+/// Parses a rejection threshold, which may omit trailing zeros
+pub fn parse_threshold(th: &str) -> Option<u64> {
+    if th.is_empty() || th.len() > HEX_DIGITS {
+        return None;
+    }
+    let value = u64::from_str_radix(th, 16).ok()?;
+    let shift = u32::try_from(4 * (HEX_DIGITS - th.len())).ok()?;
+    Some(value << shift)
+}
+
+/// Encodes a rejection threshold without trailing zeros
+pub fn encode_threshold(threshold: u64) -> String {
+    let encoded = format!("{:014x}", threshold & (MAX_THRESHOLD - 1));
+    let trimmed = encoded.trim_end_matches('0');
+    if trimmed.is_empty() {
+        "0".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// The rejection threshold for a sampling probability
+pub fn threshold_for_probability(probability: f64) -> u64 {
+    let probability = probability.clamp(0.0, 1.0);
+    // rounding to the 56 bits of precision of the thresholds is intended
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_precision_loss
+    )]
+    let threshold = ((1.0 - probability) * MAX_THRESHOLD as f64).round() as u64;
+    threshold.min(MAX_THRESHOLD)
+}
+
+/// The 56 bits of randomness of a trace, taken from the `rv` sub-key of the
+/// tracestate when present and from the trace id otherwise
+pub fn randomness(trace_id: &[u8], trace_state: &str) -> Option<u64> {
+    if let Some(rv) = ot_value(trace_state).and_then(|ot| ot_sub_value(ot, "rv")) {
+        if rv.len() == HEX_DIGITS {
+            return u64::from_str_radix(rv, 16).ok();
+        }
+    }
+    let bytes: [u8; 16] = trace_id.try_into().ok()?;
+    let value = u128::from_be_bytes(bytes);
+    if value == 0 {
+        return None;
+    }
+    Some((value as u64) & (MAX_THRESHOLD - 1))
+}
+
+/// The rejection threshold recorded in a tracestate
+pub fn trace_state_threshold(trace_state: &str) -> Option<u64> {
+    ot_value(trace_state)
+        .and_then(|ot| ot_sub_value(ot, "th"))
+        .and_then(parse_threshold)
+}
+
+/// Sets the `th` sub-key of the `ot` tracestate entry, moving the entry to the
+/// front of the list as required for modified entries
+pub fn with_trace_state_threshold(trace_state: &str, threshold: u64) -> String {
+    let th = format!("th:{}", encode_threshold(threshold));
+    let ot = match ot_value(trace_state) {
+        Some(ot) => {
+            let fields: Vec<&str> = ot
+                .split(';')
+                .filter(|f| !f.is_empty() && !f.starts_with("th:"))
+                .collect();
+            std::iter::once(th.as_str())
+                .chain(fields)
+                .collect::<Vec<_>>()
+                .join(";")
+        }
+        None => th,
+    };
+    std::iter::once(format!("ot={ot}"))
+        .chain(
+            trace_state
+                .split(',')
+                .map(str::trim)
+                .filter(|m| !m.is_empty() && !m.starts_with("ot="))
+                .map(ToString::to_string),
+        )
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Keeps or drops whole traces deterministically based on their randomness
+pub struct ProbabilisticSampler {
+    threshold: u64,
+    keep_without_trace_id: bool,
+    sampled_in: AtomicU64,
+    sampled_out: AtomicU64,
+}
+
+impl ProbabilisticSampler {
+    /// Creates a sampler keeping traces with the given probability
+    pub fn with_probability(probability: f64) -> Self {
+        Self::with_threshold(threshold_for_probability(probability))
+    }
+
+    /// Creates a sampler keeping traces whose randomness is at least the threshold
+    pub fn with_threshold(threshold: u64) -> Self {
+        Self {
+            threshold: threshold.min(MAX_THRESHOLD),
+            keep_without_trace_id: true,
+            sampled_in: AtomicU64::new(0),
+            sampled_out: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether items without a valid trace id are kept, they are by default
+    pub fn keep_without_trace_id(mut self, keep: bool) -> Self {
+        self.keep_without_trace_id = keep;
+        self
+    }
+
+    /// The rejection threshold of the sampler
+    pub fn threshold(&self) -> u64 {
+        self.threshold
+    }
+
+    /// The number of items kept so far
+    pub fn sampled_in(&self) -> u64 {
+        self.sampled_in.load(Ordering::Relaxed)
+    }
+
+    /// The number of items dropped by sampling so far. These are not rejections
+    /// and are not reported to clients.
+    pub fn sampled_out(&self) -> u64 {
+        self.sampled_out.load(Ordering::Relaxed)
+    }
+
+    /// The effective threshold for an item that may already have been sampled
+    /// upstream, the stricter one wins
+    fn effective_threshold(&self, trace_state: &str) -> u64 {
+        trace_state_threshold(trace_state).map_or(self.threshold, |t| t.max(self.threshold))
+    }
+
+    /// Decides if an item of a trace is kept
+    pub fn should_sample(&self, trace_id: &[u8], trace_state: &str) -> bool {
+        match randomness(trace_id, trace_state) {
+            Some(r) => r >= self.effective_threshold(trace_state),
+            None => self.keep_without_trace_id,
+        }
+    }
+
+    #[cfg(any(feature = "otel-trace", feature = "otel-logs"))]
+    fn count(&self, kept: usize, dropped: usize) {
+        self.sampled_in.fetch_add(kept as u64, Ordering::Relaxed);
+        self.sampled_out
+            .fetch_add(dropped as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "otel-trace")]
+impl Processor<crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest>
+    for ProbabilisticSampler
+{
+    fn process(
+        &self,
+        request: &mut crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest,
+    ) -> Result<Disposition, tonic::Status> {
+        let dropped = request
+            .retain_spans(|_, _, span| self.should_sample(&span.trace_id, &span.trace_state));
+        for (_, _, span) in request.spans_mut() {
+            if randomness(&span.trace_id, &span.trace_state).is_some() {
+                let threshold = self.effective_threshold(&span.trace_state);
+                span.trace_state = with_trace_state_threshold(&span.trace_state, threshold);
+            }
+        }
+        self.count(request.span_count(), dropped);
+        Ok(Disposition::accepted())
+    }
+}
+
+#[cfg(feature = "otel-logs")]
+impl Processor<crate::opentelemetry::proto::collector::logs::v1::ExportLogsServiceRequest>
+    for ProbabilisticSampler
+{
+    fn process(
+        &self,
+        request: &mut crate::opentelemetry::proto::collector::logs::v1::ExportLogsServiceRequest,
+    ) -> Result<Disposition, tonic::Status> {
+        let dropped = request.retain_log_records(|_, _, record| {
+            if record.trace_id.is_empty() {
+                self.keep_without_trace_id
+            } else {
+                self.should_sample(&record.trace_id, "")
+            }
+        });
+        self.count(request.log_record_count(), dropped);
+        Ok(Disposition::accepted())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn thresholds() {
+        assert_eq!(encode_threshold(threshold_for_probability(1.0)), "0");
+        assert_eq!(encode_threshold(threshold_for_probability(0.5)), "8");
+        assert_eq!(encode_threshold(threshold_for_probability(0.25)), "c");
+        assert_eq!(parse_threshold("c"), Some(threshold_for_probability(0.25)));
+        assert_eq!(parse_threshold("snot"), None);
+        assert_eq!(
+            with_trace_state_threshold("a=b,ot=rv:00000000000001;th:8", 0xc << 52),
+            "ot=th:c;rv:00000000000001,a=b"
+        );
+    }
+
+    #[test]
+    pub fn consistent_decisions() {
+        let sampler = ProbabilisticSampler::with_probability(0.5);
+        let mut low = [0u8; 16];
+        low[15] = 1;
+        let mut high = [0u8; 16];
+        high[9] = 0x80;
+        assert!(!sampler.should_sample(&low, ""));
+        assert!(sampler.should_sample(&high, ""));
+        // the explicit randomness takes precedence over the trace id
+        assert!(sampler.should_sample(&low, "ot=rv:ffffffffffffff"));
+        // an upstream threshold that is stricter wins
+        assert!(!sampler.should_sample(&high, "ot=th:c"));
+    }
+}
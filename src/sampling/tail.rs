@@ -0,0 +1,594 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tail-based sampling of complete traces.
+//!
+//! The [`TailSampler`] buffers spans from many export requests grouped by their
+//! trace id. Once no span of a trace arrived for the decision window the trace
+//! is evaluated against the configured [`Policy`]s and either emitted as part
+//! of a new export request or dropped. Spans arriving after the decision follow
+//! the decision that was made for their trace.
+//!
+//! The sampler does not spawn timers, time is passed in by the caller so that it
+//! can be driven from any runtime:
+//!
+//! ```ignore
+//! sampler.ingest(request, Instant::now());
+//! // periodically
+//! if let Some(kept) = sampler.poll(Instant::now()) {
+//!     sender.send(kept).await?;
+//! }
+//! ```
+
+use crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest;
+use crate::opentelemetry::proto::common::v1::{any_value, AnyValue, KeyValue};
+use crate::opentelemetry::proto::trace::v1::{status::StatusCode, ResourceSpans, ScopeSpans, Span};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// The resource attribute naming the service that emitted a span
+const SERVICE_NAME_KEY: &str = "service.name";
+
+/// A policy selecting traces to keep
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Policy {
+    /// Keeps traces with at least one span with an error status
+    StatusError,
+    /// Keeps traces whose root span lasted at least the given duration. Traces
+    /// whose root span was not received are measured from their earliest start
+    /// to their latest end.
+    Latency(Duration),
+    /// Keeps traces with a span or resource attribute of the given key whose
+    /// value renders as the given string
+    Attribute {
+        /// The attribute key
+        key: String,
+        /// The expected attribute value
+        value: String,
+    },
+}
+
+/// Counters of the decisions made by a tail sampler
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TailSamplerStats {
+    /// Traces that were kept
+    pub traces_kept: u64,
+    /// Traces that were dropped by the policies or the rate limit
+    pub traces_dropped: u64,
+    /// Traces evicted undecided to honour the memory bounds
+    pub traces_evicted: u64,
+    /// Spans of already decided traces that were dropped on arrival, either
+    /// because their trace was dropped or because the span bound was reached
+    pub late_spans_dropped: u64,
+}
+
+/// The spans of one trace waiting for a decision
+struct PendingTrace {
+    request: ExportTraceServiceRequest,
+    span_count: usize,
+    last_seen: Instant,
+}
+
+/// Number of traces kept for a service within the current one second window
+struct RateWindow {
+    start: Instant,
+    kept: u32,
+}
+
+/// Buffers spans into complete traces and keeps those selected by its policies
+pub struct TailSampler {
+    decision_wait: Duration,
+    max_traces: usize,
+    max_spans: usize,
+    policies: Vec<Policy>,
+    traces_per_second: Option<u32>,
+    pending: HashMap<Vec<u8>, PendingTrace>,
+    arrival: VecDeque<Vec<u8>>,
+    buffered_spans: usize,
+    decided: HashMap<Vec<u8>, bool>,
+    decision_order: VecDeque<Vec<u8>>,
+    late: ExportTraceServiceRequest,
+    late_spans: usize,
+    rates: HashMap<String, RateWindow>,
+    stats: TailSamplerStats,
+}
+
+impl Default for TailSampler {
+    fn default() -> Self {
+        Self {
+            decision_wait: Duration::from_secs(30),
+            max_traces: 50_000,
+            max_spans: 1_000_000,
+            policies: Vec::new(),
+            traces_per_second: None,
+            pending: HashMap::new(),
+            arrival: VecDeque::new(),
+            buffered_spans: 0,
+            decided: HashMap::new(),
+            decision_order: VecDeque::new(),
+            late: ExportTraceServiceRequest::default(),
+            late_spans: 0,
+            rates: HashMap::new(),
+            stats: TailSamplerStats::default(),
+        }
+    }
+}
+
+impl TailSampler {
+    /// Creates a sampler waiting 30 seconds after the last span of a trace and
+    /// buffering at most 50 000 traces or 1 000 000 spans. Without policies
+    /// every trace is kept.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how long to wait after the last span of a trace before deciding
+    pub fn with_decision_wait(mut self, decision_wait: Duration) -> Self {
+        self.decision_wait = decision_wait;
+        self
+    }
+
+    /// Sets the maximum number of traces buffered, the oldest are evicted first
+    pub fn with_max_traces(mut self, max_traces: usize) -> Self {
+        self.max_traces = max_traces.max(1);
+        self
+    }
+
+    /// Sets the maximum number of spans buffered, the oldest traces are evicted first
+    pub fn with_max_spans(mut self, max_spans: usize) -> Self {
+        self.max_spans = max_spans.max(1);
+        self
+    }
+
+    /// Adds a policy, a trace is kept when any of the policies selects it
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policies.push(policy);
+        self
+    }
+
+    /// Limits the number of traces kept per second for each `service.name`
+    pub fn with_rate_limit(mut self, traces_per_second: u32) -> Self {
+        self.traces_per_second = Some(traces_per_second);
+        self
+    }
+
+    /// The decision counters so far
+    pub fn stats(&self) -> TailSamplerStats {
+        self.stats
+    }
+
+    /// The number of traces waiting for a decision
+    pub fn pending_traces(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The number of spans waiting for a decision
+    pub fn pending_spans(&self) -> usize {
+        self.buffered_spans
+    }
+
+    /// Buffers the spans of an export request. Spans of traces that were
+    /// already kept are queued for the next [`poll`](Self::poll) as long as
+    /// they fit within the span bound, spans of dropped traces are discarded.
+    pub fn ingest(&mut self, request: ExportTraceServiceRequest, now: Instant) {
+        for rs in request.resource_spans {
+            let ResourceSpans {
+                resource,
+                scope_spans,
+                schema_url: resource_schema_url,
+            } = rs;
+            for ss in scope_spans {
+                let ScopeSpans {
+                    scope,
+                    spans,
+                    schema_url,
+                } = ss;
+                let mut by_trace: Vec<(Vec<u8>, Vec<Span>)> = Vec::new();
+                let mut index: HashMap<Vec<u8>, usize> = HashMap::new();
+                for span in spans {
+                    match index.get(&span.trace_id).and_then(|i| by_trace.get_mut(*i)) {
+                        Some((_, group)) => group.push(span),
+                        None => {
+                            index.insert(span.trace_id.clone(), by_trace.len());
+                            by_trace.push((span.trace_id.clone(), vec![span]));
+                        }
+                    }
+                }
+                for (trace_id, spans) in by_trace {
+                    let fragment = ExportTraceServiceRequest {
+                        resource_spans: vec![ResourceSpans {
+                            resource: resource.clone(),
+                            scope_spans: vec![ScopeSpans {
+                                scope: scope.clone(),
+                                spans,
+                                schema_url: schema_url.clone(),
+                            }],
+                            schema_url: resource_schema_url.clone(),
+                        }],
+                    };
+                    self.buffer(trace_id, fragment, now);
+                }
+            }
+        }
+        self.enforce_bounds();
+    }
+
+    fn buffer(&mut self, trace_id: Vec<u8>, fragment: ExportTraceServiceRequest, now: Instant) {
+        let span_count = fragment.span_count();
+        match self.decided.get(&trace_id) {
+            Some(true) if self.buffered_spans + self.late_spans + span_count <= self.max_spans => {
+                self.late.merge_request(fragment);
+                self.late_spans += span_count;
+            }
+            Some(_) => self.stats.late_spans_dropped += span_count as u64,
+            None => {
+                let arrival = &mut self.arrival;
+                let pending = self.pending.entry(trace_id).or_insert_with_key(|id| {
+                    arrival.push_back(id.clone());
+                    PendingTrace {
+                        request: ExportTraceServiceRequest::default(),
+                        span_count: 0,
+                        last_seen: now,
+                    }
+                });
+                pending.request.merge_request(fragment);
+                pending.span_count += span_count;
+                pending.last_seen = now;
+                self.buffered_spans += span_count;
+            }
+        }
+    }
+
+    /// Evicts the oldest traces until the buffer is within its bounds
+    fn enforce_bounds(&mut self) {
+        while self.pending.len() > self.max_traces || self.buffered_spans > self.max_spans {
+            let Some(trace_id) = self.arrival.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = self.pending.remove(&trace_id) {
+                self.buffered_spans -= evicted.span_count;
+                self.stats.traces_evicted += 1;
+                self.record_decision(trace_id, false);
+            }
+        }
+    }
+
+    /// Remembers a decision for late spans, bounded like the buffer itself
+    fn record_decision(&mut self, trace_id: Vec<u8>, keep: bool) {
+        if self.decided.insert(trace_id.clone(), keep).is_none() {
+            self.decision_order.push_back(trace_id);
+        }
+        while self.decided.len() > self.max_traces {
+            match self.decision_order.pop_front() {
+                Some(oldest) => {
+                    self.decided.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Decides every trace whose decision window has elapsed and returns the
+    /// kept traces, along with late spans of previously kept traces, as a
+    /// single export request
+    pub fn poll(&mut self, now: Instant) -> Option<ExportTraceServiceRequest> {
+        let decision_wait = self.decision_wait;
+        self.decide(now, |trace| {
+            now.saturating_duration_since(trace.last_seen) >= decision_wait
+        })
+    }
+
+    /// Decides every buffered trace regardless of its decision window, for
+    /// instance on shutdown
+    pub fn flush(&mut self, now: Instant) -> Option<ExportTraceServiceRequest> {
+        self.decide(now, |_| true)
+    }
+
+    fn decide<F>(&mut self, now: Instant, ready: F) -> Option<ExportTraceServiceRequest>
+    where
+        F: Fn(&PendingTrace) -> bool,
+    {
+        let mut output = std::mem::take(&mut self.late);
+        self.late_spans = 0;
+        let arrival = std::mem::take(&mut self.arrival);
+        for trace_id in arrival {
+            let is_ready = match self.pending.get(&trace_id) {
+                Some(trace) => ready(trace),
+                None => continue,
+            };
+            if !is_ready {
+                self.arrival.push_back(trace_id);
+                continue;
+            }
+            if let Some(trace) = self.pending.remove(&trace_id) {
+                self.buffered_spans -= trace.span_count;
+                let keep = self.selected(&trace.request) && self.within_rate(&trace.request, now);
+                if keep {
+                    self.stats.traces_kept += 1;
+                    output.merge_request(trace.request);
+                } else {
+                    self.stats.traces_dropped += 1;
+                }
+                self.record_decision(trace_id, keep);
+            }
+        }
+        if output.resource_spans.is_empty() {
+            None
+        } else {
+            Some(output)
+        }
+    }
+
+    /// Checks if any policy selects the trace
+    fn selected(&self, trace: &ExportTraceServiceRequest) -> bool {
+        self.policies.is_empty()
+            || self.policies.iter().any(|policy| match policy {
+                Policy::StatusError => trace.spans().any(|(_, _, span)| {
+                    span.status
+                        .as_ref()
+                        .is_some_and(|s| s.code == StatusCode::Error as i32)
+                }),
+                Policy::Latency(min) => root_duration(trace) >= *min,
+                Policy::Attribute { key, value } => trace.spans().any(|(resource, _, span)| {
+                    has_attribute(&span.attributes, key, value)
+                        || has_attribute(&resource.attributes, key, value)
+                }),
+            })
+    }
+
+    /// Counts the trace against the rate limit of its service, returning false
+    /// if the limit is exhausted
+    fn within_rate(&mut self, trace: &ExportTraceServiceRequest, now: Instant) -> bool {
+        let Some(limit) = self.traces_per_second else {
+            return true;
+        };
+        let window = self.rates.entry(service_name(trace)).or_insert(RateWindow {
+            start: now,
+            kept: 0,
+        });
+        if now.saturating_duration_since(window.start) >= Duration::from_secs(1) {
+            window.start = now;
+            window.kept = 0;
+        }
+        if window.kept < limit {
+            window.kept += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The root span of a trace, the one without a parent
+fn root_span(trace: &ExportTraceServiceRequest) -> Option<&Span> {
+    trace
+        .spans()
+        .map(|(_, _, span)| span)
+        .find(|span| span.parent_span_id.is_empty())
+}
+
+/// The duration of the root span of a trace, or the extent of all of its spans
+/// when the root was not received
+fn root_duration(trace: &ExportTraceServiceRequest) -> Duration {
+    let (start, end) = match root_span(trace) {
+        Some(root) => (root.start_time_unix_nano, root.end_time_unix_nano),
+        None => trace
+            .spans()
+            .fold((u64::MAX, 0), |(start, end), (_, _, span)| {
+                (
+                    start.min(span.start_time_unix_nano),
+                    end.max(span.end_time_unix_nano),
+                )
+            }),
+    };
+    Duration::from_nanos(end.saturating_sub(start))
+}
+
+/// The `service.name` of the root span's resource, or of the first span's
+fn service_name(trace: &ExportTraceServiceRequest) -> String {
+    trace
+        .spans()
+        .find(|(_, _, span)| span.parent_span_id.is_empty())
+        .or_else(|| trace.spans().next())
+        .and_then(|(resource, _, _)| {
+            resource
+                .attributes
+                .iter()
+                .find(|kv| kv.key == SERVICE_NAME_KEY)
+                .and_then(|kv| kv.value.as_ref())
+                .and_then(render)
+        })
+        .unwrap_or_default()
+}
+
+/// Renders a scalar attribute value as a string
+fn render(value: &AnyValue) -> Option<String> {
+    match value.value.as_ref()? {
+        any_value::Value::StringValue(s) => Some(s.clone()),
+        any_value::Value::BoolValue(b) => Some(b.to_string()),
+        any_value::Value::IntValue(i) => Some(i.to_string()),
+        any_value::Value::DoubleValue(d) => Some(d.to_string()),
+        _ => None,
+    }
+}
+
+fn has_attribute(attributes: &[KeyValue], key: &str, value: &str) -> bool {
+    attributes.iter().any(|kv| {
+        kv.key == key
+            && kv
+                .value
+                .as_ref()
+                .and_then(render)
+                .is_some_and(|rendered| rendered == value)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Policy, TailSampler};
+    use crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest;
+    use crate::opentelemetry::proto::common::v1::{any_value, AnyValue, KeyValue};
+    use crate::opentelemetry::proto::resource::v1::Resource;
+    use crate::opentelemetry::proto::trace::v1::{
+        status::StatusCode, ResourceSpans, ScopeSpans, Span, Status,
+    };
+    use std::time::{Duration, Instant};
+
+    fn span(trace: u8, parent: bool, millis: u64, error: bool) -> Span {
+        Span {
+            trace_id: vec![trace; 16],
+            span_id: vec![trace; 8],
+            parent_span_id: if parent { vec![1; 8] } else { Vec::new() },
+            start_time_unix_nano: 0,
+            end_time_unix_nano: millis * 1_000_000,
+            status: error.then(|| Status {
+                message: String::new(),
+                code: StatusCode::Error as i32,
+            }),
+            ..Span::default()
+        }
+    }
+
+    fn request(service: &str, spans: Vec<Span>) -> ExportTraceServiceRequest {
+        ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: Some(Resource {
+                    attributes: vec![KeyValue {
+                        key: "service.name".to_string(),
+                        value: Some(AnyValue {
+                            value: Some(any_value::Value::StringValue(service.to_string())),
+                        }),
+                    }],
+                    dropped_attributes_count: 0,
+                }),
+                scope_spans: vec![ScopeSpans {
+                    scope: None,
+                    spans,
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    fn trace_ids(request: &ExportTraceServiceRequest) -> Vec<u8> {
+        let mut ids: Vec<u8> = request
+            .spans()
+            .filter_map(|(_, _, s)| s.trace_id.first().copied())
+            .collect();
+        ids.dedup();
+        ids
+    }
+
+    #[test]
+    pub fn keeps_error_and_slow_traces() {
+        let start = Instant::now();
+        let wait = Duration::from_secs(5);
+        let mut sampler = TailSampler::new()
+            .with_decision_wait(wait)
+            .with_policy(Policy::StatusError)
+            .with_policy(Policy::Latency(Duration::from_millis(500)));
+        sampler.ingest(
+            request(
+                "a",
+                vec![span(1, true, 10, true), span(2, false, 10, false)],
+            ),
+            start,
+        );
+        sampler.ingest(
+            request(
+                "a",
+                vec![span(1, false, 20, false), span(3, false, 900, false)],
+            ),
+            start + Duration::from_secs(1),
+        );
+        assert_eq!(sampler.pending_traces(), 3);
+        assert!(sampler.poll(start + Duration::from_secs(4)).is_none());
+        let kept = sampler.poll(start + Duration::from_secs(6));
+        assert_eq!(kept.as_ref().map(trace_ids), Some(vec![1, 3]));
+        assert_eq!(kept.map(|k| k.span_count()), Some(3));
+        assert_eq!(sampler.stats().traces_kept, 2);
+        assert_eq!(sampler.stats().traces_dropped, 1);
+
+        // late spans follow the earlier decision
+        sampler.ingest(
+            request("a", vec![span(1, true, 1, false), span(2, true, 1, false)]),
+            start + Duration::from_secs(7),
+        );
+        let late = sampler.poll(start + Duration::from_secs(7));
+        assert_eq!(late.as_ref().map(trace_ids), Some(vec![1]));
+        assert_eq!(sampler.stats().late_spans_dropped, 1);
+    }
+
+    #[test]
+    pub fn attribute_policy_and_rate_limit() {
+        let start = Instant::now();
+        let mut sampler = TailSampler::new()
+            .with_policy(Policy::Attribute {
+                key: "service.name".to_string(),
+                value: "checkout".to_string(),
+            })
+            .with_rate_limit(2);
+        let spans = (1..=4).map(|t| span(t, false, 1, false)).collect();
+        sampler.ingest(request("checkout", spans), start);
+        sampler.ingest(request("cart", vec![span(5, false, 1, false)]), start);
+        let kept = sampler.flush(start);
+        assert_eq!(kept.as_ref().map(trace_ids), Some(vec![1, 2]));
+        assert_eq!(sampler.stats().traces_dropped, 3);
+    }
+
+    #[test]
+    pub fn evicts_oldest_traces() {
+        let start = Instant::now();
+        let mut sampler = TailSampler::new().with_max_traces(2).with_max_spans(3);
+        sampler.ingest(request("a", vec![span(1, false, 1, false)]), start);
+        sampler.ingest(request("a", vec![span(2, false, 1, false)]), start);
+        sampler.ingest(request("a", vec![span(3, false, 1, false)]), start);
+        assert_eq!(sampler.pending_traces(), 2);
+        sampler.ingest(
+            request("a", vec![span(3, true, 1, false), span(3, true, 1, false)]),
+            start,
+        );
+        assert_eq!(sampler.pending_traces(), 1);
+        assert_eq!(sampler.pending_spans(), 3);
+        assert_eq!(sampler.stats().traces_evicted, 2);
+        let kept = sampler.flush(start);
+        assert_eq!(kept.as_ref().map(trace_ids), Some(vec![3]));
+    }
+
+    #[test]
+    pub fn bounds_late_spans() {
+        let start = Instant::now();
+        let mut sampler = TailSampler::new().with_max_spans(3);
+        sampler.ingest(request("a", vec![span(1, false, 1, false)]), start);
+        assert!(sampler.flush(start).is_some());
+
+        // late spans of the kept trace are queued up to the span bound
+        let late = (0..5).map(|_| span(1, true, 1, false)).collect();
+        sampler.ingest(request("a", late), start);
+        sampler.ingest(request("a", vec![span(1, true, 1, false)]), start);
+        sampler.ingest(
+            request("a", vec![span(1, true, 1, false), span(1, true, 1, false)]),
+            start,
+        );
+        assert_eq!(sampler.stats().late_spans_dropped, 5);
+        let late = sampler.poll(start);
+        assert_eq!(late.map(|l| l.span_count()), Some(3));
+
+        // polling frees the bound again
+        sampler.ingest(request("a", vec![span(1, true, 1, false)]), start);
+        assert_eq!(sampler.poll(start).map(|l| l.span_count()), Some(1));
+        assert_eq!(sampler.stats().late_spans_dropped, 5);
+    }
+}
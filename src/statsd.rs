@@ -0,0 +1,683 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ingestion of StatsD and DogStatsD metrics.
+//!
+//! Lines are parsed with [`parse_line`] and aggregated over a flush interval by
+//! an [`Aggregator`]. Counters become delta monotonic sums, gauges become
+//! gauges, timers, histograms and distributions become delta exponential
+//! histograms and sets become gauges of the number of unique values. DogStatsD
+//! tags become attributes, tags without a value get an empty one. Events and
+//! service checks are ignored.
+//!
+//! A [`StatsdReceiver`] listens on a UDP socket and delivers the aggregated
+//! metrics through any metrics service, such as the metrics forwarders of the
+//! `metrics` and `all` channels.
+
+use crate::common::nanos;
+use crate::common::receiver::{ReceiverError, MAX_DATAGRAM};
+use crate::metrics::MetricsService;
+use crate::opentelemetry::proto::collector::metrics::v1::ExportMetricsServiceRequest;
+use crate::opentelemetry::proto::common::v1::{
+    any_value, AnyValue, InstrumentationScope, KeyValue,
+};
+use crate::opentelemetry::proto::metrics::v1::{
+    metric::Data, number_data_point, AggregationTemporality, ExponentialHistogram,
+    ExponentialHistogramDataPoint, Gauge, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+    Sum,
+};
+use crate::opentelemetry::proto::resource::v1::Resource;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+
+/// The interval metrics are aggregated over unless configured otherwise
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The number of flushes a gauge is remembered for without being updated,
+/// unless configured otherwise
+pub const DEFAULT_GAUGE_EXPIRY: u64 = 10;
+
+/// The scale histograms start out at before being downscaled to fit
+const HISTOGRAM_SCALE: i32 = 20;
+
+/// The kind of a StatsD metric
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum StatsdKind {
+    /// `c`, added up over the flush interval
+    Counter,
+    /// `g`, the last value, values with a sign are relative to the previous one
+    Gauge,
+    /// `ms`, a duration in milliseconds
+    Timer,
+    /// `h`, a sampled value
+    Histogram,
+    /// `d`, a DogStatsD distribution
+    Distribution,
+    /// `s`, a value counted once per flush interval
+    Set,
+}
+
+/// A parsed StatsD metric value
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsdMetric {
+    /// The name of the metric
+    pub name: String,
+    /// The kind of the metric
+    pub kind: StatsdKind,
+    /// The values of the metric, DogStatsD allows several per line
+    pub values: Vec<StatsdValue>,
+    /// The rate the values were sampled at, in `(0, 1]`
+    pub sample_rate: f64,
+    /// The DogStatsD tags as names and values
+    pub tags: Vec<(String, String)>,
+    /// The DogStatsD timestamp in seconds since the epoch
+    pub timestamp: Option<u64>,
+}
+
+/// A StatsD value
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatsdValue {
+    /// A number, gauge values with a leading sign are relative
+    Number {
+        /// The value
+        value: f64,
+        /// Whether the value is relative to the previous gauge value
+        relative: bool,
+    },
+    /// A set member
+    Member(String),
+}
+
+/// An error parsing a StatsD line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsdParseError {
+    /// What is wrong with the line
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for StatsdParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid statsd line: {}", self.reason)
+    }
+}
+
+impl std::error::Error for StatsdParseError {}
+
+fn invalid(reason: &'static str) -> StatsdParseError {
+    StatsdParseError { reason }
+}
+
+fn parse_kind(kind: &str) -> Result<StatsdKind, StatsdParseError> {
+    match kind {
+        "c" => Ok(StatsdKind::Counter),
+        "g" => Ok(StatsdKind::Gauge),
+        "ms" => Ok(StatsdKind::Timer),
+        "h" => Ok(StatsdKind::Histogram),
+        "d" => Ok(StatsdKind::Distribution),
+        "s" => Ok(StatsdKind::Set),
+        _ => Err(invalid("unknown metric type")),
+    }
+}
+
+fn parse_value(value: &str, kind: StatsdKind) -> Result<StatsdValue, StatsdParseError> {
+    if kind == StatsdKind::Set {
+        return Ok(StatsdValue::Member(value.to_string()));
+    }
+    let number: f64 = value.parse().map_err(|_| invalid("invalid value"))?;
+    if !number.is_finite() {
+        return Err(invalid("invalid value"));
+    }
+    Ok(StatsdValue::Number {
+        value: number,
+        relative: kind == StatsdKind::Gauge && value.starts_with(['+', '-']),
+    })
+}
+
+/// Parses a StatsD or DogStatsD line such as
+/// `page.views:1|c|@0.5|#env:prod,canary`. Events and service checks parse
+/// to `None`.
+pub fn parse_line(line: &str) -> Result<Option<StatsdMetric>, StatsdParseError> {
+    let line = line.trim();
+    if line.starts_with("_e{") || line.starts_with("_sc|") {
+        return Ok(None);
+    }
+    let (name, rest) = line.split_once(':').ok_or(invalid("missing value"))?;
+    if name.is_empty() {
+        return Err(invalid("missing name"));
+    }
+    let mut sections = rest.split('|');
+    let values = sections.next().unwrap_or_default();
+    let kind = parse_kind(sections.next().ok_or(invalid("missing metric type"))?)?;
+    let values = if kind == StatsdKind::Set {
+        vec![parse_value(values, kind)?]
+    } else {
+        values
+            .split(':')
+            .map(|value| parse_value(value, kind))
+            .collect::<Result<_, _>>()?
+    };
+    let mut metric = StatsdMetric {
+        name: name.to_string(),
+        kind,
+        values,
+        sample_rate: 1.0,
+        tags: Vec::new(),
+        timestamp: None,
+    };
+    for section in sections {
+        if let Some(rate) = section.strip_prefix('@') {
+            let rate: f64 = rate.parse().map_err(|_| invalid("invalid sample rate"))?;
+            if !(rate > 0.0 && rate <= 1.0) {
+                return Err(invalid("invalid sample rate"));
+            }
+            metric.sample_rate = rate;
+        } else if let Some(tags) = section.strip_prefix('#') {
+            for tag in tags.split(',').filter(|t| !t.is_empty()) {
+                let (name, value) = tag.split_once(':').unwrap_or((tag, ""));
+                metric.tags.push((name.to_string(), value.to_string()));
+            }
+        } else if let Some(container) = section.strip_prefix("c:") {
+            metric
+                .tags
+                .push(("container.id".to_string(), container.to_string()));
+        } else if let Some(timestamp) = section.strip_prefix('T') {
+            let timestamp = timestamp
+                .parse()
+                .map_err(|_| invalid("invalid timestamp"))?;
+            metric.timestamp = Some(timestamp);
+        }
+        // unknown extensions are ignored for forward compatibility
+    }
+    Ok(Some(metric))
+}
+
+/// Identifies an aggregated stream
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct StreamKey {
+    name: String,
+    kind: StatsdKind,
+    tags: Vec<(String, String)>,
+}
+
+/// The state of an aggregated stream
+enum Aggregate {
+    Sum(f64),
+    Gauge(f64),
+    Histogram(ExponentialHistogramDataPoint),
+    Set(HashSet<String>),
+}
+
+/// Aggregates StatsD metrics over flush intervals
+pub struct Aggregator {
+    resource: Resource,
+    start: SystemTime,
+    streams: BTreeMap<StreamKey, (Aggregate, Option<u64>)>,
+    /// The last value of every gauge and the number of flushes preceding its
+    /// last update
+    gauges: HashMap<StreamKey, (f64, u64)>,
+    flushes: u64,
+    gauge_expiry: u64,
+}
+
+// whole numbers are reported as integers, which is what StatsD clients send
+#[allow(clippy::float_cmp, clippy::cast_possible_truncation)]
+fn number(value: f64) -> number_data_point::Value {
+    if value.fract() == 0.0 && value.abs() < 9_007_199_254_740_992.0 {
+        number_data_point::Value::AsInt(value as i64)
+    } else {
+        number_data_point::Value::AsDouble(value)
+    }
+}
+
+impl Aggregator {
+    /// Creates an aggregator whose first interval starts at `now`
+    pub fn new(resource: Resource, now: SystemTime) -> Self {
+        Aggregator {
+            resource,
+            start: now,
+            streams: BTreeMap::new(),
+            gauges: HashMap::new(),
+            flushes: 0,
+            gauge_expiry: DEFAULT_GAUGE_EXPIRY,
+        }
+    }
+
+    /// Sets the number of flushes a gauge is remembered for without being
+    /// updated, relative updates to a forgotten gauge start from zero
+    pub fn with_gauge_expiry(mut self, flushes: u64) -> Self {
+        self.gauge_expiry = flushes;
+        self
+    }
+
+    /// The number of streams updated in the current interval
+    pub fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Adds a metric to the current interval
+    pub fn add(&mut self, metric: StatsdMetric) {
+        let mut tags = metric.tags;
+        tags.sort();
+        let key = StreamKey {
+            name: metric.name,
+            kind: metric.kind,
+            tags,
+        };
+        // sampled values stand for `1 / rate` values each
+        let weight = 1.0 / metric.sample_rate;
+        let previous_gauge = self.gauges.get(&key).map(|(gauge, _)| *gauge);
+        let (aggregate, timestamp) = self.streams.entry(key.clone()).or_insert_with(|| {
+            let aggregate = match key.kind {
+                StatsdKind::Counter => Aggregate::Sum(0.0),
+                StatsdKind::Gauge => Aggregate::Gauge(previous_gauge.unwrap_or_default()),
+                StatsdKind::Timer | StatsdKind::Histogram | StatsdKind::Distribution => {
+                    Aggregate::Histogram(ExponentialHistogramDataPoint {
+                        scale: HISTOGRAM_SCALE,
+                        ..ExponentialHistogramDataPoint::default()
+                    })
+                }
+                StatsdKind::Set => Aggregate::Set(HashSet::new()),
+            };
+            (aggregate, None)
+        });
+        if metric.timestamp.is_some() {
+            *timestamp = metric.timestamp;
+        }
+        for value in metric.values {
+            match (&mut *aggregate, value) {
+                (Aggregate::Sum(sum), StatsdValue::Number { value, .. }) => *sum += value * weight,
+                (Aggregate::Gauge(gauge), StatsdValue::Number { value, relative }) => {
+                    *gauge = if relative { *gauge + value } else { value };
+                    self.gauges.insert(key.clone(), (*gauge, self.flushes));
+                }
+                (Aggregate::Histogram(point), StatsdValue::Number { value, .. }) => {
+                    // rounding keeps rates that do not divide one unbiased overall
+                    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                    let count = weight.round().max(1.0) as u64;
+                    point.record(value, count);
+                }
+                (Aggregate::Set(members), StatsdValue::Member(member)) => {
+                    members.insert(member);
+                }
+                // values never mismatch the kind their stream was created for
+                (_, _) => (),
+            }
+        }
+    }
+
+    /// Ends the current interval, returning its metrics if any were added
+    pub fn flush(&mut self, now: SystemTime) -> Option<ExportMetricsServiceRequest> {
+        self.flushes += 1;
+        let (flushes, expiry) = (self.flushes, self.gauge_expiry);
+        // gauges updated in the interval just ended are not idle
+        self.gauges
+            .retain(|_, (_, updated)| flushes - *updated - 1 <= expiry);
+        if self.streams.is_empty() {
+            self.start = now;
+            return None;
+        }
+        let start_time_unix_nano = nanos(self.start);
+        let time_unix_nano = nanos(now);
+        self.start = now;
+        let mut metrics: Vec<Metric> = Vec::new();
+        for (key, (aggregate, timestamp)) in std::mem::take(&mut self.streams) {
+            let attributes = key
+                .tags
+                .into_iter()
+                .map(|(key, value)| KeyValue {
+                    key,
+                    value: Some(AnyValue {
+                        value: Some(any_value::Value::StringValue(value)),
+                    }),
+                })
+                .collect();
+            let time_unix_nano =
+                timestamp.map_or(time_unix_nano, |t| t.saturating_mul(1_000_000_000));
+            let number_point = |value: f64, attributes| NumberDataPoint {
+                attributes,
+                start_time_unix_nano,
+                time_unix_nano,
+                value: Some(number(value)),
+                ..NumberDataPoint::default()
+            };
+            let data = match aggregate {
+                Aggregate::Sum(sum) => Data::Sum(Sum {
+                    data_points: vec![number_point(sum, attributes)],
+                    aggregation_temporality: AggregationTemporality::Delta as i32,
+                    is_monotonic: true,
+                }),
+                Aggregate::Gauge(gauge) => Data::Gauge(Gauge {
+                    data_points: vec![number_point(gauge, attributes)],
+                }),
+                // the number of members is far below 2^53
+                #[allow(clippy::cast_precision_loss)]
+                Aggregate::Set(members) => Data::Gauge(Gauge {
+                    data_points: vec![number_point(members.len() as f64, attributes)],
+                }),
+                Aggregate::Histogram(point) => Data::ExponentialHistogram(ExponentialHistogram {
+                    data_points: vec![ExponentialHistogramDataPoint {
+                        attributes,
+                        start_time_unix_nano,
+                        time_unix_nano,
+                        ..point
+                    }],
+                    aggregation_temporality: AggregationTemporality::Delta as i32,
+                }),
+            };
+            let unit = if key.kind == StatsdKind::Timer {
+                "ms"
+            } else {
+                ""
+            };
+            // streams with the same name and kind share a metric
+            match metrics.last_mut() {
+                Some(last)
+                    if last.name == key.name
+                        && last.unit == unit
+                        && last.data.as_ref().map(std::mem::discriminant)
+                            == Some(std::mem::discriminant(&data)) =>
+                {
+                    match (&mut last.data, data) {
+                        (Some(Data::Sum(a)), Data::Sum(b)) => a.data_points.extend(b.data_points),
+                        (Some(Data::Gauge(a)), Data::Gauge(b)) => {
+                            a.data_points.extend(b.data_points);
+                        }
+                        (Some(Data::ExponentialHistogram(a)), Data::ExponentialHistogram(b)) => {
+                            a.data_points.extend(b.data_points);
+                        }
+                        (_, _) => (),
+                    }
+                }
+                _ => metrics.push(Metric {
+                    name: key.name,
+                    unit: unit.to_string(),
+                    data: Some(data),
+                    ..Metric::default()
+                }),
+            }
+        }
+        Some(ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(self.resource.clone()),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: "statsd".to_string(),
+                        ..InstrumentationScope::default()
+                    }),
+                    metrics,
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        })
+    }
+}
+
+/// An error receiving StatsD metrics, the socket failed or the aggregated
+/// metrics could not be delivered
+pub type StatsdError = ReceiverError;
+
+/// Receives StatsD and DogStatsD datagrams on a UDP socket, aggregates them
+/// and delivers the metrics through a metrics service on every flush
+pub struct StatsdReceiver<S> {
+    socket: UdpSocket,
+    service: S,
+    interval: Duration,
+    aggregator: Mutex<Aggregator>,
+    malformed: AtomicU64,
+}
+
+impl<S: MetricsService> StatsdReceiver<S> {
+    /// Binds a receiver to a UDP address
+    pub async fn bind<A: ToSocketAddrs>(address: A, service: S) -> std::io::Result<Self> {
+        Ok(StatsdReceiver {
+            socket: UdpSocket::bind(address).await?,
+            service,
+            interval: DEFAULT_FLUSH_INTERVAL,
+            aggregator: Mutex::new(Aggregator::new(Resource::default(), SystemTime::now())),
+            malformed: AtomicU64::new(0),
+        })
+    }
+
+    /// Sets the interval metrics are aggregated over
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the resource the metrics are reported for
+    pub fn with_resource(self, resource: Resource) -> Self {
+        self.aggregator().resource = resource;
+        self
+    }
+
+    /// Sets the number of flushes a gauge is remembered for without being
+    /// updated
+    pub fn with_gauge_expiry(self, flushes: u64) -> Self {
+        self.aggregator().gauge_expiry = flushes;
+        self
+    }
+
+    /// The address the receiver is bound to
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The number of lines dropped because they could not be parsed
+    pub fn malformed_lines(&self) -> u64 {
+        self.malformed.load(Ordering::Relaxed)
+    }
+
+    fn aggregator(&self) -> MutexGuard<'_, Aggregator> {
+        self.aggregator
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Aggregates the lines of a datagram
+    pub fn ingest(&self, datagram: &[u8]) {
+        let text = String::from_utf8_lossy(datagram);
+        let mut aggregator = self.aggregator();
+        let mut malformed = 0;
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            match parse_line(line) {
+                Ok(Some(metric)) => aggregator.add(metric),
+                Ok(None) => (),
+                Err(_) => malformed += 1,
+            }
+        }
+        self.malformed.fetch_add(malformed, Ordering::Relaxed);
+    }
+
+    /// Delivers the metrics aggregated so far
+    pub async fn flush(&self) -> Result<(), StatsdError> {
+        let request = self.aggregator().flush(SystemTime::now());
+        if let Some(request) = request {
+            self.service.export(tonic::Request::new(request)).await?;
+        }
+        Ok(())
+    }
+
+    /// Receives datagrams and delivers the metrics aggregated from them on
+    /// every interval. Returns once the socket fails or a flush is not
+    /// accepted, as happens when the metrics channel was closed.
+    pub async fn run(&self) -> Result<Infallible, StatsdError> {
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        let mut next_flush = tokio::time::Instant::now() + self.interval;
+        loop {
+            match tokio::time::timeout_at(next_flush, self.socket.recv_from(&mut buffer)).await {
+                Ok(received) => {
+                    let (length, _) = received?;
+                    self.ingest(buffer.get(..length).unwrap_or_default());
+                }
+                Err(_elapsed) => {
+                    self.flush().await?;
+                    next_flush += self.interval;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::metrics::OtelMetricsServiceForwarder;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    pub fn parse_lines() -> Result<(), StatsdParseError> {
+        let metric = parse_line("page.views:2|c|@0.5|#env:prod,canary|c:abc|T1700000000")?;
+        assert_eq!(
+            metric,
+            Some(StatsdMetric {
+                name: "page.views".to_string(),
+                kind: StatsdKind::Counter,
+                values: vec![StatsdValue::Number {
+                    value: 2.0,
+                    relative: false
+                }],
+                sample_rate: 0.5,
+                tags: vec![
+                    ("env".to_string(), "prod".to_string()),
+                    ("canary".to_string(), String::new()),
+                    ("container.id".to_string(), "abc".to_string()),
+                ],
+                timestamp: Some(1_700_000_000),
+            })
+        );
+        let gauge = parse_line("queue:-3|g")?.map(|m| m.values);
+        assert_eq!(
+            gauge,
+            Some(vec![StatsdValue::Number {
+                value: -3.0,
+                relative: true
+            }])
+        );
+        assert_eq!(
+            parse_line("latency:1:2:3|ms")?.map(|m| m.values.len()),
+            Some(3)
+        );
+        assert_eq!(parse_line("_e{5,4}:title|text")?, None);
+        assert_eq!(parse_line("nope|c"), Err(invalid("missing value")));
+        assert_eq!(parse_line("x:1|q"), Err(invalid("unknown metric type")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    pub async fn receive_and_flush() -> Result<(), Box<dyn std::error::Error>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let service = OtelMetricsServiceForwarder::with_sender(tx);
+        let receiver = StatsdReceiver::bind("127.0.0.1:0", service).await?;
+        let address = receiver.local_addr()?;
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        client
+            .send_to(
+                b"hits:1|c|#route:a\nhits:1|c|@0.5|#route:a\nq:5|g\nq:+2|g\nt:10|ms\nt:20|ms\nu:x|s\nu:x|s\nbad",
+                address,
+            )
+            .await?;
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        let (length, _) = receiver.socket.recv_from(&mut buffer).await?;
+        receiver.ingest(buffer.get(..length).unwrap_or_default());
+        assert_eq!(receiver.malformed_lines(), 1);
+        receiver.flush().await?;
+        let request = rx.recv().await.ok_or("nothing delivered")?;
+        let metrics: Vec<Metric> = request
+            .resource_metrics
+            .into_iter()
+            .flat_map(|rm| rm.scope_metrics)
+            .flat_map(|sm| sm.metrics)
+            .collect();
+        let data = |name: &str| {
+            metrics
+                .iter()
+                .find(|m| m.name == name)
+                .and_then(|m| m.data.clone())
+        };
+        let value = |data: Option<Data>| match data {
+            Some(Data::Sum(Sum { data_points, .. }) | Data::Gauge(Gauge { data_points })) => {
+                data_points.first().and_then(|p| p.value)
+            }
+            _ => None,
+        };
+        assert_eq!(
+            value(data("hits")),
+            Some(number_data_point::Value::AsInt(3))
+        );
+        assert_eq!(value(data("q")), Some(number_data_point::Value::AsInt(7)));
+        assert_eq!(value(data("u")), Some(number_data_point::Value::AsInt(1)));
+        let Some(Data::ExponentialHistogram(h)) = data("t") else {
+            panic!("expected an exponential histogram");
+        };
+        let point = h.data_points.first().cloned().unwrap_or_default();
+        assert_eq!(point.count, 2);
+        assert_eq!(point.sum, Some(30.0));
+
+        // nothing was added since, so nothing is delivered
+        receiver.flush().await?;
+        assert!(rx.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    pub fn expire_idle_gauges() -> Result<(), StatsdParseError> {
+        let gauge = |aggregator: &mut Aggregator| {
+            aggregator
+                .flush(UNIX_EPOCH)
+                .into_iter()
+                .flat_map(|r| r.resource_metrics)
+                .flat_map(|rm| rm.scope_metrics)
+                .flat_map(|sm| sm.metrics)
+                .find_map(|m| match m.data {
+                    Some(Data::Gauge(g)) => g.data_points.first().and_then(|p| p.value),
+                    _ => None,
+                })
+        };
+        let mut aggregator = Aggregator::new(Resource::default(), UNIX_EPOCH).with_gauge_expiry(1);
+        parse_line("q:5|g")?
+            .into_iter()
+            .for_each(|m| aggregator.add(m));
+        assert_eq!(
+            gauge(&mut aggregator),
+            Some(number_data_point::Value::AsInt(5))
+        );
+        // remembered over one idle flush
+        assert_eq!(gauge(&mut aggregator), None);
+        parse_line("q:+1|g")?
+            .into_iter()
+            .for_each(|m| aggregator.add(m));
+        assert_eq!(
+            gauge(&mut aggregator),
+            Some(number_data_point::Value::AsInt(6))
+        );
+        // forgotten after two
+        assert_eq!(gauge(&mut aggregator), None);
+        assert_eq!(gauge(&mut aggregator), None);
+        assert!(aggregator.gauges.is_empty());
+        parse_line("q:+1|g")?
+            .into_iter()
+            .for_each(|m| aggregator.add(m));
+        assert_eq!(
+            gauge(&mut aggregator),
+            Some(number_data_point::Value::AsInt(1))
+        );
+        Ok(())
+    }
+}
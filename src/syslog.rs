@@ -0,0 +1,407 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ingestion of RFC 5424 and RFC 3164 syslog messages as OTLP logs.
+//!
+//! Messages are parsed with [`parse_message`] and converted with
+//! [`to_logs_request`]. The hostname and app name become the `host.name` and
+//! `service.name` resource attributes, the severity becomes the severity
+//! number and text, and the facility, version, process id, message id and
+//! structured data become `syslog.*` attributes of the log record. Structured
+//! data is kept as a map of maps keyed by SD-ID and parameter name.
+//!
+//! A [`SyslogReceiver`] listens over UDP, TCP or, with the `syslog-tls`
+//! feature, TLS and delivers the logs through any logs service, such as the
+//! logs forwarders of the `logs` and `all` channels. Stream transports accept
+//! both octet counting and newline framing, as described by RFC 6587.
+
+use crate::common::receiver::{ReceiverError, MAX_DATAGRAM};
+use crate::common::{any_string, key_value, nanos, string_value};
+use crate::logs::LogsService;
+use crate::opentelemetry::proto::collector::logs::v1::ExportLogsServiceRequest;
+use crate::opentelemetry::proto::common::v1::{
+    any_value, AnyValue, InstrumentationScope, KeyValue, KeyValueList,
+};
+use crate::opentelemetry::proto::logs::v1::{LogRecord, ResourceLogs, ScopeLogs, SeverityNumber};
+use crate::opentelemetry::proto::resource::v1::Resource;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, UdpSocket};
+
+mod parse;
+
+pub use parse::{parse_message, StructuredDataElement, SyslogMessage, SyslogParseError};
+
+/// The largest octet counted frame accepted, longer ones are taken to be
+/// newline framed, and the longest newline framed message, longer ones are
+/// split
+const MAX_FRAME: usize = 1024 * 1024;
+
+fn kvlist_value(values: Vec<KeyValue>) -> AnyValue {
+    AnyValue {
+        value: Some(any_value::Value::KvlistValue(KeyValueList { values })),
+    }
+}
+
+impl SyslogMessage {
+    /// The resource the message originates from
+    pub fn resource(&self) -> Resource {
+        let mut attributes = Vec::new();
+        if let Some(hostname) = &self.hostname {
+            attributes.push(string_value("host.name", hostname));
+        }
+        if let Some(app_name) = &self.app_name {
+            attributes.push(string_value("service.name", app_name));
+        }
+        Resource {
+            attributes,
+            ..Resource::default()
+        }
+    }
+
+    /// The log record of the message, observed at `observed_time_unix_nano`
+    pub fn to_log_record(&self, observed_time_unix_nano: u64) -> LogRecord {
+        let int_value = |i| AnyValue {
+            value: Some(any_value::Value::IntValue(i)),
+        };
+        let mut attributes = vec![key_value(
+            "syslog.facility",
+            int_value(i64::from(self.facility)),
+        )];
+        if let Some(version) = self.version {
+            attributes.push(key_value("syslog.version", int_value(i64::from(version))));
+        }
+        if let Some(proc_id) = &self.proc_id {
+            attributes.push(string_value("syslog.procid", proc_id));
+        }
+        if let Some(msg_id) = &self.msg_id {
+            attributes.push(string_value("syslog.msgid", msg_id));
+        }
+        if !self.structured_data.is_empty() {
+            let elements = self
+                .structured_data
+                .iter()
+                .map(|element| {
+                    let params = element
+                        .params
+                        .iter()
+                        .map(|(name, value)| string_value(name, value))
+                        .collect();
+                    key_value(&element.id, kvlist_value(params))
+                })
+                .collect();
+            attributes.push(key_value("syslog.structured_data", kvlist_value(elements)));
+        }
+        let mut record = LogRecord {
+            time_unix_nano: self.timestamp.unwrap_or_default(),
+            observed_time_unix_nano,
+            severity_text: self.severity.keyword().to_string(),
+            body: Some(any_string(&self.message)),
+            attributes,
+            ..LogRecord::default()
+        };
+        record.set_severity_number(SeverityNumber::from(self.severity));
+        record
+    }
+}
+
+/// Converts messages into a logs request with a resource per hostname and app
+/// name, all observed at `now`
+pub fn to_logs_request(messages: &[SyslogMessage], now: SystemTime) -> ExportLogsServiceRequest {
+    let observed = nanos(now);
+    let mut resources: BTreeMap<_, Vec<&SyslogMessage>> = BTreeMap::new();
+    for message in messages {
+        let key = (message.hostname.as_deref(), message.app_name.as_deref());
+        resources.entry(key).or_default().push(message);
+    }
+    ExportLogsServiceRequest {
+        resource_logs: resources
+            .into_values()
+            .map(|messages| ResourceLogs {
+                resource: messages.first().map(|message| message.resource()),
+                scope_logs: vec![ScopeLogs {
+                    scope: Some(InstrumentationScope {
+                        name: "syslog".to_string(),
+                        ..InstrumentationScope::default()
+                    }),
+                    log_records: messages
+                        .iter()
+                        .map(|message| message.to_log_record(observed))
+                        .collect(),
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            })
+            .collect(),
+    }
+}
+
+/// Takes the complete frames off the front of a stream buffer. Frames starting
+/// with a digit are octet counted, `LENGTH SP MESSAGE`, all others end at a
+/// newline, or are split once longer than the largest frame. At the end of
+/// the stream the rest of the buffer is a frame.
+fn take_frames(buffer: &mut Vec<u8>, eof: bool) -> Vec<String> {
+    let mut frames = Vec::new();
+    let mut start = 0;
+    loop {
+        let rest = buffer.get(start..).unwrap_or_default();
+        let skipped = rest
+            .iter()
+            .take_while(|b| matches!(b, b'\n' | b'\r' | b'\0'))
+            .count();
+        start += skipped;
+        let rest = rest.get(skipped..).unwrap_or_default();
+        if rest.is_empty() {
+            break;
+        }
+        let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+        let length = std::str::from_utf8(rest.get(..digits).unwrap_or_default())
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+            .filter(|length| *length <= MAX_FRAME);
+        let frame = match (length, rest.get(digits)) {
+            (Some(length), Some(b' ')) => {
+                let end = digits + 1 + length;
+                match rest.get(digits + 1..end) {
+                    Some(frame) => {
+                        start += end;
+                        frame
+                    }
+                    None => break,
+                }
+            }
+            // the length may still be incomplete
+            (Some(_), None) => break,
+            _ => match rest.iter().position(|b| *b == b'\n') {
+                Some(newline) if newline <= MAX_FRAME => {
+                    start += newline + 1;
+                    rest.get(..newline).unwrap_or_default()
+                }
+                _ if rest.len() > MAX_FRAME => {
+                    start += MAX_FRAME;
+                    rest.get(..MAX_FRAME).unwrap_or_default()
+                }
+                _ => break,
+            },
+        };
+        frames.push(String::from_utf8_lossy(frame).into_owned());
+    }
+    if eof {
+        let rest = buffer.get(start..).unwrap_or_default();
+        if !rest.is_empty() {
+            frames.push(String::from_utf8_lossy(rest).into_owned());
+        }
+        start = buffer.len();
+    }
+    buffer.drain(..start);
+    frames
+}
+
+/// An error receiving syslog messages, the socket failed or the converted logs
+/// could not be delivered
+pub type SyslogError = ReceiverError;
+
+/// Receives syslog messages and delivers them as logs through a logs service.
+/// Every datagram, or every read from a connection, is delivered as one
+/// request. Malformed messages are dropped and counted.
+pub struct SyslogReceiver<S> {
+    service: S,
+    malformed: AtomicU64,
+}
+
+impl<S: LogsService> SyslogReceiver<S> {
+    /// Creates a receiver delivering through a logs service
+    pub fn new(service: S) -> Self {
+        SyslogReceiver {
+            service,
+            malformed: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of messages dropped because they could not be parsed
+    pub fn malformed_messages(&self) -> u64 {
+        self.malformed.load(Ordering::Relaxed)
+    }
+
+    /// Converts messages and delivers the valid ones
+    pub async fn ingest<I, T>(&self, messages: I) -> Result<(), SyslogError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        let now = SystemTime::now();
+        let mut parsed = Vec::new();
+        for message in messages {
+            match parse_message(message.as_ref(), now) {
+                Ok(message) => parsed.push(message),
+                Err(_) => {
+                    self.malformed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        if parsed.is_empty() {
+            return Ok(());
+        }
+        self.service
+            .export(tonic::Request::new(to_logs_request(&parsed, now)))
+            .await?;
+        Ok(())
+    }
+
+    /// Receives datagrams, delivering each as one message. Returns once the
+    /// socket fails or a message is not accepted, as happens when the logs
+    /// channel was closed.
+    pub async fn serve_udp(&self, socket: UdpSocket) -> Result<Infallible, SyslogError> {
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        loop {
+            let (length, _) = socket.recv_from(&mut buffer).await?;
+            let datagram = buffer.get(..length).unwrap_or_default();
+            self.ingest([String::from_utf8_lossy(datagram)]).await?;
+        }
+    }
+
+    /// Receives octet counted or newline framed messages from a connection
+    /// until it is closed
+    pub async fn serve_connection<R>(&self, mut stream: R) -> Result<(), SyslogError>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let mut buffer: Vec<u8> = Vec::new();
+        loop {
+            let read = stream.read_buf(&mut buffer).await?;
+            let frames = take_frames(&mut buffer, read == 0);
+            self.ingest(frames).await?;
+            if read == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Accepts connections and receives messages from each until accepting
+    /// fails. Connections whose logs cannot be delivered are closed.
+    pub async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> std::io::Result<Infallible>
+    where
+        S: Send + Sync + 'static,
+    {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let receiver = self.clone();
+            tokio::spawn(async move { receiver.serve_connection(stream).await });
+        }
+    }
+
+    /// Accepts TLS connections and receives messages from each until accepting
+    /// fails. Connections failing the handshake, or whose logs cannot be
+    /// delivered, are closed.
+    #[cfg(feature = "syslog-tls")]
+    pub async fn serve_tls(
+        self: Arc<Self>,
+        listener: TcpListener,
+        acceptor: tokio_rustls::TlsAcceptor,
+    ) -> std::io::Result<Infallible> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let receiver = self.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let stream = acceptor.accept(stream).await?;
+                receiver.serve_connection(stream).await
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::logs::OtelLogsServiceForwarder;
+    use crate::opentelemetry::proto::logs::v1::SeverityNumber;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    pub fn framing() {
+        let mut buffer = b"11 <13>1 - - -\n<14>hello\r\n5 <13>".to_vec();
+        assert_eq!(
+            take_frames(&mut buffer, false),
+            vec!["<13>1 - - -", "<14>hello\r"]
+        );
+        assert_eq!(buffer, b"5 <13>".to_vec());
+        buffer.extend_from_slice(b"x\n<15>tail");
+        assert_eq!(take_frames(&mut buffer, true), vec!["<13>x", "<15>tail"]);
+        assert!(buffer.is_empty());
+
+        // newline framed messages are split at the largest frame
+        let mut buffer = vec![b'a'; MAX_FRAME + 2];
+        let frames = take_frames(&mut buffer, false);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames.first().map(String::len), Some(MAX_FRAME));
+        assert_eq!(buffer, b"aa".to_vec());
+        buffer.extend_from_slice(b"\n");
+        assert_eq!(take_frames(&mut buffer, false), vec!["aa"]);
+    }
+
+    #[tokio::test]
+    pub async fn receive_messages() -> Result<(), Box<dyn std::error::Error>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        let service = OtelLogsServiceForwarder::with_sender(tx);
+        let receiver = Arc::new(SyslogReceiver::new(service));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(receiver.clone().serve_tcp(listener));
+
+        let message =
+            "<165>1 2003-10-11T22:14:15.003Z host app 42 ID47 [origin ip=\"10.0.0.1\"] hi";
+        let mut client = tokio::net::TcpStream::connect(address).await?;
+        client
+            .write_all(format!("{} {message}\nbroken\n", message.len()).as_bytes())
+            .await?;
+        client.shutdown().await?;
+        let request = rx.recv().await.ok_or("nothing delivered")?;
+        let resource_logs = request.resource_logs.first().ok_or("no resource")?;
+        let resource = resource_logs.resource.clone().unwrap_or_default();
+        let keys: Vec<&str> = resource.attributes.iter().map(|a| a.key.as_str()).collect();
+        assert_eq!(keys, vec!["host.name", "service.name"]);
+        let record = resource_logs
+            .scope_logs
+            .first()
+            .and_then(|sl| sl.log_records.first())
+            .ok_or("no record")?;
+        assert_eq!(record.severity_number(), SeverityNumber::Info2);
+        assert_eq!(record.severity_text, "notice");
+        assert_eq!(record.time_unix_nano, 1_065_910_455_003_000_000);
+        let keys: Vec<&str> = record.attributes.iter().map(|a| a.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "syslog.facility",
+                "syslog.version",
+                "syslog.procid",
+                "syslog.msgid",
+                "syslog.structured_data"
+            ]
+        );
+        assert_eq!(receiver.malformed_messages(), 1);
+
+        // a closed channel fails delivery
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        drop(rx);
+        let closed = SyslogReceiver::new(OtelLogsServiceForwarder::with_sender(tx));
+        assert!(closed.ingest(["<13>hello"]).await.is_err());
+        Ok(())
+    }
+}
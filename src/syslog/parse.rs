@@ -0,0 +1,411 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::common::{days_from_civil, parse_rfc3339, time_of_day, to_nanos, SECONDS_PER_DAY};
+use crate::logs::SyslogSeverity;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The month abbreviations of RFC 3164 timestamps
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// An error parsing a syslog message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogParseError {
+    /// What is wrong with the message
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for SyslogParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid syslog message: {}", self.reason)
+    }
+}
+
+impl std::error::Error for SyslogParseError {}
+
+impl From<&'static str> for SyslogParseError {
+    fn from(reason: &'static str) -> Self {
+        SyslogParseError { reason }
+    }
+}
+
+/// An RFC 5424 structured data element
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructuredDataElement {
+    /// The SD-ID, such as `timeQuality` or `origin@32473`
+    pub id: String,
+    /// The parameters in the order they were sent, names may repeat
+    pub params: Vec<(String, String)>,
+}
+
+/// A parsed syslog message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogMessage {
+    /// The facility code, 0 to 23
+    pub facility: u8,
+    /// The severity
+    pub severity: SyslogSeverity,
+    /// The protocol version of RFC 5424 messages, `None` for RFC 3164 ones
+    pub version: Option<u8>,
+    /// The time the message was created at, in nanoseconds since the epoch
+    pub timestamp: Option<u64>,
+    /// The host the message originates from
+    pub hostname: Option<String>,
+    /// The application, the `TAG` of RFC 3164 messages
+    pub app_name: Option<String>,
+    /// The process id
+    pub proc_id: Option<String>,
+    /// The type of the message, only sent in RFC 5424 messages
+    pub msg_id: Option<String>,
+    /// The structured data, only sent in RFC 5424 messages
+    pub structured_data: Vec<StructuredDataElement>,
+    /// The free form message
+    pub message: String,
+}
+
+/// Splits off the next space delimited token
+fn next_token(s: &str) -> (&str, &str) {
+    let s = s.trim_start_matches(' ');
+    s.split_once(' ').unwrap_or((s, ""))
+}
+
+/// The RFC 5424 NILVALUE, `-`, is no value
+fn nil(token: &str) -> Option<String> {
+    (token != "-" && !token.is_empty()).then(|| token.to_string())
+}
+
+/// The year of a number of days since the epoch
+fn year_from_days(days: i64) -> i64 {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // the year starts in March, January and February belong to the next one
+    let march_based_month = (5 * day_of_year + 2) / 153;
+    era * 400 + year_of_era + i64::from(march_based_month >= 10)
+}
+
+/// Parses an RFC 3164 timestamp, `Mmm dd hh:mm:ss`, in UTC. The year is not
+/// sent, it is taken to be the one that puts the timestamp closest before
+/// `now`, allowing for a day of clock skew.
+fn parse_rfc3164_timestamp(s: &str, now: SystemTime) -> Option<(u64, &str)> {
+    let (month, rest) = next_token(s);
+    let month = MONTHS.iter().position(|m| *m == month)?;
+    let (day, rest) = next_token(rest);
+    let day: i64 = day.parse().ok()?;
+    if !(1..=31).contains(&day) {
+        return None;
+    }
+    let (time, rest) = next_token(rest);
+    let (time, remainder) = time_of_day(time)?;
+    if !remainder.is_empty() {
+        return None;
+    }
+    let now = i64::try_from(now.duration_since(UNIX_EPOCH).ok()?.as_secs()).ok()?;
+    let month = i64::try_from(month).ok()? + 1;
+    let seconds_in = |year| days_from_civil(year, month, day) * SECONDS_PER_DAY + time;
+    let year = year_from_days(now.div_euclid(SECONDS_PER_DAY));
+    let mut seconds = seconds_in(year);
+    if seconds > now + SECONDS_PER_DAY {
+        seconds = seconds_in(year - 1);
+    }
+    Some((to_nanos(seconds, 0)?, rest))
+}
+
+/// Parses the priority, `<PRI>`, into the facility and severity
+fn parse_priority(s: &str) -> Result<(u8, SyslogSeverity, &str), SyslogParseError> {
+    let s = s.strip_prefix('<').ok_or("missing priority")?;
+    let (priority, rest) = s.split_once('>').ok_or("missing priority")?;
+    if priority.is_empty() || priority.len() > 3 || !priority.bytes().all(|b| b.is_ascii_digit()) {
+        return Err("invalid priority".into());
+    }
+    let priority: u8 = priority.parse().map_err(|_| "invalid priority")?;
+    if priority > 191 {
+        return Err("invalid priority".into());
+    }
+    let severity = SyslogSeverity::from_code(priority % 8).ok_or("invalid priority")?;
+    Ok((priority / 8, severity, rest))
+}
+
+/// Parses a structured data parameter value up to its closing quote
+fn parse_param_value(s: &str) -> Result<(String, &str), SyslogParseError> {
+    let mut value = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, escaped @ ('"' | '\\' | ']'))) => value.push(escaped),
+                // other backslashes are kept as they are
+                Some((_, other)) => {
+                    value.push('\\');
+                    value.push(other);
+                }
+                None => break,
+            },
+            '"' => return Ok((value, s.get(i + 1..).unwrap_or_default())),
+            c => value.push(c),
+        }
+    }
+    Err("unterminated structured data parameter".into())
+}
+
+/// Parses the structured data elements of an RFC 5424 message
+fn parse_structured_data(
+    mut s: &str,
+) -> Result<(Vec<StructuredDataElement>, &str), SyslogParseError> {
+    let mut elements = Vec::new();
+    if let Some(rest) = s.strip_prefix('-') {
+        return Ok((elements, rest));
+    }
+    while let Some(rest) = s.strip_prefix('[') {
+        let end = rest
+            .find([' ', ']'])
+            .ok_or("unterminated structured data")?;
+        let (id, mut rest) = rest.split_at(end);
+        if id.is_empty() {
+            return Err("missing structured data id".into());
+        }
+        let mut params = Vec::new();
+        while let Some(param) = rest.strip_prefix(' ') {
+            let (name, value) = param
+                .split_once("=\"")
+                .ok_or("invalid structured data parameter")?;
+            if name.is_empty() || name.contains([' ', ']', '"']) {
+                return Err("invalid structured data parameter".into());
+            }
+            let (value, after) = parse_param_value(value)?;
+            params.push((name.to_string(), value));
+            rest = after;
+        }
+        s = rest
+            .strip_prefix(']')
+            .ok_or("unterminated structured data")?;
+        elements.push(StructuredDataElement {
+            id: id.to_string(),
+            params,
+        });
+    }
+    if elements.is_empty() {
+        return Err("invalid structured data".into());
+    }
+    Ok((elements, s))
+}
+
+/// Parses the part of an RFC 5424 message after the priority
+fn parse_rfc5424(
+    facility: u8,
+    severity: SyslogSeverity,
+    s: &str,
+) -> Result<SyslogMessage, SyslogParseError> {
+    let (version, s) = next_token(s);
+    let version: u8 = version.parse().map_err(|_| "invalid version")?;
+    let (timestamp, s) = next_token(s);
+    let timestamp = match timestamp {
+        "-" => None,
+        timestamp => Some(parse_rfc3339(timestamp).ok_or("invalid timestamp")?),
+    };
+    let (hostname, s) = next_token(s);
+    let (app_name, s) = next_token(s);
+    let (proc_id, s) = next_token(s);
+    let (msg_id, s) = next_token(s);
+    let (structured_data, s) = parse_structured_data(s.trim_start_matches(' '))?;
+    let message = match s.strip_prefix(' ') {
+        Some(message) => message.strip_prefix('\u{feff}').unwrap_or(message),
+        None if s.is_empty() => "",
+        None => return Err("invalid structured data".into()),
+    };
+    Ok(SyslogMessage {
+        facility,
+        severity,
+        version: Some(version),
+        timestamp,
+        hostname: nil(hostname),
+        app_name: nil(app_name),
+        proc_id: nil(proc_id),
+        msg_id: nil(msg_id),
+        structured_data,
+        message: message.to_string(),
+    })
+}
+
+/// Parses an RFC 3164 `TAG`, `app[pid]:` or `app:`, into the app name and
+/// process id
+fn parse_tag(token: &str) -> Option<(String, Option<String>)> {
+    let tag = token.strip_suffix(':')?;
+    let (app_name, proc_id) = match tag.strip_suffix(']') {
+        Some(tag) => {
+            let (app_name, proc_id) = tag.split_once('[')?;
+            (app_name, Some(proc_id.to_string()))
+        }
+        None => (tag, None),
+    };
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/');
+    (!app_name.is_empty() && app_name.chars().all(valid)).then(|| (app_name.to_string(), proc_id))
+}
+
+/// Parses the part of an RFC 3164 message after the priority. Parts that are
+/// missing or malformed are left out and the rest becomes the message, as
+/// RFC 3164 asks relays to do.
+fn parse_rfc3164(
+    facility: u8,
+    severity: SyslogSeverity,
+    s: &str,
+    now: SystemTime,
+) -> SyslogMessage {
+    let (timestamp, mut s) = match parse_rfc3164_timestamp(s, now) {
+        Some((timestamp, rest)) => (Some(timestamp), rest),
+        // some senders use RFC 3339 timestamps in the legacy format
+        None => match parse_rfc3339(next_token(s).0) {
+            Some(timestamp) => (Some(timestamp), next_token(s).1),
+            None => (None, s),
+        },
+    };
+    let mut hostname = None;
+    if timestamp.is_some() {
+        let (token, rest) = next_token(s);
+        if !token.is_empty() && parse_tag(token).is_none() && !rest.is_empty() {
+            hostname = Some(token.to_string());
+            s = rest;
+        }
+    }
+    let (app_name, proc_id) = match parse_tag(next_token(s).0) {
+        Some((app_name, proc_id)) => {
+            s = next_token(s).1;
+            (Some(app_name), proc_id)
+        }
+        None => (None, None),
+    };
+    SyslogMessage {
+        facility,
+        severity,
+        version: None,
+        timestamp,
+        hostname,
+        app_name,
+        proc_id,
+        msg_id: None,
+        structured_data: Vec::new(),
+        message: s.trim_start_matches(' ').to_string(),
+    }
+}
+
+/// Parses an RFC 5424 or RFC 3164 message, telling them apart by the version
+/// following the priority. RFC 3164 timestamps do not carry a year, it is
+/// inferred from `now`.
+pub fn parse_message(text: &str, now: SystemTime) -> Result<SyslogMessage, SyslogParseError> {
+    let text = text.trim_end_matches(['\r', '\n', '\0']);
+    let (facility, severity, rest) = parse_priority(text)?;
+    let (version, _) = rest.split_once(' ').unwrap_or((rest, ""));
+    if !version.is_empty() && version.len() <= 2 && version.bytes().all(|b| b.is_ascii_digit()) {
+        parse_rfc5424(facility, severity, rest)
+    } else {
+        Ok(parse_rfc3164(facility, severity, rest, now))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    pub fn rfc5424_messages() -> Result<(), SyslogParseError> {
+        let message = parse_message(
+            "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 \
+             [exampleSDID@32473 iut=\"3\" eventSource=\"Appl\\\"ication\"][examplePriority@32473 class=\"high\"] \
+             \u{feff}An application event",
+            UNIX_EPOCH,
+        )?;
+        assert_eq!(message.facility, 20);
+        assert_eq!(message.severity, SyslogSeverity::Notice);
+        assert_eq!(message.version, Some(1));
+        assert_eq!(message.timestamp, Some(1_065_910_455_003_000_000));
+        assert_eq!(message.hostname.as_deref(), Some("mymachine.example.com"));
+        assert_eq!(message.app_name.as_deref(), Some("evntslog"));
+        assert_eq!(message.proc_id, None);
+        assert_eq!(message.msg_id.as_deref(), Some("ID47"));
+        assert_eq!(message.structured_data.len(), 2);
+        assert_eq!(
+            message.structured_data.first().map(|e| e.params.clone()),
+            Some(vec![
+                ("iut".to_string(), "3".to_string()),
+                ("eventSource".to_string(), "Appl\"ication".to_string())
+            ])
+        );
+        assert_eq!(message.message, "An application event");
+
+        let message = parse_message("<34>1 2003-10-11T22:14:15+02:00 - su - - -", UNIX_EPOCH)?;
+        assert_eq!(message.timestamp, Some(1_065_903_255_000_000_000));
+        assert_eq!(message.hostname, None);
+        assert_eq!(message.message, "");
+
+        assert_eq!(
+            parse_message("<34>1 yesterday host app - - -", UNIX_EPOCH),
+            Err(SyslogParseError {
+                reason: "invalid timestamp"
+            })
+        );
+        assert_eq!(
+            parse_message("<34>1 - host app - - [id x=\"1]", UNIX_EPOCH),
+            Err(SyslogParseError {
+                reason: "unterminated structured data parameter"
+            })
+        );
+        assert_eq!(
+            parse_message("<192>1 - - - - - -", UNIX_EPOCH),
+            Err(SyslogParseError {
+                reason: "invalid priority"
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    pub fn rfc3164_messages() -> Result<(), SyslogParseError> {
+        // 2004-01-01T00:00:00Z
+        let now = UNIX_EPOCH + Duration::from_secs(1_072_915_200);
+        let message = parse_message(
+            "<34>Oct 11 22:14:15 mymachine su[123]: 'su root' failed for lonvick on /dev/pts/8",
+            now,
+        )?;
+        assert_eq!(message.facility, 4);
+        assert_eq!(message.severity, SyslogSeverity::Critical);
+        assert_eq!(message.version, None);
+        // the year is inferred from `now`
+        assert_eq!(message.timestamp, Some(1_065_910_455_000_000_000));
+        assert_eq!(message.hostname.as_deref(), Some("mymachine"));
+        assert_eq!(message.app_name.as_deref(), Some("su"));
+        assert_eq!(message.proc_id.as_deref(), Some("123"));
+        assert_eq!(
+            message.message,
+            "'su root' failed for lonvick on /dev/pts/8"
+        );
+
+        let message = parse_message("<13>Jan  1 00:00:00 cron: started", now)?;
+        assert_eq!(message.timestamp, Some(1_072_915_200_000_000_000));
+        assert_eq!(message.hostname, None);
+        assert_eq!(message.app_name.as_deref(), Some("cron"));
+        assert_eq!(message.message, "started");
+
+        let message = parse_message("<13>just some text", now)?;
+        assert_eq!(message.timestamp, None);
+        assert_eq!(message.app_name, None);
+        assert_eq!(message.message, "just some text");
+        Ok(())
+    }
+}
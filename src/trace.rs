@@ -15,12 +15,23 @@
 use crate::opentelemetry::proto::collector::trace::v1 as base;
 use crate::opentelemetry::proto::collector::trace::v1::trace_service_server as skel;
 
+/// Span tree reconstruction and trace analysis
+pub mod analysis;
+mod iter;
+mod merge;
+mod split;
+
+pub use merge::merge;
+
 /// Alias tonic TraceRequest
 pub type OtelTraceRequest = tonic::Request<base::ExportTraceServiceRequest>;
 
 /// Alias tonic TraceResponse
 pub type OtelTraceResponse = tonic::Response<base::ExportTraceServiceResponse>;
 
+/// Alias service skeleton
+pub use skel::TraceService;
+
 /// Alias the generated server skeletons
 pub use skel::TraceServiceServer;
 
@@ -54,3 +65,11 @@ impl skel::TraceService for OtelTraceService {
         (self.on_trace)(request)
     }
 }
+
+/// Alias processor chain for trace export requests
+pub type TraceProcessorChain = crate::processor::ProcessorChain<base::ExportTraceServiceRequest>;
+
+crate::processor::partial_success_from_disposition!(
+    base::ExportTracePartialSuccess,
+    rejected_spans
+);
@@ -0,0 +1,538 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reconstruction of the span tree of a trace and analysis of its timing.
+//!
+//! A [`SpanTree`] links the spans of one trace through their parent span ids.
+//! Spans without a parent are roots, spans whose parent is not among the spans
+//! are orphans, both start a subtree at depth 0. Spans whose parent links form
+//! a cycle are treated as orphans too, so every span is in exactly one subtree.
+//! Spans repeating a span id that was already seen are dropped and counted.
+
+use crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest;
+use crate::opentelemetry::proto::trace::v1::Span;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// An error building a span tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceAnalysisError {
+    /// The spans belong to more than one trace
+    MixedTraceIds,
+}
+
+impl std::fmt::Display for TraceAnalysisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceAnalysisError::MixedTraceIds => {
+                write!(f, "spans of more than one trace cannot form a span tree")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TraceAnalysisError {}
+
+/// A span in a span tree
+#[derive(Debug, Clone)]
+pub struct SpanNode<'a> {
+    /// The span
+    pub span: &'a Span,
+    /// The index of the parent node, `None` for roots and orphans
+    pub parent: Option<usize>,
+    /// The indices of the child nodes, ordered by start time
+    pub children: Vec<usize>,
+    /// The number of ancestors in the tree
+    pub depth: usize,
+}
+
+impl SpanNode<'_> {
+    /// The start time of the span
+    pub fn start(&self) -> u64 {
+        self.span.start_time_unix_nano
+    }
+
+    /// The end time of the span, spans ending before they start end at their
+    /// start
+    pub fn end(&self) -> u64 {
+        self.span
+            .end_time_unix_nano
+            .max(self.span.start_time_unix_nano)
+    }
+
+    /// The duration of the span in nanoseconds
+    pub fn duration(&self) -> u64 {
+        self.end() - self.start()
+    }
+}
+
+/// A stretch of time on the critical path and the span it is spent in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathSegment {
+    /// The index of the node the time is spent in
+    pub node: usize,
+    /// The start of the stretch in nanoseconds since the epoch
+    pub start: u64,
+    /// The end of the stretch in nanoseconds since the epoch
+    pub end: u64,
+}
+
+/// A child span starting before its parent, which with synchronous calls means
+/// the clocks of the two hosts disagree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSkew {
+    /// The index of the child node
+    pub node: usize,
+    /// The index of the parent node
+    pub parent: usize,
+    /// How long before its parent the child starts in nanoseconds
+    pub skew: u64,
+}
+
+/// The spans of one trace linked into a tree
+#[derive(Debug, Clone)]
+pub struct SpanTree<'a> {
+    trace_id: &'a [u8],
+    nodes: Vec<SpanNode<'a>>,
+    roots: Vec<usize>,
+    orphans: Vec<usize>,
+    duplicates: usize,
+}
+
+/// A frame of the depth first walk along the critical path
+struct PathFrame {
+    node: usize,
+    start: u64,
+    cursor: u64,
+    children: Vec<usize>,
+    next: usize,
+}
+
+impl<'a> SpanTree<'a> {
+    /// Builds the span tree of the spans of one trace
+    pub fn new<I>(spans: I) -> Result<Self, TraceAnalysisError>
+    where
+        I: IntoIterator<Item = &'a Span>,
+    {
+        let spans: Vec<&Span> = spans.into_iter().collect();
+        let trace_id = spans
+            .first()
+            .map_or(&[][..], |span| span.trace_id.as_slice());
+        if spans.iter().any(|span| span.trace_id != trace_id) {
+            return Err(TraceAnalysisError::MixedTraceIds);
+        }
+        Ok(Self::build(trace_id, spans))
+    }
+
+    /// Builds the span trees of all traces in a request, ordered by trace id
+    pub fn from_request(request: &'a ExportTraceServiceRequest) -> Vec<Self> {
+        let mut traces: BTreeMap<&[u8], Vec<&Span>> = BTreeMap::new();
+        for (_, _, span) in request.spans() {
+            traces
+                .entry(span.trace_id.as_slice())
+                .or_default()
+                .push(span);
+        }
+        traces
+            .into_iter()
+            .map(|(trace_id, spans)| Self::build(trace_id, spans))
+            .collect()
+    }
+
+    fn build(trace_id: &'a [u8], spans: Vec<&'a Span>) -> Self {
+        let mut index: HashMap<&[u8], usize> = HashMap::new();
+        let mut nodes = Vec::with_capacity(spans.len());
+        let mut duplicates = 0;
+        for span in spans {
+            if index.contains_key(span.span_id.as_slice()) {
+                duplicates += 1;
+                continue;
+            }
+            index.insert(span.span_id.as_slice(), nodes.len());
+            nodes.push(SpanNode {
+                span,
+                parent: None,
+                children: Vec::new(),
+                depth: 0,
+            });
+        }
+
+        let mut roots = Vec::new();
+        let mut orphans = Vec::new();
+        for i in 0..nodes.len() {
+            let Some(node) = nodes.get(i) else {
+                continue;
+            };
+            let parent_span_id = node.span.parent_span_id.as_slice();
+            if parent_span_id.is_empty() {
+                roots.push(i);
+            } else {
+                match index.get(parent_span_id) {
+                    Some(parent) if *parent != i => {
+                        if let Some(node) = nodes.get_mut(i) {
+                            node.parent = Some(*parent);
+                        }
+                    }
+                    _ => orphans.push(i),
+                }
+            }
+        }
+
+        let mut tree = SpanTree {
+            trace_id,
+            nodes,
+            roots,
+            orphans,
+            duplicates,
+        };
+        tree.link();
+        tree
+    }
+
+    /// Links parents to their children and computes depths, cutting cycles
+    fn link(&mut self) {
+        for i in 0..self.nodes.len() {
+            if let Some(parent) = self.nodes.get(i).and_then(|node| node.parent) {
+                if let Some(parent) = self.nodes.get_mut(parent) {
+                    parent.children.push(i);
+                }
+            }
+        }
+        for node in 0..self.nodes.len() {
+            let mut children = self
+                .nodes
+                .get(node)
+                .map(|node| node.children.clone())
+                .unwrap_or_default();
+            children.sort_by_key(|child| self.nodes.get(*child).map(SpanNode::start));
+            if let Some(node) = self.nodes.get_mut(node) {
+                node.children = children;
+            }
+        }
+
+        let mut visited = vec![false; self.nodes.len()];
+        let mut queue: VecDeque<usize> = self.roots.iter().chain(&self.orphans).copied().collect();
+        let mut next_unvisited = 0;
+        loop {
+            while let Some(i) = queue.pop_front() {
+                if let Some(seen) = visited.get_mut(i) {
+                    *seen = true;
+                }
+                let Some(node) = self.nodes.get(i) else {
+                    continue;
+                };
+                let depth = node.depth + 1;
+                for child in node.children.clone() {
+                    if let Some(child_node) = self.nodes.get_mut(child) {
+                        child_node.depth = depth;
+                    }
+                    queue.push_back(child);
+                }
+            }
+            // spans left unvisited are on a cycle, the first one becomes an
+            // orphan and the others hang off it
+            while visited.get(next_unvisited) == Some(&true) {
+                next_unvisited += 1;
+            }
+            let Some(parent) = self.nodes.get(next_unvisited).and_then(|node| node.parent) else {
+                break;
+            };
+            if let Some(parent) = self.nodes.get_mut(parent) {
+                parent.children.retain(|child| *child != next_unvisited);
+            }
+            if let Some(node) = self.nodes.get_mut(next_unvisited) {
+                node.parent = None;
+                node.depth = 0;
+            }
+            self.orphans.push(next_unvisited);
+            queue.push_back(next_unvisited);
+        }
+    }
+
+    /// The id of the trace, empty if the tree has no spans
+    pub fn trace_id(&self) -> &[u8] {
+        self.trace_id
+    }
+
+    /// The nodes of the tree
+    pub fn nodes(&self) -> &[SpanNode<'a>] {
+        &self.nodes
+    }
+
+    /// The node at an index
+    pub fn node(&self, index: usize) -> Option<&SpanNode<'a>> {
+        self.nodes.get(index)
+    }
+
+    /// The index of the node of a span id
+    pub fn find(&self, span_id: &[u8]) -> Option<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.span.span_id == span_id)
+    }
+
+    /// The number of spans in the tree
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Whether the tree has no spans
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The indices of the spans without a parent
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+
+    /// The indices of the spans whose parent is missing or on a cycle
+    pub fn orphans(&self) -> &[usize] {
+        &self.orphans
+    }
+
+    /// The number of spans dropped for repeating a span id
+    pub fn duplicates(&self) -> usize {
+        self.duplicates
+    }
+
+    /// The depth of the deepest span
+    pub fn max_depth(&self) -> usize {
+        self.nodes
+            .iter()
+            .map(|node| node.depth)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// The number of children of a span
+    pub fn fan_out(&self, index: usize) -> usize {
+        self.nodes.get(index).map_or(0, |node| node.children.len())
+    }
+
+    /// The index of the span with the most children and their number
+    pub fn max_fan_out(&self) -> Option<(usize, usize)> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (i, node.children.len()))
+            .max_by_key(|(i, fan_out)| (*fan_out, std::cmp::Reverse(*i)))
+    }
+
+    /// The time a span spends outside of its children, in nanoseconds. Time
+    /// covered by several children or by children outliving the span is
+    /// counted once.
+    pub fn self_time(&self, index: usize) -> u64 {
+        let Some(node) = self.nodes.get(index) else {
+            return 0;
+        };
+        let (start, end) = (node.start(), node.end());
+        let mut intervals: Vec<(u64, u64)> = node
+            .children
+            .iter()
+            .filter_map(|child| self.nodes.get(*child))
+            .map(|child| (child.start().max(start), child.end().min(end)))
+            .filter(|(child_start, child_end)| child_start < child_end)
+            .collect();
+        intervals.sort_unstable();
+        let mut covered = 0;
+        let mut covered_until = start;
+        for (child_start, child_end) in intervals {
+            let child_start = child_start.max(covered_until);
+            if child_end > child_start {
+                covered += child_end - child_start;
+                covered_until = child_end;
+            }
+        }
+        node.duration() - covered
+    }
+
+    /// The critical path of the trace, starting at the longest root or, if
+    /// there is none, the longest orphan
+    pub fn critical_path(&self) -> Vec<PathSegment> {
+        let longest = |indices: &[usize]| {
+            indices
+                .iter()
+                .copied()
+                .max_by_key(|i| self.nodes.get(*i).map(SpanNode::duration))
+        };
+        longest(&self.roots)
+            .or_else(|| longest(&self.orphans))
+            .map(|start| self.critical_path_from(start))
+            .unwrap_or_default()
+    }
+
+    /// The critical path of the subtree of a span: the stretches of time, in
+    /// order, that the end of the span waits on. Walking back from the end of
+    /// a span, the time goes to the child finishing last before that point,
+    /// recursively, and the gaps between children go to the span itself.
+    pub fn critical_path_from(&self, index: usize) -> Vec<PathSegment> {
+        let mut segments = Vec::new();
+        let Some(root) = self.nodes.get(index) else {
+            return segments;
+        };
+        let frame = |node: usize, start: u64, cursor: u64| {
+            let mut children = self
+                .nodes
+                .get(node)
+                .map(|node| node.children.clone())
+                .unwrap_or_default();
+            children
+                .sort_by_key(|child| std::cmp::Reverse(self.nodes.get(*child).map(SpanNode::end)));
+            PathFrame {
+                node,
+                start,
+                cursor,
+                children,
+                next: 0,
+            }
+        };
+        let mut stack = vec![frame(index, root.start(), root.end())];
+        // segments are found walking backwards in time
+        while let Some(top) = stack.last_mut() {
+            let eligible = top
+                .children
+                .iter()
+                .enumerate()
+                .skip(top.next)
+                .find_map(|(i, child)| {
+                    let node = self.nodes.get(*child)?;
+                    let (start, end) = (node.start().max(top.start), node.end().min(top.cursor));
+                    (start < end).then_some((i, *child, start, end))
+                });
+            match eligible {
+                Some((i, child, start, end)) => {
+                    top.next = i + 1;
+                    if end < top.cursor {
+                        segments.push(PathSegment {
+                            node: top.node,
+                            start: end,
+                            end: top.cursor,
+                        });
+                    }
+                    top.cursor = start;
+                    let child = frame(child, start, end);
+                    stack.push(child);
+                }
+                None => {
+                    if top.cursor > top.start {
+                        segments.push(PathSegment {
+                            node: top.node,
+                            start: top.start,
+                            end: top.cursor,
+                        });
+                    }
+                    stack.pop();
+                }
+            }
+        }
+        segments.reverse();
+        segments
+    }
+
+    /// The spans starting before their parent
+    pub fn clock_skews(&self) -> Vec<ClockSkew> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, node)| {
+                let parent = node.parent?;
+                let parent_start = self.nodes.get(parent)?.start();
+                (node.start() < parent_start).then(|| ClockSkew {
+                    node: i,
+                    parent,
+                    skew: parent_start - node.start(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn span(id: u8, parent: Option<u8>, start: u64, end: u64) -> Span {
+        Span {
+            trace_id: vec![1; 16],
+            span_id: vec![id; 8],
+            parent_span_id: parent.map(|p| vec![p; 8]).unwrap_or_default(),
+            start_time_unix_nano: start,
+            end_time_unix_nano: end,
+            ..Span::default()
+        }
+    }
+
+    #[test]
+    pub fn analyse_span_tree() -> Result<(), TraceAnalysisError> {
+        let spans = vec![
+            span(1, None, 1000, 1100),
+            span(2, Some(1), 1010, 1050),
+            span(3, Some(1), 1040, 1090),
+            span(4, Some(3), 1045, 1060),
+            span(5, Some(9), 1000, 1001),
+            span(6, Some(1), 995, 1005),
+            span(2, Some(1), 0, 1),
+        ];
+        let tree = SpanTree::new(&spans)?;
+        let index = |id: u8| tree.find(&[id; 8]).unwrap_or(usize::MAX);
+        assert_eq!(tree.len(), 6);
+        assert_eq!(tree.duplicates(), 1);
+        assert_eq!(tree.roots(), &[index(1)]);
+        assert_eq!(tree.orphans(), &[index(5)]);
+        assert_eq!(tree.max_depth(), 2);
+        assert_eq!(tree.max_fan_out(), Some((index(1), 3)));
+        assert_eq!(tree.self_time(index(1)), 15);
+        assert_eq!(tree.self_time(index(3)), 35);
+        assert_eq!(
+            tree.clock_skews(),
+            vec![ClockSkew {
+                node: index(6),
+                parent: index(1),
+                skew: 5
+            }]
+        );
+
+        let path: Vec<(u8, u64, u64)> = tree
+            .critical_path()
+            .iter()
+            .filter_map(|s| Some((*tree.node(s.node)?.span.span_id.first()?, s.start, s.end)))
+            .collect();
+        assert_eq!(
+            path,
+            vec![
+                (6, 1000, 1005),
+                (1, 1005, 1010),
+                (2, 1010, 1040),
+                (3, 1040, 1045),
+                (4, 1045, 1060),
+                (3, 1060, 1090),
+                (1, 1090, 1100)
+            ]
+        );
+
+        // cycles are cut into orphans
+        let cycle = vec![span(7, Some(8), 0, 10), span(8, Some(7), 1, 5)];
+        let tree = SpanTree::new(&cycle)?;
+        assert_eq!(tree.orphans().len(), 1);
+        assert_eq!(tree.max_depth(), 1);
+
+        let mut other = span(1, None, 0, 1);
+        other.trace_id = vec![2; 16];
+        assert_eq!(
+            SpanTree::new([&other, &span(2, None, 0, 1)]).err(),
+            Some(TraceAnalysisError::MixedTraceIds)
+        );
+        Ok(())
+    }
+}
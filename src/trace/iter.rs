@@ -0,0 +1,137 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::common::{EMPTY_RESOURCE, EMPTY_SCOPE};
+use crate::opentelemetry::proto::collector::trace::v1 as base;
+use crate::opentelemetry::proto::common::v1::InstrumentationScope;
+use crate::opentelemetry::proto::resource::v1::Resource;
+use crate::opentelemetry::proto::trace::v1::Span;
+
+impl base::ExportTraceServiceRequest {
+    /// Iterates over every span in the request alongside its resource and
+    /// instrumentation scope. Missing resources or scopes are presented as empty ones.
+    pub fn spans(&self) -> impl Iterator<Item = (&Resource, &InstrumentationScope, &Span)> {
+        self.resource_spans.iter().flat_map(|rs| {
+            let resource = rs.resource.as_ref().unwrap_or(&EMPTY_RESOURCE);
+            rs.scope_spans.iter().flat_map(move |ss| {
+                let scope = ss.scope.as_ref().unwrap_or(&EMPTY_SCOPE);
+                ss.spans.iter().map(move |span| (resource, scope, span))
+            })
+        })
+    }
+
+    /// Mutably iterates over every span in the request alongside its resource and
+    /// instrumentation scope
+    pub fn spans_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (&Resource, &InstrumentationScope, &mut Span)> {
+        self.resource_spans.iter_mut().flat_map(|rs| {
+            let resource = rs.resource.as_ref().unwrap_or(&EMPTY_RESOURCE);
+            rs.scope_spans.iter_mut().flat_map(move |ss| {
+                let scope = ss.scope.as_ref().unwrap_or(&EMPTY_SCOPE);
+                ss.spans.iter_mut().map(move |span| (resource, scope, span))
+            })
+        })
+    }
+
+    /// The total number of spans in the request
+    pub fn span_count(&self) -> usize {
+        self.resource_spans
+            .iter()
+            .flat_map(|rs| rs.scope_spans.iter())
+            .map(|ss| ss.spans.len())
+            .sum()
+    }
+
+    /// Retains only the spans for which the predicate returns true. Scopes and
+    /// resources that are left without spans are removed.
+    ///
+    /// Returns the number of spans that were removed.
+    pub fn retain_spans<F>(&mut self, mut keep: F) -> usize
+    where
+        F: FnMut(&Resource, &InstrumentationScope, &Span) -> bool,
+    {
+        let before = self.span_count();
+        for rs in &mut self.resource_spans {
+            let resource = rs.resource.as_ref().unwrap_or(&EMPTY_RESOURCE);
+            for ss in &mut rs.scope_spans {
+                let scope = ss.scope.as_ref().unwrap_or(&EMPTY_SCOPE);
+                ss.spans.retain(|span| keep(resource, scope, span));
+            }
+            rs.scope_spans.retain(|ss| !ss.spans.is_empty());
+        }
+        self.resource_spans.retain(|rs| !rs.scope_spans.is_empty());
+        before - self.span_count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest;
+    use crate::opentelemetry::proto::trace::v1::{ResourceSpans, ScopeSpans, Span};
+
+    fn span(name: &str) -> Span {
+        Span {
+            name: name.to_string(),
+            ..Span::default()
+        }
+    }
+
+    fn request() -> ExportTraceServiceRequest {
+        ExportTraceServiceRequest {
+            resource_spans: vec![
+                ResourceSpans {
+                    resource: None,
+                    scope_spans: vec![ScopeSpans {
+                        scope: None,
+                        spans: vec![span("a"), span("b")],
+                        schema_url: String::new(),
+                    }],
+                    schema_url: String::new(),
+                },
+                ResourceSpans {
+                    resource: None,
+                    scope_spans: vec![ScopeSpans {
+                        scope: None,
+                        spans: vec![span("c")],
+                        schema_url: String::new(),
+                    }],
+                    schema_url: String::new(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    pub fn iterate_spans() {
+        let mut req = request();
+        assert_eq!(req.span_count(), 3);
+        let names: Vec<&str> = req.spans().map(|(_, _, s)| s.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        for (_, _, span) in req.spans_mut() {
+            span.name.push('!');
+        }
+        let names: Vec<&str> = req.spans().map(|(_, _, s)| s.name.as_str()).collect();
+        assert_eq!(names, vec!["a!", "b!", "c!"]);
+    }
+
+    #[test]
+    pub fn retain_drops_empty_envelopes() {
+        let mut req = request();
+        assert_eq!(req.retain_spans(|_, _, s| s.name != "c"), 1);
+        assert_eq!(req.resource_spans.len(), 1);
+        assert_eq!(req.retain_spans(|_, _, _| false), 2);
+        assert!(req.resource_spans.is_empty());
+    }
+}
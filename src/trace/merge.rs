@@ -0,0 +1,134 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::common::{find_or_push, same_resource, same_scope};
+use crate::opentelemetry::proto::collector::trace::v1 as base;
+use crate::opentelemetry::proto::trace::v1::{ResourceSpans, ScopeSpans};
+
+impl base::ExportTraceServiceRequest {
+    /// Merges another request into this one. Spans are appended to the
+    /// resource and scope envelopes of this request that carry the same resource,
+    /// instrumentation scope and schema URLs, new envelopes are added otherwise.
+    /// Attributes are compared regardless of their order.
+    pub fn merge_request(&mut self, other: Self) {
+        for rs in other.resource_spans {
+            let ResourceSpans {
+                resource,
+                scope_spans,
+                schema_url,
+            } = rs;
+            let target = find_or_push(
+                &mut self.resource_spans,
+                |t| {
+                    same_resource(t.resource.as_ref(), resource.as_ref())
+                        && t.schema_url == schema_url
+                },
+                || ResourceSpans {
+                    resource: resource.clone(),
+                    scope_spans: Vec::new(),
+                    schema_url: schema_url.clone(),
+                },
+            );
+            for ss in scope_spans {
+                let ScopeSpans {
+                    scope,
+                    spans,
+                    schema_url,
+                } = ss;
+                let target = find_or_push(
+                    &mut target.scope_spans,
+                    |t| same_scope(t.scope.as_ref(), scope.as_ref()) && t.schema_url == schema_url,
+                    || ScopeSpans {
+                        scope: scope.clone(),
+                        spans: Vec::new(),
+                        schema_url: schema_url.clone(),
+                    },
+                );
+                target.spans.extend(spans);
+            }
+        }
+    }
+}
+
+/// Combines several trace export requests into one, deduplicating identical
+/// resources and instrumentation scopes
+pub fn merge<I>(requests: I) -> base::ExportTraceServiceRequest
+where
+    I: IntoIterator<Item = base::ExportTraceServiceRequest>,
+{
+    requests
+        .into_iter()
+        .fold(base::ExportTraceServiceRequest::default(), |mut acc, r| {
+            acc.merge_request(r);
+            acc
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest;
+    use crate::opentelemetry::proto::common::v1::{
+        any_value, AnyValue, InstrumentationScope, KeyValue,
+    };
+    use crate::opentelemetry::proto::resource::v1::Resource;
+    use crate::opentelemetry::proto::trace::v1::{ResourceSpans, ScopeSpans, Span};
+
+    fn attribute(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+        }
+    }
+
+    fn request(attributes: Vec<KeyValue>, scope: &str) -> ExportTraceServiceRequest {
+        ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: Some(Resource {
+                    attributes,
+                    dropped_attributes_count: 0,
+                }),
+                scope_spans: vec![ScopeSpans {
+                    scope: Some(InstrumentationScope {
+                        name: scope.to_string(),
+                        ..InstrumentationScope::default()
+                    }),
+                    spans: vec![Span::default()],
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    #[test]
+    pub fn merge_deduplicates_envelopes() {
+        let a = attribute("service.name", "a");
+        let b = attribute("service.name", "b");
+        let host = attribute("host.name", "h");
+        let merged = super::merge(vec![
+            request(vec![a.clone(), host.clone()], "x"),
+            request(vec![b], "x"),
+            request(vec![host, a.clone()], "x"),
+            request(vec![a, attribute("host.name", "h")], "y"),
+        ]);
+        assert_eq!(merged.resource_spans.len(), 2);
+        assert_eq!(
+            merged.resource_spans.first().map(|r| r.scope_spans.len()),
+            Some(2)
+        );
+        assert_eq!(merged.span_count(), 4);
+    }
+}
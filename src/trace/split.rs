@@ -0,0 +1,102 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::common::{split_envelopes, SplitLimits};
+use crate::opentelemetry::proto::collector::trace::v1 as base;
+
+impl base::ExportTraceServiceRequest {
+    /// Splits the request into requests whose encoded size and number of spans
+    /// stay within the given limits. Resource and scope envelopes are repeated in
+    /// every chunk carrying some of their spans. A single oversized span is sent
+    /// in a chunk of its own.
+    pub fn split(self, limits: &SplitLimits) -> Vec<Self> {
+        split_envelopes(
+            self.resource_spans,
+            limits,
+            |r| &mut r.scope_spans,
+            |s| &mut s.spans,
+            |_| 1,
+            |item, _| vec![item],
+        )
+        .into_iter()
+        .map(|resource_spans| Self { resource_spans })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::SplitLimits;
+    use crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest;
+    use crate::opentelemetry::proto::common::v1::InstrumentationScope;
+    use crate::opentelemetry::proto::trace::v1::{ResourceSpans, ScopeSpans, Span};
+    use prost::Message;
+
+    fn scope(name: &str, items: usize) -> ScopeSpans {
+        ScopeSpans {
+            scope: Some(InstrumentationScope {
+                name: name.to_string(),
+                ..InstrumentationScope::default()
+            }),
+            spans: (0..items)
+                .map(|i| Span {
+                    start_time_unix_nano: i as u64,
+                    ..Span::default()
+                })
+                .collect(),
+            schema_url: String::new(),
+        }
+    }
+
+    fn request(a: usize, b: usize) -> ExportTraceServiceRequest {
+        ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: None,
+                scope_spans: vec![scope("a", a), scope("b", b)],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    #[test]
+    pub fn split_by_items() {
+        let chunks = request(4, 3).split(&SplitLimits::with_max_items(3));
+        assert_eq!(
+            chunks.iter().map(|c| c.span_count()).collect::<Vec<_>>(),
+            vec![3, 3, 1]
+        );
+        // the second chunk repeats both scope envelopes
+        let scopes: Vec<Vec<&str>> = chunks
+            .iter()
+            .map(|c| {
+                c.resource_spans
+                    .iter()
+                    .flat_map(|r| r.scope_spans.iter())
+                    .filter_map(|s| s.scope.as_ref().map(|s| s.name.as_str()))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(scopes, vec![vec!["a"], vec!["a", "b"], vec!["b"]]);
+    }
+
+    #[test]
+    pub fn split_by_bytes() {
+        let req = request(500, 500);
+        let max = req.encoded_len() / 4;
+        let chunks = req.split(&SplitLimits::with_max_bytes(max));
+        assert!(chunks.len() >= 4);
+        assert!(chunks.iter().all(|c| c.encoded_len() <= max));
+        assert_eq!(chunks.iter().map(|c| c.span_count()).sum::<usize>(), 1000);
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Conversions between OTLP traces and Zipkin v2 spans.
+//!
+//! Spans are converted following the OpenTelemetry Zipkin mapping. The local
+//! endpoint of a Zipkin span becomes the resource, its remote endpoint the
+//! `peer.service`, `net.peer.ip` and `net.peer.port` attributes, tags become
+//! attributes and annotations become events. The `otel.status_code`, `error`
+//! and `otel.scope.*` tags carry the span status and the instrumentation scope.
+//!
+//! Both the JSON and the `zipkin.proto3` encodings decode to, and encode from,
+//! the generated [`proto::Span`].
+
+mod convert;
+mod json;
+#[cfg(feature = "zipkin-receiver")]
+mod receiver;
+
+pub use convert::{from_spans, to_spans};
+pub use json::{decode_json, encode_json};
+#[cfg(feature = "zipkin-receiver")]
+pub use receiver::{ZipkinReceiver, SPANS_PATH};
+
+#[cfg(feature = "zipkin-receiver")]
+use crate::common::receiver::ReceiverError;
+use prost::Message;
+
+/// The Zipkin v2 protocol buffers
+#[allow(clippy::all, clippy::pedantic, missing_docs)]
+pub mod proto {
+    tonic::include_proto!("zipkin.proto3");
+}
+
+/// An error decoding Zipkin spans
+#[derive(Debug)]
+pub enum ZipkinError {
+    /// The body is not valid JSON
+    Json(serde_json::Error),
+    /// The body is not a valid `ListOfSpans` message
+    Decode(prost::DecodeError),
+    /// A span is not a valid Zipkin v2 span
+    Invalid {
+        /// What is invalid about the span
+        reason: &'static str,
+    },
+    /// The spans could not be delivered to the channel
+    #[cfg(feature = "zipkin-receiver")]
+    Receive(ReceiverError),
+}
+
+impl std::fmt::Display for ZipkinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ZipkinError::Json(e) => write!(f, "invalid Zipkin JSON: {e}"),
+            ZipkinError::Decode(e) => write!(f, "invalid Zipkin protobuf: {e}"),
+            ZipkinError::Invalid { reason } => write!(f, "invalid Zipkin span: {reason}"),
+            #[cfg(feature = "zipkin-receiver")]
+            ZipkinError::Receive(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ZipkinError {}
+
+impl From<serde_json::Error> for ZipkinError {
+    fn from(e: serde_json::Error) -> Self {
+        ZipkinError::Json(e)
+    }
+}
+
+impl From<prost::DecodeError> for ZipkinError {
+    fn from(e: prost::DecodeError) -> Self {
+        ZipkinError::Decode(e)
+    }
+}
+
+#[cfg(feature = "zipkin-receiver")]
+impl From<tonic::Status> for ZipkinError {
+    fn from(status: tonic::Status) -> Self {
+        ZipkinError::Receive(ReceiverError::from(status))
+    }
+}
+
+/// Decodes a `ListOfSpans` message
+pub fn decode_proto(body: &[u8]) -> Result<Vec<proto::Span>, ZipkinError> {
+    Ok(proto::ListOfSpans::decode(body)?.spans)
+}
+
+/// Encodes spans as a `ListOfSpans` message
+pub fn encode_proto(spans: &[proto::Span]) -> Vec<u8> {
+    proto::ListOfSpans {
+        spans: spans.to_vec(),
+    }
+    .encode_to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::{any_value_to_string, hex};
+    use crate::opentelemetry::proto::trace::v1::{span::SpanKind, status::StatusCode};
+
+    const SPANS: &str = r#"[{
+        "traceId": "0123456789abcdef",
+        "parentId": "0000000000000001",
+        "id": "0000000000000002",
+        "kind": "CLIENT",
+        "name": "get /cart",
+        "timestamp": 1700000000000000,
+        "duration": 1500,
+        "localEndpoint": {"serviceName": "web", "ipv4": "10.0.0.1", "port": 8080},
+        "remoteEndpoint": {"serviceName": "cart", "port": 9000},
+        "annotations": [
+            {"timestamp": 1700000000000100, "value": "sent"},
+            {"timestamp": 1700000000000200, "value": "\"retry\":{\"attempt\":2}"}
+        ],
+        "tags": {
+            "http.method": "GET",
+            "otel.status_code": "ERROR",
+            "error": "timeout",
+            "otel.scope.name": "http-client"
+        }
+    }]"#;
+
+    #[test]
+    pub fn zipkin_conversion() -> Result<(), Box<dyn std::error::Error>> {
+        let spans = decode_json(SPANS.as_bytes())?;
+        assert_eq!(decode_proto(&encode_proto(&spans))?, spans);
+
+        let request = from_spans(&spans)?;
+        let (resource, scope, span) = request.spans().next().ok_or("no span")?;
+        let attributes = |attributes: &[crate::opentelemetry::proto::common::v1::KeyValue]| {
+            attributes
+                .iter()
+                .map(|kv| {
+                    let value = kv.value.as_ref().map(any_value_to_string);
+                    format!("{}={}", kv.key, value.unwrap_or_default())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            attributes(&resource.attributes),
+            vec![
+                "service.name=web",
+                "net.host.ip=10.0.0.1",
+                "net.host.port=8080"
+            ]
+        );
+        assert_eq!(scope.name, "http-client");
+        assert_eq!(
+            attributes(&span.attributes),
+            vec!["http.method=GET", "peer.service=cart", "net.peer.port=9000"]
+        );
+        assert_eq!(span.trace_id.get(..8), Some(&[0; 8][..]));
+        assert_eq!(span.kind(), SpanKind::Client);
+        assert_eq!(
+            span.status.as_ref().map(|s| (s.code(), s.message.as_str())),
+            Some((StatusCode::Error, "timeout"))
+        );
+        assert_eq!(
+            span.end_time_unix_nano - span.start_time_unix_nano,
+            1_500_000
+        );
+        let events: Vec<(&str, Vec<String>)> = span
+            .events
+            .iter()
+            .map(|e| (e.name.as_str(), attributes(&e.attributes)))
+            .collect();
+        assert_eq!(
+            events,
+            vec![("sent", vec![]), ("retry", vec!["attempt=2".to_string()])]
+        );
+
+        let encoded = decode_json(&encode_json(&to_spans(&request)))?;
+        let span = encoded.first().ok_or("no span")?;
+        assert_eq!(hex(&span.trace_id), "00000000000000000123456789abcdef");
+        assert_eq!(span.kind(), proto::span::Kind::Client);
+        assert_eq!(span.duration, 1500);
+        assert_eq!(
+            span.local_endpoint,
+            spans.first().and_then(|s| s.local_endpoint.clone())
+        );
+        assert_eq!(
+            span.remote_endpoint
+                .as_ref()
+                .map(|e| (e.service_name.as_str(), e.port)),
+            Some(("cart", 9000))
+        );
+        let annotations: Vec<&str> = span.annotations.iter().map(|a| a.value.as_str()).collect();
+        assert_eq!(annotations, vec!["sent", "\"retry\":{\"attempt\":2}"]);
+        let mut tags: Vec<(&String, &String)> = span.tags.iter().collect();
+        tags.sort();
+        assert_eq!(
+            tags,
+            vec![
+                (&"error".to_string(), &"timeout".to_string()),
+                (&"http.method".to_string(), &"GET".to_string()),
+                (&"net.peer.port".to_string(), &"9000".to_string()),
+                (&"otel.scope.name".to_string(), &"http-client".to_string()),
+                (&"otel.status_code".to_string(), &"ERROR".to_string()),
+                (&"peer.service".to_string(), &"cart".to_string()),
+            ]
+        );
+
+        assert!(matches!(
+            decode_json(br#"[{"traceId":"01","id":"0000000000000002"}]"#),
+            Err(ZipkinError::Invalid { .. })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,425 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::proto::{self, span::Kind, Annotation, Endpoint};
+use super::ZipkinError;
+use crate::common::{any_value_to_string, find_or_push, key_value, string_value};
+use crate::opentelemetry::proto::collector::trace::v1::ExportTraceServiceRequest;
+use crate::opentelemetry::proto::common::v1::{
+    any_value, AnyValue, InstrumentationScope, KeyValue,
+};
+use crate::opentelemetry::proto::resource::v1::Resource;
+use crate::opentelemetry::proto::trace::v1::{
+    span::{Event, SpanKind},
+    status::StatusCode,
+    ResourceSpans, ScopeSpans, Span, Status,
+};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const SERVICE_NAME: &str = "service.name";
+const HOST_IP: &str = "net.host.ip";
+const HOST_PORT: &str = "net.host.port";
+const PEER_SERVICE: &str = "peer.service";
+const PEER_IP: &str = "net.peer.ip";
+const PEER_PORT: &str = "net.peer.port";
+
+const STATUS_CODE_TAG: &str = "otel.status_code";
+const STATUS_DESCRIPTION_TAG: &str = "otel.status_description";
+const ERROR_TAG: &str = "error";
+const SCOPE_NAME_TAG: &str = "otel.scope.name";
+const SCOPE_VERSION_TAG: &str = "otel.scope.version";
+const LIBRARY_NAME_TAG: &str = "otel.library.name";
+const LIBRARY_VERSION_TAG: &str = "otel.library.version";
+const DROPPED_ATTRIBUTES_TAG: &str = "otel.dropped_attributes_count";
+const DROPPED_EVENTS_TAG: &str = "otel.dropped_events_count";
+
+/// The service name of spans whose resource has none
+const UNKNOWN_SERVICE: &str = "unknown_service";
+
+/// The attributes naming the remote endpoint, in order of preference
+const PEER_SERVICE_ATTRIBUTES: [&str; 3] = [PEER_SERVICE, "server.address", "net.peer.name"];
+/// The attributes carrying the address of the remote endpoint
+const PEER_IP_ATTRIBUTES: [&str; 3] = [PEER_IP, "network.peer.address", "net.sock.peer.addr"];
+/// The attributes carrying the port of the remote endpoint
+const PEER_PORT_ATTRIBUTES: [&str; 3] = [PEER_PORT, "server.port", "network.peer.port"];
+
+fn invalid(reason: &'static str) -> ZipkinError {
+    ZipkinError::Invalid { reason }
+}
+
+fn int_value(key: &str, value: i64) -> KeyValue {
+    key_value(
+        key,
+        AnyValue {
+            value: Some(any_value::Value::IntValue(value)),
+        },
+    )
+}
+
+fn endpoint_ip(endpoint: &Endpoint) -> Option<IpAddr> {
+    if let Ok(octets) = <[u8; 4]>::try_from(endpoint.ipv4.as_slice()) {
+        return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+    }
+    <[u8; 16]>::try_from(endpoint.ipv6.as_slice())
+        .ok()
+        .map(|octets| IpAddr::V6(Ipv6Addr::from(octets)))
+}
+
+/// Converts a JSON value of an event annotation into an attribute value
+fn attribute_value(value: serde_json::Value) -> AnyValue {
+    let value = match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::Bool(b) => Some(any_value::Value::BoolValue(b)),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Some(any_value::Value::IntValue(i)),
+            None => n.as_f64().map(any_value::Value::DoubleValue),
+        },
+        serde_json::Value::String(s) => Some(any_value::Value::StringValue(s)),
+        other => Some(any_value::Value::StringValue(other.to_string())),
+    };
+    AnyValue { value }
+}
+
+/// Converts an annotation into an event, values of the form
+/// `"name":{attributes}` carry the attributes of the event as JSON
+fn event(annotation: &Annotation) -> Event {
+    let time_unix_nano = annotation.timestamp.saturating_mul(1000);
+    let value = &annotation.value;
+    if value.starts_with('"') && value.ends_with('}') {
+        if let Ok(serde_json::Value::Object(event)) =
+            serde_json::from_str::<serde_json::Value>(&format!("{{{value}}}"))
+        {
+            if let Some((name, serde_json::Value::Object(attributes))) = event.into_iter().next() {
+                return Event {
+                    time_unix_nano,
+                    name,
+                    attributes: attributes
+                        .into_iter()
+                        .map(|(key, value)| KeyValue {
+                            key,
+                            value: Some(attribute_value(value)),
+                        })
+                        .collect(),
+                    dropped_attributes_count: 0,
+                };
+            }
+        }
+    }
+    Event {
+        time_unix_nano,
+        name: value.clone(),
+        ..Event::default()
+    }
+}
+
+fn span(zipkin: &proto::Span) -> Result<(Resource, InstrumentationScope, Span), ZipkinError> {
+    let trace_id = match zipkin.trace_id.len() {
+        16 => zipkin.trace_id.clone(),
+        8 => [0; 8].iter().chain(&zipkin.trace_id).copied().collect(),
+        _ => return Err(invalid("trace id is not 8 or 16 bytes")),
+    };
+    if zipkin.id.len() != 8 {
+        return Err(invalid("span id is not 8 bytes"));
+    }
+    if !(zipkin.parent_id.is_empty() || zipkin.parent_id.len() == 8) {
+        return Err(invalid("parent id is not 8 bytes"));
+    }
+
+    let mut resource = Resource::default();
+    if let Some(local) = &zipkin.local_endpoint {
+        if !local.service_name.is_empty() {
+            resource
+                .attributes
+                .push(string_value(SERVICE_NAME, &local.service_name));
+        }
+        if let Some(ip) = endpoint_ip(local) {
+            resource
+                .attributes
+                .push(string_value(HOST_IP, &ip.to_string()));
+        }
+        if local.port != 0 {
+            resource
+                .attributes
+                .push(int_value(HOST_PORT, i64::from(local.port)));
+        }
+    }
+
+    let mut tags: BTreeMap<&str, &str> = zipkin
+        .tags
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    let scope = InstrumentationScope {
+        name: tags
+            .remove(SCOPE_NAME_TAG)
+            .or_else(|| tags.remove(LIBRARY_NAME_TAG))
+            .unwrap_or_default()
+            .to_string(),
+        version: tags
+            .remove(SCOPE_VERSION_TAG)
+            .or_else(|| tags.remove(LIBRARY_VERSION_TAG))
+            .unwrap_or_default()
+            .to_string(),
+        ..InstrumentationScope::default()
+    };
+    let error = tags.remove(ERROR_TAG);
+    let description = tags.remove(STATUS_DESCRIPTION_TAG);
+    let code = match (tags.remove(STATUS_CODE_TAG), error) {
+        (Some("OK"), _) => Some(StatusCode::Ok),
+        (Some("ERROR"), _) | (None, Some(_)) => Some(StatusCode::Error),
+        _ => None,
+    };
+    let status = code.map(|code| {
+        let mut status = Status {
+            message: error.or(description).unwrap_or_default().to_string(),
+            ..Status::default()
+        };
+        status.set_code(code);
+        status
+    });
+    let dropped_attributes_count = tags
+        .remove(DROPPED_ATTRIBUTES_TAG)
+        .and_then(|count| count.parse().ok())
+        .unwrap_or_default();
+    let dropped_events_count = tags
+        .remove(DROPPED_EVENTS_TAG)
+        .and_then(|count| count.parse().ok())
+        .unwrap_or_default();
+
+    let mut attributes: Vec<KeyValue> = tags
+        .into_iter()
+        .map(|(key, value)| string_value(key, value))
+        .collect();
+    if let Some(remote) = &zipkin.remote_endpoint {
+        let mut remote_attributes = Vec::new();
+        if !remote.service_name.is_empty() {
+            remote_attributes.push(string_value(PEER_SERVICE, &remote.service_name));
+        }
+        if let Some(ip) = endpoint_ip(remote) {
+            remote_attributes.push(string_value(PEER_IP, &ip.to_string()));
+        }
+        if remote.port != 0 {
+            remote_attributes.push(int_value(PEER_PORT, i64::from(remote.port)));
+        }
+        for attribute in remote_attributes {
+            if !attributes.iter().any(|kv| kv.key == attribute.key) {
+                attributes.push(attribute);
+            }
+        }
+    }
+
+    let start_time_unix_nano = zipkin.timestamp.saturating_mul(1000);
+    let mut span = Span {
+        trace_id,
+        span_id: zipkin.id.clone(),
+        parent_span_id: zipkin.parent_id.clone(),
+        name: zipkin.name.clone(),
+        start_time_unix_nano,
+        end_time_unix_nano: start_time_unix_nano
+            .saturating_add(zipkin.duration.saturating_mul(1000)),
+        attributes,
+        dropped_attributes_count,
+        events: zipkin.annotations.iter().map(event).collect(),
+        dropped_events_count,
+        status,
+        ..Span::default()
+    };
+    span.set_kind(match zipkin.kind() {
+        Kind::Client => SpanKind::Client,
+        Kind::Server => SpanKind::Server,
+        Kind::Producer => SpanKind::Producer,
+        Kind::Consumer => SpanKind::Consumer,
+        Kind::SpanKindUnspecified => SpanKind::Internal,
+    });
+    Ok((resource, scope, span))
+}
+
+/// Converts Zipkin spans into a trace request, grouping them by local
+/// endpoint and instrumentation scope
+pub fn from_spans(spans: &[proto::Span]) -> Result<ExportTraceServiceRequest, ZipkinError> {
+    let mut resource_spans: Vec<ResourceSpans> = Vec::new();
+    for zipkin in spans {
+        let (resource, scope, span) = span(zipkin)?;
+        let rs = find_or_push(
+            &mut resource_spans,
+            |rs| rs.resource.as_ref() == Some(&resource),
+            || ResourceSpans {
+                resource: Some(resource.clone()),
+                ..ResourceSpans::default()
+            },
+        );
+        let ss = find_or_push(
+            &mut rs.scope_spans,
+            |ss| ss.scope.as_ref() == Some(&scope),
+            || ScopeSpans {
+                scope: Some(scope.clone()),
+                ..ScopeSpans::default()
+            },
+        );
+        ss.spans.push(span);
+    }
+    Ok(ExportTraceServiceRequest { resource_spans })
+}
+
+fn find<'a>(attributes: &'a [KeyValue], keys: &[&str]) -> Option<&'a AnyValue> {
+    keys.iter().find_map(|key| {
+        attributes
+            .iter()
+            .find(|kv| kv.key == *key)
+            .and_then(|kv| kv.value.as_ref())
+    })
+}
+
+fn port(value: Option<&AnyValue>) -> i32 {
+    match value.and_then(|v| v.value.as_ref()) {
+        Some(any_value::Value::IntValue(port)) => u16::try_from(*port).map_or(0, i32::from),
+        Some(any_value::Value::StringValue(port)) => port.parse::<u16>().map_or(0, i32::from),
+        _ => 0,
+    }
+}
+
+fn set_ip(endpoint: &mut Endpoint, value: Option<&AnyValue>) {
+    match value
+        .map(any_value_to_string)
+        .and_then(|ip| ip.parse().ok())
+    {
+        Some(IpAddr::V4(ip)) => endpoint.ipv4 = ip.octets().to_vec(),
+        Some(IpAddr::V6(ip)) => endpoint.ipv6 = ip.octets().to_vec(),
+        None => {}
+    }
+}
+
+/// Renders an event as an annotation, events with attributes as
+/// `"name":{attributes}`
+fn annotation(event: &Event) -> Annotation {
+    let value = if event.attributes.is_empty() {
+        event.name.clone()
+    } else {
+        let attributes = AnyValue {
+            value: Some(any_value::Value::KvlistValue(
+                crate::opentelemetry::proto::common::v1::KeyValueList {
+                    values: event.attributes.clone(),
+                },
+            )),
+        };
+        format!(
+            "{}:{}",
+            serde_json::Value::from(event.name.as_str()),
+            any_value_to_string(&attributes)
+        )
+    };
+    Annotation {
+        timestamp: event.time_unix_nano / 1000,
+        value,
+    }
+}
+
+/// Converts the spans of a trace request into Zipkin spans. Resource and scope
+/// attributes become tags, links are dropped.
+pub fn to_spans(request: &ExportTraceServiceRequest) -> Vec<proto::Span> {
+    let mut spans = Vec::new();
+    for rs in &request.resource_spans {
+        let resource = rs.resource.as_ref().map_or(&[][..], |r| &r.attributes);
+        let mut local = Endpoint {
+            service_name: find(resource, &[SERVICE_NAME])
+                .map_or_else(|| UNKNOWN_SERVICE.to_string(), any_value_to_string),
+            port: port(find(resource, &[HOST_PORT])),
+            ..Endpoint::default()
+        };
+        set_ip(&mut local, find(resource, &[HOST_IP]));
+        let resource_tags = resource
+            .iter()
+            .filter(|kv| ![SERVICE_NAME, HOST_IP, HOST_PORT].contains(&kv.key.as_str()));
+
+        for ss in &rs.scope_spans {
+            let scope = ss.scope.clone().unwrap_or_default();
+            for span in &ss.spans {
+                let mut tags: std::collections::HashMap<String, String> = resource_tags
+                    .clone()
+                    .chain(&span.attributes)
+                    .filter_map(|kv| {
+                        kv.value
+                            .as_ref()
+                            .map(|value| (kv.key.clone(), any_value_to_string(value)))
+                    })
+                    .collect();
+                if !scope.name.is_empty() {
+                    tags.insert(SCOPE_NAME_TAG.to_string(), scope.name.clone());
+                }
+                if !scope.version.is_empty() {
+                    tags.insert(SCOPE_VERSION_TAG.to_string(), scope.version.clone());
+                }
+                match span.status.as_ref().map(|s| (s.code(), &s.message)) {
+                    Some((StatusCode::Ok, _)) => {
+                        tags.insert(STATUS_CODE_TAG.to_string(), "OK".to_string());
+                    }
+                    Some((StatusCode::Error, message)) => {
+                        tags.insert(STATUS_CODE_TAG.to_string(), "ERROR".to_string());
+                        tags.insert(ERROR_TAG.to_string(), message.clone());
+                    }
+                    Some((StatusCode::Unset, _)) | None => {}
+                }
+                if span.dropped_attributes_count > 0 {
+                    tags.insert(
+                        DROPPED_ATTRIBUTES_TAG.to_string(),
+                        span.dropped_attributes_count.to_string(),
+                    );
+                }
+                if span.dropped_events_count > 0 {
+                    tags.insert(
+                        DROPPED_EVENTS_TAG.to_string(),
+                        span.dropped_events_count.to_string(),
+                    );
+                }
+
+                let mut remote = Endpoint {
+                    service_name: find(&span.attributes, &PEER_SERVICE_ATTRIBUTES)
+                        .map(any_value_to_string)
+                        .unwrap_or_default(),
+                    port: port(find(&span.attributes, &PEER_PORT_ATTRIBUTES)),
+                    ..Endpoint::default()
+                };
+                set_ip(&mut remote, find(&span.attributes, &PEER_IP_ATTRIBUTES));
+
+                let duration = span
+                    .end_time_unix_nano
+                    .saturating_sub(span.start_time_unix_nano);
+                let mut zipkin = proto::Span {
+                    trace_id: span.trace_id.clone(),
+                    parent_id: span.parent_span_id.clone(),
+                    id: span.span_id.clone(),
+                    name: span.name.clone(),
+                    timestamp: span.start_time_unix_nano / 1000,
+                    // durations of less than a microsecond are rounded up
+                    duration: duration.div_ceil(1000),
+                    local_endpoint: Some(local.clone()),
+                    remote_endpoint: (remote != Endpoint::default()).then_some(remote),
+                    annotations: span.events.iter().map(annotation).collect(),
+                    tags,
+                    ..proto::Span::default()
+                };
+                zipkin.set_kind(match span.kind() {
+                    SpanKind::Client => Kind::Client,
+                    SpanKind::Server => Kind::Server,
+                    SpanKind::Producer => Kind::Producer,
+                    SpanKind::Consumer => Kind::Consumer,
+                    SpanKind::Internal | SpanKind::Unspecified => Kind::SpanKindUnspecified,
+                });
+                spans.push(zipkin);
+            }
+        }
+    }
+    spans
+}
@@ -0,0 +1,256 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::proto::{span::Kind, Annotation, Endpoint, Span};
+use super::ZipkinError;
+use crate::common::hex;
+use serde_json::{Map, Value};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+fn invalid(reason: &'static str) -> ZipkinError {
+    ZipkinError::Invalid { reason }
+}
+
+/// Parses hex of an even number of digits
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len() / 2)
+        .map(|i| {
+            text.get(2 * i..2 * i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+        })
+        .collect()
+}
+
+fn id(
+    value: Option<&Value>,
+    lengths: &[usize],
+    reason: &'static str,
+) -> Result<Vec<u8>, ZipkinError> {
+    match value {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::String(text)) => parse_hex(text)
+            .filter(|bytes| lengths.contains(&bytes.len()))
+            .ok_or_else(|| invalid(reason)),
+        Some(_) => Err(invalid(reason)),
+    }
+}
+
+fn string(value: Option<&Value>, reason: &'static str) -> Result<String, ZipkinError> {
+    match value {
+        None | Some(Value::Null) => Ok(String::new()),
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(_) => Err(invalid(reason)),
+    }
+}
+
+fn number(value: Option<&Value>, reason: &'static str) -> Result<u64, ZipkinError> {
+    match value {
+        None | Some(Value::Null) => Ok(0),
+        Some(Value::Number(n)) => n.as_u64().ok_or_else(|| invalid(reason)),
+        Some(_) => Err(invalid(reason)),
+    }
+}
+
+fn endpoint(value: Option<&Value>) -> Result<Option<Endpoint>, ZipkinError> {
+    let fields = match value {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::Object(fields)) => fields,
+        Some(_) => return Err(invalid("endpoint is not an object")),
+    };
+    let ipv4 = match string(fields.get("ipv4"), "ipv4 is not a string")?.as_str() {
+        "" => Vec::new(),
+        ip => ip
+            .parse::<Ipv4Addr>()
+            .map_err(|_| invalid("ipv4 is not an IPv4 address"))?
+            .octets()
+            .to_vec(),
+    };
+    let ipv6 = match string(fields.get("ipv6"), "ipv6 is not a string")?.as_str() {
+        "" => Vec::new(),
+        ip => ip
+            .parse::<Ipv6Addr>()
+            .map_err(|_| invalid("ipv6 is not an IPv6 address"))?
+            .octets()
+            .to_vec(),
+    };
+    let port = number(fields.get("port"), "port is not a port number")?;
+    Ok(Some(Endpoint {
+        service_name: string(fields.get("serviceName"), "serviceName is not a string")?,
+        ipv4,
+        ipv6,
+        port: u16::try_from(port)
+            .map(i32::from)
+            .map_err(|_| invalid("port is not a port number"))?,
+    }))
+}
+
+fn span(value: &Value) -> Result<Span, ZipkinError> {
+    let Value::Object(fields) = value else {
+        return Err(invalid("span is not an object"));
+    };
+    let trace_id = id(
+        fields.get("traceId"),
+        &[8, 16],
+        "traceId is not 16 or 32 hex digits",
+    )?;
+    let span_id = id(fields.get("id"), &[8], "id is not 16 hex digits")?;
+    if trace_id.is_empty() || span_id.is_empty() {
+        return Err(invalid("traceId and id are required"));
+    }
+    let kind = match string(fields.get("kind"), "kind is not a string")?.as_str() {
+        "" => Kind::SpanKindUnspecified,
+        kind => Kind::from_str_name(kind).ok_or_else(|| invalid("unknown kind"))?,
+    };
+    let annotations = match fields.get("annotations") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(annotations)) => annotations
+            .iter()
+            .map(|annotation| {
+                Ok(Annotation {
+                    timestamp: number(
+                        annotation.get("timestamp"),
+                        "annotation timestamp is not a number",
+                    )?,
+                    value: string(annotation.get("value"), "annotation value is not a string")?,
+                })
+            })
+            .collect::<Result<_, ZipkinError>>()?,
+        Some(_) => return Err(invalid("annotations is not an array")),
+    };
+    let tags = match fields.get("tags") {
+        None | Some(Value::Null) => Default::default(),
+        Some(Value::Object(tags)) => tags
+            .iter()
+            .map(|(key, value)| match value {
+                Value::String(s) => Ok((key.clone(), s.clone())),
+                Value::Number(n) => Ok((key.clone(), n.to_string())),
+                Value::Bool(b) => Ok((key.clone(), b.to_string())),
+                _ => Err(invalid("tag value is not a string")),
+            })
+            .collect::<Result<_, ZipkinError>>()?,
+        Some(_) => return Err(invalid("tags is not an object")),
+    };
+    let flag = |name: &str| fields.get(name).and_then(Value::as_bool).unwrap_or(false);
+    let mut span = Span {
+        trace_id,
+        parent_id: id(
+            fields.get("parentId"),
+            &[8],
+            "parentId is not 16 hex digits",
+        )?,
+        id: span_id,
+        name: string(fields.get("name"), "name is not a string")?,
+        timestamp: number(fields.get("timestamp"), "timestamp is not a number")?,
+        duration: number(fields.get("duration"), "duration is not a number")?,
+        local_endpoint: endpoint(fields.get("localEndpoint"))?,
+        remote_endpoint: endpoint(fields.get("remoteEndpoint"))?,
+        annotations,
+        tags,
+        debug: flag("debug"),
+        shared: flag("shared"),
+        ..Span::default()
+    };
+    span.set_kind(kind);
+    Ok(span)
+}
+
+/// Decodes a JSON array of Zipkin v2 spans
+pub fn decode_json(body: &[u8]) -> Result<Vec<Span>, ZipkinError> {
+    match serde_json::from_slice(body)? {
+        Value::Array(spans) => spans.iter().map(span).collect(),
+        _ => Err(invalid("expected an array of spans")),
+    }
+}
+
+fn endpoint_json(endpoint: &Endpoint) -> Value {
+    let mut fields = Map::new();
+    if !endpoint.service_name.is_empty() {
+        fields.insert("serviceName".into(), endpoint.service_name.clone().into());
+    }
+    if let Ok(octets) = <[u8; 4]>::try_from(endpoint.ipv4.as_slice()) {
+        fields.insert("ipv4".into(), Ipv4Addr::from(octets).to_string().into());
+    }
+    if let Ok(octets) = <[u8; 16]>::try_from(endpoint.ipv6.as_slice()) {
+        fields.insert("ipv6".into(), Ipv6Addr::from(octets).to_string().into());
+    }
+    if endpoint.port != 0 {
+        fields.insert("port".into(), endpoint.port.into());
+    }
+    Value::Object(fields)
+}
+
+fn span_json(span: &Span) -> Value {
+    let mut fields = Map::new();
+    fields.insert("traceId".into(), hex(&span.trace_id).into());
+    if !span.parent_id.is_empty() {
+        fields.insert("parentId".into(), hex(&span.parent_id).into());
+    }
+    fields.insert("id".into(), hex(&span.id).into());
+    if span.kind() != Kind::SpanKindUnspecified {
+        fields.insert("kind".into(), span.kind().as_str_name().into());
+    }
+    if !span.name.is_empty() {
+        fields.insert("name".into(), span.name.clone().into());
+    }
+    if span.timestamp != 0 {
+        fields.insert("timestamp".into(), span.timestamp.into());
+    }
+    if span.duration != 0 {
+        fields.insert("duration".into(), span.duration.into());
+    }
+    if let Some(endpoint) = &span.local_endpoint {
+        fields.insert("localEndpoint".into(), endpoint_json(endpoint));
+    }
+    if let Some(endpoint) = &span.remote_endpoint {
+        fields.insert("remoteEndpoint".into(), endpoint_json(endpoint));
+    }
+    if !span.annotations.is_empty() {
+        let annotations = span
+            .annotations
+            .iter()
+            .map(|annotation| {
+                let mut fields = Map::new();
+                fields.insert("timestamp".into(), annotation.timestamp.into());
+                fields.insert("value".into(), annotation.value.clone().into());
+                Value::Object(fields)
+            })
+            .collect();
+        fields.insert("annotations".into(), Value::Array(annotations));
+    }
+    if !span.tags.is_empty() {
+        let tags = span
+            .tags
+            .iter()
+            .map(|(key, value)| (key.clone(), Value::from(value.clone())))
+            .collect();
+        fields.insert("tags".into(), Value::Object(tags));
+    }
+    if span.debug {
+        fields.insert("debug".into(), true.into());
+    }
+    if span.shared {
+        fields.insert("shared".into(), true.into());
+    }
+    Value::Object(fields)
+}
+
+/// Encodes spans as a JSON array of Zipkin v2 spans
+pub fn encode_json(spans: &[Span]) -> Vec<u8> {
+    Value::Array(spans.iter().map(span_json).collect())
+        .to_string()
+        .into_bytes()
+}
@@ -0,0 +1,226 @@
+// Copyright 2020-2022, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{decode_json, decode_proto, from_spans, ZipkinError};
+use crate::common::DEFAULT_MAX_MESSAGE_SIZE;
+use crate::trace::TraceService;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// The path Zipkin reporters post spans to
+pub const SPANS_PATH: &str = "/api/v2/spans";
+
+/// The content type of `ListOfSpans` bodies, any other is decoded as JSON
+const PROTOBUF_CONTENT_TYPE: &str = "application/x-protobuf";
+
+fn respond(status: StatusCode, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(body.into()));
+    *response.status_mut() = status;
+    response
+}
+
+/// Receives Zipkin v2 spans posted over HTTP and delivers them through a
+/// trace service. JSON and protobuf bodies are accepted, optionally gzip
+/// compressed. Requests that cannot be decoded are rejected and counted.
+pub struct ZipkinReceiver<S> {
+    service: S,
+    max_body_size: usize,
+    rejected: AtomicU64,
+}
+
+impl<S: TraceService> ZipkinReceiver<S> {
+    /// Creates a receiver delivering through a trace service
+    pub fn new(service: S) -> Self {
+        ZipkinReceiver {
+            service,
+            max_body_size: DEFAULT_MAX_MESSAGE_SIZE,
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Limits the size of request bodies, after decompression
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// The number of requests rejected because their spans could not be
+    /// decoded
+    pub fn rejected_requests(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Decodes a body of the given content type and delivers its spans
+    pub async fn ingest(&self, content_type: Option<&str>, body: &[u8]) -> Result<(), ZipkinError> {
+        let protobuf = content_type.is_some_and(|content_type| {
+            content_type
+                .split(';')
+                .next()
+                .is_some_and(|mime| mime.trim().eq_ignore_ascii_case(PROTOBUF_CONTENT_TYPE))
+        });
+        let spans = if protobuf {
+            decode_proto(body)
+        } else {
+            decode_json(body)
+        };
+        let request = match spans.and_then(|spans| from_spans(&spans)) {
+            Ok(request) => request,
+            Err(e) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+        if request.resource_spans.is_empty() {
+            return Ok(());
+        }
+        self.service.export(tonic::Request::new(request)).await?;
+        Ok(())
+    }
+
+    async fn handle(&self, request: Request<Incoming>) -> Response<Full<Bytes>> {
+        if request.uri().path() != SPANS_PATH {
+            return respond(StatusCode::NOT_FOUND, "not found");
+        }
+        if request.method() != Method::POST {
+            return respond(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+        }
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value: &hyper::header::HeaderValue| value.to_str().ok())
+                .map(ToString::to_string)
+        };
+        let content_type = header(CONTENT_TYPE);
+        let gzip = match header(CONTENT_ENCODING).as_deref() {
+            None | Some("identity") => false,
+            Some("gzip") => true,
+            Some(_) => {
+                return respond(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported encoding");
+            }
+        };
+        let body = match Limited::new(request.into_body(), self.max_body_size)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(e) if e.is::<LengthLimitError>() => {
+                return respond(StatusCode::PAYLOAD_TOO_LARGE, "body too large");
+            }
+            Err(e) => return respond(StatusCode::BAD_REQUEST, e.to_string()),
+        };
+        let body = if gzip {
+            let mut decompressed = Vec::new();
+            let limit = u64::try_from(self.max_body_size).unwrap_or(u64::MAX);
+            if let Err(e) = flate2::read::GzDecoder::new(body.as_ref())
+                .take(limit.saturating_add(1))
+                .read_to_end(&mut decompressed)
+            {
+                return respond(StatusCode::BAD_REQUEST, e.to_string());
+            }
+            if decompressed.len() > self.max_body_size {
+                return respond(StatusCode::PAYLOAD_TOO_LARGE, "body too large");
+            }
+            Bytes::from(decompressed)
+        } else {
+            body
+        };
+        match self.ingest(content_type.as_deref(), &body).await {
+            Ok(()) => respond(StatusCode::ACCEPTED, Bytes::new()),
+            Err(e @ ZipkinError::Receive(_)) => {
+                respond(StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+            }
+            Err(e) => respond(StatusCode::BAD_REQUEST, e.to_string()),
+        }
+    }
+
+    /// Accepts connections and serves HTTP/1.1 requests on each until
+    /// accepting fails
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> std::io::Result<Infallible> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let receiver = self.clone();
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(move |request| {
+                    let receiver = receiver.clone();
+                    async move { Ok::<_, Infallible>(receiver.handle(request).await) }
+                });
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::all::{OpenTelemetryEvents, TraceServiceForwarder};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn post(
+        address: std::net::SocketAddr,
+        path: &str,
+        body: &str,
+    ) -> std::io::Result<String> {
+        let mut stream = tokio::net::TcpStream::connect(address).await?;
+        let request = format!(
+            "POST {path} HTTP/1.1\r\nhost: localhost\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    pub async fn receive_json_spans() -> Result<(), Box<dyn std::error::Error>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let receiver = Arc::new(ZipkinReceiver::new(TraceServiceForwarder::with_sender(tx)));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(receiver.clone().serve(listener));
+
+        let body = r#"[{"traceId":"0123456789abcdef","id":"0000000000000001","name":"get","timestamp":1000,"duration":5,"localEndpoint":{"serviceName":"web"}}]"#;
+        let response = post(address, SPANS_PATH, body).await?;
+        assert!(response.starts_with("HTTP/1.1 202"), "{response}");
+        let Some(OpenTelemetryEvents::Trace(request, _)) = rx.recv().await else {
+            return Err("no trace delivered".into());
+        };
+        assert_eq!(
+            request
+                .spans()
+                .map(|(_, _, span)| span.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["get"]
+        );
+
+        let response = post(address, SPANS_PATH, "{}").await?;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        assert_eq!(receiver.rejected_requests(), 1);
+        let response = post(address, "/api/v1/spans", body).await?;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+        Ok(())
+    }
+}